@@ -3,7 +3,7 @@
 set -e
 cd "`dirname $0`"
 
-RUSTFLAGS='-C link-arg=-s' cargo build --all --target wasm32-unknown-unknown --release
+RUSTFLAGS='-C link-arg=-s' cargo build -p quid --target wasm32-unknown-unknown --release
 
 mkdir res
 
@@ -0,0 +1,469 @@
+/*  Deterministic, off-chain replay of the protocol's risk engine: a
+ *  price path drives the same volatility estimate, solvency, scale,
+ *  premium, shrink and liquidation math that the contract runs, over
+ *  synthetic borrowers and SolvencyPool deposits, so parameters can
+ *  be tried out before they're proposed on-chain. Pools are the
+ *  contract's own, and so is every formula (see quid::math); what's
+ *  not modelled: turns against individual Pledges (the SolvencyPool
+ *  and then the DeadPool take the other side of every trade, as in
+ *  `redeem` and `invert`), liquidation protection by SP deposits,
+ *  and DeadPool absorption by SolvencyProviders. Keepers are assumed
+ *  to `clip` every Pledge below min CR at each update.
+ */
+use std::collections::VecDeque;
+
+use quid::config::Config;
+use quid::math::*;
+use quid::pool::{DeadPool, LongPod, Pod, Pool, ShortPod};
+use quid::units::*;
+use quid::utils::*;
+
+pub enum Borrower { // same units as the Pledge side it stands for
+    Long(LongPod),
+    Short(ShortPod),
+}
+
+// state of the protocol after an update, one line of output
+#[derive(Debug, Clone)]
+pub struct Row {
+    pub timestamp: u64, // nanosecs
+    pub price: Price,
+    pub vol: u128, // annualized, fractions are 1e24-scaled like amounts
+    pub solvency_long: u128,
+    pub solvency_short: u128,
+    pub scale_long: u128,
+    pub scale_short: u128,
+    pub premiums_long: NearAmount, // this update
+    pub premiums_short: QdAmount, // this update
+    pub shrunk: u32,
+    pub liquidated: u32,
+    pub blood: Pod<QdAmount, NearAmount>,
+    pub live: Pool,
+    pub dead: DeadPool,
+    pub gfund: Pool,
+} impl Row {
+    pub fn header() -> String {
+        let mut cols = vec![
+            "timestamp", "price", "vol", "solvency_long", "solvency_short",
+            "scale_long", "scale_short", "premiums_long", "premiums_short",
+            "shrunk", "liquidated", "blood_credit", "blood_debit"
+        ].into_iter().map(String::from).collect::<Vec<_>>();
+        for pool in ["live", "dead", "gfund"] {
+            for pod in ["long_credit", "long_debit", "short_credit", "short_debit"] {
+                cols.push(format!("{}_{}", pool, pod));
+            }
+        }
+        cols.join(",")
+    }
+
+    pub fn csv(&self) -> String {
+        let mut cols = vec![
+            self.timestamp.to_string(), self.price.to_string(), self.vol.to_string(),
+            self.solvency_long.to_string(), self.solvency_short.to_string(),
+            self.scale_long.to_string(), self.scale_short.to_string(),
+            self.premiums_long.to_string(), self.premiums_short.to_string(),
+            self.shrunk.to_string(), self.liquidated.to_string(),
+            self.blood.credit.to_string(), self.blood.debit.to_string()
+        ];
+        cols.extend(pool_csv(&self.live));
+        cols.extend(pool_csv(&self.dead));
+        cols.extend(pool_csv(&self.gfund));
+        cols.join(",")
+    }
+}
+
+// a Pool's four amounts, in whichever units its Pods hold them
+fn pool_csv<A, B, C, D>(pool: &Pool<Pod<A, B>, Pod<C, D>>) -> [String; 4]
+where A: ToString, B: ToString, C: ToString, D: ToString {
+    [pool.long.credit.to_string(), pool.long.debit.to_string(),
+     pool.short.credit.to_string(), pool.short.debit.to_string()]
+}
+
+// same estimate as the contract's Volatility, over a deque instead of a History
+struct Closes {
+    closes: VecDeque<(u128, u64)>,
+    sum: u128,
+    annual: u128, // scaled by VOL_SCALE
+} impl Closes {
+    fn new() -> Self {
+        Self { closes: VecDeque::new(), sum: 0, annual: DEFAULT_VOL }
+    }
+
+    fn observe(&mut self, price: u128, now: u64) {
+        if let Some(&(last, last_time)) = self.closes.back() {
+            if now.saturating_sub(last_time) < VOL_INTERVAL {
+                return;
+            }
+            if self.closes.len() as u64 == VOL_WINDOW {
+                let (from, from_time) = self.closes.pop_front().unwrap();
+                let (to, to_time) = self.closes[0];
+                self.sum -= squared_return(from, from_time, to, to_time);
+            }
+            self.sum += squared_return(last, last_time, price, now);
+        }
+        self.closes.push_back((price, now));
+        let len = self.closes.len() as u64;
+        if len >= VOL_MIN_CLOSES {
+            self.annual = realized_vol(self.sum, len - 1);
+        }
+    }
+
+    fn annual(&self) -> u128 {
+        self.annual * (ONE / VOL_SCALE)
+    }
+}
+
+pub struct Sim {
+    pub config: Config,
+    pub target_long: u128, // voted solvency targets, 0 when nobody voted
+    pub target_short: u128,
+    pub price: Price,
+    pub borrowers: Vec<Borrower>,
+    pub blood: Pod<QdAmount, NearAmount>,
+    pub live: Pool,
+    pub dead: DeadPool,
+    pub gfund: Pool,
+    vol: Closes,
+    last_update: Option<u64>,
+    solvency: (u128, u128), // long, short
+    scale: (u128, u128),
+} impl Sim {
+    pub fn new(config: Config, price: Price) -> Self {
+        Self {
+            config,
+            target_long: 0,
+            target_short: 0,
+            price,
+            borrowers: vec![],
+            blood: Pod::default(),
+            live: Pool::new(),
+            dead: DeadPool::new(),
+            gfund: Pool::new(),
+            vol: Closes::new(),
+            last_update: None,
+            solvency: (0, 0),
+            scale: (ONE, ONE),
+        }
+    }
+
+    // SolvencyPool deposit
+    pub fn provide(&mut self, near: NearAmount, qd: QdAmount) {
+        self.blood.debit += near;
+        self.blood.credit += qd;
+    }
+
+    // borrow QD at `cr` against NEAR collateral
+    pub fn borrow_long(&mut self, collateral: NearAmount, cr: Ratio) {
+        assert!(cr >= self.config.min_cr, "{}", ERR_BELOW_MIN_CR);
+        let debt = collateral / cr * self.price;
+        self.live.long.credit += collateral;
+        self.live.long.debit += debt;
+        self.borrowers.push(Borrower::Long(Pod::new(collateral, debt)));
+    }
+
+    // borrow NEAR at `cr` against QD collateral
+    pub fn borrow_short(&mut self, collateral: QdAmount, cr: Ratio) {
+        assert!(cr >= self.config.min_cr, "{}", ERR_BELOW_MIN_CR);
+        let debt = collateral / cr / self.price;
+        self.live.short.credit += collateral;
+        self.live.short.debit += debt;
+        self.borrowers.push(Borrower::Short(Pod::new(collateral, debt)));
+    }
+
+    // feed the next price, returns a Row whenever an update was due
+    pub fn step(&mut self, price: Price, now: u64) -> Option<Row> {
+        assert!(!price.is_zero(), "{}", ERR_PRICE_ZERO);
+        self.price = price;
+        self.vol.observe(price.0, now);
+        let due = match self.last_update {
+            Some(last) => now.saturating_sub(last) >= self.config.update_interval,
+            None => true,
+        };
+        if due {
+            self.last_update = Some(now);
+            Some(self.update(now))
+        } else {
+            None
+        }
+    }
+
+    fn update(&mut self, now: u64) -> Row {
+        let (ivol, price) = (self.vol.annual(), self.price);
+        let val_sp = (self.blood.credit + self.blood.debit * price).0;
+        // sides without borrowers (or an SCR to speak of) keep their last figures
+        let long = ((self.live.long.credit * price).0, self.live.long.debit.0);
+        if long.1 > 0 {
+            if let Some(s) = solvency(val_sp, long.0, long.1, ivol, false) {
+                self.solvency.0 = s;
+                self.scale.0 = scale(self.target_long, s, self.config.min_scale, self.config.max_scale);
+            }
+        }
+        let short = ((self.live.short.debit * price).0, self.live.short.credit.0);
+        if short.0 > 0 {
+            if let Some(s) = solvency(val_sp, short.0, short.1, ivol, true) {
+                self.solvency.1 = s;
+                self.scale.1 = scale(self.target_short, s, self.config.min_scale, self.config.max_scale);
+            }
+        }
+        let (mut shrunk, mut liquidated) = (0, 0);
+        let (mut premiums_long, mut premiums_short) = (NearAmount::ZERO, QdAmount::ZERO);
+        let mut borrowers = std::mem::take(&mut self.borrowers);
+        for b in borrowers.iter_mut() {
+            let (cr, short) = match b {
+                Borrower::Long(pod) if !pod.debit.is_zero() => (computeCR(price, pod.credit, pod.debit), false),
+                Borrower::Short(pod) if !pod.debit.is_zero() => (computeCR(price, pod.credit, pod.debit), true),
+                _ => continue,
+            };
+            if cr < self.config.kill_cr {
+                match b {
+                    Borrower::Long(pod) => {
+                        Pool::long_snatch(&mut self.live, &mut self.dead, &mut self.gfund,
+                            pod.debit, pod.credit, price);
+                        *pod = Pod::default();
+                    }
+                    Borrower::Short(pod) => {
+                        Pool::short_snatch(&mut self.live, &mut self.dead, &mut self.gfund,
+                            pod.debit, pod.credit, price);
+                        *pod = Pod::default();
+                    }
+                }
+                liquidated += 1;
+                continue;
+            }
+            if cr < self.config.min_cr {
+                match b {
+                    Borrower::Long(pod) => self.long_shrink(pod),
+                    Borrower::Short(pod) => self.short_shrink(pod),
+                }
+                shrunk += 1;
+            }
+            let (val_near, qd) = match b {
+                Borrower::Long(pod) => ((pod.credit * price).0, pod.debit.0),
+                Borrower::Short(pod) => ((pod.debit * price).0, pod.credit.0),
+            };
+            let scale = if short { self.scale.1 } else { self.scale.0 };
+            let quote = quote(val_near, qd, ratio(ivol, ivol, ONE), scale, short); // NEAR alone
+            let due = QdAmount(self.config.per_period(quote.premiums));
+            match b {
+                Borrower::Long(pod) => {
+                    let due = std::cmp::min(due / price, pod.credit);
+                    pod.credit -= due;
+                    Pool::long_premium(&mut self.live, &mut self.dead, &mut self.gfund,
+                        due, self.config.gf_cut(due));
+                    premiums_long += due;
+                }
+                Borrower::Short(pod) => {
+                    let due = std::cmp::min(due, pod.credit);
+                    pod.credit -= due;
+                    Pool::short_premium(&mut self.live, &mut self.dead, &mut self.gfund,
+                        due, self.config.gf_cut(due));
+                    premiums_short += due;
+                }
+            }
+        }
+        self.borrowers = borrowers;
+        Row {
+            timestamp: now,
+            price,
+            vol: ivol,
+            solvency_long: self.solvency.0,
+            solvency_short: self.solvency.1,
+            scale_long: self.scale.0,
+            scale_short: self.scale.1,
+            premiums_long,
+            premiums_short,
+            shrunk,
+            liquidated,
+            blood: self.blood.clone(),
+            live: self.live.clone(),
+            dead: self.dead.clone(),
+            gfund: self.gfund.clone(),
+        }
+    }
+
+    // the contract's `shrink` of a long, trading against the SolvencyPool and then the DeadPool
+    fn long_shrink(&mut self, pod: &mut LongPod) { // NEAR collateral sold for QD to repay
+        let delta = shrinkage(pod.credit * self.price, pod.debit, self.config.min_cr);
+        let delta_near = delta / self.price;
+        pod.credit -= delta_near;
+        pod.debit -= delta;
+        self.live.long.credit -= delta_near;
+        self.live.long.debit -= delta;
+        let min = std::cmp::min(self.blood.credit, delta);
+        self.blood.credit -= min;
+        self.blood.debit += min / self.price;
+        let rest = delta - min;
+        if !rest.is_zero() {
+            self.dead.long.credit += rest;
+            self.dead.long.debit += rest / self.price;
+        }
+    }
+
+    // same for a short
+    fn short_shrink(&mut self, pod: &mut ShortPod) { // QD collateral sold for NEAR to repay
+        let delta = shrinkage(pod.credit, pod.debit * self.price, self.config.min_cr);
+        let delta_near = delta / self.price;
+        pod.credit -= delta;
+        pod.debit -= delta_near;
+        self.live.short.credit -= delta;
+        self.live.short.debit -= delta_near;
+        let min = std::cmp::min(self.blood.debit, delta_near);
+        self.blood.debit -= min;
+        self.blood.credit += min * self.price;
+        let rest = delta_near - min;
+        if !rest.is_zero() {
+            self.dead.short.credit += rest;
+            self.dead.short.debit += rest * self.price;
+        }
+    }
+}
+
+// xorshift64*, so that runs with the same seed are the same runs
+pub struct Rng(u64);
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed })
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    // uniform in [lo, hi)
+    pub fn between(&mut self, lo: f64, hi: f64) -> f64 {
+        lo + (hi - lo) * (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// what to populate the protocol with before replaying prices
+pub struct Population {
+    pub borrowers: u32,
+    pub short_share: f64, // fraction of borrowers that go short
+    pub max_cr: f64, // borrowers open between min CR and this
+    pub collateral: f64, // mean collateral per borrower, in QD
+    pub sp_share: f64, // SolvencyPool deposits as a fraction of collateral
+} impl Default for Population {
+    fn default() -> Self {
+        Self { borrowers: 100, short_share: 0.3, max_cr: 2.0, collateral: 1000.0, sp_share: 0.5 }
+    }
+}
+
+fn to_balance(amt: f64) -> u128 {
+    (amt * 1e6).round() as u128 * (ONE / 1_000_000)
+}
+
+pub fn populate(sim: &mut Sim, pop: &Population, seed: u64) {
+    let mut rng = Rng::new(seed);
+    let min_cr = sim.config.min_cr.0 as f64 / ONE as f64;
+    let mut total = 0.0;
+    for _ in 0..pop.borrowers {
+        let short = rng.between(0.0, 1.0) < pop.short_share;
+        let qd = rng.between(0.5, 1.5) * pop.collateral;
+        let cr = Ratio(to_balance(rng.between(min_cr, pop.max_cr.max(min_cr))));
+        let collateral = QdAmount(to_balance(qd));
+        if short {
+            sim.borrow_short(collateral, cr);
+        } else {
+            sim.borrow_long(collateral / sim.price, cr);
+        }
+        total += qd;
+    }
+    let half = QdAmount(to_balance(total * pop.sp_share / 2.0));
+    sim.provide(half / sim.price, half);
+}
+
+// `timestamp,price` per line, secs and QD per NEAR, a header is optional
+pub fn parse_prices(csv: &str) -> Result<Vec<(u64, Price)>, String> {
+    let mut path = vec![];
+    for (n, line) in csv.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut cols = line.split(',').map(str::trim);
+        let (ts, px) = (cols.next().unwrap_or(""), cols.next().unwrap_or(""));
+        match (ts.parse::<u64>(), px.parse::<f64>()) {
+            (Ok(ts), Ok(px)) if px > 0.0 => path.push((ts * 1_000_000_000, Price(to_balance(px)))),
+            _ if n == 0 => continue, // header
+            _ => return Err(format!("line {}: expected timestamp,price but got {}", n + 1, line)),
+        }
+    }
+    Ok(path)
+}
+
+pub fn run(sim: &mut Sim, path: &[(u64, Price)]) -> Vec<Row> {
+    path.iter().filter_map(|&(now, price)| sim.step(price, now)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 3600;
+
+    fn path(prices: &[f64]) -> Vec<(u64, Price)> {
+        prices.iter().enumerate()
+            .map(|(i, p)| (i as u64 * HOUR * 1_000_000_000, Price(to_balance(*p))))
+            .collect()
+    }
+
+    fn sim(seed: u64) -> Sim {
+        let mut sim = Sim::new(Config::new(), Price(to_balance(5.0)));
+        populate(&mut sim, &Population::default(), seed);
+        sim
+    }
+
+    #[test]
+    fn parses_prices() {
+        let prices = parse_prices("timestamp,price\n0,5.0\n\n3600, 4.5\n").unwrap();
+        assert_eq!(prices, vec![(0, Price(5 * ONE)), (HOUR * 1_000_000_000, Price(45 * ONE / 10))]);
+        assert!(parse_prices("0,5\nfoo,4").is_err());
+    }
+
+    #[test]
+    fn deterministic() {
+        let prices = path(&[5.0; 48]);
+        let (a, b) = (run(&mut sim(7), &prices), run(&mut sim(7), &prices));
+        assert_eq!(a.len(), 6); // every 8 hours
+        assert_eq!(a.last().unwrap().csv(), b.last().unwrap().csv());
+        assert_ne!(a.last().unwrap().csv(), run(&mut sim(8), &prices).last().unwrap().csv());
+    }
+
+    #[test]
+    fn flat_prices_only_charge_premiums() {
+        let mut sim = sim(1);
+        let before = (sim.live.long.credit, sim.live.short.credit);
+        let rows = run(&mut sim, &path(&[5.0; 48]));
+        assert!(rows.iter().all(|r| r.shrunk == 0 && r.liquidated == 0));
+        let (long, short) = rows.iter()
+            .fold((NearAmount::ZERO, QdAmount::ZERO), |acc, r| (acc.0 + r.premiums_long, acc.1 + r.premiums_short));
+        assert!(!long.is_zero() && !short.is_zero());
+        assert_eq!(sim.live.long.credit, before.0 - long);
+        assert_eq!(sim.live.short.credit, before.1 - short);
+        // each premium is cut separately, so this may round down by a yocto per borrower
+        let cut: NearAmount = rows.iter().map(|r| sim.config.gf_cut(r.premiums_long)).sum();
+        assert!(sim.gfund.long.credit <= cut);
+        assert!((cut - sim.gfund.long.credit).0 <= (rows.len() * sim.borrowers.len()) as u128);
+    }
+
+    #[test]
+    fn crash_shrinks_and_liquidates() {
+        let mut prices = vec![5.0; 8];
+        prices.extend((0..40).map(|i| 5.0 * (1.0 - 0.02 * i as f64)));
+        let mut sim = sim(3);
+        let rows = run(&mut sim, &path(&prices));
+        assert!(rows.iter().map(|r| r.shrunk).sum::<u32>() > 0);
+        assert!(rows.iter().map(|r| r.liquidated).sum::<u32>() > 0);
+        assert!(!sim.gfund.long.debit.is_zero()); // shortfall of liquidated longs
+        // pooled collateral always matches what's left with borrowers
+        let live: NearAmount = sim.borrowers.iter()
+            .filter_map(|b| match b { Borrower::Long(pod) => Some(pod.credit), _ => None })
+            .sum();
+        assert_eq!(live, sim.live.long.credit);
+    }
+}
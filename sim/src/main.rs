@@ -0,0 +1,77 @@
+/*  quid-sim <prices.csv> [--min-cr 1.1] [--kill-cr 1.0] [--fee 0.009]
+ *           [--min-scale 0.042] [--max-scale 4.2] [--interval-hours 8]
+ *           [--target-long -1] [--target-short -1] [--borrowers 100]
+ *           [--short-share 0.3] [--max-cr 2.0] [--sp-share 0.5] [--seed 42]
+ *
+ *  Replays `timestamp,price` rows (secs, QD per NEAR) and prints one
+ *  CSV row per update; amounts are in yocto units, like on-chain
+ */
+use std::process::exit;
+
+use quid::config::Config;
+use quid::units::Ratio;
+use quid_sim::*;
+
+const ONE: f64 = 1e24;
+
+fn usage(err: &str) -> ! {
+    eprintln!("{}", err);
+    eprintln!("usage: quid-sim <prices.csv> [--min-cr 1.1] [--kill-cr 1.0] [--fee 0.009] \
+        [--min-scale 0.042] [--max-scale 4.2] [--interval-hours 8] [--target-long -1] \
+        [--target-short -1] [--borrowers 100] [--short-share 0.3] [--max-cr 2.0] \
+        [--sp-share 0.5] [--seed 42]");
+    exit(2);
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let mut config = Config::new();
+    let mut pop = Population::default();
+    let (mut target_long, mut target_short) = (0, 0); // nobody voted
+    let mut seed = 42;
+    let mut file = None;
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            file = Some(arg);
+            continue;
+        }
+        let val: f64 = match args.next().map(|v| v.parse()) {
+            Some(Ok(val)) => val,
+            _ => usage(&format!("{} expects a number", arg)),
+        };
+        match arg.as_str() {
+            "--min-cr" => config.min_cr = Ratio((val * ONE) as u128),
+            "--kill-cr" => config.kill_cr = Ratio((val * ONE) as u128),
+            "--fee" => config.fee = Ratio((val * ONE) as u128),
+            "--min-scale" => config.min_scale = (val * ONE) as u128,
+            "--max-scale" => config.max_scale = (val * ONE) as u128,
+            "--interval-hours" => config.update_interval = (val * 3.6e12) as u64,
+            "--target-long" => target_long = (val.max(0.0) * ONE) as u128,
+            "--target-short" => target_short = (val.max(0.0) * ONE) as u128,
+            "--borrowers" => pop.borrowers = val as u32,
+            "--short-share" => pop.short_share = val,
+            "--max-cr" => pop.max_cr = val,
+            "--sp-share" => pop.sp_share = val,
+            "--seed" => seed = val as u64,
+            _ => usage(&format!("unknown flag {}", arg)),
+        }
+    }
+    if config.kill_cr >= config.min_cr || config.update_interval == 0 {
+        usage("kill CR must be below min CR, and the interval above 0");
+    }
+    let file = file.unwrap_or_else(|| usage("missing prices.csv"));
+    let csv = std::fs::read_to_string(&file)
+        .unwrap_or_else(|e| usage(&format!("can't read {}: {}", file, e)));
+    let path = parse_prices(&csv).unwrap_or_else(|e| usage(&e));
+    if path.is_empty() {
+        usage("no prices to replay");
+    }
+    let mut sim = Sim::new(config, path[0].1);
+    sim.target_long = target_long;
+    sim.target_short = target_short;
+    populate(&mut sim, &pop, seed);
+    println!("{}", Row::header());
+    for row in run(&mut sim, &path) {
+        println!("{}", row.csv());
+    }
+}
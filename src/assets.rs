@@ -0,0 +1,583 @@
+use crate::*;
+
+use near_sdk::{env, serde_json, Balance, Promise, PromiseResult};
+use near_sdk::serde::Serialize;
+use near_sdk::json_types::U128;
+
+/*  Collateral besides NEAR: NEP-141 tokens (staked NEAR like stNEAR, or
+ *  wNEAR) that the owner lists, each with its own Oracle (fed by the same
+ *  reporters as NEAR's, the median of their fresh reports), volatility and
+ *  haircut. Tokens sent in with `ft_transfer_call` land in the basket of
+ *  the sender's long side, where they count towards its CR at their
+ *  price less the haircut, on top of its NEAR. Shrinks and deleverages
+ *  only ever sell NEAR (a long side that doesn't have enough of it gets
+ *  liquidated instead), and premiums come out of the basket for what its
+ *  NEAR can't pay. Unlike NEAR, assets don't get pools of their own:
+ *  the trees sort longs by their basket's worth in NEAR as of their
+ *  last save (so redemptions reach them, for their NEAR only), and as
+ *  that worth moves with more than NEAR's price, they're also kept in
+ *  `baskets` for keepers to `clip` one by one (see `get_basket_pledges`).
+ *  Liquidated baskets, premiums and withdrawal fees go to each asset's
+ *  `dead` balance, held by the protocol against the debt that the
+ *  GuaranteeFund took on for them (the DeadPool only takes NEAR and QD),
+ *  which anyone may buy (`buy_asset`) to repay it with.
+ *  Listed tokens are assumed to have 24 decimals
+ */
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Asset {
+    pub oracle: Oracle, // its price, QD per token, 1e24-scaled
+    pub vol: u128, // annualized, scaled by VOL_SCALE
+    pub haircut: Balance, // fraction of the price that isn't counted, 1e24-scaled
+    pub live: Balance, // in baskets of Pledges
+    pub dead: Balance, // seized from liquidated Pledges, or paid as premiums
+} impl Asset {
+    pub fn new(token: &AccountId) -> Self {
+        Self {
+            // tokens can't have a '/' in them, so no prefix is another's prefix
+            oracle: Oracle::new([b"n", token.as_bytes(), b"/"].concat()),
+            vol: 0, haircut: 0, live: 0, dead: 0
+        }
+    }
+
+    pub fn price(&self) -> Balance {
+        self.oracle.price.0
+    }
+
+    pub fn is_stale(&self) -> bool {
+        self.oracle.is_stale()
+    }
+
+    // QD value of `amt` of this asset, less the haircut
+    pub fn value(&self, amt: Balance) -> Balance {
+        ratio(ONE - self.haircut, ratio(self.price(), amt, ONE), ONE)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AssetView {
+    pub token: AccountId,
+    pub price: U128,
+    pub last: u64,
+    pub vol: U128,
+    pub haircut: U128,
+    pub live: U128,
+    pub dead: U128,
+    pub stale: bool,
+}
+
+impl AssetView {
+    fn of(token: AccountId, asset: &Asset) -> Self {
+        Self {
+            token,
+            price: asset.price().into(),
+            last: asset.oracle.last,
+            vol: asset.vol.into(),
+            haircut: asset.haircut.into(),
+            live: asset.live.into(),
+            dead: asset.dead.into(),
+            stale: asset.is_stale(),
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract
+{
+    // lists `token` as collateral (or updates it), its price is reported separately
+    #[payable]
+    pub fn set_asset(&mut self, token: ValidAccountId, haircut: U128, vol: U128) {
+        assert_one_yocto();
+        self.assert_owner();
+        let token: AccountId = token.into();
+        let (haircut, vol): (Balance, u128) = (haircut.into(), vol.into());
+        check(haircut <= MAX_HAIRCUT, "asset_haircut");
+        check(vol > 0 && vol <= MAX_ASSET_VOL, "asset_vol");
+        let mut asset = self.assets.get(&token).unwrap_or_else(|| Asset::new(&token));
+        changed(&format!("{}_haircut", token), asset.haircut, haircut);
+        changed(&format!("{}_vol", token), asset.vol, vol);
+        asset.haircut = haircut;
+        asset.vol = vol;
+        self.assets.insert(&token, &asset);
+    }
+
+    // whoever may report NEAR's price may report the asset's, into its own Oracle
+    pub fn report_asset_price(&mut self, token: ValidAccountId, price: U128) {
+        let reporter = env::predecessor_account_id();
+        assert!(self.oracle.is_reporter(&reporter), "{}", ERR_NOT_REPORTER);
+        let token: AccountId = token.into();
+        let mut asset = self.assets.get(&token).expect(ERR_NO_ASSET);
+        asset.oracle.add_reporter(&reporter);
+        asset.oracle.report(&reporter, price.into());
+        self.assets.insert(&token, &asset);
+        Event::AssetPrice { reporter, token, reported: price, price: asset.price().into() }.emit();
+    }
+
+    // drops the reporter's reports from every asset's Oracle too
+    pub(crate) fn remove_asset_reporter(&mut self, reporter: &AccountId) {
+        let tokens: Vec<AccountId> = self.assets.keys().collect();
+        for token in tokens {
+            let mut asset = self.assets.get(&token).unwrap();
+            asset.oracle.remove_reporter(reporter);
+            self.assets.insert(&token, &asset);
+        }
+    }
+
+    pub fn get_asset(&self, token: ValidAccountId) -> Option<AssetView> {
+        let token: AccountId = token.into();
+        self.assets.get(&token).map(|asset| AssetView::of(token, &asset))
+    }
+
+    pub fn get_assets(&self) -> Vec<AssetView> {
+        self.assets.iter().map(|(token, asset)| AssetView::of(token, &asset)).collect()
+    }
+
+    pub fn get_asset_price_history(&self, token: ValidAccountId, from_index: u64, limit: u64) -> Vec<Report> {
+        self.assets.get(token.as_ref()).map(|asset| asset.oracle.history(from_index, limit)).unwrap_or_default()
+    }
+
+    /*  Sends `amount` of `token` from the caller's basket back to them,
+     *  less the fee, as long as their long side stays above MIN_CR. The
+     *  fee is kept as a premium (see `Pool::basket_premium`), the rest
+     *  is put back in the basket if the transfer fails (`on_asset_withdrawn`)
+     */
+    #[payable]
+    pub fn withdraw_asset(&mut self, token: ValidAccountId, amount: U128) -> Promise {
+        assert_one_yocto();
+        self.assert_not_paused();
+        self.assert_fresh_price();
+        let token: AccountId = token.into();
+        let amt: Balance = amount.into();
+        assert!(amt > 0, "{}", ERR_AMT_TOO_LOW);
+        let account = env::predecessor_account_id();
+        let mut pledge = self.fetch_pledge(&account, false);
+        self.assert_fresh_basket(&pledge);
+        let cr_before = self.crs(&pledge);
+        let held = pledge.basket.iter_mut().find(|(t, _)| *t == token).expect(ERR_NO_ASSET);
+        held.1 = held.1.checked_sub(amt).expect(ERR_SUB);
+        pledge.basket.retain(|(_, amt)| *amt > 0);
+        if !pledge.long.debit.is_zero() {
+            assert!(self.long_cr(&pledge) >= self.config.min_cr, "{}", ERR_BELOW_MIN_CR);
+        }
+        let mut asset = self.assets.get(&token).expect(ERR_NO_ASSET);
+        let fee = ratio(self.config.fee.0, amt, ONE);
+        asset.live = asset.live.checked_sub(amt).expect(ERR_SUB);
+        asset.dead = asset.dead.checked_add(fee).expect(ERR_ADD);
+        self.assets.insert(&token, &asset);
+        let worth = QdAmount(ratio(asset.price(), fee, ONE));
+        self.token.internal_deposit(&env::current_account_id(), worth.0);
+        Pool::basket_premium(&mut self.dead, &mut self.gfund, worth, self.config.gf_cut(worth));
+        self.save_pledge(&account, &mut pledge, true, false);
+        Event::Collateral {
+            account: account.clone(), token: token.clone(), deposit: false, amount, cr_before,
+            cr_after: self.crs(&pledge), price: asset.price().into()
+        }.emit();
+        self.debug_invariants();
+        let sent = U128(amt - fee);
+        let transfer = serde_json::json!({ "receiver_id": account, "amount": sent }).to_string();
+        let resolve = serde_json::json!({ "account": account, "token": token, "amount": sent }).to_string();
+        Promise::new(token)
+            .function_call(b"ft_transfer".to_vec(), transfer.into_bytes(), 1, GAS_FOR_FT_TRANSFER)
+            .then(Promise::new(env::current_account_id()).function_call(
+                b"on_asset_withdrawn".to_vec(), resolve.into_bytes(), 0, GAS_FOR_RESOLVE
+            ))
+    }
+
+    /*  Sells `amount` of `token` out of what the protocol holds, at its
+     *  price less the haircut, for QD that gets burned against the QD debt
+     *  of the GuaranteeFund (what's left of it goes to the GuaranteeFund).
+     *  The sale is undone if the transfer fails (see `on_asset_bought`)
+     */
+    #[payable]
+    pub fn buy_asset(&mut self, token: ValidAccountId, amount: U128) -> Promise {
+        assert_one_yocto();
+        self.assert_not_paused();
+        let token: AccountId = token.into();
+        let amt: Balance = amount.into();
+        assert!(amt > 0, "{}", ERR_AMT_TOO_LOW);
+        let account = env::predecessor_account_id();
+        let mut asset = self.assets.get(&token).expect(ERR_NO_ASSET);
+        assert!(!asset.is_stale(), "{}", ERR_STALE_PRICE);
+        asset.dead = asset.dead.checked_sub(amt).expect(ERR_SUB);
+        self.assets.insert(&token, &asset);
+        let qd = QdAmount(asset.value(amt));
+        self.token.internal_withdraw(&account, qd.0);
+        let settled = std::cmp::min(self.gfund.long.debit, qd);
+        self.gfund.long.debit -= settled;
+        self.token.internal_deposit(&env::current_account_id(), (qd - settled).0);
+        self.gfund.short.credit = self.gfund.short.credit
+            .checked_add(qd - settled).expect(ERR_ADD);
+        Event::BuyAsset {
+            account: account.clone(), token: token.clone(), amount, qd: qd.into(),
+            settled: settled.into(), price: asset.price().into()
+        }.emit();
+        self.debug_invariants();
+        let transfer = serde_json::json!({ "receiver_id": account, "amount": amount }).to_string();
+        let resolve = serde_json::json!({
+            "account": account, "token": token, "amount": amount, "qd": U128(qd.0), "settled": U128(settled.0)
+        }).to_string();
+        Promise::new(token)
+            .function_call(b"ft_transfer".to_vec(), transfer.into_bytes(), 1, GAS_FOR_FT_TRANSFER)
+            .then(Promise::new(env::current_account_id()).function_call(
+                b"on_asset_bought".to_vec(), resolve.into_bytes(), 0, GAS_FOR_RESOLVE
+            ))
+    }
+
+    // gives the buyer their QD back, and the GuaranteeFund its debt
+    #[private]
+    pub fn on_asset_bought(&mut self, account: AccountId, token: AccountId, amount: U128, qd: U128, settled: U128) {
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            return;
+        }
+        let mut asset = self.assets.get(&token).expect(ERR_NO_ASSET);
+        asset.dead = asset.dead.checked_add(amount.into()).expect(ERR_ADD);
+        self.assets.insert(&token, &asset);
+        let (qd, settled) = (QdAmount::from(qd), QdAmount::from(settled));
+        // whatever the GuaranteeFund spent since, it owes
+        let back = std::cmp::min(self.gfund.short.credit, qd - settled);
+        self.gfund.short.credit -= back;
+        self.token.internal_withdraw(&env::current_account_id(), back.0);
+        self.gfund.long.debit = self.gfund.long.debit
+            .checked_add(qd - back).expect(ERR_ADD);
+        self.token.internal_deposit(&account, qd.0);
+    }
+
+    #[private]
+    pub fn on_asset_withdrawn(&mut self, account: AccountId, token: AccountId, amount: U128) {
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            return;
+        }
+        self.stash_asset(&account, &token, amount.into());
+    }
+
+    // adds `amt` of a listed `token` to the basket of the account's long side
+    pub(crate) fn stash_asset(&mut self, account: &AccountId, token: &AccountId, amt: Balance) {
+        let mut asset = self.assets.get(token).expect(ERR_NO_ASSET);
+        let mut pledge = self.fetch_pledge(account, true);
+        let cr_before = self.crs(&pledge);
+        match pledge.basket.iter_mut().find(|(t, _)| t == token) {
+            Some(held) => held.1 = held.1.checked_add(amt).expect(ERR_ADD),
+            None => pledge.basket.push((token.clone(), amt)),
+        }
+        asset.live = asset.live.checked_add(amt).expect(ERR_ADD);
+        self.assets.insert(token, &asset);
+        self.save_pledge(account, &mut pledge, true, false);
+        Event::Collateral {
+            account: account.clone(), token: token.clone(), deposit: true, amount: amt.into(),
+            cr_before, cr_after: self.crs(&pledge), price: asset.price().into()
+        }.emit();
+    }
+
+    // moves a liquidated long side's basket out of the LivePool, to be held by the protocol
+    pub(crate) fn seize_basket(&mut self, pledge: &mut Pledge) {
+        for (token, amt) in pledge.basket.drain(..) {
+            let mut asset = self.assets.get(&token).expect(ERR_NO_ASSET);
+            asset.live = asset.live.checked_sub(amt).expect(ERR_SUB);
+            asset.dead = asset.dead.checked_add(amt).expect(ERR_ADD);
+            self.assets.insert(&token, &asset);
+            Event::Seize {
+                account: pledge.id.clone(), token, amount: amt.into(), price: asset.price().into()
+            }.emit();
+        }
+    }
+
+    /*  Pays up to `qd` (at market prices) out of the Pledge's basket, in
+     *  the order its tokens came in, to be held by the protocol until it's
+     *  sold. Returns the QD value paid
+     */
+    pub(crate) fn pay_from_basket(&mut self, pledge: &mut Pledge, qd: QdAmount) -> QdAmount {
+        let mut left = qd;
+        for (token, amt) in pledge.basket.iter_mut() {
+            if left.is_zero() {
+                break;
+            }
+            let mut asset = self.assets.get(token).expect(ERR_NO_ASSET);
+            let paid = std::cmp::min(*amt, ratio(ONE, left.0, asset.price()));
+            *amt -= paid;
+            asset.live = asset.live.checked_sub(paid).expect(ERR_SUB);
+            asset.dead = asset.dead.checked_add(paid).expect(ERR_ADD);
+            self.assets.insert(token, &asset);
+            left = left.saturating_sub(QdAmount(ratio(asset.price(), paid, ONE)));
+        }
+        pledge.basket.retain(|(_, amt)| *amt > 0);
+        qd - left
+    }
+
+    // QD value of the Pledge's basket, after haircuts
+    pub(crate) fn basket_val(&self, pledge: &Pledge) -> QdAmount {
+        pledge.basket.iter().map(|(token, amt)| {
+            let asset = self.assets.get(token).expect(ERR_NO_ASSET);
+            QdAmount(asset.value(*amt))
+        }).sum()
+    }
+
+    // the basket's worth in NEAR, for the math that only knows NEAR collateral
+    pub(crate) fn basket_near(&self, pledge: &Pledge) -> NearAmount {
+        if pledge.basket.is_empty() {
+            return NearAmount::ZERO;
+        }
+        self.basket_val(pledge) / self.get_price()
+    }
+
+    // market value (no haircuts) of each asset in the basket
+    pub(crate) fn basket_worth(&self, pledge: &Pledge) -> Vec<(AccountId, Balance)> {
+        pledge.basket.iter().map(|(token, amt)| {
+            let asset = self.assets.get(token).expect(ERR_NO_ASSET);
+            (token.clone(), ratio(asset.price(), *amt, ONE))
+        }).collect()
+    }
+
+    // CR of the long side, counting its basket
+    pub(crate) fn long_cr(&self, pledge: &Pledge) -> Ratio {
+        computeBasketCR(self.get_price(), pledge.long.credit, self.basket_val(pledge), pledge.long.debit)
+    }
+
+    pub(crate) fn crs(&self, pledge: &Pledge) -> CRs {
+        CRs {
+            long: self.long_cr(pledge).into(),
+            short: computeCR(self.get_price(), pledge.short.credit, pledge.short.debit).into(),
+        }
+    }
+
+    pub(crate) fn assert_fresh_basket(&self, pledge: &Pledge) {
+        for (token, _) in pledge.basket.iter() {
+            let asset = self.assets.get(token).expect(ERR_NO_ASSET);
+            assert!(!asset.is_stale(), "{}", ERR_STALE_PRICE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::*;
+    use near_sdk::test_utils::accounts;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::{testing_env, MockedBlockchain};
+
+    #[test]
+    fn values_after_haircut() {
+        testing_env!(VMContextBuilder::new().block_timestamp(1).build());
+        let mut asset = Asset::new(&"stnear".to_string());
+        asset.haircut = ONE / 4;
+        let reporter: AccountId = accounts(REPORTER).into();
+        asset.oracle.add_reporter(&reporter);
+        asset.oracle.report(&reporter, 6 * ONE);
+        testing_env!(VMContextBuilder::new().block_timestamp(PRICE_MAX_AGE).build());
+        assert_eq!(asset.value(10 * ONE), 45 * ONE);
+        assert!(!asset.is_stale());
+        testing_env!(VMContextBuilder::new().block_timestamp(PRICE_MAX_AGE + 2).build());
+        assert!(asset.is_stale());
+        // 10 NEAR at 5 plus 45 QD of basket against 50 QD
+        let (price, near) = (Price(5 * ONE), NearAmount(10 * ONE));
+        assert_eq!(computeBasketCR(price, near, QdAmount(45 * ONE), QdAmount(50 * ONE)), Ratio(19 * ONE / 10));
+        assert_eq!(computeBasketCR(price, near, QdAmount::ZERO, QdAmount(50 * ONE)), Ratio::ONE);
+        assert_eq!(computeBasketCR(price, NearAmount::ZERO, QdAmount(45 * ONE), QdAmount::ZERO), Ratio::MAX);
+    }
+
+    // Alice's 100 stNEAR, at 5.5 less a 10% haircut (495 QD), backing 400 QD
+    fn staked_long() -> (Harness, MockToken) {
+        let mut h = Harness::new(PRICE);
+        let mut stnear = MockToken::new("stnear");
+        let id = stnear.id.clone();
+        h.call(OWNER, 1, |c| c.set_asset(id.clone(), U128(ONE / 10), U128(DEFAULT_VOL)));
+        h.call(REPORTER, 0, |c| c.report_asset_price(id, U128(55 * ONE / 10)));
+        stnear.mint(ALICE, 100 * ONE);
+        assert_eq!(h.transfer_asset(&mut stnear, ALICE, 100 * ONE, STAKE), 0);
+        assert_eq!(h.events("collateral"), 1);
+        h.call(ALICE, 1, |c| c.borrow(U128(400 * ONE), false));
+        (h, stnear)
+    }
+
+    // no single reporter sets stNEAR's price, and the ones that are removed stop counting
+    #[test]
+    fn asset_price_is_a_median() {
+        let (mut h, stnear) = staked_long();
+        let id = stnear.id.clone();
+        h.call(OWNER, 1, |c| c.add_reporter(accounts(CAROL)));
+        h.call(OWNER, 1, |c| c.add_reporter(accounts(DAVE)));
+        h.call(CAROL, 0, |c| c.report_asset_price(id.clone(), U128(6 * ONE)));
+        h.call(DAVE, 0, |c| c.report_asset_price(id.clone(), U128(100 * ONE)));
+        assert_eq!(h.event("asset_price")["price"], (6 * ONE).to_string());
+        assert_eq!(h.view(|c| c.get_asset(id.clone())).unwrap().price.0, 6 * ONE);
+        h.call(OWNER, 1, |c| c.remove_reporter(accounts(DAVE)));
+        assert_eq!(h.view(|c| c.get_asset(id.clone())).unwrap().price.0, 575 * ONE / 100);
+        let history = h.view(|c| c.get_asset_price_history(id.clone(), 0, 10));
+        let prices: Vec<Balance> = history.iter().map(|r| r.price.0).collect();
+        assert_eq!(prices, vec![55 * ONE / 10, 575 * ONE / 100, 6 * ONE, 575 * ONE / 100]);
+        assert_eq!(h.view(|c| c.get_price()), Price(PRICE)); // NEAR's is its own
+    }
+
+    #[test]
+    fn borrows_against_stnear() {
+        let (h, stnear) = staked_long();
+        let pledge = h.pledge(ALICE).unwrap();
+        assert_eq!((pledge.credit.0, pledge.debit.0), (0, 400 * ONE));
+        assert_eq!(pledge.basket[0], ("stnear".to_string(), U128(100 * ONE)));
+        assert_eq!(h.qd(ALICE), 400 * ONE);
+        assert_eq!((stnear.balance_of("quid"), stnear.balance_of("charlie")), (100 * ONE, 0));
+        let asset = h.view(|c| c.get_asset(stnear.id.clone())).unwrap();
+        assert_eq!((asset.live.0, asset.dead.0), (100 * ONE, 0));
+        assert_eq!(h.pools().live_long_debit.0, 400 * ONE);
+    }
+
+    #[test]
+    fn stnear_diversifies_premiums() {
+        let (mut h, stnear) = staked_long();
+        h.call(ALICE, 50 * ONE, |c| c.deposit(U128(0), true));
+        let tick = |h: &mut Harness| {
+            h.advance(EIGHT_HOURS);
+            h.set_price(PRICE);
+            h.call(REPORTER, 0, |c| c.report_asset_price(stnear.id.clone(), U128(55 * ONE / 10)));
+            crank(h);
+        };
+        tick(&mut h);
+        let together = h.view(|c| c.get_pledge_stats(accounts(ALICE), false));
+        let vol = h.view(|c| c.annual_vol());
+        assert_eq!(together.var.0, ratio(vol, vol, ONE)); // no credit for moving together
+        h.call(OWNER, 1, |c| c.set_correlation(stnear.id.clone(), "near".try_into().unwrap(), 0));
+        tick(&mut h);
+        let apart = h.view(|c| c.get_pledge_stats(accounts(ALICE), false));
+        assert!(apart.var < together.var);
+        assert!(apart.premiums <= together.premiums);
+    }
+
+    #[test]
+    fn withdraws_stnear_above_min_cr() {
+        let (mut h, mut stnear) = staked_long();
+        let dead_short = h.pools().dead_short_debit.0;
+        h.withdraw_asset(&mut stnear, ALICE, 10 * ONE, false); // 445.5 QD against 400
+        assert_eq!(h.pledge(ALICE).unwrap().basket[0].1, U128(90 * ONE));
+        assert_eq!(stnear.balance_of("charlie"), 10 * ONE - fee(10 * ONE));
+        // the fee is kept as a premium, worth 5.5 QD per stNEAR
+        let asset = h.view(|c| c.get_asset(stnear.id.clone())).unwrap();
+        assert_eq!((asset.live.0, asset.dead.0), (90 * ONE, fee(10 * ONE)));
+        let worth = ratio(55 * ONE / 10, fee(10 * ONE), ONE);
+        let pools = h.pools();
+        assert_eq!(pools.gfund_long_debit.0, worth);
+        assert_eq!(pools.dead_short_debit.0 - dead_short, worth - worth / GFUND_DIV as Balance);
+        // a transfer that fails puts what was sent back in the basket
+        h.withdraw_asset(&mut stnear, ALICE, ONE, true);
+        assert_eq!(h.pledge(ALICE).unwrap().basket[0].1, U128(90 * ONE - fee(ONE)));
+        assert_eq!(stnear.balance_of("charlie"), 10 * ONE - fee(10 * ONE));
+        assert_eq!(h.view(|c| c.get_asset(stnear.id.clone())).unwrap().live.0, 90 * ONE - fee(ONE));
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot do operation that would result in CR below min")]
+    fn no_withdrawing_stnear_below_min_cr() {
+        let (mut h, mut stnear) = staked_long();
+        h.withdraw_asset(&mut stnear, ALICE, 20 * ONE, false); // 396 QD against 400
+    }
+
+    #[test]
+    fn clip_seizes_stnear() {
+        let (mut h, mut stnear) = staked_long();
+        let id = stnear.id.clone();
+        h.call(ALICE, 1, |c| c.ft_transfer(accounts(OWNER), U128(400 * ONE), None));
+        // 405 QD against 400 is above KILL_CR, but there's no NEAR to shrink with
+        h.call(REPORTER, 0, |c| c.report_asset_price(id.clone(), U128(45 * ONE / 10)));
+        h.call(OWNER, 1, |c| c.clip(accounts(ALICE)));
+        assert_eq!((h.events("liquidate"), h.events("seize")), (1, 1));
+        let pledge = h.pledge(ALICE).unwrap();
+        assert_eq!(pledge.debit.0, 0);
+        assert!(pledge.basket.is_empty());
+        let asset = h.view(|c| c.get_asset(stnear.id.clone())).unwrap();
+        assert_eq!((asset.live.0, asset.dead.0), (0, 100 * ONE));
+        let pools = h.pools();
+        assert_eq!(pools.live_long_debit.0, 0);
+        assert_eq!(pools.gfund_long_debit.0, 400 * ONE);
+
+        // selling the seized stNEAR repays the GuaranteeFund's debt, at 4.5 less the haircut
+        h.buy_asset(&mut stnear, OWNER, 80 * ONE, false);
+        assert_eq!((stnear.balance_of("alice"), h.qd(OWNER)), (80 * ONE, 76 * ONE));
+        assert_eq!(h.view(|c| c.get_asset(id.clone())).unwrap().dead.0, 20 * ONE);
+        assert_eq!(h.pools().gfund_long_debit.0, 76 * ONE);
+        // a transfer that fails undoes the sale
+        h.buy_asset(&mut stnear, OWNER, 10 * ONE, true);
+        assert_eq!((stnear.balance_of("alice"), h.qd(OWNER)), (80 * ONE, 76 * ONE));
+        assert_eq!(h.view(|c| c.get_asset(id.clone())).unwrap().dead.0, 20 * ONE);
+        assert_eq!(h.pools().gfund_long_debit.0, 76 * ONE);
+    }
+
+    // Alice borrows mostly against stNEAR, Carol against NEAR, and both fall below MIN_CR
+    #[test]
+    fn basket_longs_sort_by_their_worth_in_near() {
+        let (mut h, stnear) = staked_long();
+        h.call(ALICE, 10 * ONE, |c| c.borrow(U128(20 * ONE), false));
+        h.call(ALICE, 1, |c| c.ft_transfer(accounts(OWNER), U128(100 * ONE), None));
+        h.call(BOB, 200 * ONE, |c| c.borrow(U128(400 * ONE), false));
+        h.call(CAROL, 100 * ONE, |c| c.borrow(U128(380 * ONE), false));
+        h.call(CAROL, 1, |c| c.ft_transfer(accounts(OWNER), U128(380 * ONE), None));
+        h.set_price(39 * ONE / 10);
+        h.call(REPORTER, 0, |c| c.report_asset_price(stnear.id.clone(), U128(429 * ONE / 100)));
+        // 99 NEAR's worth of stNEAR when she borrowed, and it moved with NEAR since
+        let tree = h.view(|c| c.get_pledge_tree(false, 0, 10, None, None));
+        let ids: Vec<AccountId> = tree.iter().map(|row| row.0.clone()).collect();
+        assert_eq!(ids, vec![accounts(ALICE).to_string(), accounts(CAROL).to_string(), accounts(BOB).to_string()]);
+        let baskets = h.view(|c| c.get_basket_pledges(0, 10));
+        assert_eq!(baskets.len(), 1);
+        assert_eq!(baskets[0].0, accounts(ALICE).to_string());
+        assert_eq!(tree[0].2, baskets[0].2); // 39 QD of NEAR and 386.1 of stNEAR against 420
+        // redemptions reach her first, but only take her NEAR
+        h.call(OWNER, 1, |c| c.swap(U128(419 * ONE), false, false));
+        assert_eq!(h.pledge(CAROL).unwrap().debit.0, 0);
+        let alice = h.pledge(ALICE).unwrap();
+        assert_eq!((alice.debit.0, alice.credit.0), (381 * ONE, 0));
+        assert_eq!(alice.basket[0].1.0, 100 * ONE);
+    }
+
+    #[test]
+    fn premiums_come_out_of_the_basket() {
+        let (mut h, stnear) = staked_long();
+        h.call(BOB, 50 * ONE, |c| c.deposit(U128(0), false));
+        let dead = h.pools().dead_short_debit.0;
+        h.call(OWNER, 0, |c| c.update());
+        assert_eq!(crank(&mut h), 1);
+        let pledge = h.pledge(ALICE).unwrap();
+        let paid = 100 * ONE - pledge.basket[0].1.0; // of the stNEAR, as she has no NEAR
+        assert!(paid > 0);
+        let asset = h.view(|c| c.get_asset(stnear.id.clone())).unwrap();
+        assert_eq!((asset.live.0, asset.dead.0), (100 * ONE - paid, paid));
+        // paid in QD as shorts pay, which the protocol owes until it sells the stNEAR;
+        // the GuaranteeFund takes its cut, and Bob's NEAR deposit has the rest to absorb
+        let worth = ratio(55 * ONE / 10, paid, ONE);
+        let pools = h.pools();
+        assert_eq!(pools.gfund_long_debit.0, worth);
+        assert_eq!(pools.dead_short_debit.0 - dead, worth - worth / GFUND_DIV as Balance);
+    }
+
+    #[test]
+    fn fold_sells_only_near() {
+        let (mut h, _) = staked_long();
+        h.call(BOB, 200 * ONE, |c| c.borrow(U128(400 * ONE), false));
+        h.call(BOB, 1, |c| c.deposit(U128(300 * ONE), false));
+        h.call(ALICE, 50 * ONE, |c| c.deposit(U128(0), true));
+        h.call(ALICE, 1, |c| c.fold(false));
+        let pledge = h.pledge(ALICE).unwrap();
+        assert_eq!((pledge.credit.0, pledge.debit.0), (0, 150 * ONE)); // 50 NEAR repaid 250 QD
+        assert_eq!(pledge.basket[0].1.0, 100 * ONE);
+    }
+
+    #[test]
+    fn clip_seizes_stnear_after_cross_margin() {
+        let (mut h, stnear) = staked_long();
+        let id = stnear.id.clone();
+        h.call(BOB, 50 * ONE, |c| c.deposit(U128(0), false)); // NEAR to borrow
+        h.call(ALICE, ONE / 100, |c| c.set_cross_margin(true));
+        h.call(ALICE, 1, |c| c.deposit(U128(130 * ONE), true));
+        let out = h.call(ALICE, 1, |c| c.borrow(U128(20 * ONE), true)); // short at 130%
+        h.paid(out, 20 * ONE);
+        h.call(ALICE, 1, |c| c.ft_transfer(accounts(OWNER), U128(270 * ONE), None));
+        // the short's 20 QD of surplus takes the long to ~106%, still without NEAR to shrink
+        h.call(REPORTER, 0, |c| c.report_asset_price(id, U128(45 * ONE / 10)));
+        h.call(OWNER, 1, |c| c.clip(accounts(ALICE)));
+        assert_eq!((h.events("margin"), h.events("liquidate"), h.events("seize")), (1, 1, 1));
+        let pledge = h.pledge(ALICE).unwrap();
+        assert_eq!((pledge.debit.0, pledge.s_credit.0, pledge.s_debit.0), (0, 110 * ONE, 20 * ONE));
+        assert!(pledge.basket.is_empty());
+        let pools = h.pools();
+        assert_eq!((pools.live_long_debit.0, pools.live_short_credit.0), (0, 110 * ONE));
+        assert_eq!(pools.gfund_long_debit.0, 380 * ONE); // what the margin didn't repay
+    }
+}
@@ -0,0 +1,366 @@
+use crate::*;
+
+use near_sdk::{env, Balance, Promise, Timestamp};
+use near_sdk::serde::Serialize;
+use near_sdk::json_types::U128;
+
+/*  When enabled, liquidated collateral goes up in a Dutch auction
+ *  instead of straight into the DeadPool. A lot's price starts at
+ *  `start` times the price when it was kicked, and decays linearly
+ *  to `floor` times that over `duration`, where it stays. Anyone may
+ *  fill a lot: long lots sell NEAR for QD (which gets burned against
+ *  the defaulted QD debt), short lots sell QD for NEAR (which repays
+ *  the defaulted NEAR debt). Once the debt is repaid, collateral that
+ *  is left over goes to the SolvencyPool (by way of the DeadPool, which
+ *  SP deposits absorb from); once collateral runs out, debt that is
+ *  left over goes to the GuaranteeFund. Lots that are still open past
+ *  `duration` may be closed into the DeadPool by anyone
+ */
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Lot {
+    pub account: AccountId, // whose Pledge was liquidated
+    pub short: bool,
+    pub near: NearAmount, // collateral of long lots, debt of short lots
+    pub qd: QdAmount, // debt of long lots, collateral of short lots
+    pub price: Price, // oracle price when the lot was kicked
+    pub start: Timestamp,
+} impl Lot {
+    // collateral and debt, in whichever units the side holds them
+    fn left(&self) -> (Balance, Balance) {
+        if self.short { (self.qd.0, self.near.0) } else { (self.near.0, self.qd.0) }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Auctions {
+    pub enabled: bool,
+    pub start: Ratio, // multiple of the kick price that lots start at
+    pub floor: Ratio, // multiple that lots decay to
+    pub duration: u64, // nanosecs to decay from start to floor
+    pub near: NearAmount, // NEAR collateral in open lots
+    pub qd: QdAmount, // QD collateral in open lots
+    pub near_debt: NearAmount, // NEAR owed by open short lots
+    next: u64, // id of the next lot
+    lots: UnorderedMap<u64, Lot>,
+} impl Auctions {
+    pub fn new(prefix: Vec<u8>) -> Self {
+        Self {
+            enabled: false,
+            start: AUCTION_START,
+            floor: AUCTION_FLOOR,
+            duration: AUCTION_DURATION,
+            near: NearAmount::ZERO,
+            qd: QdAmount::ZERO,
+            near_debt: NearAmount::ZERO,
+            next: 0,
+            lots: UnorderedMap::new(prefix),
+        }
+    }
+
+    // multiple of the kick price that the lot sells at, `now`
+    fn factor(&self, lot: &Lot, now: Timestamp) -> Ratio {
+        let elapsed = std::cmp::min(now.saturating_sub(lot.start), self.duration);
+        self.start - Ratio(ratio((self.start - self.floor).0, elapsed as Balance, self.duration as Balance))
+    }
+
+    // QD per NEAR that the lot sells at, `now`
+    pub fn price(&self, lot: &Lot, now: Timestamp) -> Price {
+        let factor = self.factor(lot, now);
+        if lot.short { // buyers pay NEAR for QD, at a discount as time goes by
+            lot.price / factor
+        } else { // buyers pay QD for NEAR, the price drops as time goes by
+            lot.price * factor
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LotView {
+    pub id: u64,
+    pub account: AccountId,
+    pub short: bool,
+    pub collateral: U128,
+    pub debt: U128,
+    pub kicked_at: U128, // oracle price when kicked
+    pub price: U128, // QD per NEAR the lot sells at now
+    pub start: u64,
+    pub expired: bool, // may be closed into the DeadPool
+}
+
+#[near_bindgen]
+impl Contract
+{
+    /*  Puts liquidated debt and collateral (already out of the LivePool)
+     *  up for auction: `near` and `qd` are the collateral and the debt
+     *  of a long, the other way around for a short
+     */
+    pub(crate) fn kick(&mut self, account: &AccountId, near: NearAmount, qd: QdAmount, short: bool) {
+        let id = self.auctions.next;
+        self.auctions.next += 1;
+        if short {
+            self.auctions.qd += qd;
+            self.auctions.near_debt += near;
+        } else {
+            self.auctions.near += near;
+        }
+        let lot = Lot {
+            account: account.clone(), short, near, qd,
+            price: self.get_price(), start: env::block_timestamp()
+        };
+        self.auctions.lots.insert(&id, &lot);
+        let (collateral, debt) = lot.left();
+        Event::Kick {
+            lot: id, account: account.clone(), short, collateral: collateral.into(),
+            debt: debt.into(), price: lot.price.into()
+        }.emit();
+    }
+
+    /*  Buys collateral from a lot by paying `pay` towards its debt:
+     *  QD out of the caller's balance for long lots (attach 1 yocto),
+     *  attached NEAR for short lots (what isn't used is refunded).
+     *  Pays no more than the debt, nor more than all the collateral
+     *  is worth at the current price, panics if less than `min_out`
+     *  would be bought. Returns the collateral bought
+     */
+    #[payable]
+    pub fn bid(&mut self, lot: u64, pay: U128, min_out: U128) -> PromiseOrValue<U128> {
+        self.assert_not_paused();
+        let id = lot;
+        let mut lot = self.auctions.lots.get(&id).expect(ERR_NO_LOT);
+        let bidder = env::predecessor_account_id();
+        let deposit = env::attached_deposit();
+        let price = self.auctions.price(&lot, env::block_timestamp());
+        let (paid, out): (Balance, Balance);
+        let result = if lot.short {
+            assert!(deposit >= pay.0, "{}", ERR_AMT_TOO_LOW);
+            let all = lot.qd / price; // NEAR it takes to buy all collateral
+            let pay = std::cmp::min(std::cmp::min(NearAmount::from(pay), lot.near), all);
+            let bought = if pay == all { lot.qd } else { pay * price };
+            assert!(!bought.is_zero() && bought.0 >= min_out.0, "{}", ERR_MIN_OUT);
+            lot.near -= pay;
+            lot.qd -= bought;
+            self.auctions.near_debt -= pay;
+            self.auctions.qd -= bought;
+            self.token.internal_transfer(&env::current_account_id(), &bidder, bought.0, None);
+            (paid, out) = (pay.0, bought.0);
+            if deposit > pay.0 {
+                PromiseOrValue::Promise(Promise::new(bidder.clone()).transfer(deposit - pay.0))
+            } else {
+                PromiseOrValue::Value(U128(out))
+            }
+        } else {
+            assert_one_yocto();
+            let all = lot.near * price; // QD it takes to buy all collateral
+            let pay = std::cmp::min(std::cmp::min(QdAmount::from(pay), lot.qd), all);
+            let bought = if pay == all { lot.near } else { pay / price };
+            assert!(!bought.is_zero() && bought.0 >= min_out.0, "{}", ERR_MIN_OUT);
+            lot.qd -= pay;
+            lot.near -= bought;
+            self.token.internal_withdraw(&bidder, pay.0); // the debt's QD is retired
+            self.auctions.near -= bought;
+            (paid, out) = (pay.0, bought.0);
+            PromiseOrValue::Promise(Promise::new(bidder.clone()).transfer(out))
+        };
+        Event::Bid {
+            lot: id, bidder, short: lot.short,
+            paid: paid.into(), bought: out.into(), price: price.into()
+        }.emit();
+        self.settle(id, lot);
+        self.debug_invariants();
+        result
+    }
+
+    // anyone may hand a lot that outlived its auction to the DeadPool
+    pub fn close_lot(&mut self, lot: u64) {
+        let id = lot;
+        let lot = self.auctions.lots.get(&id).expect(ERR_NO_LOT);
+        assert!(env::block_timestamp() >= lot.start + self.auctions.duration, "{}", ERR_LOT_NOT_EXPIRED);
+        self.auctions.lots.remove(&id);
+        self.unlot(&lot);
+        let price = self.get_price();
+        if lot.short {
+            Pool::short_bury(&mut self.dead, &mut self.gfund, lot.near, lot.qd, price);
+        } else {
+            Pool::long_bury(&mut self.dead, &mut self.gfund, lot.qd, lot.near, price);
+        }
+        Event::Settle { lot: id, surplus: U128(0), shortfall: U128(0), closed: true }.emit();
+        self.debug_invariants();
+    }
+
+    // a lot is done when either its debt or its collateral runs out
+    fn settle(&mut self, id: u64, lot: Lot) {
+        if !lot.near.is_zero() && !lot.qd.is_zero() {
+            self.auctions.lots.insert(&id, &lot);
+            return;
+        }
+        self.auctions.lots.remove(&id);
+        self.unlot(&lot);
+        // what's left of the collateral goes to the DeadPool, for SPs to absorb
+        // as they would a liquidation's, the GuaranteeFund owes what's left of the debt
+        if lot.short {
+            self.dead.short.debit += lot.qd;
+            self.gfund.short.debit += lot.near;
+        } else {
+            self.dead.long.debit += lot.near;
+            self.gfund.long.debit += lot.qd;
+        }
+        let (surplus, shortfall) = lot.left();
+        Event::Settle {
+            lot: id, surplus: surplus.into(), shortfall: shortfall.into(), closed: false
+        }.emit();
+    }
+
+    // what's left of a lot is no longer held by the auctions
+    fn unlot(&mut self, lot: &Lot) {
+        if lot.short {
+            self.auctions.qd -= lot.qd;
+            self.auctions.near_debt -= lot.near;
+        } else {
+            self.auctions.near -= lot.near;
+        }
+    }
+
+    pub fn get_lot(&self, lot: u64) -> Option<LotView> {
+        self.auctions.lots.get(&lot).map(|l| self.lot_view(lot, &l))
+    }
+
+    pub fn get_lots(&self, from_index: u64, limit: u64) -> Vec<LotView> {
+        let ids = self.auctions.lots.keys_as_vector();
+        let lots = self.auctions.lots.values_as_vector();
+        (from_index..std::cmp::min(from_index.saturating_add(limit), ids.len()))
+            .map(|index| self.lot_view(ids.get(index).unwrap(), &lots.get(index).unwrap()))
+            .collect()
+    }
+
+    fn lot_view(&self, id: u64, lot: &Lot) -> LotView {
+        let now = env::block_timestamp();
+        let (collateral, debt) = lot.left();
+        LotView {
+            id,
+            account: lot.account.clone(),
+            short: lot.short,
+            collateral: collateral.into(),
+            debt: debt.into(),
+            kicked_at: lot.price.into(),
+            price: self.auctions.price(lot, now).into(),
+            start: lot.start,
+            expired: now >= lot.start + self.auctions.duration,
+        }
+    }
+
+    #[payable]
+    pub fn set_auctions(&mut self, enabled: bool, start: U128, floor: U128, duration: u64) {
+        assert_one_yocto();
+        self.assert_owner();
+        let (start, floor): (Ratio, Ratio) = (start.into(), floor.into());
+        check(start >= Ratio::ONE && start.0 <= 2 * ONE, "auction_start");
+        check(floor.0 >= ONE / 2 && floor < start, "auction_floor");
+        check((ONE_HOUR / 6..=24 * ONE_HOUR).contains(&duration), "auction_duration");
+        changed("auctions", self.auctions.enabled, enabled);
+        changed("auction_start", self.auctions.start, start);
+        changed("auction_floor", self.auctions.floor, floor);
+        changed("auction_duration", self.auctions.duration, duration);
+        self.auctions.enabled = enabled;
+        self.auctions.start = start;
+        self.auctions.floor = floor;
+        self.auctions.duration = duration;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::*;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, MockedBlockchain};
+
+    fn lot(short: bool) -> Lot {
+        Lot { account: accounts(1).into(), short, near: NearAmount(ONE), qd: QdAmount(ONE), price: Price(4 * ONE), start: 0 }
+    }
+
+    #[test]
+    fn prices_decay_to_floor() {
+        testing_env!(VMContextBuilder::new().build());
+        let auctions = Auctions::new(b"a".to_vec());
+        let (long, short) = (lot(false), lot(true));
+        assert_eq!(auctions.price(&long, 0).0, 48 * ONE / 10);
+        assert_eq!(auctions.price(&long, ONE_HOUR / 2).0, 38 * ONE / 10);
+        assert_eq!(auctions.price(&long, 2 * ONE_HOUR).0, 28 * ONE / 10);
+        // short lots sell QD, so buyers get more of it per NEAR as time goes by
+        assert_eq!(auctions.price(&short, 0).0, ratio(4 * ONE, ONE, AUCTION_START.0));
+        assert_eq!(auctions.price(&short, 2 * ONE_HOUR).0, ratio(4 * ONE, ONE, AUCTION_FLOOR.0));
+    }
+
+    // Alice's long liquidated at 3.9 into a lot of 100 NEAR for 400 QD
+    fn auctioned() -> Harness {
+        let mut h = leveraged_long();
+        h.call(OWNER, 1, |c| c.set_auctions(true, AUCTION_START.into(), AUCTION_FLOOR.into(), ONE_HOUR));
+        h.set_price(39 * ONE / 10);
+        h.call(OWNER, 1, |c| c.clip(accounts(ALICE)));
+        assert_eq!((h.events("liquidate"), h.events("kick")), (1, 1));
+        h
+    }
+
+    #[test]
+    fn auction_surplus_goes_to_solvency_pool() {
+        let mut h = auctioned();
+        let lot = h.view(|c| c.get_lots(0, 10)).pop().unwrap();
+        assert_eq!((lot.collateral.0, lot.debt.0), (100 * ONE, 400 * ONE));
+        assert_eq!(lot.price.0, 39 * ONE * 12 / 100); // 120% of the kick price
+        let pools = h.pools();
+        assert_eq!((pools.live_long_credit.0, pools.live_long_debit.0), (200 * ONE, 400 * ONE));
+        assert_eq!((pools.dead_long_credit.0, pools.gfund_long_debit.0), (0, 0));
+
+        let (qd, dead) = (h.qd(BOB), pools.dead_long_debit.0);
+        let out = h.call(BOB, 1, |c| c.bid(lot.id, U128(400 * ONE), U128(85 * ONE)));
+        let bought = ratio(ONE, 400 * ONE, lot.price.0);
+        h.paid(out, bought);
+        assert_eq!(h.qd(BOB), qd - 400 * ONE);
+        assert_eq!(h.events("settle"), 1);
+        assert!(h.view(|c| c.get_lots(0, 10)).is_empty());
+        assert_eq!(h.pools().dead_long_debit.0, dead + 100 * ONE - bought);
+        h.call(BOB, 1, |c| c.deposit(U128(0), false)); // the only QD deposit absorbs all of it
+        let near_sp = h.pledge(BOB).unwrap().near_sp.0;
+        assert!(dead + 100 * ONE - bought - near_sp < 1000); // the index rounds down
+        assert_eq!(h.pools().blood_debit.0, near_sp);
+    }
+
+    #[test]
+    fn auction_shortfall_goes_to_gfund() {
+        let mut h = auctioned();
+        h.advance(ONE_HOUR / 2);
+        let lot = h.call(ALICE, 0, |c| c.get_lot(0)).unwrap(); // views as of now
+        assert_eq!(lot.price.0, 39 * ONE * 95 / 1000); // halfway from 120% to 70%
+        let out = h.call(BOB, 1, |c| c.bid(0, U128(ONE), U128(0)));
+        h.paid(out, ratio(ONE, ONE, lot.price.0));
+        assert_eq!(h.view(|c| c.get_lot(0)).unwrap().debt.0, 399 * ONE);
+
+        let out = h.call(BOB, 1, |c| c.bid(0, U128(400 * ONE), U128(0))); // more than it's worth
+        h.paid(out, 100 * ONE - ratio(ONE, ONE, lot.price.0));
+        assert!(h.view(|c| c.get_lot(0)).is_none());
+        let paid = ONE + ratio(lot.price.0, 100 * ONE - ratio(ONE, ONE, lot.price.0), ONE);
+        assert!(rounded(h.pools().gfund_long_debit.0, 400 * ONE - paid));
+    }
+
+    #[test]
+    fn expired_lot_goes_to_dead_pool() {
+        let mut h = auctioned();
+        h.advance(ONE_HOUR);
+        assert!(h.call(ALICE, 0, |c| c.get_lot(0)).unwrap().expired);
+        h.set_price(39 * ONE / 10);
+        h.call(ALICE, 0, |c| c.close_lot(0));
+        let pools = h.pools();
+        assert_eq!(pools.dead_long_credit.0, 390 * ONE); // the QD debt that was backed
+        assert_eq!(pools.dead_long_debit.0, 100 * ONE); // by all of the NEAR
+        assert_eq!(pools.gfund_long_debit.0, 10 * ONE);
+    }
+
+    #[test]
+    #[should_panic(expected = "The lot is still being auctioned")]
+    fn lot_closes_after_auction() {
+        let mut h = auctioned();
+        h.advance(ONE_HOUR - 1);
+        h.call(ALICE, 0, |c| c.close_lot(0));
+    }
+}
@@ -1,73 +1,172 @@
 use crate::*;
 
 
-use near_sdk::{env, log, Balance, Promise};
-use near_sdk::serde::{Deserialize, Serialize};
-use near_sdk::json_types::{WrappedBalance, WrappedTimestamp, U128};
+use near_sdk::env;
+use near_sdk::serde::Serialize;
+use near_sdk::json_types::U128;
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Clipped {
+    pub account: AccountId,
+    pub liquidated: bool, // otherwise it was rescued (from its SP deposit, or shrunk)
+    pub cr_before: U128,
+    pub cr_after: U128,
+    pub cleared: U128, // debt cleared, in QD for longs and NEAR for shorts
+    pub bounty: U128, // QD accrued to the caller
+}
 
 #[near_bindgen]
 impl Contract 
 {    
     // QuiD's bot script will continuously call this liquidation function on distressed 
-    // Pledges. For this reason there is no liquidation fee, because there is an implicit
-    // incentive for anyone to run this function, otherwise the peg will be destroyed.
+    // Pledges. There is no liquidation fee for the Pledge, instead the caller is paid a
+    // bounty out of the GuaranteeFund for the debt that got shrunk or liquidated (see keeper.rs)
+    #[payable]
     pub fn clip(&mut self, account: ValidAccountId) { 
         assert_one_yocto();
-        let mut long_touched = false;
-        let mut short_touched = false;
+        self.assert_not_paused();
+        self.assert_fresh_price();
         let id: AccountId = account.clone().into();
         // We don't use fetch_pledge because we'd rather not absorb into
         // a pledge until after they are rescued, to keep their SP balances
         // as high as possible in the interest of rescuing
         if let Some(mut pledge) = self.pledges.get(&id) {
+            let debts = (pledge.long.debit, pledge.short.debit);
             // TODO clip biggest one first, or the lowest CR first if same size 
-            let mut cr = computeCR(self.get_price(), pledge.long.credit, pledge.long.debit, false);
-            // TODO if the position is in the user defined range, shrink it
-            if cr < MIN_CR {
-                let nums = self.try_kill_pledge(&pledge, false);
-                pledge.long.credit = nums.1;
-                pledge.long.debit = nums.3;
-                pledge.near = nums.0;
-                pledge.quid = nums.2;
-                long_touched = true;
+            let credits = (pledge.long.credit, pledge.short.credit);
+            let long_cr = self.clip_side(&mut pledge, false);
+            let short_cr = self.clip_side(&mut pledge, true);
+            // with cross-margin, clipping one side may draw on the other
+            let long_touched = long_cr.is_some() || pledge.long.credit != credits.0;
+            let short_touched = short_cr.is_some() || pledge.short.credit != credits.1;
+            // QD value of the debt cleared below MIN_CR, on either side
+            // (deleveraging to its owner's target isn't worth a bounty)
+            let below = |cr: Option<Ratio>| cr.is_some_and(|cr| cr < self.config.min_cr);
+            let mut cleared = QdAmount::ZERO;
+            if below(long_cr) {
+                cleared += debts.0.saturating_sub(pledge.long.debit);
             }
-            cr = computeCR(self.get_price(), pledge.short.credit, pledge.short.debit, true);
-            if cr < MIN_CR {
-                let nums = self.try_kill_pledge(&pledge, true);
-                pledge.short.credit = nums.1;
-                pledge.short.debit = nums.3;
-                pledge.quid = nums.0;
-                pledge.near = nums.2;
-                short_touched = true;
+            if below(short_cr) {
+                cleared += debts.1.saturating_sub(pledge.short.debit) * self.get_price();
             }
             self.save_pledge(&id, &mut pledge, long_touched, short_touched);
+            self.clip_bounty(cleared);
+        }
+        self.debug_invariants();
+    }
+
+    /*  Clips up to `max` Pledges on one side that are below MIN_CR,
+     *  riskiest band of CR first, largest debt first within a band,
+     *  for as long as the gas attached lasts. Returns what happened
+     *  to each. Longs with a basket are sorted by what it was worth at
+     *  their last save, `clip` those that fell since (`get_basket_pledges`)
+     */
+    #[payable]
+    pub fn clip_many(&mut self, short: bool, max: u32) -> Vec<Clipped> {
+        assert_one_yocto();
+        self.assert_not_paused();
+        self.assert_fresh_price();
+        let price = self.get_price();
+        let tree = if short { &self.short_crs } else { &self.long_crs };
+        let ids: Vec<AccountId> = tree.between(None, Some(self.config.min_cr), price)
+            .take(max as usize).map(|(pledge, _)| pledge.id).collect();
+        let mut clipped = Vec::new();
+        let mut most: Gas = 0; // most gas clipping one Pledge took so far
+        for id in ids {
+            let before = env::used_gas();
+            if before + most + CRANK_GAS_RESERVE > env::prepaid_gas() {
+                break;
+            }
+            let mut pledge = self.pledges.get(&id).unwrap();
+            let debts = (pledge.long.debit, pledge.short.debit);
+            let credits = (pledge.long.credit, pledge.short.credit);
+            // the map holds a copy of the Pledge, the Pledge itself decides
+            if let Some(cr_before) = self.clip_side(&mut pledge, short) {
+                // debt cleared (and what it's worth in QD), whether it's all gone, CR after
+                let (cleared, value, liquidated, cr_after): (U128, QdAmount, bool, Ratio) = if short {
+                    let cleared = debts.1.saturating_sub(pledge.short.debit);
+                    (cleared.into(), cleared * price, pledge.short.debit.is_zero(),
+                     computeCR(price, pledge.short.credit, pledge.short.debit))
+                } else {
+                    let cleared = debts.0.saturating_sub(pledge.long.debit);
+                    (cleared.into(), cleared, pledge.long.debit.is_zero(), self.long_cr(&pledge))
+                };
+                let long_touched = !short || pledge.long.credit != credits.0; // cross-margin
+                let short_touched = short || pledge.short.credit != credits.1;
+                self.save_pledge(&id, &mut pledge, long_touched, short_touched);
+                clipped.push(Clipped {
+                    account: id,
+                    liquidated,
+                    cr_before: cr_before.into(),
+                    cr_after: cr_after.into(),
+                    cleared,
+                    bounty: if cr_before < self.config.min_cr { self.clip_bounty(value).into() } else { U128(0) },
+                });
+            }
+            most = std::cmp::max(most, env::used_gas() - before);
+        }
+        self.debug_invariants();
+        clipped
+    }
+
+    // liquidates or shrinks one side of the Pledge if it's below MIN_CR (or
+    // deleverages it if it's below its owner's target), returns its CR before
+    fn clip_side(&mut self, pledge: &mut Pledge, short: bool) -> Option<Ratio> {
+        let no_debt = if short { pledge.short.debit.is_zero() } else { pledge.long.debit.is_zero() };
+        if no_debt {
+            return None;
+        }
+        self.assert_fresh_basket(pledge);
+        let cr = if short { computeCR(self.get_price(), pledge.short.credit, pledge.short.debit) } else { self.long_cr(pledge) };
+        if cr >= self.config.min_cr {
+            return if self.deleverage(pledge, short) { Some(cr) } else { None };
         }
+        self.try_kill_pledge(pledge, short);
+        Some(cr)
+    }
+
+    // pays the caller their share of the debt (QD value) that a clip cleared
+    fn clip_bounty(&mut self, cleared: QdAmount) -> QdAmount {
+        let bounty = std::cmp::min(
+            cleared * self.keepers.clip_bounty, self.keepers.clip_cap
+        );
+        self.reward(&env::predecessor_account_id(), bounty, "clip")
     }
 
-    pub(crate) fn try_kill_pledge(&mut self, pledge: &Pledge, short: bool) -> (Balance, Balance, Balance, Balance) {
-        let available: Balance = self.token.ft_balance_of(
+    // rescues one side of the Pledge, or liquidates it, and writes what's left into the Pledge
+    pub(crate) fn try_kill_pledge(&mut self, pledge: &mut Pledge, short: bool) {
+        let available = QdAmount(self.token.ft_balance_of(
             ValidAccountId::try_from(pledge.id.clone()).unwrap()
-        ).into(); 
-        let mut old_nums: (Balance, Balance, Balance, Balance);
-        let mut nums: (Balance, Balance, Balance, Balance);
-        let mut cr: u128;
-        /** Liquidation protection does an off-setting where deficit margin (delta from min CR)
-        in a Pledge can be covered by either its SP deposit, or possibly (TODO) the opposite 
-        borrowing position. However, it's rare that a Pledge will borrow both long & short. **/
+        ).into()); 
+        let price = self.get_price();
+        let mut cr: Ratio;
+        /* Liquidation protection does an off-setting where deficit margin (delta from min CR)
+        in a Pledge can be covered by either its SP deposit, or (if the Pledge opted into
+        cross-margin) the surplus of the opposite borrowing position, see `cross_margin`. */
         if short {
-            old_nums = (
+            let old_nums = (
                 pledge.quid, pledge.short.credit, 
                 pledge.near, pledge.short.debit
             );
-            nums = self.short_save(&pledge, available);
-            cr = computeCR(self.get_price(), nums.1, nums.3, true);
-            if cr < KILL_CR { // we are liquidating this pledge
+            let cr_before = computeCR(price, old_nums.1, old_nums.3); // before any rescue
+            let mut nums = self.short_save(pledge, available);
+            cr = computeCR(price, nums.1, nums.3);
+            if cr < self.config.min_cr && self.margined.contains(&pledge.id) {
+                nums.3 = self.short_cross_margin(pledge, nums.1, nums.3);
+                cr = computeCR(price, nums.1, nums.3);
+            }
+            if cr < self.config.kill_cr { // we are liquidating this pledge
                 // undo asset displacement by short_save
-                let now_available: Balance = self.token.ft_balance_of(
+                let now_available = QdAmount(self.token.ft_balance_of(
                     ValidAccountId::try_from(pledge.id.clone()).unwrap()
-                ).into();
-                if available > now_available {
-                    self.token.internal_withdraw(&pledge.id, available - now_available);
+                ).into());
+                if available > now_available { // give back liquid QD
+                    let delta = available - now_available;
+                    self.token.internal_withdraw(&env::current_account_id(), delta.0);
+                    self.token.internal_deposit(&pledge.id, delta.0);
+                    self.live.short.credit = self.live.short.credit
+                        .checked_sub(delta).expect(ERR_SUB);
                 }
                 if old_nums.0 > nums.0 { // SP QD changed
                     let delta = old_nums.0 - nums.0;
@@ -83,25 +182,52 @@ impl Contract
                     self.blood.debit = self.blood.debit
                         .checked_add(delta).expect(ERR_ADD);
                 }
+                nums = (old_nums.0, QdAmount::ZERO, old_nums.2, NearAmount::ZERO); // zero out the pledge
+                Event::Liquidate { 
+                    account: pledge.id.clone(), short, debt: old_nums.3.into(), collateral: old_nums.1.into(),
+                    cr_before: cr_before.into(), cr_after: computeCR(price, nums.1, nums.3).into(), price: price.into()
+                }.emit();
                 // move liquidated assets from LivePool to DeadPool
-                self.snatch(nums.3, nums.1, true);
-                return (old_nums.0, 0, old_nums.2, 0); // zero out the pledge
-            } else if cr < MIN_CR {
-                (nums.1, nums.3) = self.shrink(nums.1, nums.3, true);
+                self.short_snatch(&pledge.id, old_nums.3, old_nums.1);
+            } else if cr < self.config.min_cr {
+                let coll = nums.1;
+                (nums.1, nums.3) = self.short_shrink(nums.1, nums.3, self.config.min_cr);
+                Event::Shrink { 
+                    account: pledge.id.clone(), short, sold: (coll - nums.1).into(), cr_before: cr.into(),
+                    cr_after: computeCR(price, nums.1, nums.3).into(),
+                    price: price.into()
+                }.emit();
             }
+            (pledge.quid, pledge.short.credit, pledge.near, pledge.short.debit) = nums;
         } else {
-            old_nums = (
+            let old_nums = (
                 pledge.near, pledge.long.credit, 
                 pledge.quid, pledge.long.debit
             );
-            nums = self.long_save(&pledge, available);
-            cr = computeCR(self.get_price(), nums.1, nums.3, false);
-            if cr < KILL_CR {
-                let now_available: Balance = self.token.ft_balance_of(
+            let extra = self.basket_near(pledge); // counted as if it were NEAR
+            let cr_before = computeCR(price, old_nums.1 + extra, old_nums.3); // before any rescue
+            let mut nums = self.long_save(pledge, available);
+            cr = computeCR(price, nums.1 + extra, nums.3);
+            let mut margin = QdAmount::ZERO; // debt repaid by the short side, for good
+            if cr < self.config.min_cr && self.margined.contains(&pledge.id) {
+                let debit = self.long_cross_margin(pledge, nums.1 + extra, nums.3);
+                margin = nums.3 - debit;
+                nums.3 = debit;
+                cr = computeCR(price, nums.1 + extra, nums.3);
+            }
+            // only NEAR gets sold by shrinking, without enough of it the side is liquidated
+            let short_of_near = !extra.is_zero() && cr < self.config.min_cr && shrinkage(
+                (nums.1 + extra) * price, nums.3, self.config.min_cr
+            ) / price > nums.1;
+            if cr < self.config.kill_cr || short_of_near {
+                let now_available = QdAmount(self.token.ft_balance_of(
                     ValidAccountId::try_from(pledge.id.clone()).unwrap()
-                ).into();
-                if available > now_available {
-                    self.token.internal_withdraw(&pledge.id, available - now_available);
+                ).into());
+                if available > now_available { // un-burn liquid QD
+                    let delta = available - now_available;
+                    self.token.internal_deposit(&pledge.id, delta.0);
+                    self.live.long.debit = self.live.long.debit
+                        .checked_add(delta).expect(ERR_ADD);
                 }
                 if old_nums.0 > nums.0 { // SP NEAR changed
                     let delta = old_nums.0 - nums.0;
@@ -117,70 +243,230 @@ impl Contract
                     self.blood.credit = self.blood.credit
                         .checked_add(delta).expect(ERR_ADD);
                 }
-                self.snatch(nums.3, nums.1, false);
-                return (old_nums.0, 0, old_nums.2, 0);
-            } else if cr < MIN_CR {
-                (nums.1, nums.3) = self.shrink(nums.1, nums.3, false);
+                // the margin isn't undone: it stays repaid, and the rest is liquidated
+                let debt = old_nums.3 - margin;
+                nums = (old_nums.0, NearAmount::ZERO, old_nums.2, QdAmount::ZERO);
+                Event::Liquidate { 
+                    account: pledge.id.clone(), short, debt: debt.into(), collateral: old_nums.1.into(),
+                    cr_before: cr_before.into(), cr_after: computeCR(price, nums.1, nums.3).into(), price: price.into()
+                }.emit();
+                self.long_snatch(&pledge.id, debt, old_nums.1);
+                self.seize_basket(pledge);
+            } else if cr < self.config.min_cr {
+                let coll = nums.1;
+                (nums.1, nums.3) = self.long_shrink(nums.1 + extra, nums.3, self.config.min_cr);
+                nums.1 -= extra;
+                Event::Shrink { 
+                    account: pledge.id.clone(), short, sold: (coll - nums.1).into(), cr_before: cr.into(),
+                    cr_after: computeCR(price, nums.1 + extra, nums.3).into(),
+                    price: price.into()
+                }.emit();
             }
+            (pledge.near, pledge.long.credit, pledge.quid, pledge.long.debit) = nums;
         }
-        return nums;
     }    
 
-    pub(crate) fn shrink(&mut self, credit: Balance, debit: Balance, short: bool) -> (Balance, Balance) {
-        /* Shrinking is atomically selling an amount of collateral and 
-           immediately using the exact output of that to reduce debt to
-           get its CR up to min. How to calculate amount to be sold:
-           CR = (coll - x) / (debt - x)
-           CR * debt - CR * x = coll - x
-           x(1 - CR) = coll - CR * debt
-           x = (CR * debt - coll) * 10
-       */
-       let mut coll: Balance;
-       let mut debt: Balance;
-       if short {
-           coll = credit;
-           debt = ratio(self.get_price(), debit, KILL_CR);
-       } else {
-           coll = ratio(self.get_price(), credit, KILL_CR);
-           debt = debit;
-       }
-       let CR_x_debt = ratio(MIN_CR, debt, KILL_CR);
-       let mut delta: Balance = 10;
-       delta = delta.checked_mul(
-           CR_x_debt.checked_sub(coll).expect(ERR_SUB)
-       ).expect(ERR_MUL);
-       coll = coll.checked_sub(delta).expect(ERR_SUB);
-       debt = debt.checked_sub(delta).expect(ERR_SUB);
-       if short {
-           self.redeem(delta);
-           self.live.short.credit = self.live.short.credit
-               .checked_sub(delta).expect(ERR_SUB);
-           delta = ratio(KILL_CR, delta, self.get_price());
-           self.live.short.debit = self.live.short.debit
-               .checked_sub(delta).expect(ERR_SUB);
-           return (
-               coll,
-               ratio(KILL_CR, debt, self.get_price()),
-           );
-       } else {
-           self.live.long.debit = self.live.long.debit
-               .checked_sub(delta).expect(ERR_SUB);
-           delta = ratio(KILL_CR, delta, self.get_price());
-           self.invert(delta);
-           self.live.long.credit = self.live.long.credit
-               .checked_sub(delta).expect(ERR_SUB);
-           return (
-               ratio(KILL_CR, coll, self.get_price()),
-               debt
-           );    
-       }
-   }
+    /*  Repays debt of the failing side with collateral that the other
+     *  side of the same Pledge holds above MIN_CR: longs owe QD, which
+     *  shorts hold as collateral, and shorts owe NEAR, which longs hold
+     *  as collateral, so nothing needs to be sold. Only done if it saves
+     *  the failing side from liquidation (otherwise the other side keeps
+     *  its collateral). Returns the failing side's debt after
+     */
+    pub(crate) fn long_cross_margin(&mut self, pledge: &mut Pledge, credit: NearAmount, debit: QdAmount) -> QdAmount {
+        let price = self.get_price();
+        let min_cr = self.config.min_cr;
+        let need = debit.saturating_sub(credit * price / min_cr);
+        let surplus = pledge.short.credit.saturating_sub(pledge.short.debit * price * min_cr);
+        let moved = std::cmp::min(need, surplus);
+        let cr_after = computeCR(price, credit, debit - moved);
+        if moved.is_zero() || cr_after < self.config.kill_cr {
+            return debit;
+        }
+        pledge.short.credit -= moved;
+        self.live.short.credit = self.live.short.credit
+            .checked_sub(moved).expect(ERR_SUB);
+        self.live.long.debit = self.live.long.debit
+            .checked_sub(moved).expect(ERR_SUB);
+        // the QD that repaid the debt is burned
+        self.token.internal_withdraw(&env::current_account_id(), moved.0);
+        Event::Margin {
+            account: pledge.id.clone(), short: false, moved: moved.into(),
+            cr_before: computeCR(price, credit, debit).into(),
+            cr_after: cr_after.into(), price: price.into()
+        }.emit();
+        debit - moved
+    }
+
+    // the same for a short, repaying its NEAR debt with the long's NEAR collateral
+    pub(crate) fn short_cross_margin(&mut self, pledge: &mut Pledge, credit: QdAmount, debit: NearAmount) -> NearAmount {
+        let price = self.get_price();
+        let min_cr = self.config.min_cr;
+        let need = debit.saturating_sub(credit / min_cr / price);
+        let surplus = pledge.long.credit.saturating_sub(pledge.long.debit * min_cr / price);
+        let moved = std::cmp::min(need, surplus);
+        let cr_after = computeCR(price, credit, debit - moved);
+        if moved.is_zero() || cr_after < self.config.kill_cr {
+            return debit;
+        }
+        pledge.long.credit -= moved;
+        self.live.long.credit = self.live.long.credit
+            .checked_sub(moved).expect(ERR_SUB);
+        self.live.short.debit = self.live.short.debit
+            .checked_sub(moved).expect(ERR_SUB);
+        Event::Margin {
+            account: pledge.id.clone(), short: true, moved: moved.into(),
+            cr_before: computeCR(price, credit, debit).into(),
+            cr_after: cr_after.into(), price: price.into()
+        }.emit();
+        debit - moved
+    }
+
+    // opts the caller's Pledge in (or out) of covering one side with the other's
+    // surplus; the attached NEAR pays for the storage that opting in takes up,
+    // the rest is refunded (as is the storage when opting out, which works
+    // even after the Pledge is gone)
+    #[payable]
+    pub fn set_cross_margin(&mut self, enabled: bool) {
+        let deposit = env::attached_deposit();
+        assert!(deposit > 0, "{}", ERR_AMT_TOO_LOW);
+        let account = env::predecessor_account_id();
+        let storage = env::storage_usage();
+        if enabled {
+            assert!(self.pledges.get(&account).is_some(), "{}", ERR_NO_PLEDGE);
+            self.margined.insert(&account);
+            let used = Balance::from(env::storage_usage().saturating_sub(storage));
+            let cost = used * env::storage_byte_cost();
+            assert!(deposit >= cost, "{}", ERR_MARGIN_STORAGE);
+            if deposit > cost {
+                Promise::new(account).transfer(deposit - cost);
+            }
+        } else {
+            self.margined.remove(&account);
+            let freed = Balance::from(storage.saturating_sub(env::storage_usage()));
+            Promise::new(account).transfer(deposit + freed * env::storage_byte_cost());
+        }
+    }
+
+    pub fn get_cross_margin(&self, account: ValidAccountId) -> bool {
+        self.margined.contains(account.as_ref())
+    }
+
+    /*  Auto-deleverage: when a side's CR is above MIN_CR but below the
+     *  target that its owner set, collateral is sold to repay debt until
+     *  the CR is back up to the target. Skipped if the contract couldn't
+     *  cover the trade (so `update` never gets stuck on it). Returns
+     *  whether the side was shrunk
+     */
+    pub(crate) fn deleverage(&mut self, pledge: &mut Pledge, short: bool) -> bool {
+        let price = self.get_price();
+        let target = if short { pledge.s_target } else { pledge.target };
+        let extra = if short { NearAmount::ZERO } else { self.basket_near(pledge) }; // counted as if it were NEAR
+        let (cr, no_debt) = if short {
+            (computeCR(price, pledge.short.credit, pledge.short.debit), pledge.short.debit.is_zero())
+        } else {
+            (computeCR(price, pledge.long.credit + extra, pledge.long.debit), pledge.long.debit.is_zero())
+        };
+        if no_debt || cr < self.config.min_cr || cr >= target {
+            return false;
+        }
+        let sold = if short { // QD value, at most what `redeem` or `invert` would need on hand
+            shrinkage(pledge.short.credit, pledge.short.debit * price, target)
+        } else {
+            shrinkage((pledge.long.credit + extra) * price, pledge.long.debit, target)
+        };
+        let on_hand = if short {
+            (sold / price).0 < env::account_balance()
+        } else { // and only NEAR gets sold, not the basket
+            sold.0 < self.token.ft_balance_of(
+                ValidAccountId::try_from(env::current_account_id()).unwrap()
+            ).0 && sold / price <= pledge.long.credit
+        };
+        if sold.is_zero() || !on_hand {
+            return false;
+        }
+        let (sold, cr_after): (U128, Ratio) = if short {
+            let credit = pledge.short.credit;
+            (pledge.short.credit, pledge.short.debit) = self.short_shrink(credit, pledge.short.debit, target);
+            ((credit - pledge.short.credit).into(), computeCR(price, pledge.short.credit, pledge.short.debit))
+        } else {
+            let credit = pledge.long.credit;
+            let (coll, debt) = self.long_shrink(credit + extra, pledge.long.debit, target);
+            pledge.long.credit = coll - extra;
+            pledge.long.debit = debt;
+            ((credit - pledge.long.credit).into(), computeCR(price, coll, debt))
+        };
+        Event::Deleverage {
+            account: pledge.id.clone(), short, sold,
+            cr_before: cr.into(), cr_after: cr_after.into(),
+            target: target.into(), price: price.into()
+        }.emit();
+        true
+    }
+
+    // the CR that the caller's long (or short) gets deleveraged back up to, MIN_CR turns it off
+    #[payable]
+    pub fn set_target(&mut self, short: bool, cr: U128) {
+        assert_one_yocto();
+        let id = env::predecessor_account_id();
+        let mut pledge = self.pledges.get(&id).expect(ERR_NO_PLEDGE);
+        let target = Ratio::from(cr);
+        assert!(target >= self.config.min_cr && target <= MAX_TARGET_CR, "{}", ERR_TARGET);
+        if short {
+            pledge.s_target = target;
+        } else {
+            pledge.target = target;
+        }
+        self.save_pledge(&id, &mut pledge, !short, short);
+        Event::Target { account: id, short, cr }.emit();
+    }
+
+    /* Shrinking is atomically selling an amount of collateral and 
+       immediately using the exact output of that to reduce debt to
+       get its CR up to target. How to calculate amount to be sold:
+       CR = (coll - x) / (debt - x)
+       CR * debt - CR * x = coll - x
+       x(1 - CR) = coll - CR * debt
+       x = (CR * debt - coll) / (CR - 1)
+       The side denominated in NEAR is reduced by the
+       same converted amount in the Pledge and the Pool
+    */
+    pub(crate) fn long_shrink(&mut self, credit: NearAmount, debit: QdAmount, target: Ratio) -> (NearAmount, QdAmount) {
+        let delta = shrinkage(credit * self.get_price(), debit, target);
+        let debt = debit.checked_sub(delta).expect(ERR_SUB);
+        self.live.long.debit = self.live.long.debit
+            .checked_sub(delta).expect(ERR_SUB);
+        let delta = delta / self.get_price();
+        self.invert(delta);
+        self.live.long.credit = self.live.long.credit
+            .checked_sub(delta).expect(ERR_SUB);
+        (
+            credit.checked_sub(delta).expect(ERR_SUB),
+            debt
+        )
+    }
+
+    pub(crate) fn short_shrink(&mut self, credit: QdAmount, debit: NearAmount, target: Ratio) -> (QdAmount, NearAmount) {
+        let delta = shrinkage(credit, debit * self.get_price(), target);
+        let coll = credit.checked_sub(delta).expect(ERR_SUB);
+        self.redeem(delta);
+        self.live.short.credit = self.live.short.credit
+            .checked_sub(delta).expect(ERR_SUB);
+        let delta = delta / self.get_price();
+        self.live.short.debit = self.live.short.debit
+            .checked_sub(delta).expect(ERR_SUB);
+        (
+            coll,
+            debit.checked_sub(delta).expect(ERR_SUB),
+        )
+    }
 
-   pub(crate) fn long_save(&mut self, pledge: &Pledge, available: Balance) -> (Balance, Balance, Balance, Balance) {
+   pub(crate) fn long_save(&mut self, pledge: &Pledge, available: QdAmount) -> (NearAmount, NearAmount, QdAmount, QdAmount) {
        let mut near = pledge.near;
        let mut quid = pledge.quid;
        let mut credit = pledge.long.credit;
        let mut debit = pledge.long.debit;
+       let extra = self.basket_near(pledge); // counted as if it were NEAR
        // attempt to rescue the Pledge by dipping into its SolvencyPool deposit (if any)
        // try NEAR deposit *first*, because long liquidation means NEAR is falling, so
        // we want to keep as much QD in the SolvencyPool as we can before touching it 
@@ -190,10 +476,10 @@ impl Contract
            x = CR * debt / price - coll
            ^ subtracting the same units
        */ 
-       let mut delta = ratio(MIN_CR, debit, self.get_price())
-           .checked_sub(credit).expect(ERR_SUB);
+       let delta = (debit * self.config.min_cr / self.get_price())
+           .saturating_sub(credit + extra);
        
-       let mut min = std::cmp::min(near, delta);
+       let min = std::cmp::min(near, delta);
        near -= min;
        credit = credit
            .checked_add(min).expect(ERR_ADD);
@@ -210,18 +496,20 @@ impl Contract
                x = debt - (coll * price) / CR
                ^ subtracting the same units
            */
-           delta = debit.checked_sub( // find remaining delta using updated credit
-               ratio(self.get_price(), credit, MIN_CR)
-           ).expect(ERR_SUB);
+           let mut delta = debit.saturating_sub( // find remaining delta using updated credit
+               (credit + extra) * self.get_price() / self.config.min_cr
+           );
            // first, try to claim liquid QD from user's FungibleToken balance
-           min = std::cmp::min(available, delta);
+           let mut min = std::cmp::min(available, delta);
            delta -= min;
            debit = debit
                .checked_sub(min).expect(ERR_SUB);
            // we only withdraw, but do not deposit because we are burning debt 
-           self.token.internal_withdraw(&pledge.id, min);
+           self.token.internal_withdraw(&pledge.id, min.0);
+           self.live.long.debit = self.live.long.debit
+               .checked_sub(min).expect(ERR_SUB);
            
-           if delta > 0 {
+           if !delta.is_zero() {
                min = std::cmp::min(quid, delta);
                quid -= min;
                debit = debit
@@ -232,11 +520,11 @@ impl Contract
                    .checked_sub(min).expect(ERR_SUB);
            }
        }
-       return (near, credit, quid, debit); // we did the best we could, 
+       (near, credit, quid, debit) // we did the best we could, 
        // but there is no guarantee that the CR is back up to MIN_CR
    }
 
-   pub(crate) fn short_save(&mut self, pledge: &Pledge, available: Balance) -> (Balance, Balance, Balance, Balance) {
+   pub(crate) fn short_save(&mut self, pledge: &Pledge, available: QdAmount) -> (QdAmount, QdAmount, NearAmount, NearAmount) {
        let mut near = pledge.near;
        let mut quid = pledge.quid;
        let mut credit = pledge.short.credit;
@@ -245,23 +533,23 @@ impl Contract
        // attempt to rescue the Pledge using its SolvencyPool deposit (if any exists)
        // try QD deposit *first*, because short liquidation means NEAR is rising, so
        // we want to keep as much NEAR in the SolvencyPool as we can before touching it
-       let val_debt = ratio(self.get_price(), debit, ONE);
+       let val_debt = debit * self.get_price();
         // first, try to claim liquid QD from user's FungibleToken balance
        // if they have NEAR in the SP it should stay there b/c it's growing
        // as we know this is what put the short in jeopardy of liquidation
-       let final_qd = ratio(MIN_CR, val_debt, KILL_CR);
+       let final_qd = val_debt * self.config.min_cr;
        let mut delta = final_qd.checked_sub(credit).expect(ERR_SUB);
        // first, try to claim liquid QD from user's FungibleToken balance
        let mut min = std::cmp::min(available, delta);
        delta -= min;
 
        credit = credit.checked_add(min).expect(ERR_ADD);
-       self.token.internal_withdraw(&pledge.id, min);
-       self.token.internal_deposit(&env::current_account_id(), min);
+       self.token.internal_withdraw(&pledge.id, min.0);
+       self.token.internal_deposit(&env::current_account_id(), min.0);
        self.live.short.credit = self.live.short.credit
            .checked_add(min).expect(ERR_ADD);
        
-       if delta > 0 {
+       if !delta.is_zero() {
            min = std::cmp::min(quid, delta);
            credit = credit.checked_add(min).expect(ERR_ADD);
            self.live.short.credit = self.live.short.credit
@@ -271,17 +559,18 @@ impl Contract
            quid -= min;
            self.blood.credit -= min;
 
-           if delta > 0 {
-               /*  How much to decrease debt of long side of pledge, to get its CR up to min
+           if !delta.is_zero() {
+               /*  How much to decrease debt of short side of pledge, to get its CR up to min
                    CR = coll / (debt * price - x)
                    debt * price - x = coll / CR
                    x = debt * price - coll / CR
+                   ^ in QD, and the debt is repaid in NEAR
                */
-               delta = val_debt.checked_sub(
-                   ratio(ONE, credit, MIN_CR)
-               ).expect(ERR_SUB);
+               let delta = val_debt.checked_sub(
+                   credit / self.config.min_cr
+               ).expect(ERR_SUB) / self.get_price();
                
-               min = std::cmp::min(near, delta);
+               let min = std::cmp::min(near, delta);
                near -= min;
                debit -= min;
                self.blood.debit -= min;
@@ -289,60 +578,248 @@ impl Contract
                    .checked_sub(min).expect(ERR_SUB);
            }
        }
-       return (quid, credit, near, debit);
+       (quid, credit, near, debit)
    }
 
    /**
      * You have to bring it back. That's why every magic trick has
      * a third act, the hardest part, which we call "The Prestige"
     */
-    pub(crate) fn snatch(&mut self, debt: Balance, collat: Balance, short: bool) {
-        if short { // we are moving crypto debt and QD collateral from LivePool to DeadPool
-            self.live.short.credit = self.live.short.credit
-                .checked_sub(collat).expect(ERR_SUB);
-
-            self.dead.short.credit = self.dead.short.credit
-                .checked_add(collat).expect(ERR_ADD);
-
-            self.live.short.debit = self.live.short.debit
-                .checked_sub(debt).expect(ERR_SUB);
-            
-            let val_debt = ratio(self.get_price(), debt, ONE);
-            
-            let delta = val_debt - collat; 
-            assert!(delta > 0, "Borrower was not supposed to be liquidated");
-            
-            let delta_debt = ratio(ONE, delta, self.get_price());
-                
-            let debt_minus_delta = debt - delta_debt;
-    
-            self.dead.short.debit = self.dead.short.debit
-                .checked_add(debt_minus_delta).expect(ERR_ADD);
-            
-            self.gfund.short.debit = self.gfund.short.debit
-                .checked_add(delta_debt).expect(ERR_ADD);
-        } 
-        else { // we are moving QD debt and crypto collateral
-            self.live.long.credit = self.live.long.credit
-                .checked_sub(collat).expect(ERR_SUB);
-
-            self.dead.long.credit = self.dead.long.credit
-                .checked_add(collat).expect(ERR_ADD);
-            
-            self.live.long.debit = self.live.long.debit
-                .checked_sub(debt).expect(ERR_SUB);
-
-            let val_coll = ratio(self.get_price(), collat, ONE);
-
-            let delta = debt - val_coll;
-            assert!(delta > 0, "Borrower was not supposed to be liquidated");
-            let debt_minus_delta = debt - delta;
-
-            self.dead.long.debit = self.dead.long.debit
-                .checked_add(debt_minus_delta).expect(ERR_ADD);
-            
-            self.gfund.long.debit = self.dead.long.debit
-                .checked_add(delta).expect(ERR_ADD);
+    pub(crate) fn long_snatch(&mut self, account: &AccountId, debt: QdAmount, collat: NearAmount) {
+        if self.auctions.enabled { // the collateral is sold off to settle the debt
+            self.live.long.credit = self.live.long.credit.checked_sub(collat).expect(ERR_SUB);
+            self.live.long.debit = self.live.long.debit.checked_sub(debt).expect(ERR_SUB);
+            self.kick(account, collat, debt, false);
+        } else {
+            let price = self.get_price();
+            Pool::long_snatch(&mut self.live, &mut self.dead, &mut self.gfund, debt, collat, price);
+        }
+    }
+
+    pub(crate) fn short_snatch(&mut self, account: &AccountId, debt: NearAmount, collat: QdAmount) {
+        if self.auctions.enabled {
+            self.live.short.credit = self.live.short.credit.checked_sub(collat).expect(ERR_SUB);
+            self.live.short.debit = self.live.short.debit.checked_sub(debt).expect(ERR_SUB);
+            self.kick(account, debt, collat, true);
+        } else {
+            let price = self.get_price();
+            Pool::short_snatch(&mut self.live, &mut self.dead, &mut self.gfund, debt, collat, price);
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::*;
+    use near_sdk::test_utils::accounts;
+
+    #[test]
+    fn clip_shrinks() {
+        let mut h = leveraged_long();
+        h.set_price(43 * ONE / 10);
+        let dead = h.pools().dead_long_debit.0;
+        h.call(OWNER, 1, |c| c.clip(accounts(ALICE)));
+        assert_eq!(h.events("shrink"), 1);
+        let pledge = h.pledge(ALICE).unwrap();
+        assert_eq!(pledge.debit.0, 300 * ONE);
+        let cr = computeCR(h.contract.get_price(), NearAmount::from(pledge.credit), QdAmount::from(pledge.debit)).0;
+        assert!(rounded(cr, MIN_CR.0));
+        let pools = h.pools();
+        assert_eq!(pools.live_long_debit.0, 700 * ONE);
+        assert!(rounded(pools.live_long_credit.0, 200 * ONE + pledge.credit.0));
+        // the NEAR sold by Alice was bought with the SolvencyPool's QD, by way of the DeadPool
+        assert!(rounded(pools.dead_long_credit.0, 100 * ONE));
+        assert!(rounded(pools.dead_long_debit.0 - dead, 100 * ONE - pledge.credit.0));
+        h.absorb(BOB);
+        let pools = h.pools();
+        assert!(absorbed(pools.blood_credit.0, 200 * ONE));
+        assert!(absorbed(pools.blood_debit.0, 100 * ONE - pledge.credit.0 + dead));
+    }
+
+    #[test]
+    fn clip_liquidates() {
+        let mut h = leveraged_long();
+        h.call(BOB, 1, |c| c.ft_transfer(accounts(ALICE), U128(5 * ONE), None));
+        h.set_price(39 * ONE / 10);
+        h.call(OWNER, 1, |c| c.clip(accounts(ALICE)));
+        assert_eq!(h.events("liquidate"), 1);
+        let liquidate = h.event("liquidate");
+        // 100 NEAR against 400 QD, her 5 QD weren't enough to save her (and she gets them back)
+        assert_eq!(liquidate["cr_before"], (39 * ONE / 40).to_string());
+        assert_eq!(liquidate["cr_after"], "0"); // nothing's left of the side
+        assert_eq!(h.qd(ALICE), 5 * ONE);
+        let pledge = h.pledge(ALICE).unwrap();
+        assert_eq!((pledge.credit.0, pledge.debit.0), (0, 0));
+        let pools = h.pools();
+        assert_eq!(pools.live_long_credit.0, 200 * ONE);
+        assert_eq!(pools.live_long_debit.0, 400 * ONE);
+        assert_eq!(pools.gfund_long_debit.0, 10 * ONE); // 400 QD of debt against 390 worth of NEAR
+    }
+
+    #[test]
+    fn clip_ignores_healthy() {
+        let mut h = leveraged_long();
+        h.call(OWNER, 1, |c| c.clip(accounts(ALICE)));
+        assert_eq!(h.pledge(ALICE).unwrap().debit.0, 400 * ONE);
+        assert_eq!(h.events("shrink") + h.events("liquidate"), 0);
+    }
+
+    #[test]
+    fn clip_many_from_the_riskiest() {
+        let mut h = leveraged_long();
+        h.call(CAROL, 100 * ONE, |c| c.borrow(U128(380 * ONE), false));
+        h.call(CAROL, 1, |c| c.ft_transfer(accounts(BOB), U128(380 * ONE), None));
+        h.set_price(39 * ONE / 10); // Alice below KILL_CR, Carol below MIN_CR, Bob healthy
+        let clipped = h.call(OWNER, 1, |c| c.clip_many(false, 10));
+        let order: Vec<AccountId> = clipped.iter().map(|c| c.account.clone()).collect();
+        assert_eq!(order, vec![accounts(ALICE).to_string(), accounts(CAROL).to_string()]);
+        assert!(clipped[0].liquidated && !clipped[1].liquidated);
+        assert_eq!(clipped[0].cleared.0, 400 * ONE);
+        assert!(rounded(clipped[1].cr_after.0, MIN_CR.0));
+        assert_eq!((h.events("liquidate"), h.events("shrink")), (1, 1));
+        assert_eq!(h.pledge(BOB).unwrap().debit.0, 400 * ONE);
+        assert!(h.call(OWNER, 1, |c| c.clip_many(false, 10)).is_empty());
+    }
+
+    #[test]
+    fn clip_many_stops_at_max() {
+        let mut h = leveraged_long();
+        h.call(CAROL, 100 * ONE, |c| c.borrow(U128(380 * ONE), false));
+        h.call(CAROL, 1, |c| c.ft_transfer(accounts(BOB), U128(380 * ONE), None));
+        h.set_price(39 * ONE / 10);
+        let clipped = h.call(OWNER, 1, |c| c.clip_many(false, 1));
+        assert_eq!(clipped.len(), 1);
+        assert_eq!(h.pledge(CAROL).unwrap().debit.0, 380 * ONE);
+    }
+
+    #[test]
+    fn cr_tree_survives_price_moves() {
+        let mut h = leveraged_long();
+        assert_eq!(h.view(|c| c.long_crs.len()), 2);
+        h.set_price(43 * ONE / 10); // between Alice going into the tree and coming out of it
+        h.call(OWNER, 1, |c| c.clip(accounts(ALICE)));
+        assert_eq!(h.view(|c| c.long_crs.len()), 2); // Alice was shrunk in place
+        let top = h.view(|c| c.long_crs.min()).unwrap();
+        assert_eq!((top.id, top.long.debit), (accounts(ALICE).to_string(), QdAmount(300 * ONE)));
+        h.set_price(2 * ONE);
+        h.call(OWNER, 1, |c| c.clip(accounts(ALICE)));
+        assert_eq!(h.events("liquidate"), 1);
+        assert_eq!(h.view(|c| c.long_crs.len()), 1);
+        assert_eq!(h.view(|c| c.long_crs.min()).unwrap().id, accounts(BOB).to_string());
+    }
+
+    // Alice is long 100 NEAR for 400 QD, and short 20 NEAR against 300 of that QD
+    fn both_sides(cross_margin: bool) -> Harness {
+        let mut h = leveraged_long();
+        h.call(ALICE, ONE / 100, |c| c.set_cross_margin(cross_margin));
+        h.call(BOB, 1, |c| c.ft_transfer(accounts(ALICE), U128(300 * ONE), None));
+        h.call(ALICE, 1, |c| c.deposit(U128(300 * ONE), true));
+        let out = h.call(ALICE, 1, |c| c.borrow(U128(20 * ONE), true));
+        h.paid(out, 20 * ONE);
+        h
+    }
+
+    #[test]
+    fn cross_margin_saves_long() {
+        let mut h = both_sides(true);
+        assert!(h.view(|c| c.get_cross_margin(accounts(ALICE))));
+        h.set_price(39 * ONE / 10); // long at 97.5%, short at ~385%
+        h.call(OWNER, 1, |c| c.clip(accounts(ALICE)));
+        assert_eq!((h.events("margin"), h.events("liquidate"), h.events("shrink")), (1, 0, 0));
+        let pledge = h.pledge(ALICE).unwrap();
+        let repaid = 400 * ONE - pledge.debit.0; // out of the short's surplus QD
+        assert!(rounded(repaid, 400 * ONE - ratio(39 * ONE / 10, 100 * ONE, MIN_CR.0)));
+        assert_eq!(pledge.s_credit.0, 300 * ONE - repaid);
+        assert_eq!(pledge.credit.0, 100 * ONE);
+        let cr = computeCR(Price(39 * ONE / 10), NearAmount::from(pledge.credit), QdAmount::from(pledge.debit)).0;
+        assert!(rounded(cr, MIN_CR.0));
+        assert_eq!(h.pools().live_short_credit.0, 300 * ONE - repaid);
+    }
+
+    #[test]
+    fn cross_margin_saves_short() {
+        let mut h = both_sides(true);
+        h.set_price(18 * ONE); // short at ~83%, long at 450%
+        h.call(OWNER, 1, |c| c.clip(accounts(ALICE)));
+        assert_eq!((h.events("margin"), h.events("liquidate")), (1, 0));
+        let pledge = h.pledge(ALICE).unwrap();
+        let repaid = 20 * ONE - pledge.s_debit.0; // out of the long's surplus NEAR
+        assert_eq!(pledge.credit.0, 100 * ONE - repaid);
+        let cr = computeCR(Price(18 * ONE), QdAmount::from(pledge.s_credit), NearAmount::from(pledge.s_debit)).0;
+        assert!(rounded(cr, MIN_CR.0));
+        assert_eq!(pledge.debit.0, 400 * ONE);
+    }
+
+    #[test]
+    fn no_cross_margin_unless_opted_in() {
+        let mut h = both_sides(false);
+        h.set_price(39 * ONE / 10);
+        h.call(OWNER, 1, |c| c.clip(accounts(ALICE)));
+        assert_eq!((h.events("margin"), h.events("liquidate")), (0, 1));
+        assert_eq!(h.pledge(ALICE).unwrap().s_credit.0, 300 * ONE);
+    }
+
+    #[test]
+    fn cross_margin_pays_for_storage() {
+        let mut h = leveraged_long();
+        h.call(ALICE, ONE / 100, |c| c.set_cross_margin(true));
+        let cost = ONE / 100 - refunded();
+        assert!(cost > 0);
+        h.call(ALICE, 1, |c| c.set_cross_margin(false));
+        assert_eq!(refunded(), cost + 1);
+        assert!(!h.view(|c| c.get_cross_margin(accounts(ALICE))));
+    }
+
+    #[test]
+    #[should_panic(expected = "Attach enough NEAR to pay for cross-margin's storage")]
+    fn no_cross_margin_without_storage() {
+        let mut h = leveraged_long();
+        h.call(ALICE, 1, |c| c.set_cross_margin(true));
+    }
+
+    #[test]
+    #[should_panic(expected = "Pledge doesn't exist")]
+    fn no_cross_margin_without_pledge() {
+        let mut h = leveraged_long();
+        h.call(DAVE, ONE / 100, |c| c.set_cross_margin(true));
+    }
+
+    #[test]
+    fn clip_deleverages_to_target() {
+        let mut h = leveraged_long();
+        h.call(ALICE, 1, |c| c.set_target(false, U128(MIN_CR.0 + ONE / 10)));
+        assert_eq!(h.pledge(ALICE).unwrap().target.0, MIN_CR.0 + ONE / 10);
+        h.call(OWNER, 1, |c| c.clip(accounts(ALICE))); // 125% is above her target
+        assert_eq!(h.events("deleverage"), 0);
+        h.set_price(46 * ONE / 10); // 115%
+        h.call(OWNER, 1, |c| c.clip(accounts(ALICE)));
+        assert_eq!((h.events("deleverage"), h.events("liquidate")), (1, 0));
+        let pledge = h.pledge(ALICE).unwrap();
+        assert!(pledge.debit.0 < 400 * ONE);
+        let cr = computeCR(Price(46 * ONE / 10), NearAmount::from(pledge.credit), QdAmount::from(pledge.debit)).0;
+        assert!(rounded(cr, MIN_CR.0 + ONE / 10));
+        assert_eq!(h.pools().live_long_debit.0, 400 * ONE + pledge.debit.0);
+    }
+
+    #[test]
+    fn update_deleverages_to_target() {
+        let mut h = leveraged_long();
+        h.call(BOB, 50 * ONE, |c| c.deposit(U128(0), false));
+        h.call(ALICE, 1, |c| c.set_target(false, U128(3 * ONE / 2))); // sells 200 of the SP's 300 QD
+        h.call(OWNER, 0, |c| c.update()); // stress the SolvencyPool
+        h.call(OWNER, 0, |c| c.update()); // stress each Pledge
+        assert_eq!(h.events("deleverage"), 1);
+        let pledge = h.pledge(ALICE).unwrap(); // her premium is paid after
+        assert!(rounded(pledge.debit.0, 200 * ONE));
+        assert!(pledge.credit.0 < 60 * ONE);
+    }
+
+    #[test]
+    #[should_panic(expected = "Target CR must be between the configured min_cr and 1000%")]
+    fn no_target_below_min_cr() {
+        let mut h = leveraged_long();
+        h.call(OWNER, 1, |c| c.set_min_cr(U128(3 * ONE / 2))); // governed, above MIN_CR
+        h.call(ALICE, 1, |c| c.set_target(false, U128(7 * ONE / 5)));
+    }
+}
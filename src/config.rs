@@ -0,0 +1,225 @@
+use crate::*;
+
+use near_sdk::{env, Balance};
+use near_sdk::serde::Serialize;
+use near_sdk::json_types::U128;
+use std::ops::Div;
+
+/*  Protocol parameters that the owner may tune without redeploying,
+ *  each setter is bounded so that a fat finger can't brick the peg.
+ *  Initial values are the constants of the same name in utils.rs
+ */
+#[derive(BorshDeserialize, BorshSerialize, Debug, Clone, Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Config {
+    pub min_cr: Ratio, // below which pledges get shrunk, and can't borrow
+    pub kill_cr: Ratio, // below which pledges get liquidated
+    pub fee: Ratio, // charged on swaps, withdrawals and valve borrowing
+    pub min_debt: QdAmount, // QD value of the smallest borrowable position
+    pub update_interval: u64, // nanosecs between `update` cranks (premiums are due per interval)
+    pub crank_batch: u32, // max Pledges stressed per `update` call
+    pub turn_many: u32, // max Pledges visited per redemption or inversion
+    pub gfund_div: u32, // 1/gfund_div of fees and premiums go to the GuaranteeFund
+    pub min_scale: u128, // bounds of the solvency scale factor computed in `risk`, 1e24-scaled
+    pub max_scale: u128,
+} impl Config {
+    pub fn new() -> Self {
+        Self {
+            min_cr: MIN_CR,
+            kill_cr: KILL_CR,
+            fee: FEE,
+            min_debt: MIN_DEBT,
+            update_interval: EIGHT_HOURS,
+            crank_batch: CRANK_BATCH,
+            turn_many: TURN_MANY,
+            gfund_div: GFUND_DIV,
+            min_scale: MIN_SCALE,
+            max_scale: MAX_SCALE,
+        }
+    }
+
+    // the part of an annual amount (premiums) that's due every update interval
+    pub fn per_period(&self, annual: Balance) -> Balance {
+        ratio(self.update_interval as u128, annual, ONE_YEAR as u128)
+    }
+
+    // the GuaranteeFund's cut of a fee or premium, in either currency
+    pub fn gf_cut<A: Div<u128, Output = A>>(&self, amt: A) -> A {
+        amt / self.gfund_div as u128
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub(crate) fn check(ok: bool, param: &str) {
+    if !ok {
+        env::panic(format!("{}: {}", ERR_OUT_OF_BOUNDS, param).as_bytes());
+    }
+}
+
+pub(crate) fn changed<T: ToString>(param: &str, old: T, new: T) {
+    Event::SetConfig { param: param.to_string(), old: old.to_string(), new: new.to_string() }.emit();
+}
+
+#[near_bindgen]
+impl Contract
+{
+    pub fn get_config(&self) -> Config {
+        self.config.clone()
+    }
+
+    #[payable]
+    pub fn set_min_cr(&mut self, min_cr: U128) {
+        assert_one_yocto();
+        self.assert_owner();
+        let min_cr: Ratio = min_cr.into();
+        check(min_cr > self.config.kill_cr && min_cr.0 <= 2 * ONE, "min_cr");
+        changed("min_cr", self.config.min_cr, min_cr);
+        self.config.min_cr = min_cr;
+    }
+
+    #[payable]
+    pub fn set_kill_cr(&mut self, kill_cr: U128) {
+        assert_one_yocto();
+        self.assert_owner();
+        let kill_cr: Ratio = kill_cr.into();
+        check(kill_cr >= Ratio::ONE && kill_cr < self.config.min_cr, "kill_cr");
+        changed("kill_cr", self.config.kill_cr, kill_cr);
+        self.config.kill_cr = kill_cr;
+    }
+
+    #[payable]
+    pub fn set_fee(&mut self, fee: U128) {
+        assert_one_yocto();
+        self.assert_owner();
+        let fee: Ratio = fee.into();
+        check(fee.0 >= ONE / 1000 && fee.0 <= ONE / 20, "fee"); // 0.1% to 5%
+        changed("fee", self.config.fee, fee);
+        self.config.fee = fee;
+    }
+
+    #[payable]
+    pub fn set_min_debt(&mut self, min_debt: U128) {
+        assert_one_yocto();
+        self.assert_owner();
+        let min_debt: QdAmount = min_debt.into();
+        check(min_debt.0 <= 10_000 * ONE, "min_debt");
+        changed("min_debt", self.config.min_debt, min_debt);
+        self.config.min_debt = min_debt;
+    }
+
+    #[payable]
+    pub fn set_update_interval(&mut self, update_interval: u64) {
+        assert_one_yocto();
+        self.assert_owner();
+        check((ONE_HOUR..=24 * ONE_HOUR).contains(&update_interval), "update_interval");
+        changed("update_interval", self.config.update_interval, update_interval);
+        self.config.update_interval = update_interval;
+    }
+
+    #[payable]
+    pub fn set_crank_batch(&mut self, crank_batch: u32) {
+        assert_one_yocto();
+        self.assert_owner();
+        check((1..=200).contains(&crank_batch), "crank_batch");
+        changed("crank_batch", self.config.crank_batch, crank_batch);
+        self.config.crank_batch = crank_batch;
+    }
+
+    #[payable]
+    pub fn set_turn_many(&mut self, turn_many: u32) {
+        assert_one_yocto();
+        self.assert_owner();
+        check((1..=50).contains(&turn_many), "turn_many");
+        changed("turn_many", self.config.turn_many, turn_many);
+        self.config.turn_many = turn_many;
+    }
+
+    #[payable]
+    pub fn set_gfund_div(&mut self, gfund_div: u32) {
+        assert_one_yocto();
+        self.assert_owner();
+        check((2..=100).contains(&gfund_div), "gfund_div"); // 50% to 1%
+        changed("gfund_div", self.config.gfund_div, gfund_div);
+        self.config.gfund_div = gfund_div;
+    }
+
+    #[payable]
+    pub fn set_scale_bounds(&mut self, min_scale: U128, max_scale: U128) {
+        assert_one_yocto();
+        self.assert_owner();
+        let (min_scale, max_scale): (u128, u128) = (min_scale.into(), max_scale.into());
+        check(min_scale >= ONE / 100 && min_scale < max_scale && max_scale <= 10 * ONE, "scale");
+        changed("min_scale", self.config.min_scale, min_scale);
+        changed("max_scale", self.config.max_scale, max_scale);
+        self.config.min_scale = min_scale;
+        self.config.max_scale = max_scale;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, MockedBlockchain};
+
+    fn setup() -> Contract {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        Contract::new(accounts(0))
+    }
+
+    #[test]
+    fn defaults_match_constants() {
+        let contract = setup();
+        let config = contract.get_config();
+        assert_eq!(config.min_cr, MIN_CR);
+        assert_eq!(config.gf_cut(QdAmount(11 * ONE)), QdAmount(ONE));
+        assert_eq!(config.per_period(1095 * ONE), ONE);
+    }
+
+    #[test]
+    fn setters_within_bounds() {
+        let mut contract = setup();
+        contract.set_min_cr(U128(ONE * 120 / 100));
+        contract.set_kill_cr(U128(ONE * 105 / 100));
+        contract.set_crank_batch(10);
+        contract.set_scale_bounds(U128(ONE / 10), U128(2 * ONE));
+        let config = contract.get_config();
+        assert_eq!(config.min_cr, Ratio(ONE * 120 / 100));
+        assert_eq!(config.kill_cr, Ratio(ONE * 105 / 100));
+        assert_eq!(config.crank_batch, 10);
+        assert_eq!(config.max_scale, 2 * ONE);
+    }
+
+    #[test]
+    #[should_panic(expected = "Parameter is out of bounds: kill_cr")]
+    fn kill_cr_below_min_cr() {
+        let mut contract = setup();
+        contract.set_kill_cr(MIN_CR.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "Parameter is out of bounds: fee")]
+    fn fee_too_high() {
+        let mut contract = setup();
+        contract.set_fee(U128(ONE / 10));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn only_owner_sets() {
+        let mut contract = setup();
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.set_fee(FEE.into());
+    }
+}
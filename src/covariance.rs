@@ -0,0 +1,160 @@
+use crate::*;
+
+use near_sdk::{env, Balance};
+use near_sdk::json_types::{I128, U128};
+
+/*  Covariance between the assets held by Pledges and the SolvencyPool,
+ *  for the variance of a portfolio of them (wᵀΣw, see `portfolio_var`).
+ *  Σij = ρij σi σj, where each asset's volatility is NEAR's realized one,
+ *  the one it was listed with (see assets.rs), or QD's own (against the
+ *  dollar) which is set here, as are the correlations between pairs.
+ *  NEAR is keyed as NEAR_ASSET, QD as this contract's account. Until a
+ *  pair is set, two crypto assets are taken to move together (so there
+ *  is no credit for diversification), and QD to move apart from crypto
+ */
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Covariance {
+    pub qd_vol: u128, // annualized, scaled by VOL_SCALE
+    corr: LookupMap<(AccountId, AccountId), i64>, // scaled by CORR_SCALE, keyed in order
+} impl Covariance {
+    pub fn new(prefix: Vec<u8>) -> Self {
+        Self { qd_vol: DEFAULT_QD_VOL, corr: LookupMap::new(prefix) }
+    }
+
+    fn key(a: &AccountId, b: &AccountId) -> (AccountId, AccountId) {
+        if a < b { (a.clone(), b.clone()) } else { (b.clone(), a.clone()) }
+    }
+
+    // correlation between `a` and `b`, scaled by CORR_SCALE
+    pub fn get(&self, a: &AccountId, b: &AccountId, qd: &AccountId) -> i64 {
+        if a == b {
+            return CORR_SCALE;
+        }
+        self.corr.get(&Self::key(a, b)).unwrap_or(
+            if a == qd || b == qd { 0 } else { CORR_SCALE }
+        )
+    }
+}
+
+#[near_bindgen]
+impl Contract
+{
+    #[payable]
+    pub fn set_correlation(&mut self, a: ValidAccountId, b: ValidAccountId, corr: i64) {
+        assert_one_yocto();
+        self.assert_owner();
+        let (a, b): (AccountId, AccountId) = (a.into(), b.into());
+        assert!(self.is_priced(&a) && self.is_priced(&b), "{}", ERR_NO_ASSET);
+        check(a != b, "correlation_pair");
+        check((-CORR_SCALE..=CORR_SCALE).contains(&corr), "correlation");
+        let qd = env::current_account_id();
+        changed(&format!("correlation_{}_{}", a, b), self.covariance.get(&a, &b, &qd), corr);
+        self.covariance.corr.insert(&Covariance::key(&a, &b), &corr);
+    }
+
+    #[payable]
+    pub fn set_qd_vol(&mut self, vol: U128) {
+        assert_one_yocto();
+        self.assert_owner();
+        check(vol.0 <= MAX_ASSET_VOL, "qd_vol");
+        changed("qd_vol", self.covariance.qd_vol, vol.0);
+        self.covariance.qd_vol = vol.0;
+    }
+
+    // Σ between `assets` (NEAR_ASSET, this contract for QD, or listed ones), annualized, 1e24-scaled
+    pub fn get_covariance(&self, assets: Vec<ValidAccountId>) -> Vec<Vec<I128>> {
+        let assets: Vec<AccountId> = assets.into_iter().map(|a| a.into()).collect();
+        for asset in assets.iter() {
+            assert!(self.is_priced(asset), "{}", ERR_NO_ASSET);
+        }
+        self.cov_of(&assets).into_iter().map(|row| row.into_iter().map(I128).collect()).collect()
+    }
+
+    fn is_priced(&self, asset: &AccountId) -> bool {
+        asset == NEAR_ASSET || *asset == env::current_account_id() || self.assets.get(asset).is_some()
+    }
+
+    // annualized volatility of the asset, as a 1e24-scaled fraction
+    fn vol_of(&self, asset: &AccountId) -> u128 {
+        if asset == NEAR_ASSET {
+            self.annual_vol()
+        } else if *asset == env::current_account_id() {
+            self.covariance.qd_vol * (ONE / VOL_SCALE)
+        } else {
+            self.assets.get(asset).expect(ERR_NO_ASSET).vol * (ONE / VOL_SCALE)
+        }
+    }
+
+    pub(crate) fn cov_of(&self, assets: &[AccountId]) -> Vec<Vec<i128>> {
+        let qd = env::current_account_id();
+        let vols: Vec<u128> = assets.iter().map(|a| self.vol_of(a)).collect();
+        let corr: Vec<Vec<i128>> = assets.iter().map(|a| assets.iter()
+            .map(|b| self.covariance.get(a, b, &qd) as i128 * (ONE / CORR_SCALE as u128) as i128).collect()
+        ).collect();
+        covariance(&vols, &corr)
+    }
+
+    // total value of `holdings` (QD value of each asset), and their variance as a portfolio
+    pub(crate) fn portfolio(&self, holdings: &[(AccountId, Balance)]) -> (Balance, u128) {
+        let total = holdings.iter().fold(0 as Balance, |sum, (_, val)| sum.checked_add(*val).expect(ERR_ADD));
+        if total == 0 {
+            return (0, 0);
+        }
+        let assets: Vec<AccountId> = holdings.iter().map(|(a, _)| a.clone()).collect();
+        let w: Vec<u128> = holdings.iter().map(|(_, val)| ratio(ONE, *val, total)).collect();
+        (total, portfolio_var(&w, &self.cov_of(&assets)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, MockedBlockchain};
+
+    fn setup() -> Contract {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        let mut contract = Contract::new(accounts(0));
+        contract.set_asset(accounts(1), U128(ONE / 10), U128(DEFAULT_VOL / 2));
+        contract
+    }
+
+    #[test]
+    fn diversifies_once_correlation_is_set() {
+        let mut contract = setup();
+        let (near, stnear): (AccountId, AccountId) = (NEAR_ASSET.to_string(), accounts(1).into());
+        let (vn, vs) = (DEFAULT_VOL * (ONE / VOL_SCALE), DEFAULT_VOL / 2 * (ONE / VOL_SCALE));
+        let sq = |x: u128| ratio(x, x, ONE);
+        let near_enough = |got: u128, want: u128| got.max(want) - got.min(want) < 10;
+        // alone, or half and half with assets that move together
+        let (val, var) = contract.portfolio(&[(near.clone(), ONE)]);
+        assert_eq!((val, var), (ONE, sq(vn)));
+        let half = [(near.clone(), ONE), (stnear.clone(), ONE)];
+        let (_, var) = contract.portfolio(&half);
+        assert!(near_enough(var, sq((vn + vs) / 2)));
+        // independently moving assets have a lower variance together
+        contract.set_correlation(accounts(1), NEAR_ASSET.to_string().try_into().unwrap(), 0);
+        let (_, var) = contract.portfolio(&half);
+        assert!(near_enough(var, (sq(vn) + sq(vs)) / 4));
+        assert_eq!(contract.get_covariance(vec![accounts(1)]), vec![vec![I128(sq(vs) as i128)]]);
+    }
+
+    #[test]
+    fn qd_moves_apart_from_crypto() {
+        let contract = setup();
+        let qd = env::current_account_id();
+        let cov = contract.cov_of(&[NEAR_ASSET.to_string(), qd]);
+        let vq = DEFAULT_QD_VOL * (ONE / VOL_SCALE);
+        assert_eq!((cov[0][1], cov[1][0], cov[1][1]), (0, 0, ratio(vq, vq, ONE) as i128));
+    }
+
+    #[test]
+    #[should_panic(expected = "This asset isn't listed as collateral")]
+    fn correlates_only_priced_assets() {
+        let mut contract = setup();
+        contract.set_correlation(accounts(1), accounts(2), 0);
+    }
+}
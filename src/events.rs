@@ -0,0 +1,132 @@
+use crate::*;
+
+use near_sdk::{log, serde_json};
+use near_sdk::serde::Serialize;
+use near_sdk::json_types::U128;
+
+pub const EVENT_STANDARD: &str = "quid";
+pub const EVENT_VERSION: &str = "1.0.0";
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CRs { // collateralization ratio of both sides of a Pledge, see `crs`
+    pub long: U128,
+    pub short: U128,
+}
+
+/*  NEP-297 events, logged as `EVENT_JSON:{"standard":"quid",...}`
+ *  Amounts are yoctoNEAR for `near`, and 1e24-scaled for `qd`
+ *  and `price`, same as CRs (where 1e24 stands for 100%). Amounts
+ *  that aren't labeled by currency (e.g. `debt`, `due`, `sold`)
+ *  are in the units of the Pod side they refer to: for longs
+ *  collateral is NEAR and debt is QD, for shorts it's the reverse
+ */
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum Event {
+    Borrow { account: AccountId, short: bool, amount: U128, near: U128,
+             cr_before: CRs, cr_after: CRs, price: U128 },
+    Deposit { account: AccountId, live: bool, near: U128, qd: U128,
+              cr_before: CRs, cr_after: CRs, price: U128 },
+    Renege { account: AccountId, sp: bool, qd: bool, amount: U128, fee: U128,
+             cr_before: CRs, cr_after: CRs, price: U128 },
+    Redeem { account: AccountId, qd: U128, near: U128, fee: U128, // CRs of the LivePool's long side
+             cr_before: U128, cr_after: U128, price: U128 },
+    Invert { account: AccountId, near: U128, qd: U128, fee: U128, // CRs of the LivePool's short side
+             cr_before: U128, cr_after: U128, price: U128 },
+    Repay { account: AccountId, short: bool, amount: U128,
+            cr_before: CRs, cr_after: CRs, price: U128 },
+    Fold { account: AccountId, short: bool, debt: U128,
+           cr_before: CRs, cr_after: CRs, price: U128 },
+    Shrink { account: AccountId, short: bool, sold: U128,
+             cr_before: U128, cr_after: U128, price: U128 },
+    Liquidate { account: AccountId, short: bool, debt: U128, collateral: U128,
+                cr_before: U128, cr_after: U128, price: U128 },
+    Deleverage { account: AccountId, short: bool, sold: U128,
+                 cr_before: U128, cr_after: U128, target: U128, price: U128 },
+    Target { account: AccountId, short: bool, cr: U128 },
+    Margin { account: AccountId, short: bool, moved: U128, // debt of `short` repaid by the other side
+             cr_before: U128, cr_after: U128, price: U128 },
+    Premium { account: AccountId, short: bool, due: U128, rate: U128,
+              cr_before: U128, cr_after: U128, price: U128 },
+    Absorb { account: AccountId, near_gain: U128, near_loss: U128, qd_gain: U128,
+             qd_loss: U128, gfund_near: U128, gfund_qd: U128, price: U128 },
+    Price { reporter: AccountId, reported: U128, price: U128 },
+    AssetPrice { reporter: AccountId, token: AccountId, reported: U128, price: U128 },
+    Collateral { account: AccountId, token: AccountId, deposit: bool, amount: U128, // of `token`
+                 cr_before: CRs, cr_after: CRs, price: U128 }, // of `token`, in QD
+    Seize { account: AccountId, token: AccountId, amount: U128, price: U128 },
+    BuyAsset { account: AccountId, token: AccountId, amount: U128, qd: U128, // paid, `settled` of it
+               settled: U128, price: U128 }, // against the GuaranteeFund's QD debt
+    SetConfig { param: String, old: String, new: String },
+    SetOwner { old: AccountId, new: AccountId },
+    SetGuardian { old: Option<AccountId>, new: Option<AccountId> },
+    AddReporter { account: AccountId },
+    RemoveReporter { account: AccountId },
+    Pause { by: AccountId },
+    Unpause { by: AccountId },
+    Reward { keeper: AccountId, action: String, qd: U128 },
+    Claim { keeper: AccountId, qd: U128 },
+    Kick { lot: u64, account: AccountId, short: bool, collateral: U128, debt: U128, price: U128 },
+    Bid { lot: u64, bidder: AccountId, short: bool, paid: U128, bought: U128, price: U128 },
+    Settle { lot: u64, surplus: U128, shortfall: U128, closed: bool },
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct Envelope<'a> {
+    standard: &'a str,
+    version: &'a str,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+impl Event {
+    pub fn emit(&self) {
+        let envelope = Envelope {
+            standard: EVENT_STANDARD,
+            version: EVENT_VERSION,
+            event: self,
+        };
+        log!("EVENT_JSON:{}", serde_json::to_string(&envelope).unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::{testing_env, MockedBlockchain};
+
+    #[test]
+    fn nep297_format() {
+        testing_env!(VMContextBuilder::new().build());
+        Event::SetConfig {
+            param: "fee".to_string(), old: "1".to_string(), new: "2".to_string()
+        }.emit();
+        assert_eq!(get_logs(), vec![
+            r#"EVENT_JSON:{"standard":"quid","version":"1.0.0","event":"set_config","data":{"param":"fee","old":"1","new":"2"}}"#
+        ]);
+    }
+
+    #[test]
+    fn setters_emit() {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        let mut contract = Contract::new(accounts(0));
+        contract.set_turn_many(5);
+        contract.propose_owner(accounts(1));
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        contract.accept_owner();
+        let logs = get_logs();
+        assert_eq!(logs.len(), 1); // logs are reset along with the context
+        assert!(logs[0].starts_with(r#"EVENT_JSON:{"standard":"quid","version":"1.0.0","event":"set_owner""#));
+        assert!(logs[0].ends_with(r#""data":{"old":"alice","new":"bob"}}"#));
+    }
+}
@@ -0,0 +1,234 @@
+use crate::*;
+
+/*  Deterministic fixed-point math for the risk engine, so that premiums
+ *  come out of integers only. Numbers are 1e24-scaled like amounts (ONE
+ *  is 1.0): i128 where they may be negative, u128 where they may not.
+ *  Each function works in U256 at 1e36 internally, so its error before
+ *  rounding stays well below the 1e-24 of the result (bounds are given
+ *  per function, and checked against arbitrary precision in the tests),
+ *  then it rounds towards zero. Results that don't fit panic, the same
+ *  as `ratio` does
+ */
+const P: u128 = 1_000000_000000_000000_000000_000000_000000; // internal scale
+const GUARD: u128 = 1_000000_000000; // from the scale of results to P
+const LN2: u128 = 693147180559945309417232121458176568; // at P
+const SQRT_PI: u128 = 1772453850905516027298167483341145183; // at P
+const ERFC_SERIES: u128 = 3 * P; // below which erfc sums the Taylor series of erf
+const ERFC_TERMS: u128 = 80; // of the continued fraction, above that
+pub const SQRT_TWO: u128 = 1_414213_562373_095048_801689;
+pub const SQRT_TWO_PI: u128 = 2_506628_274631_000502_415765;
+pub const EXP_MAX: i128 = 33 * ONE as i128; // e^33 is about as much as fits in a u128
+pub const ERFC_ZERO: u128 = 8 * ONE; // erfc is below 1e-24 from here on
+
+fn up(x: u128) -> U256 {
+    U256::from(x) * U256::from(GUARD)
+}
+
+fn down(x: U256) -> u128 {
+    let x = x / U256::from(GUARD);
+    assert!(x <= U256::from(u128::MAX), "Overflow");
+    x.as_u128()
+}
+
+fn signed(neg: bool, abs: u128) -> i128 {
+    assert!(abs <= i128::MAX as u128, "Overflow");
+    if neg { -(abs as i128) } else { abs as i128 }
+}
+
+// a * b, error below 1e-24
+pub fn mul(a: i128, b: i128) -> i128 {
+    signed((a < 0) != (b < 0), ratio(a.unsigned_abs(), b.unsigned_abs(), ONE))
+}
+
+// a / b, error below 1e-24
+pub fn div(a: i128, b: i128) -> i128 {
+    assert!(b != 0, "Division by zero");
+    signed((a < 0) != (b < 0), ratio(ONE, a.unsigned_abs(), b.unsigned_abs()))
+}
+
+// √x, error below 1e-24 (it's the floor of the exact root)
+pub fn sqrt(x: u128) -> u128 {
+    let x = U256::from(x) * U256::from(ONE);
+    if x.is_zero() {
+        return 0;
+    }
+    let mut root = U256::one() << x.bits().div_ceil(2); // above the root
+    loop { // Newton's method, which decreases towards the floor from above
+        let next = (root + x / root) >> 1;
+        if next >= root {
+            return root.as_u128();
+        }
+        root = next;
+    }
+}
+
+// e^x at P, for x ≥ 0 at P: Taylor series of e^r, where x = k ln2 + r
+fn exp_p(x: U256) -> U256 {
+    let (p, ln2) = (U256::from(P), U256::from(LN2));
+    let k = x / ln2;
+    assert!(k < U256::from(100), "Overflow");
+    let r = x - k * ln2;
+    let (mut sum, mut term, mut n) = (p, p, U256::one());
+    loop {
+        term = term * r / (n * p);
+        if term.is_zero() {
+            return sum << k.as_usize();
+        }
+        sum += term;
+        n += U256::one();
+    }
+}
+
+// e^-x at P, for x ≥ 0 at P, zero once it's below 1e-30
+fn exp_neg_p(x: U256) -> U256 {
+    if x >= U256::from(LN2) * U256::from(100) {
+        return U256::zero();
+    }
+    let p = U256::from(P);
+    p * p / exp_p(x)
+}
+
+// e^x, relative error below 1e-30 (or 1e-24 absolute, for tiny results)
+pub fn exp(x: i128) -> u128 {
+    let abs = up(x.unsigned_abs());
+    down(if x < 0 { exp_neg_p(abs) } else { exp_p(abs) })
+}
+
+// ln x at P, for x > 0 at P: ln m = 2 atanh((m - 1) / (m + 1)), where x = m 2^k
+fn ln_p(x: U256) -> i128 {
+    let (p, two) = (U256::from(P), U256::from(2 * P));
+    let (mut m, mut k) = (x, 0i128);
+    while m >= two {
+        m >>= 1;
+        k += 1;
+    }
+    while m < p {
+        m <<= 1;
+        k -= 1;
+    }
+    let z = (m - p) * p / (m + p); // below 1/3, so the series converges fast
+    let z2 = z * z / p;
+    let (mut sum, mut term, mut n) = (U256::zero(), z, 1u32);
+    while !term.is_zero() {
+        sum += term / U256::from(n);
+        term = term * z2 / p;
+        n += 2;
+    }
+    k * LN2 as i128 + 2 * sum.as_u128() as i128
+}
+
+// ln x for x > 0, error below 1e-24
+pub fn ln(x: u128) -> i128 {
+    assert!(x > 0, "ln of zero");
+    ln_p(up(x)) / GUARD as i128
+}
+
+// erfc x at P, for x ≥ 0 at P
+fn erfc_p(x: U256) -> U256 {
+    let p = U256::from(P);
+    if x >= up(ERFC_ZERO) {
+        return U256::zero();
+    }
+    let x2 = x * x / p;
+    if x < U256::from(ERFC_SERIES) {
+        // erf x = 2/√π Σ (-1)^n x^(2n+1) / (n! (2n+1)), summed apart by sign
+        let (mut plus, mut minus, mut term, mut n) = (U256::zero(), U256::zero(), x, 0u32);
+        while !term.is_zero() {
+            let t = term / U256::from(2 * n + 1);
+            if n % 2 == 0 { plus += t } else { minus += t }
+            n += 1;
+            term = term * x2 / (U256::from(n) * p);
+        }
+        let erf = (plus - minus) * U256::from(2) * p / U256::from(SQRT_PI);
+        return p.saturating_sub(erf);
+    }
+    // e^-x² / √π / (x + (1/2) / (x + 1 / (x + (3/2) / (x + ...)))), from the bottom up
+    let mut f = x;
+    for n in (1..=ERFC_TERMS).rev() {
+        f = x + U256::from(n * P / 2) * p / f;
+    }
+    exp_neg_p(x2) * p / U256::from(SQRT_PI) * p / f
+}
+
+// erfc x = 1 - erf x, error below 1e-24
+pub fn erfc(x: i128) -> u128 {
+    let e = erfc_p(up(x.unsigned_abs()));
+    down(if x < 0 { U256::from(2 * P) - e } else { e })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ULP: u128 = 1; // results round towards zero, so they may be a unit below
+
+    fn f(x: u128) -> f64 {
+        x as f64 / ONE as f64
+    }
+
+    fn fi(x: i128) -> f64 {
+        x as f64 / ONE as f64
+    }
+
+    fn fixed(x: f64) -> i128 {
+        (x * 1e12).round() as i128 * GUARD as i128
+    }
+
+    fn close(got: f64, want: f64) -> bool {
+        (got - want).abs() <= 1e-14 * want.abs().max(1.0)
+    }
+
+    #[test]
+    fn exact_against_arbitrary_precision() {
+        // rounded to the nearest 1e-24 with mpmath, at 60 digits
+        let near = |got: u128, want: u128| got <= want && want - got <= ULP;
+        assert!(near(exp(ONE as i128), 2718281828459045235360287));
+        assert!(near(exp(-(ONE as i128)), 367879441171442321595524));
+        assert!(near(exp(10 * ONE as i128), 22026465794806716516957900645));
+        assert_eq!(exp(0), ONE);
+        assert!(near(ln(10 * ONE) as u128, 2302585092994045684017991));
+        assert!(near((-ln(ONE / 1_000_000)) as u128, 13815510557964274104107949));
+        assert_eq!(ln(ONE), 0);
+        assert!(near(sqrt(2 * ONE), SQRT_TWO));
+        assert_eq!(sqrt(4 * ONE), 2 * ONE);
+        assert!(near(erfc(ONE as i128 / 2), 479500122186953462317253));
+        assert!(near(erfc(ONE as i128), 157299207050285130658779));
+        assert!(near(erfc(2 * ONE as i128), 4677734981047265837931));
+        assert!(near(erfc(3 * ONE as i128), 22090496998585441373));
+        assert!(near(erfc(5 * ONE as i128), 1537459794428));
+        assert!(near(erfc(-(ONE as i128)), 1842700792949714869341221));
+        assert_eq!((erfc(0), erfc(ERFC_ZERO as i128)), (ONE, 0));
+    }
+
+    #[test]
+    fn same_as_floats() {
+        let mut x = -30.0;
+        while x < 30.0 {
+            let fx = fixed(x);
+            assert!(close(f(exp(fx)), fi(fx).exp()), "exp {}", x);
+            x += 0.37;
+        }
+        let mut x = 1e-9;
+        while x < 1e12 {
+            let fx = fixed(x) as u128;
+            assert!(close(fi(ln(fx)), f(fx).ln()), "ln {}", x);
+            assert!(close(f(sqrt(fx)), f(fx).sqrt()), "sqrt {}", x);
+            x *= 3.7;
+        }
+        let mut x = -7.9;
+        while x < 7.9 {
+            // erfc is as good in absolute terms, the float's tail isn't
+            let fx = fixed(x);
+            assert!((f(erfc(fx)) - libm::erfc(fi(fx))).abs() < 1e-15, "erfc {}", x);
+            x += 0.0731;
+        }
+        assert!(close(fi(mul(-3 * ONE as i128, ONE as i128 / 4)), -0.75));
+        assert!(close(fi(div(-3 * ONE as i128, -4 * ONE as i128)), 0.75));
+    }
+
+    #[test]
+    #[should_panic(expected = "Overflow")]
+    fn exp_overflows_past_the_max() {
+        exp(EXP_MAX + 2 * ONE as i128);
+    }
+}
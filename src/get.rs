@@ -1,80 +1,40 @@
 use crate::*;
 
-use near_sdk::{env, log, Balance, Promise};
-use near_sdk::serde::{Deserialize, Serialize};
-use near_sdk::json_types::{WrappedBalance, WrappedTimestamp, U128};
-
-pub trait PledgeForTreeMap: Clone + BorshSerialize + BorshDeserialize {
-    fn get_id(&self) -> AccountId;
-    fn get_debt_amt(&self, short: bool) -> U128;
-    fn get_coll_val(&self, short: bool) -> U128;
-    fn get_CR(&self, short: bool, price: u128) -> U128;
-}
-impl PledgeForTreeMap for Pledge {
-    fn get_id(&self) -> AccountId {
-        self.id.clone()
-    }
-
-    fn get_debt_amt(&self, short: bool) -> U128 {
-        if short {
-            return self.short.debit.into()
-        } else {
-            return self.long.debit.into()
-        }
-    }
-
-    fn get_coll_val(&self, short: bool) -> U128 {
-        if short {
-            return self.short.credit.into()
-        } else {
-            return self.long.credit.into()
-        }
-    }
-
-    fn get_CR(&self, short: bool, price: u128) -> U128 {
-        if short {
-            return computeCR(price, self.short.credit, self.short.debit, true).into();
-        } else {
-            return computeCR(price, self.long.credit, self.long.debit, false).into();
-        }
-    }
-}
+use near_sdk::{env, Balance};
+use near_sdk::json_types::{WrappedBalance, U128};
 
 #[near_bindgen]
 impl Contract 
 {
-    pub fn get_price(&self) -> u128 { 
-        return self.price;
+    pub fn get_price(&self) -> Price { 
+        self.oracle.price
     }
     
+    // annualized realized volatility of NEAR, scaled by VOL_SCALE
     pub fn get_vol(&self) -> u128 { 
-        return self.vol;
-    }
-
-    // // TODO Flux, push-based not pull-based
-    // https://github.com/fluxprotocol/fpo-near/blob/main/consumer/src/lib.rs
-    // 1req/min rate limited
-    pub fn set_price(&mut self, _price: u128) { // TODO remove
-        self.price = _price;
+        self.vol.annual
     }
     
-    pub fn set_vol(&mut self, _vol: u128) { // TODO remove
-        self.vol = _vol;
-    }    
-    
     pub fn get_pool_stats(&self) -> PoolStats {
-        PoolStats::new(&self)
+        PoolStats::new(self)
     }
 
     pub fn get_pledge(&self, account: ValidAccountId) -> Option<PledgeView> {
         self.pledges.get(account.as_ref()).map(|a| (&a).into())
     }
 
+    pub fn get_vote(&self, account: ValidAccountId) -> Option<Vote> {
+        self.votes.get(account.as_ref())
+    }
+
+    // when the Pledge last absorbed its share of the DeadPool
+    pub fn get_last_absorbed(&self, account: ValidAccountId) -> Option<u64> {
+        self.pledges.get(account.as_ref())
+            .map(|_| self.marks.get(account.as_ref()).unwrap_or_default().last_absorbed)
+    }
+
     pub fn get_qd_balance(&self, account: ValidAccountId) -> WrappedBalance {
-        let balance: WrappedBalance = self.token.ft_balance_of(
-            ValidAccountId::try_from(account.clone()).unwrap()
-        ).into();   
-        return balance;
+        self.token.ft_balance_of(account)
     }
 
     pub fn get_pledges(&self, from_index: u64, limit: u64) -> Vec<(AccountId, PledgeView)> {
@@ -92,154 +52,301 @@ impl Contract
     pub fn get_pledge_stats(&self, account: ValidAccountId, short: bool) -> Stats {
         if let Some(pledge) = self.pledges.get(account.as_ref()) {  
             if short {
-                return pledge.stats.short.clone();
+                pledge.stats.short.clone()
             } else {
-                return pledge.stats.long.clone();
+                pledge.stats.long.clone()
             }
         } else {
             if short {
-                return self.stats.short.clone();
+                self.stats.short.clone()
             } else {
-                return self.stats.long.clone();
+                self.stats.long.clone()
             }   
         }
     }
 
-    pub fn get_pledge_tree(&self, from_index: u64, limit: u64) {
-        let keys: Vec<AccountId> = self.long_crs.to_vec()
-                                .iter()
-                                .map(|(u, _)| u.id.clone())
-                                .collect();
+    /*  Pledges borrowing long (or short) with a CR in [min_cr, max_cr)
+     *  (either bound optional), in the order that redemptions (inversions)
+     *  and `clip_many` visit them: riskiest band of CR first, largest debt
+     *  first within a band. Rows are the account, its Pledge, its CR at
+     *  the current price and its debt (QD for longs, NEAR for shorts)
+     */
+    pub fn get_pledge_tree(&self, short: bool, from_index: u64, limit: u64,
+                           min_cr: Option<U128>, max_cr: Option<U128>) -> Vec<(AccountId, PledgeView, U128, U128)> {
+        let price = self.get_price();
+        let tree = if short { &self.short_crs } else { &self.long_crs };
+        tree.between(min_cr.map(Ratio::from), max_cr.map(Ratio::from), price)
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .map(|(pledge, _)| {
+                let pledge = self.pledges.get(&pledge.id).unwrap(); // the tree holds a copy
+                let view = (&pledge).into();
+                let debt = if short { pledge.get_short_debt().into() } else { pledge.get_long_debt().into() };
+                (pledge.id.clone(), view, pledge.get_CR(short, price).into(), debt)
+            })
+            .collect()
+    }
 
+    /*  Pledges borrowing long against a basket: `long_crs` sorts them by
+     *  what the basket was worth when they were last saved, but their CR
+     *  moves with more than NEAR's price, so keepers also `clip` those
+     *  below MIN_CR from here one by one. Rows are as in `get_pledge_tree`
+     */
+    pub fn get_basket_pledges(&self, from_index: u64, limit: u64) -> Vec<(AccountId, PledgeView, U128, U128)> {
+        self.baskets.iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .map(|id| {
+                let pledge = self.pledges.get(&id).unwrap();
+                let view = (&pledge).into();
+                (id, view, self.long_cr(&pledge).into(), pledge.long.debit.into())
+            })
+            .collect()
     }
 
     pub(crate) fn fetch_pledge(&mut self, id: &AccountId, create: bool) -> Pledge {
-        if let Some(mut pledge) = self.pledges.get(&id) 
+        if !self.crank.done && self.pledges.get(id).is_some()
+        && self.charged.get(id) != Some(self.crank.epoch) {
+            // the update hasn't reached it yet: it pays this epoch's premium
+            // on what it was, before the caller changes it
+            self.stress_pledge(id.clone());
+        }
+        if let Some(mut pledge) = self.pledges.get(id) 
         {
-            let val_near_sp = self.blood.debit
-                .checked_div(KILL_CR).expect(ERR_DIV);
-                    
-            self.stats.val_near_sp = val_near_sp
-                .checked_mul(self.get_price()).expect(ERR_MUL);
+            self.stats.val_near_sp = self.blood.debit * self.get_price();
+            
+            self.stats.val_total_sp = self.blood.credit + self.stats.val_near_sp;
             
-            self.stats.val_total_sp = self.blood.credit
-                .checked_add(self.stats.val_near_sp).expect(ERR_ADD);
+            // retrieve the Pledge's pending allocation of fees as well as defaulted
+            // long/short Pledges' collateral and debt, post redemptions/inversions
+            self.absorption.accrue(&self.dead, self.blood.credit, self.blood.debit);
+            let mark = self.marks.get(id).unwrap_or_default();
+            let index = self.absorption.index.clone();
+            // what the index grew by since this Pledge last absorbed, times its deposit,
+            // capped by what's in the DeadPool, which the index rounds down to
+            let share = |amt: u128, deposit: u128, now: Balance, then: Balance| {
+                std::cmp::min(amt, ratio(deposit, now - then, ONE))
+            };
+            let (in_qd, in_near) = (pledge.quid.0, pledge.near.0);
+            let mut near = NearAmount(share(self.dead.long.debit.0, in_qd, index.near, mark.index.near));
+            let mut qd_debt = QdAmount(share(self.dead.long.credit.0, in_qd, index.qd_debt, mark.index.qd_debt));
+            let near_debt = NearAmount(share(self.dead.short.credit.0, in_near, index.near_debt, mark.index.near_debt));
+            let mut qd = QdAmount(share(self.dead.short.debit.0, in_near, index.qd, mark.index.qd));
+            let (old_near, old_quid) = (pledge.near, pledge.quid);
+            let (old_gf_near, old_gf_qd) = (self.gfund.long.credit, self.gfund.short.credit);
             
-            if self.sp_stress(None, false) > 0.0 // stress the long side of the SolvencyPool
-            && self.sp_stress(None, true) > 0.0 { // stress the short side of the SolvencyPool
-                // retrieve the Pledge's pending allocation of fees as well as defaulted
-                // long/short Pledges' collateral and debt, post redemptions/inversions
-                // stressed value of SolvencyPool's short side, exclusing given pledge
-                let s_stress_ins_x = self.sp_stress(Some(id.clone()), true);
-                let s_delta = self.stats.short.stress_val - s_stress_ins_x;
-                let s_pcs: f64 = s_delta / self.stats.short.stress_val; // % contrib. to short solvency
-                
-                // stressed value of SolvencyPool's long side, exclusing given pledge
-                let l_stress_ins_x = self.sp_stress(Some(id.clone()), false);
-                let l_delta = self.stats.long.stress_val - l_stress_ins_x;
-                let l_pcs: f64 = l_delta / self.stats.long.stress_val; // % contrib. to long solvency
-                
-                if s_pcs > 0.0 && l_pcs > 0.0 {
-                    // Calculate DeadPool shares to absorb by this pledge
-                    // TODO attack where net postive DP is drained by repeated 
-                    // micro pledge updates...limit updates to occur only once an hour
-                    let mut near = (self.dead.long.debit as f64 * l_pcs).round() as Balance;
-                    let mut near_debt = (self.dead.short.credit as f64 * s_pcs).round() as Balance;
-                    let mut qd_debt = (self.dead.long.credit as f64 * l_pcs).round() as Balance;
-                    let mut qd = (self.dead.short.debit as f64 * s_pcs).round() as Balance;
-                    
-                    if near_debt >= near 
-                    { // net loss in terms of NEAR
-                        let mut delta = near_debt - near;
-                        self.dead.long.debit -= near; // the NEAR gain has been absorbed 
-                        if delta > 0 {
-                            // absorb as much as we can from the pledge
-                            let min = std::cmp::min(pledge.near, delta);
-                            self.dead.short.credit -= min; // TODO -= delta
-                            
-                            pledge.near = pledge.near // decrement user's recorded SP deposit
-                                .checked_sub(min).expect(ERR_SUB);
-                            self.blood.debit = self.blood.debit // decrement deposit from SP
-                                .checked_sub(min).expect(ERR_SUB);
-                            delta -= min;
-                            if delta > 0 { // TODO calling multiple times will suck all money 
-                                // from the cold pool until the DeadPool gets emptied out
-                                // limit to once per hour, perhaps? Don't fuck with Batfrog
-                                self.gfund.long.credit = self.gfund.long.credit
-                                    .checked_sub(delta).expect(ERR_SUB);
-                            }
-                        }
-                    } else 
-                    { // net gain in terms of NEAR 
-                        self.dead.short.credit -= near_debt;
-                        self.dead.long.debit -= near;
-                        near -= near_debt;
-                        // TODO if pledge has any CR between 100-110, take the smaller one first
-                        // add enough NEAR collat to long / remove enough NEAR debt from short
-                        // such that the new CR is > 110, repeat again for larger CR side 
-                        // remaining NEAR goes to SP deposit...
-                        pledge.near = pledge.near
-                            .checked_add(near).expect(ERR_ADD);
-                        self.blood.debit = self.blood.debit
-                            .checked_add(near).expect(ERR_ADD);
+            // the DeadPool gives up all of this Pledge's share either way
+            self.dead.long.debit -= near;
+            self.dead.long.credit -= qd_debt;
+            self.dead.short.credit -= near_debt;
+            self.dead.short.debit -= qd;
+            if near_debt >= near 
+            { // net loss in terms of NEAR
+                let mut delta = near_debt - near;
+                if !delta.is_zero() {
+                    // absorb as much as we can from the pledge
+                    let min = std::cmp::min(pledge.near, delta);
+                    pledge.near = pledge.near // decrement user's recorded SP deposit
+                        .checked_sub(min).expect(ERR_SUB);
+                    self.blood.debit = self.blood.debit // decrement deposit from SP
+                        .checked_sub(min).expect(ERR_SUB);
+                    delta -= min;
+                    if !delta.is_zero() { // each share of a loss is only absorbed once
+                        // what the GuaranteeFund can't pay, it owes (as NEAR debt)
+                        let paid = std::cmp::min(self.gfund.long.credit, delta);
+                        self.gfund.long.credit -= paid;
+                        self.gfund.short.debit += delta - paid;
                     }
-                    if qd_debt >= qd 
-                    { // net loss in terms of QD
-                        let mut delta = qd_debt - qd;
-                        self.dead.short.debit -= qd; // the QD gain has been absorbed
-                        if delta > 0 {
-                            let min = std::cmp::min(pledge.quid, delta);
-                            self.dead.long.credit -= min; // TODO -= delta
-                            pledge.quid = pledge.quid
-                                .checked_sub(min).expect(ERR_SUB);
-                            self.blood.credit = self.blood.credit
-                                .checked_sub(min).expect(ERR_SUB);
-                            delta -= min;   
-                            if delta > 0 {
-                                // TODO
-                                // use x_margin to absorb into the borrowing position, 
-                                // only remainder after x_margin should be absorbed by gfund pool
-                                self.gfund.short.credit = self.gfund.short.credit
-                                    .checked_sub(delta).expect(ERR_SUB);
-                            }
-                        }
-                    } else 
-                    { // net gain in terms of QD
-                        self.dead.long.credit -= qd_debt;
-                        self.dead.short.debit -= qd;
-                        qd -= qd_debt;
-                        // TODO if pledge has any CR between 100-110, take the smaller one first
-                        // let mut min = std::min(pledge.long.debit, qd);
-                        // pledge.long.debit -= min;
-                        // qd -= min;
-                        // remove enough QD debt such that CR > 110
-                        // if short CR between 100-110
-                        // add enough QD to collat such that CR > 110 
-                        // remaininf QD goes to SP deposit...
-                        pledge.quid = pledge.quid
-                            .checked_add(qd).expect(ERR_ADD);
-                        self.blood.credit = self.blood.credit
-                            .checked_add(qd).expect(ERR_ADD);
+                }
+            } else { // net gain in terms of NEAR 
+                near -= near_debt;
+                // TODO if pledge has any CR between 100-110, take the smaller one first
+                // add enough NEAR collat to long / remove enough NEAR debt from short
+                // such that the new CR is > 110, repeat again for larger CR side 
+                // remaining NEAR goes to SP deposit...
+                pledge.near = pledge.near
+                    .checked_add(near).expect(ERR_ADD);
+                self.blood.debit = self.blood.debit
+                    .checked_add(near).expect(ERR_ADD);
+            }
+            if qd_debt >= qd 
+            { // net loss in terms of QD
+                qd_debt -= qd;
+                if !qd_debt.is_zero() {
+                    let min = std::cmp::min(pledge.quid, qd_debt);
+                    pledge.quid = pledge.quid
+                        .checked_sub(min).expect(ERR_SUB);
+                    self.blood.credit = self.blood.credit
+                        .checked_sub(min).expect(ERR_SUB);
+                    qd_debt -= min;   
+                    if !qd_debt.is_zero() {
+                        // TODO
+                        // use x_margin to absorb into the borrowing position, 
+                        // only remainder after x_margin should be absorbed by gfund pool
+                        let paid = std::cmp::min(self.gfund.short.credit, qd_debt);
+                        self.gfund.short.credit -= paid;
+                        self.gfund.long.debit += qd_debt - paid; // owed as QD debt
                     }
                 }
+            } else { // net gain in terms of QD
+                qd -= qd_debt;
+                // TODO if pledge has any CR between 100-110, take the smaller one first
+                // let mut min = std::min(pledge.long.debit, qd);
+                // pledge.long.debit -= min;
+                // qd -= min;
+                // remove enough QD debt such that CR > 110
+                // if short CR between 100-110
+                // add enough QD to collat such that CR > 110 
+                // remaininf QD goes to SP deposit...
+                pledge.quid = pledge.quid
+                    .checked_add(qd).expect(ERR_ADD);
+                self.blood.credit = self.blood.credit
+                    .checked_add(qd).expect(ERR_ADD);
+            }
+            self.absorption.accrue(&self.dead, self.blood.credit, self.blood.debit);
+            self.marks.insert(id, &Mark { index, last_absorbed: env::block_timestamp() });
+            if pledge.near != old_near || pledge.quid != old_quid {
+                // saved right away, the caller may have nothing else to save
+                self.save_pledge(id, &mut pledge, false, false);
+                self.on_stake_change(&pledge);
             }
-            return pledge;
+            if pledge.near != old_near || pledge.quid != old_quid 
+            || self.gfund.long.credit != old_gf_near || self.gfund.short.credit != old_gf_qd {
+                Event::Absorb { 
+                    account: id.clone(),
+                    near_gain: pledge.near.saturating_sub(old_near).into(),
+                    near_loss: old_near.saturating_sub(pledge.near).into(),
+                    qd_gain: pledge.quid.saturating_sub(old_quid).into(),
+                    qd_loss: old_quid.saturating_sub(pledge.quid).into(),
+                    gfund_near: (old_gf_near - self.gfund.long.credit).into(),
+                    gfund_qd: (old_gf_qd - self.gfund.short.credit).into(),
+                    price: self.get_price().into()
+                }.emit();
+            }
+            pledge
         } 
         else if create {
             let mut prefix = Vec::with_capacity(33);
             prefix.push(b's');
             prefix.extend(env::sha256(id.as_bytes()));
-            return Pledge {
-                long: Pod::new(0, 0),
-                short: Pod::new(0, 0),
+            Pledge {
+                long: Pod::default(),
+                short: Pod::default(),
                 stats: PledgeStats::new(),
-                quid: 0, near: 0,
+                quid: QdAmount::ZERO, near: NearAmount::ZERO,
                 id: id.clone(),
-                target: MIN_CR
+                target: self.config.min_cr,
+                s_target: self.config.min_cr,
+                basket: vec![],
+                basket_near: NearAmount::ZERO
             }
         } else {
             env::panic(b"Pledge doesn't exist"); 
         }
     }
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::*;
+    use near_sdk::test_utils::accounts;
+
+    #[test]
+    fn pledge_tree_pages_pledges_at_risk() {
+        let mut h = Harness::new(PRICE);
+        h.call(BOB, 500 * ONE, |c| c.borrow(U128(1000 * ONE), false));
+        h.call(ALICE, 25 * ONE, |c| c.borrow(U128(100 * ONE), false));
+        h.call(CAROL, 30 * ONE, |c| c.borrow(U128(120 * ONE), false));
+        h.set_price(43 * ONE / 10); // Alice and Carol at 107.5%, Bob at 215%
+        let at_risk = h.view(|c| c.get_pledge_tree(false, 0, 10, None, Some(U128(MIN_CR.0))));
+        assert_eq!(at_risk.len(), 2);
+        let (account, pledge, cr, debt) = &at_risk[0];
+        assert_eq!(account, &accounts(CAROL).to_string()); // same band as Alice, with more debt
+        assert_eq!((pledge.credit.0, debt.0), (30 * ONE, 120 * ONE));
+        assert_eq!(cr.0, computeCR(Price(43 * ONE / 10), NearAmount(30 * ONE), QdAmount(120 * ONE)).0);
+
+        let page = h.view(|c| c.get_pledge_tree(false, 1, 1, None, Some(U128(MIN_CR.0))));
+        assert_eq!(page[0].0, accounts(ALICE).to_string());
+        let healthy = h.view(|c| c.get_pledge_tree(false, 0, 10, Some(U128(MIN_CR.0)), None));
+        assert_eq!(healthy.len(), 1);
+        assert_eq!(healthy[0].0, accounts(BOB).to_string());
+        assert!(h.view(|c| c.get_pledge_tree(true, 0, 10, None, None)).is_empty());
+    }
+
+    // Bob's and Carol's QD deposits share the NEAR that premiums paid
+    #[test]
+    fn absorbs_by_index() {
+        let mut h = leveraged_long();
+        h.call(CAROL, 50 * ONE, |c| c.borrow(U128(100 * ONE), false));
+        h.call(CAROL, 1, |c| c.deposit(U128(100 * ONE), false));
+        assert_eq!(crank(&mut h), 3); // premiums' NEAR goes to QD deposits
+        let gain = h.pools().dead_long_debit.0;
+        assert!(gain > 0);
+
+        h.call(CAROL, 1, |c| c.deposit(U128(0), false));
+        assert_eq!(h.events("absorb"), 1);
+        h.call(CAROL, 1, |c| c.deposit(U128(0), false)); // absorbs nothing twice
+        assert_eq!(h.events("absorb"), 0);
+        assert_eq!(h.view(|c| c.get_last_absorbed(accounts(CAROL))), Some(h.now));
+        h.call(BOB, 1, |c| c.deposit(U128(0), false));
+        let (bob, carol) = (h.pledge(BOB).unwrap(), h.pledge(CAROL).unwrap());
+        assert_eq!(bob.near_sp.0, 3 * carol.near_sp.0);
+        assert!(gain - bob.near_sp.0 - carol.near_sp.0 < 1000); // the index rounds down
+        assert!(h.pools().dead_long_debit.0 < 1000);
+
+        // joining after the fact absorbs none of it
+        h.call(DAVE, 50 * ONE, |c| c.borrow(U128(100 * ONE), false));
+        h.call(DAVE, 1, |c| c.deposit(U128(10 * ONE), false));
+        h.call(DAVE, 1, |c| c.deposit(U128(0), false));
+        assert_eq!(h.pledge(DAVE).unwrap().near_sp.0, 0);
+    }
+
+    // absorbing changes the deposit that weighs Carol's vote, whatever the call
+    #[test]
+    fn absorbing_reweighs_votes() {
+        let mut h = leveraged_long();
+        h.call(CAROL, 50 * ONE, |c| c.borrow(U128(100 * ONE), false));
+        h.call(CAROL, 1, |c| c.deposit(U128(100 * ONE), false));
+        h.call(CAROL, ONE / 100, |c| c.vote(false, 150));
+        crank(&mut h);
+        h.call(CAROL, ONE, |c| c.borrow(U128(2 * ONE), false));
+        assert_eq!(h.events("absorb"), 1);
+        let carol = h.pledge(CAROL).unwrap();
+        assert!(carol.near_sp.0 > 0);
+        let stake = ratio(PRICE, carol.near_sp.0, ONE) + carol.quid_sp.0;
+        assert_eq!(h.view(|c| c.get_vote(accounts(CAROL))).unwrap().stake, stake);
+    }
+
+    // Bob's 50 NEAR deposit is all there is to repay 100 NEAR of defaulted debt
+    #[test]
+    fn gfund_owes_what_it_cannot_absorb() {
+        let mut h = leveraged_long();
+        h.call(BOB, 50 * ONE, |c| c.deposit(U128(0), false));
+        h.call(OWNER, 0, |c| c.dead.short.credit = NearAmount(100 * ONE));
+        assert_eq!(h.pools().gfund_long_credit.0, 0);
+        h.call(BOB, 1, |c| c.deposit(U128(0), false));
+        assert_eq!(h.events("absorb"), 1);
+        assert_eq!(h.pledge(BOB).unwrap().near_sp.0, 0);
+        let pools = h.pools();
+        assert_eq!((pools.gfund_long_credit.0, pools.gfund_short_debit.0), (0, 50 * ONE));
+    }
+
+    // Dave has no debt, whatever he calls still gives up his share of Bob's defaulted QD
+    #[test]
+    fn absorbs_without_debt() {
+        let mut h = leveraged_long();
+        h.call(OWNER, 0, |c| c.token.internal_register_account(&accounts(DAVE).into()));
+        h.call(BOB, 1, |c| c.ft_transfer(accounts(DAVE), U128(100 * ONE), None));
+        h.call(DAVE, 1, |c| c.deposit(U128(100 * ONE), false));
+        h.call(OWNER, 0, |c| c.dead.long.credit = QdAmount(80 * ONE));
+        h.call(DAVE, 1, |c| c.fold(false));
+        assert_eq!(h.pledge(DAVE).unwrap().quid_sp.0, 80 * ONE);
+
+        h.call(OWNER, 0, |c| c.dead.long.credit += QdAmount(38 * ONE));
+        h.call(DAVE, 1, |c| c.swap(U128(ONE), true, false)); // repays nothing
+        assert_eq!(h.pledge(DAVE).unwrap().quid_sp.0, 72 * ONE);
+        assert_eq!(h.pools().blood_credit.0, 372 * ONE); // Bob hasn't absorbed yet
+    }
+}
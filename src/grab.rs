@@ -1,9 +1,8 @@
 use crate::*;
 
 
-use near_sdk::{env, log, Balance, Promise};
-use near_sdk::serde::{Deserialize, Serialize};
-use near_sdk::json_types::{WrappedBalance, WrappedTimestamp, U128};
+use near_sdk::{env, Balance, Promise};
+use near_sdk::json_types::U128;
 
 #[near_bindgen]
 impl Contract 
@@ -13,62 +12,95 @@ impl Contract
         let mut amt: Balance = amount.into();
         let deposit = env::attached_deposit();
         let account = env::predecessor_account_id();
-        assert!(self.crank.done, "Update in progress");
-        assert!(deposit > 0, ERR_AMT_TOO_LOW);
+        self.assert_not_paused();
+        self.assert_fresh_price();
+        assert!(deposit > 0, "{}", ERR_AMT_TOO_LOW);
         if !repay {
             if short { // NEAR ==> QD (short collat), AKA inverting NEAR debt
-                assert!(deposit >= ONE, ERR_AMT_TOO_LOW);
+                assert!(deposit >= ONE, "{}", ERR_AMT_TOO_LOW);
+                let cr_before = computeCR(self.get_price(), self.live.short.credit, self.live.short.debit);
                 // TODO if account == richtobacco.near
                 // do invertFrom
-                self.invert(deposit);
+                self.invert(NearAmount(deposit));
 
-                let mut quid = ratio(self.get_price(), deposit, ONE);        
-                let mut fee_amt = ratio(FEE, quid, ONE);
+                let mut quid = NearAmount(deposit) * self.get_price();        
+                let mut fee_amt = quid * self.config.fee;
                 // https://www.youtube.com/watch?v=KoIqcDZ5ewY
                 
-                let gf_cut = fee_amt.checked_div(11).expect(ERR_DIV);
+                let gf_cut = self.config.gf_cut(fee_amt);
                 self.gfund.short.credit = self.gfund.short.credit
                     .checked_add(gf_cut).expect(ERR_ADD);
                     
                 quid -= fee_amt;
+                Event::Invert { 
+                    account: account.clone(), near: deposit.into(), qd: quid.into(), fee: fee_amt.into(),
+                    cr_before: cr_before.into(),
+                    cr_after: computeCR(self.get_price(), self.live.short.credit, self.live.short.debit).into(),
+                    price: self.get_price().into()
+                }.emit();
                 fee_amt -= gf_cut;
 
                 self.dead.short.debit = self.dead.short.debit
                     .checked_add(fee_amt).expect(ERR_ADD);
                 
-                self.token.internal_deposit(&account, quid);
+                self.token.internal_deposit(&account, quid.0);
             } 
             else { // QD ==> NEAR (long collat), AKA redeeming $QDebt 
-                assert!(amt >= ONE, ERR_AMT_TOO_LOW);
-                
-                self.redeem(amt);
-                self.token.internal_withdraw(&account, amt); // burn the QD being sold 
-                let mut near = ratio(ONE, amt, self.get_price());
-                let mut fee_amt = ratio(FEE, near, ONE);
-            
-                let gf_cut = fee_amt.checked_div(11).expect(ERR_DIV);
-                self.gfund.long.credit = self.gfund.long.credit 
-                    .checked_add(gf_cut).expect(ERR_ADD);
-                
-                near -= fee_amt;
-                fee_amt -= gf_cut;
-                
-                self.dead.long.debit = self.dead.long.debit
-                    .checked_add(fee_amt).expect(ERR_ADD);
-
-                Promise::new(account).transfer(near); // send NEAR to redeemer
+                self.sell_qd(&account, QdAmount(amt), &account);
             }    
         } else { // decrement caller's NEAR or QDebt without releasing collateral
             let mut pledge = self.fetch_pledge(&account, false);
+            let cr_before = self.crs(&pledge);
             if !short { // repay QD debt, distinct from premium payment which does not burn debt but instead distributes payment
-                self.token.internal_withdraw(&account, amt); // burn the QD being paid in as premiums 
-                self.turn(amt, true, false, &mut pledge);
+                amt = self.repay_qd(&mut pledge, QdAmount(amt), &account).0;
             }
             else { // repay NEAR debt, distinct from premium payment (see previous comment next to `else if`)
-                assert!(deposit > 1, ERR_AMT_TOO_LOW);
-                self.turn(deposit, true, true, &mut pledge);
+                assert!(deposit > 1, "{}", ERR_AMT_TOO_LOW);
+                amt = deposit;
+                self.short_turn(NearAmount(deposit), true, &mut pledge);
             }
+            Event::Repay { 
+                account, short, amount: amt.into(), cr_before,
+                cr_after: self.crs(&pledge), price: self.get_price().into()
+            }.emit();
         }
+        self.debug_invariants();
+    }
+
+    // redeems `amt` QD out of `payer`'s balance for NEAR, sent to the account, returns the NEAR
+    pub(crate) fn sell_qd(&mut self, account: &AccountId, amt: QdAmount, payer: &AccountId) -> NearAmount {
+        assert!(amt.0 >= ONE, "{}", ERR_AMT_TOO_LOW);
+        let cr_before = computeCR(self.get_price(), self.live.long.credit, self.live.long.debit);
+        self.redeem(amt);
+        self.token.internal_withdraw(payer, amt.0); // burn the QD being sold 
+        let mut near = amt / self.get_price();
+        let mut fee_amt = near * self.config.fee;
+    
+        let gf_cut = self.config.gf_cut(fee_amt);
+        self.gfund.long.credit = self.gfund.long.credit 
+            .checked_add(gf_cut).expect(ERR_ADD);
+        
+        near -= fee_amt;
+        Event::Redeem { 
+            account: account.clone(), qd: amt.into(), near: near.into(), fee: fee_amt.into(),
+            cr_before: cr_before.into(),
+            cr_after: computeCR(self.get_price(), self.live.long.credit, self.live.long.debit).into(),
+            price: self.get_price().into()
+        }.emit();
+        fee_amt -= gf_cut;
+        
+        self.dead.long.debit = self.dead.long.debit
+            .checked_add(fee_amt).expect(ERR_ADD);
+
+        Promise::new(account.clone()).transfer(near.0); // send NEAR to redeemer
+        near
+    }
+
+    // burns up to `amt` of the Pledge's QD debt out of `payer`'s balance, returns how much
+    pub(crate) fn repay_qd(&mut self, pledge: &mut Pledge, amt: QdAmount, payer: &AccountId) -> QdAmount {
+        let repaid = self.long_turn(amt, true, pledge);
+        self.token.internal_withdraw(payer, repaid.0); // burn the QD being paid in
+        repaid
     }
 
     /**
@@ -78,151 +110,128 @@ impl Contract
      * really looking...you don't really want to know, you wanna be fooled, but
      * you wouldn't clap yet...because makin' somethin' disappear ain't enough  
      */
-     pub(crate) fn turn(&mut self, amt: u128, 
-                        repay: bool, short: bool,
-                        pledge: &mut Pledge) -> Balance {
-        let min: Balance;
-        let id = pledge.id.clone();
-        if !short { // burn QD up to the pledge's total long debt
-            min = std::cmp::min(pledge.long.debit, amt);
-            if min > 0 { // there is any amount of QD debt to burn
-                pledge.long.debit -= min;
-                self.live.long.debit = self.live.long.debit
-                    .checked_sub(min).expect(ERR_SUB);
-            }
-        } 
-        else { // burn NEAR debt
-            min = std::cmp::min(pledge.short.debit, amt);
-            if min > 0 {
-                pledge.short.debit -= min;
-                self.live.short.debit = self.live.short.debit
-                    .checked_sub(min).expect(ERR_SUB);
+     pub(crate) fn long_turn(&mut self, amt: QdAmount, repay: bool, pledge: &mut Pledge) -> QdAmount {
+        // burn QD up to the pledge's total long debt
+        let mut min = std::cmp::min(pledge.long.debit, amt);
+        if !repay { // redemptions only take NEAR, never the basket
+            min = std::cmp::min(min, pledge.long.credit * self.get_price());
+        }
+        if !min.is_zero() { // there is any amount of QD debt to burn, the Pledge was touched
+            pledge.long.debit -= min;
+            self.live.long.debit = self.live.long.debit
+                .checked_sub(min).expect(ERR_SUB);
+            if !repay { // release NEAR collateral as a consequence of redeeming debt
+                let redempt = min / self.get_price();
+                
+                pledge.long.credit = pledge.long.credit
+                    .checked_sub(redempt).expect(ERR_SUB);
+                
+                self.live.long.credit = self.live.long.credit
+                    .checked_sub(redempt).expect(ERR_SUB);
             }
+            let id = pledge.id.clone();
+            self.save_pledge(&id, pledge, true, false); 
         }
-        if min > 0 { // the Pledge was touched
-            if !repay { 
-                if !short { // release NEAR collateral as a consequence of redeeming debt
-                    let redempt = ratio(KILL_CR, min, self.get_price());
-                    
-                    pledge.long.credit = pledge.long.credit
-                        .checked_sub(redempt).expect(ERR_SUB);
+        min // how much was redeemed, used for total tallying in turnFrom 
+    }
+
+    // same as `long_turn`, burning NEAR debt (and releasing QD collateral)
+    pub(crate) fn short_turn(&mut self, amt: NearAmount, repay: bool, pledge: &mut Pledge) -> NearAmount {
+        let min = std::cmp::min(pledge.short.debit, amt);
+        if !min.is_zero() {
+            pledge.short.debit -= min;
+            self.live.short.debit = self.live.short.debit
+                .checked_sub(min).expect(ERR_SUB);
+            if !repay { // release QD collateral...
+                // how much QD is `min` worth
+                let redempt = min * self.get_price();
                     
-                    self.live.long.credit = self.live.long.credit
-                        .checked_sub(redempt).expect(ERR_SUB);
-                } 
-                else { // release QD collateral...
-                    // how much QD is `min` worth
-                    let redempt = ratio(self.get_price(), min, ONE);
-                        
-                    pledge.short.credit = pledge.short.credit
-                        .checked_sub(redempt).expect(ERR_SUB);
-    
-                    self.live.short.credit = self.live.short.credit
-                        .checked_sub(redempt).expect(ERR_SUB);
-                }
-            } 
-            self.save_pledge(&id, pledge, !short, short); 
-        } 
-        return min; // how much was redeemed, used for total tallying in turnFrom 
+                pledge.short.credit = pledge.short.credit
+                    .checked_sub(redempt).expect(ERR_SUB);
+
+                self.live.short.credit = self.live.short.credit
+                    .checked_sub(redempt).expect(ERR_SUB);
+            }
+            let id = pledge.id.clone();
+            self.save_pledge(&id, pledge, false, true); 
+        }
+        min
     }
 
     /*
      * loop through active Pledges in CR range 100-110%
-     * by sorted order of increasing CR (lowest CR first),
-     * through a composite key that prioritizes higher debt,
-     * and burn from their QD/NEAR debt, while withdrawing 
-     * equal value in NEAR/QD collateral to send to invoker
+     * band by band of increasing CR (lowest CR first),
+     * largest debt first within each band, and burn
+     * from their QD/NEAR debt (with `long_turn`/`short_turn`),
+     * while withdrawing equal value in NEAR/QD collateral
+     * to send to invoker
      */ 
-    pub(crate) fn turnFrom(&mut self, mut amt: u128, short: bool, many: usize) -> Balance {
-        // TODO what if there's not even `many` elements to iterate?
-        if !short { // amt is interpreted as QD debt
-            // TODO if many > the length 
-            for (mut pledge, _) in self.long_crs.get_top(many) {
-                if amt > 0 {
-                    let CR = pledge.get_CR(false, self.get_price());
-                    // For liquidation (full via `clip` or partial from
-                    // within invert/redeem) we are only interested in 
-                    // pledges between 90-100 LTV CR, since the map is 
-                    // sorted we can break the loop on the first LTV<90
-                    if CR.0 >= MIN_CR { break; }
-                    else if CR.0 < KILL_CR { continue; }
-                    // this is a rare edge case as 'clip bots'
-                    // will continuously churn liquidatables
-                    // by reading from the head of this map
-                    // and calling `clip` on risky pledges
-                }
-                amt -= self.turn(amt, false, false, &mut pledge); // burn QD debt from pledge
-            }
-        } else { // amt is interpreted as NEAR debt 
-            for (mut pledge, _) in self.short_crs.get_top(many) { 
-                if amt > 0 {
-                    let CR = pledge.get_CR(true, self.get_price());
-                    if CR.0 >= MIN_CR { break; }
-                    // TODO this fails by having big pledges on top, never reaching
-                    // small Pledges with CRs above 90 because the magnitude of 
-                    // big Pledges will just overtake their index in the treemap
-                    else if CR.0 < KILL_CR { continue; }
-                    // No need to skip the originator of the redemption/inversion
-                    // if they are in the 100-110 range who cares if someone else
-                    // partially liquidates them or if they do it to themselves
-                    amt -= self.turn(amt, false, true, &mut pledge); // burn NEAR debt from pledge
-                } else { break; }
+    #[allow(non_snake_case)]
+    pub(crate) fn turnFrom<A, T>(&mut self, mut amt: A, short: bool, many: usize, turn: T) -> A
+    where A: Amount, T: Fn(&mut Self, A, bool, &mut Pledge) -> A {
+        let tree = if short { &self.short_crs } else { &self.long_crs };
+        // No need to skip the originator of the redemption/inversion
+        // if they are in the 100-110 range who cares if someone else
+        // partially liquidates them or if they do it to themselves
+        let ids: Vec<AccountId> = tree.between(
+            Some(self.config.kill_cr), Some(self.config.min_cr), self.get_price()
+        ).take(many).map(|(pledge, _)| pledge.id).collect();
+        for id in ids {
+            if amt.is_zero() {
+                break;
             }
+            // the tree's copy is only as fresh as the last time this side was saved
+            let mut pledge = self.pledges.get(&id).unwrap();
+            amt = amt - turn(self, amt, false, &mut pledge); // burn QD (long) or NEAR (short) debt
         }
-        return amt; // remaining amount to redeem
+        amt // remaining amount to redeem
     }
 
     // pub(crate) fn redeemFrom(&mut self, quid: Balance) {
     //     // TODO move turnFrom piece here and let `update` bot handle this using GFund for liquidity
     // }
-    pub(crate) fn redeem(&mut self, quid: Balance) {
-        let bought: Balance; // NEAR collateral to be released from DeadPool's long portion
-        let mut redempt: Balance = 0; // amount of QD debt being cleared from the DP
-        let mut amt = self.turnFrom(quid, false, 10); // TODO 10 hardcoded
-        if amt > 0 {  // fund redemption by burning against pending DP debt
-            let mut val_collat = ratio(self.get_price(), self.dead.long.debit, ONE);
+    pub(crate) fn redeem(&mut self, quid: QdAmount) {
+        let bought: NearAmount; // NEAR collateral to be released from DeadPool's long portion
+        let redempt: QdAmount; // amount of QD debt being cleared from the DP
+        let mut amt = self.turnFrom(quid, false, self.config.turn_many as usize, Self::long_turn);
+        if !amt.is_zero() {  // fund redemption by burning against pending DP debt
+            let mut val_collat = self.dead.long.debit * self.get_price();
             if val_collat > self.dead.long.credit { // QD in DP worth less than NEAR in DP
                 val_collat = self.dead.long.credit; // max QDebt amount that's clearable 
                 // otherwise, we can face an edge case where tx throws as a result of
                 // not being able to draw equally from both sides of DeadPool.long
-            } if val_collat >= amt { // there is more QD in the DP than amt sold
+            }
+            if val_collat >= amt { // there is more QD in the DP than amt sold
                 redempt = amt; 
-                amt = 0; // there will be 0 QD left to clear
+                amt = QdAmount::ZERO; // there will be 0 QD left to clear
             } else {
                 redempt = val_collat;
                 amt -= redempt; // we'll still have to clear some QD
             }
-            if redempt > 0 {
+            if !redempt.is_zero() {
                 // NEAR's worth of the QD we're about to displace in the DeadPool
-                bought = ratio(ONE, redempt, self.get_price());
+                bought = redempt / self.get_price();
                 // paying the DeadPool's long side by destroying QDebt
                 self.dead.long.credit = self.dead.long.credit
                     .checked_sub(redempt).expect(ERR_SUB);
                 self.dead.long.debit = self.dead.long.debit
                     .checked_sub(bought).expect(ERR_SUB);
             }
-            if amt > 0 { // there is remaining QD to redeem after redeeming from DeadPool  
-                let mut near = ratio(ONE, amt, self.get_price());
-                assert!(env::account_balance() > near, 
+            if !amt.is_zero() { // there is remaining QD to redeem after redeeming from DeadPool  
+                let near = amt / self.get_price();
+                assert!(env::account_balance() > near.0, 
                     "Insufficient NEAR in the contract to clear this redemption"
                 );
-                let mut min = std::cmp::min(self.blood.debit, near); // maximum NEAR dispensable by SolvencyPool
-                amt = ratio(self.get_price(), amt, ONE); // QD paid to SP for NEAR sold 
-                self.token.internal_deposit(&env::current_account_id(), amt);
-                self.blood.credit // offset, in equal value, the NEAR sold by SP
+                // NEAR deposits sell it for QD: rather than taking it out of `blood`,
+                // which would leave the Pledges' deposits behind, the trade goes
+                // through the DeadPool, for each Pledge to absorb its share of it
+                self.token.internal_deposit(&env::current_account_id(), amt.0);
+                // DP's QD will get debited (canceling NEAR debt) in inversions
+                self.dead.short.debit = self.dead.short.debit
                     .checked_add(amt).expect(ERR_ADD);
-                self.blood.debit -= min; // sub NEAR that's getting debited out of the SP
-                near -= min;
-                if near > 0 { // hint, das haben eine kleine lobstah boobie 
-                    amt = ratio(self.get_price(), near, ONE); // in QD
-                    self.token.internal_deposit(&env::current_account_id(), amt);
-                    // DP's QD will get debited (canceling NEAR debt) in inversions
-                    self.dead.short.debit = self.dead.short.debit
-                        .checked_add(amt).expect(ERR_ADD);
-                    // append defaulted NEAR debt to the DP as retroactive settlement
-                    self.dead.short.credit = self.dead.short.credit
-                        .checked_add(near).expect(ERR_ADD);   
-                }
+                // NEAR sold, or without enough deposits, defaulted NEAR debt as retroactive settlement
+                self.dead.short.credit = self.dead.short.credit
+                    .checked_add(near).expect(ERR_ADD);   
             }
         }
     }
@@ -230,59 +239,114 @@ impl Contract
     // pub(crate) fn invertFrom(&mut self, quid: Balance) {
     //     // TODO move turnFrom piece here and let `update` bot handle this using GFund for liquidity
     // }
-    pub(crate) fn invert(&mut self, near: Balance) {
-        let mut bought: Balance = 0; // QD collateral to be released from DeadPool's short portion
-        let mut redempt: Balance = 0; // amount of NEAR debt that's been cleared from DP
+    pub(crate) fn invert(&mut self, near: NearAmount) {
+        let mut bought = QdAmount::ZERO; // QD collateral to be released from DeadPool's short portion
+        let mut redempt = NearAmount::ZERO; // amount of NEAR debt that's been cleared from DP
         // invert against LivePool, `true` for short, returns NEAR remainder to invert
-        let mut amt = self.turnFrom(near, true, 10); // TODO 10 hardcoded
-        if amt > 0 { // there is remaining NEAR to be bought 
+        let mut amt = self.turnFrom(near, true, self.config.turn_many as usize, Self::short_turn);
+        if !amt.is_zero() { // there is remaining NEAR to be bought 
             // can't clear more NEAR debt than is available in the DeadPool
-            let mut val = std::cmp::min(amt, self.dead.short.credit);
-            val = ratio(self.get_price(), val, ONE); // QD value
-            log!("vaaalll...{}", val);
-            if val > 0 && self.dead.short.debit >= val { // sufficient QD collateral vs value of NEAR sold
-                log!("self.dead.short.debit...{}", self.dead.short.debit);
-                redempt = amt; // amount of NEAR credit to be cleared from the DeadPool
-                log!("redempt...{}", redempt);
+            let clearable = std::cmp::min(amt, self.dead.short.credit);
+            let val = clearable * self.get_price(); // QD value
+            if !val.is_zero() && self.dead.short.debit >= val { // sufficient QD collateral vs value of NEAR sold
+                redempt = clearable; // amount of NEAR credit to be cleared from the DeadPool
                 bought = val; // amount of QD to debit against short side of DeadPool
-                log!("bought...{}", bought);
-                amt = 0; // there remains no NEAR debt left to clear in the inversion
-            } else if self.dead.short.debit > 0 { // there is less NEAR credit to clear than the amount being redeemed
+                amt -= clearable; // NEAR debt left to clear in the inversion, if any
+            } else if !val.is_zero() && !self.dead.short.debit.is_zero() { // there is less QD collateral than the value of NEAR debt
                 bought = self.dead.short.debit; // debit all QD collateral in the DeadPool
-                redempt = ratio(ONE, bought, self.get_price());
+                redempt = bought / self.get_price();
                 amt -= redempt;
             }
-            if redempt > 0 {
-                log!("redempt...{}", redempt);
+            if !redempt.is_zero() {
                 self.dead.short.credit = self.dead.short.credit // NEAR Debt
                     .checked_sub(redempt).expect(ERR_SUB);
                 self.dead.short.debit = self.dead.short.debit // QD Collat
                     .checked_sub(bought).expect(ERR_SUB);
             }
-            if amt > 0 { // remaining NEAR to redeem after clearing against LivePool and DeadPool
-                let mut quid = ratio(self.get_price(), amt, ONE);
+            if !amt.is_zero() { // remaining NEAR to redeem after clearing against LivePool and DeadPool
+                let quid = amt * self.get_price();
                 let liq_qd: Balance = self.token.ft_balance_of(
                     ValidAccountId::try_from(env::current_account_id()).unwrap()).into();
-                assert!(liq_qd > quid, "Insufficient QD in the contract to clear this inversion");
-                
-                let min = std::cmp::min(quid, self.blood.credit);
-                let min_near = ratio(ONE, min, self.get_price());
-                self.token.internal_withdraw(&env::current_account_id(), min);
-                self.blood.debit = self.blood.debit
-                    .checked_add(min_near).expect(ERR_ADD);
-                self.blood.credit -= min;
-                amt -= min_near;
-                quid -= min;
-                if quid > 0 { // und das auch 
-                    // we credit NEAR to the long side of the DeadPool, which gets debited when redeeming QDebt
-                    self.dead.long.debit = self.dead.long.debit
-                        .checked_add(amt).expect(ERR_ADD);
-                    // append defaulted $QDebt to the DeadPool as retroactive settlement to withdraw SPs' $QD
-                    self.dead.long.credit = self.dead.long.credit
-                        .checked_add(quid).expect(ERR_ADD);
-                    // TODO how come we don't get to print when we do this?
-                }
+                assert!(liq_qd > quid.0, "Insufficient QD in the contract to clear this inversion");
+                // QD deposits sell it for NEAR, through the DeadPool as in `redeem`;
+                // the QD they have left once they absorb what's pending gets burned
+                let min = std::cmp::min(quid, self.blood.credit.saturating_sub(self.dead.long.credit));
+                self.token.internal_withdraw(&env::current_account_id(), min.0);
+                // we credit NEAR to the long side of the DeadPool, which gets debited when redeeming QDebt
+                self.dead.long.debit = self.dead.long.debit
+                    .checked_add(amt).expect(ERR_ADD);
+                // QD sold, or without enough deposits, defaulted $QDebt as retroactive settlement
+                self.dead.long.credit = self.dead.long.credit
+                    .checked_add(quid).expect(ERR_ADD);
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::*;
+    use near_sdk::test_utils::accounts;
+
+    #[test]
+    fn swaps_against_solvency_pool() {
+        let mut h = Harness::new(PRICE);
+        h.call(BOB, 100 * ONE, |c| c.borrow(U128(200 * ONE), false));
+        h.call(BOB, 20 * ONE, |c| c.deposit(U128(100 * ONE), false));
+        // QD ==> NEAR, sold to the SolvencyPool's NEAR
+        h.call(ALICE, 50 * ONE, |c| c.borrow(U128(100 * ONE), false));
+        h.absorb(BOB); // the borrowing fees' NEAR, out of the way
+        let near = h.pools().blood_debit.0;
+        let live_cr = |h: &Harness| {
+            let pools = h.pools();
+            ratio(PRICE, pools.live_long_credit.0, pools.live_long_debit.0)
+        };
+        let cr_before = live_cr(&h);
+        h.call(ALICE, 1, |c| c.swap(U128(25 * ONE), false, false));
+        assert_eq!(h.events("redeem"), 1);
+        let redeem = h.event("redeem");
+        assert_eq!(redeem["cr_before"], cr_before.to_string());
+        assert_eq!(redeem["cr_after"], live_cr(&h).to_string());
+        assert_eq!(h.qd(ALICE), 75 * ONE);
+        let pools = h.pools();
+        assert_eq!((pools.dead_short_credit.0, pools.dead_short_debit.0), (5 * ONE, 25 * ONE));
+        assert_eq!(pools.gfund_long_credit.0, fee(5 * ONE) / GFUND_DIV as Balance);
+        let fees = pools.dead_long_debit.0; // the rest of the fee, and what the index left
+        h.absorb(BOB); // Bob's NEAR is all there is to sell
+        let pools = h.pools();
+        assert!(absorbed(pools.blood_debit.0, near - 5 * ONE + fees));
+        assert!(absorbed(pools.blood_credit.0, 125 * ONE));
+        // NEAR ==> QD, bought from the SolvencyPool's QD
+        h.call(ALICE, 2 * ONE, |c| c.swap(U128(0), false, true));
+        assert_eq!(h.events("invert"), 1);
+        assert_eq!(h.event("invert")["cr_after"], "0"); // nobody's short
+        assert_eq!(h.qd(ALICE), 85 * ONE - fee(10 * ONE));
+        let (before, dead) = (h.pools(), h.pools().dead_long_debit.0);
+        h.absorb(BOB);
+        let pools = h.pools();
+        assert!(absorbed(pools.blood_debit.0, before.blood_debit.0 + dead));
+        assert!(dead >= 2 * ONE);
+        // less the 10 QD sold, plus what's left of the fee, which NEAR deposits absorb
+        let fee = before.dead_short_debit.0;
+        assert!(absorbed(pools.blood_credit.0, before.blood_credit.0 - 10 * ONE + fee));
+    }
+
+    #[test]
+    fn redemptions_reach_small_pledges() {
+        let mut h = Harness::new(PRICE);
+        h.call(BOB, 500 * ONE, |c| c.borrow(U128(1000 * ONE), false)); // big and healthy
+        h.call(ALICE, 25 * ONE, |c| c.borrow(U128(100 * ONE), false));
+        h.call(CAROL, 27 * ONE, |c| c.borrow(U128(120 * ONE), false));
+        h.set_price(43 * ONE / 10); // Alice at 107.5%, Carol at ~96.8%, Bob at 215%
+        let order: Vec<AccountId> = h.view(|c| c.get_pledge_tree(false, 0, 10, None, None))
+            .into_iter().map(|row| row.0).collect();
+        let expected: Vec<AccountId> = [CAROL, ALICE, BOB].iter().map(|&a| accounts(a).to_string()).collect();
+        assert_eq!(order, expected);
+
+        h.call(BOB, 1, |c| c.swap(U128(60 * ONE), false, false));
+        assert_eq!(h.pledge(ALICE).unwrap().debit.0, 40 * ONE); // below KILL_CR, Carol is left to `clip`
+        assert_eq!(h.pledge(CAROL).unwrap().debit.0, 120 * ONE);
+        assert_eq!(h.pledge(BOB).unwrap().debit.0, 1000 * ONE);
+    }
+}
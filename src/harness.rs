@@ -0,0 +1,261 @@
+use crate::*;
+
+use near_sdk::test_utils::{accounts, get_created_receipts, get_logs, VMContextBuilder};
+use near_sdk::{serde_json, testing_env, MockedBlockchain, PromiseResult, RuntimeFeesConfig, VMConfig};
+
+/*  Drives the Contract through the same entry points as transactions,
+ *  one VMContext per call, on a simulated clock. Accounts are indices
+ *  into `accounts()`: 0 is the owner, 1 reports prices, rest are users.
+ *  The contract's NEAR balance is tracked here, because the mocked
+ *  blockchain doesn't add attached deposits nor subtract transfers.
+ *  Scenarios are tested next to the module they exercise, starting
+ *  from the fixtures at the bottom of this file
+ */
+pub const OWNER: usize = 0;
+pub const REPORTER: usize = 1;
+pub const START: u64 = 1_650_000_000_000_000_000; // nanosecs
+pub const GAS: Gas = 300_000_000_000_000; // most a call can attach
+
+pub struct Harness {
+    pub contract: Contract,
+    pub now: u64, // block_timestamp of the next call
+    pub balance: Balance, // contract's NEAR
+    pub gas: Gas, // prepaid by each call
+}
+
+impl Harness {
+    pub fn new(price: Balance) -> Self {
+        testing_env!(Self::builder(OWNER, 0, START, 0, GAS).build());
+        let contract = Contract::new(accounts(OWNER));
+        let mut h = Self { contract, now: START, balance: 0, gas: GAS };
+        h.call(OWNER, 1, |c| c.add_reporter(accounts(REPORTER)));
+        h.set_price(price);
+        h
+    }
+
+    fn builder(account: usize, deposit: Balance, now: u64, balance: Balance, gas: Gas) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder.current_account_id("quid".to_string().try_into().unwrap())
+            .predecessor_account_id(accounts(account))
+            .signer_account_id(accounts(account))
+            .block_timestamp(now)
+            .account_balance(balance)
+            .attached_deposit(deposit)
+            .prepaid_gas(gas);
+        builder
+    }
+
+    // calls `f` as if `account` signed a transaction attaching `deposit`
+    pub fn call<T>(&mut self, account: usize, deposit: Balance, f: impl FnOnce(&mut Contract) -> T) -> T {
+        self.balance += deposit;
+        testing_env!(Self::builder(account, deposit, self.now, self.balance, self.gas).build());
+        f(&mut self.contract)
+    }
+
+    // the NEAR a call sent out (if any) leaves the contract's balance
+    pub fn paid<T>(&mut self, out: PromiseOrValue<T>, near: Balance) {
+        if let PromiseOrValue::Promise(_) = out {
+            self.balance -= near;
+        }
+    }
+
+    /*  `ft_transfer_call` of QD to the contract itself: the transfer, then
+     *  `ft_on_transfer` (called by the contract), then refunding what it
+     *  didn't use as `ft_resolve_transfer` would. Returns that refund
+     */
+    pub fn transfer_call(&mut self, account: usize, amount: Balance, msg: &str) -> Balance {
+        let quid: ValidAccountId = "quid".to_string().try_into().unwrap();
+        self.call(account, 1, |c| c.ft_transfer_call(quid.clone(), U128(amount), None, msg.to_string()));
+        let mut builder = Self::builder(account, 0, self.now, self.balance, self.gas);
+        testing_env!(builder.predecessor_account_id(quid.clone()).build());
+        let unused = match self.contract.ft_on_transfer(accounts(account), U128(amount), msg.to_string()) {
+            PromiseOrValue::Value(unused) => unused.0,
+            PromiseOrValue::Promise(_) => unreachable!(),
+        };
+        if unused > 0 {
+            self.contract.token.internal_transfer(quid.as_ref(), accounts(account).as_ref(), unused, None);
+        }
+        unused
+    }
+
+    pub fn view<T>(&self, f: impl FnOnce(&Contract) -> T) -> T {
+        f(&self.contract)
+    }
+
+    pub fn advance(&mut self, nanos: u64) {
+        self.now += nanos;
+    }
+
+    pub fn set_price(&mut self, price: Balance) {
+        self.call(REPORTER, 0, |c| c.report_price(U128(price)));
+    }
+
+    pub fn pools(&self) -> PoolStats {
+        self.view(|c| c.get_pool_stats())
+    }
+
+    pub fn pledge(&self, account: usize) -> Option<PledgeView> {
+        self.view(|c| c.get_pledge(accounts(account)))
+    }
+
+    pub fn qd(&self, account: usize) -> Balance {
+        self.view(|c| c.get_qd_balance(accounts(account)).0)
+    }
+
+    // the account's Pledge absorbs its share of the DeadPool
+    pub fn absorb(&mut self, account: usize) {
+        self.call(account, 0, |c| { c.fetch_pledge(&accounts(account).into(), false); });
+    }
+
+    pub fn events(&self, name: &str) -> usize {
+        let tag = format!(r#""event":"{}""#, name);
+        get_logs().iter().filter(|l| l.contains(&tag)).count()
+    }
+
+    // data of the last event by that name, out of the last call's logs
+    pub fn event(&self, name: &str) -> serde_json::Value {
+        let tag = format!(r#""event":"{}""#, name);
+        let log = get_logs().into_iter().rev().find(|l| l.contains(&tag)).expect("no such event");
+        let json: serde_json::Value = serde_json::from_str(&log["EVENT_JSON:".len()..]).unwrap();
+        json["data"].clone()
+    }
+}
+
+/*  A NEP-141 token other than QD (e.g. stNEAR) with balances of its
+ *  own, that accounts send to the contract as collateral
+ */
+pub struct MockToken {
+    pub id: ValidAccountId,
+    balances: std::collections::HashMap<AccountId, Balance>,
+}
+
+impl MockToken {
+    pub fn new(id: &str) -> Self {
+        Self { id: id.to_string().try_into().unwrap(), balances: Default::default() }
+    }
+
+    pub fn mint(&mut self, account: usize, amount: Balance) {
+        *self.balances.entry(accounts(account).into()).or_default() += amount;
+    }
+
+    pub fn balance_of(&self, account: &str) -> Balance {
+        self.balances.get(account).cloned().unwrap_or_default()
+    }
+
+    pub fn ft_transfer(&mut self, from: &str, to: &str, amount: Balance) {
+        let balance = self.balances.entry(from.to_string()).or_default();
+        *balance = balance.checked_sub(amount).expect("The account doesn't have enough balance");
+        *self.balances.entry(to.to_string()).or_default() += amount;
+    }
+}
+
+impl Harness {
+    // `ft_transfer_call` of `token` to the contract, refunding what it didn't use
+    pub fn transfer_asset(&mut self, token: &mut MockToken, account: usize, amount: Balance, msg: &str) -> Balance {
+        token.ft_transfer(accounts(account).as_ref(), "quid", amount);
+        let mut builder = Self::builder(account, 0, self.now, self.balance, self.gas);
+        testing_env!(builder.predecessor_account_id(token.id.clone()).build());
+        let unused = match self.contract.ft_on_transfer(accounts(account), U128(amount), msg.to_string()) {
+            PromiseOrValue::Value(unused) => unused.0,
+            PromiseOrValue::Promise(_) => unreachable!(),
+        };
+        token.ft_transfer("quid", accounts(account).as_ref(), unused);
+        unused
+    }
+
+    // `withdraw_asset`, the token's `ft_transfer` (unless it fails), then the callback
+    pub fn withdraw_asset(&mut self, token: &mut MockToken, account: usize, amount: Balance, fails: bool) {
+        let id = token.id.clone();
+        self.call(account, 1, |c| c.withdraw_asset(id, U128(amount)));
+        let amount = amount - fee(amount); // the fee stays behind
+        let result = if fails {
+            PromiseResult::Failed
+        } else {
+            token.ft_transfer("quid", accounts(account).as_ref(), amount);
+            PromiseResult::Successful(vec![])
+        };
+        let mut builder = Self::builder(0, 0, self.now, self.balance, self.gas);
+        let quid: ValidAccountId = "quid".to_string().try_into().unwrap();
+        testing_env!(builder.predecessor_account_id(quid).build(),
+            VMConfig::default(), RuntimeFeesConfig::default(), Default::default(), vec![result]);
+        self.contract.on_asset_withdrawn(accounts(account).into(), token.id.to_string(), U128(amount));
+    }
+
+    // `buy_asset`, the token's `ft_transfer` (unless it fails), then the callback
+    pub fn buy_asset(&mut self, token: &mut MockToken, account: usize, amount: Balance, fails: bool) {
+        let (id, gfund_debt) = (token.id.clone(), self.contract.gfund.long.debit);
+        let qd_before = self.qd(account);
+        self.call(account, 1, |c| c.buy_asset(id, U128(amount)));
+        let qd = qd_before - self.qd(account);
+        let settled = gfund_debt - self.contract.gfund.long.debit;
+        let result = if fails {
+            PromiseResult::Failed
+        } else {
+            token.ft_transfer("quid", accounts(account).as_ref(), amount);
+            PromiseResult::Successful(vec![])
+        };
+        let mut builder = Self::builder(0, 0, self.now, self.balance, self.gas);
+        let quid: ValidAccountId = "quid".to_string().try_into().unwrap();
+        testing_env!(builder.predecessor_account_id(quid).build(),
+            VMConfig::default(), RuntimeFeesConfig::default(), Default::default(), vec![result]);
+        self.contract.on_asset_bought(accounts(account).into(), token.id.to_string(),
+            U128(amount), U128(qd), U128(settled.0));
+    }
+}
+
+// ======= Fixtures shared by the modules' tests ==================
+
+pub const PRICE: Balance = 5 * ONE; // QD per NEAR
+pub const ALICE: usize = 2; // borrower
+pub const BOB: usize = 3; // SolvencyProvider
+pub const CAROL: usize = 4; // another borrower
+pub const DAVE: usize = 5; // a late SolvencyProvider
+
+pub fn fee(amt: Balance) -> Balance {
+    ratio(FEE.0, amt, ONE)
+}
+
+// NEAR sent back by the last call (receipts keep their actions private)
+pub fn refunded() -> Balance {
+    get_created_receipts().iter()
+        .flat_map(|r| format!("{:?}", r).split("deposit: ").skip(1)
+            .map(|d| d.split(|c: char| !c.is_ascii_digit()).next().unwrap().parse::<Balance>().unwrap())
+            .collect::<Vec<_>>())
+        .sum()
+}
+
+// for amounts that went through price conversions, which round
+pub fn rounded(a: Balance, b: Balance) -> bool {
+    a.max(b) - a.min(b) <= 10
+}
+
+// for amounts absorbed from the DeadPool, which the index rounds down
+pub fn absorbed(a: Balance, b: Balance) -> bool {
+    a.max(b) - a.min(b) < 1000
+}
+
+// Bob backs the SolvencyPool with QD, Alice borrows against
+// NEAR and spends the QD, leaving nothing to rescue her with
+pub fn leveraged_long() -> Harness {
+    let mut h = Harness::new(PRICE);
+    h.call(BOB, 200 * ONE, |c| c.borrow(U128(400 * ONE), false));
+    h.call(BOB, 1, |c| c.deposit(U128(300 * ONE), false));
+    h.call(ALICE, 100 * ONE, |c| c.borrow(U128(400 * ONE), false));
+    h.call(ALICE, 1, |c| c.ft_transfer(accounts(BOB), U128(400 * ONE), None));
+    h
+}
+
+// calls `update` until the crank is done, returns how many premiums were charged
+pub fn crank(h: &mut Harness) -> usize {
+    let mut premiums = 0;
+    loop {
+        h.call(OWNER, 0, |c| c.update());
+        premiums += h.events("premium");
+        if h.view(|c| c.crank.done) {
+            return premiums;
+        }
+    }
+}
+
+// `ft_transfer_call` message that puts listed collateral into the basket
+pub const STAKE: &str = r#"{"action":"deposit","live":true}"#;
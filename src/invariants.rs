@@ -0,0 +1,158 @@
+use crate::*;
+
+use near_sdk::{env, Balance};
+use near_sdk::serde::Serialize;
+use near_sdk::json_types::U128;
+
+/*  The protocol's books are split between the Pools and each Pledge,
+ *  these must agree: LivePool holds exactly what borrowers put in and
+ *  took out, SolvencyPool exactly what SPs deposited, and deposits in
+ *  custody must be on hand. Redemptions, inversions and auctions that
+ *  trade the pooled deposits go through the DeadPool, so that `blood`
+ *  only moves along with the Pledges' deposits as they absorb from it;
+ *  until they do, what they're due to lose isn't counted as on hand.
+ *  Sums are over the requested page of pledges, and only compared to
+ *  the Pools when the page covers all of them (or add up pages instead)
+ */
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Invariants {
+    pub from_index: u64,
+    pub checked: u64, // pledges in this page
+    pub pledges: u64, // pledges in total
+    pub long_credit: U128,
+    pub long_debit: U128,
+    pub short_credit: U128,
+    pub short_debit: U128,
+    pub near: U128, // SolvencyPool deposits
+    pub quid: U128,
+    pub violations: Vec<String>,
+}
+
+fn differ(violations: &mut Vec<String>, what: &str, pool: Balance, sum: Balance) {
+    if pool != sum {
+        violations.push(format!("{} is {} but pledges add up to {}", what, pool, sum));
+    }
+}
+
+#[near_bindgen]
+impl Contract
+{
+    pub fn check_invariants(&self, from_index: u64, limit: u64) -> Invariants {
+        let pledges = self.pledges.values_as_vector();
+        let end = std::cmp::min(from_index.saturating_add(limit), pledges.len());
+        let mut sums = [0 as Balance; 6];
+        for index in from_index..end {
+            let p = pledges.get(index).unwrap();
+            for (sum, amt) in sums.iter_mut().zip([p.long.credit.0, p.long.debit.0,
+                p.short.credit.0, p.short.debit.0, p.near.0, p.quid.0]) {
+                *sum = sum.checked_add(amt).expect(ERR_ADD);
+            }
+        }
+        let mut violations = vec![];
+        if from_index == 0 && end == pledges.len() {
+            differ(&mut violations, "live.long.credit", self.live.long.credit.0, sums[0]);
+            differ(&mut violations, "live.long.debit", self.live.long.debit.0, sums[1]);
+            differ(&mut violations, "live.short.credit", self.live.short.credit.0, sums[2]);
+            differ(&mut violations, "live.short.debit", self.live.short.debit.0, sums[3]);
+            differ(&mut violations, "blood.debit", self.blood.debit.0, sums[4]);
+            differ(&mut violations, "blood.credit", self.blood.credit.0, sums[5]);
+        }
+        // deposits in custody (and collateral up for auction) must be on hand, except for NEAR lent out
+        let qd: Balance = self.token.ft_balance_of(
+            ValidAccountId::try_from(env::current_account_id()).unwrap()).into();
+        // SP deposits net of the QD debt they're yet to absorb (and NEAR debt, below)
+        let qd_held = self.blood.credit.saturating_sub(self.dead.long.credit).0
+            .checked_add(self.live.short.credit.0)
+            .and_then(|qd| qd.checked_add(self.keepers.owed.0))
+            .and_then(|qd| qd.checked_add(self.auctions.qd.0)).expect(ERR_ADD);
+        if qd < qd_held {
+            violations.push(format!("QD balance {} is below QD deposits {}", qd, qd_held));
+        }
+        let near = env::account_balance().checked_add(self.live.short.debit.0)
+            .and_then(|near| near.checked_add(self.auctions.near_debt.0)).expect(ERR_ADD);
+        let near_held = self.blood.debit.saturating_sub(self.dead.short.credit).0
+            .checked_add(self.live.long.credit.0)
+            .and_then(|near| near.checked_add(self.auctions.near.0)).expect(ERR_ADD);
+        if near < near_held {
+            violations.push(format!("NEAR balance and loans {} are below NEAR deposits {}", near, near_held));
+        }
+        Invariants {
+            from_index,
+            checked: end.saturating_sub(from_index),
+            pledges: pledges.len(),
+            long_credit: sums[0].into(),
+            long_debit: sums[1].into(),
+            short_credit: sums[2].into(),
+            short_debit: sums[3].into(),
+            near: sums[4].into(),
+            quid: sums[5].into(),
+            violations,
+        }
+    }
+
+    // Debug builds (e.g. unit tests) check the books after every
+    // mutating call, so that a violation fails where it's introduced
+    pub(crate) fn debug_invariants(&self) {
+        if cfg!(debug_assertions) {
+            let violations = self.check_invariants(0, u64::MAX).violations;
+            assert!(violations.is_empty(), "Invariants violated: {}", violations.join("; "));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, MockedBlockchain};
+
+    fn setup() -> Contract {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(0))
+            .account_balance(0)
+            .build());
+        Contract::new(accounts(0))
+    }
+
+    fn pledge(id: usize, credit: Balance, debit: Balance) -> Pledge {
+        Pledge {
+            long: Pod::new(NearAmount(credit), QdAmount(debit)), short: Pod::default(),
+            stats: PledgeStats::new(),
+            near: NearAmount::ZERO, quid: QdAmount::ZERO,
+            id: accounts(id).into(), target: MIN_CR, s_target: MIN_CR, basket: vec![], basket_near: NearAmount::ZERO
+        }
+    }
+
+    #[test]
+    fn pages_add_up() {
+        let mut contract = setup();
+        for id in 1..4 {
+            contract.pledges.insert(&accounts(id).into(), &pledge(id, ONE, ONE));
+        }
+        contract.live.long = Pod::new(NearAmount(3 * ONE), QdAmount(3 * ONE));
+        let page = contract.check_invariants(1, 5);
+        assert_eq!((page.checked, page.pledges), (2, 3));
+        assert_eq!(page.long_credit.0, 2 * ONE);
+        // only custody is checked on partial pages
+        assert_eq!(page.violations.len(), 1);
+        let all = contract.check_invariants(0, 3);
+        assert_eq!(all.violations, vec![
+            format!("NEAR balance and loans 0 are below NEAR deposits {}", 3 * ONE)
+        ]);
+    }
+
+    #[test]
+    fn finds_violations() {
+        let mut contract = setup();
+        contract.pledges.insert(&accounts(1).into(), &pledge(1, 0, ONE));
+        contract.live.long.debit = QdAmount(ONE - 1);
+        contract.blood.credit = QdAmount(ONE);
+        contract.token.internal_deposit(&env::current_account_id(), ONE);
+        let report = contract.check_invariants(0, 10);
+        assert_eq!(report.violations, vec![
+            format!("live.long.debit is {} but pledges add up to {}", ONE - 1, ONE),
+            format!("blood.credit is {} but pledges add up to 0", ONE)
+        ]);
+    }
+}
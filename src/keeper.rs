@@ -0,0 +1,225 @@
+use crate::*;
+
+use near_sdk::env;
+use near_sdk::serde::Serialize;
+use near_sdk::json_types::U128;
+
+/*  Bots that crank `update` and `clip` distressed Pledges get paid
+ *  out of the GuaranteeFund's QD (which its cut of fees and premiums
+ *  tops up): a flat bounty per Pledge stressed, and a share of the
+ *  debt that a `clip` shrinks or liquidates, capped per clip. All
+ *  bounties paid in one update interval (crank epoch) are capped
+ *  together, and never exceed what the GuaranteeFund holds. Rewards
+ *  accrue to the keeper until claimed, as QD (so the keeper has to
+ *  be registered with the token)
+ */
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Keepers {
+    pub crank_bounty: QdAmount, // per Pledge stressed by `update`
+    pub clip_bounty: Ratio, // share of the debt (in QD) cleared by `clip`
+    pub clip_cap: QdAmount, // most paid for a single `clip`
+    pub epoch_cap: QdAmount, // most paid in bounties per crank epoch
+    pub epoch: u64, // crank epoch that `spent` counts
+    pub spent: QdAmount, // paid in bounties during `epoch`
+    pub owed: QdAmount, // accrued by all keepers and not yet claimed
+    pub paid: QdAmount, // accrued by all keepers, ever
+    accrued: LookupMap<AccountId, QdAmount>, // per keeper, not yet claimed
+} impl Keepers {
+    pub fn new(prefix: Vec<u8>) -> Self {
+        Self {
+            crank_bounty: CRANK_BOUNTY,
+            clip_bounty: CLIP_BOUNTY,
+            clip_cap: CLIP_BOUNTY_CAP,
+            epoch_cap: EPOCH_BOUNTY_CAP,
+            epoch: 0,
+            spent: QdAmount::ZERO,
+            owed: QdAmount::ZERO,
+            paid: QdAmount::ZERO,
+            accrued: LookupMap::new(prefix),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct KeepersView {
+    pub crank_bounty: U128,
+    pub clip_bounty: U128,
+    pub clip_cap: U128,
+    pub epoch_cap: U128,
+    pub epoch: u64,
+    pub spent: U128,
+    pub owed: U128,
+    pub paid: U128,
+}
+
+#[near_bindgen]
+impl Contract
+{
+    // accrues up to `qd` to the keeper, returns how much was accrued after caps
+    pub(crate) fn reward(&mut self, keeper: &AccountId, qd: QdAmount, action: &str) -> QdAmount {
+        if self.keepers.epoch != self.crank.epoch { // new update interval
+            self.keepers.epoch = self.crank.epoch;
+            self.keepers.spent = QdAmount::ZERO;
+        }
+        let left = self.keepers.epoch_cap.saturating_sub(self.keepers.spent);
+        let qd = std::cmp::min(std::cmp::min(qd, left), self.gfund.short.credit);
+        if !qd.is_zero() {
+            self.gfund.short.credit -= qd;
+            self.keepers.spent += qd;
+            self.keepers.owed += qd;
+            self.keepers.paid += qd;
+            let accrued = self.keepers.accrued.get(keeper).unwrap_or_default();
+            self.keepers.accrued.insert(keeper, &(accrued + qd));
+            Event::Reward { keeper: keeper.clone(), action: action.to_string(), qd: qd.into() }.emit();
+        }
+        qd
+    }
+
+    pub fn get_keeper_rewards(&self, account: ValidAccountId) -> U128 {
+        self.keepers.accrued.get(account.as_ref()).unwrap_or_default().into()
+    }
+
+    pub fn get_keepers(&self) -> KeepersView {
+        let k = &self.keepers;
+        KeepersView {
+            crank_bounty: k.crank_bounty.into(),
+            clip_bounty: k.clip_bounty.into(),
+            clip_cap: k.clip_cap.into(),
+            epoch_cap: k.epoch_cap.into(),
+            epoch: k.epoch,
+            spent: k.spent.into(),
+            owed: k.owed.into(),
+            paid: k.paid.into(),
+        }
+    }
+
+    #[payable]
+    pub fn claim_rewards(&mut self) -> U128 {
+        assert_one_yocto();
+        let keeper = env::predecessor_account_id();
+        let qd = self.keepers.accrued.remove(&keeper).unwrap_or_default();
+        assert!(!qd.is_zero(), "{}", ERR_NOTHING_TO_CLAIM);
+        self.keepers.owed -= qd;
+        self.token.internal_transfer(&env::current_account_id(), &keeper, qd.0, None);
+        Event::Claim { keeper, qd: qd.into() }.emit();
+        self.debug_invariants();
+        qd.into()
+    }
+
+    #[payable]
+    pub fn set_keeper_bounties(&mut self, crank_bounty: U128, clip_bounty: U128,
+                               clip_cap: U128, epoch_cap: U128) {
+        assert_one_yocto();
+        self.assert_owner();
+        let (crank_bounty, clip_bounty): (QdAmount, Ratio) = (crank_bounty.into(), clip_bounty.into());
+        let (clip_cap, epoch_cap): (QdAmount, QdAmount) = (clip_cap.into(), epoch_cap.into());
+        check(crank_bounty.0 <= ONE, "crank_bounty"); // 1 QD per Pledge
+        check(clip_bounty.0 <= ONE / 20, "clip_bounty"); // 5% of the debt cleared
+        check(clip_cap <= epoch_cap, "clip_cap");
+        check(epoch_cap.0 <= 100_000 * ONE, "epoch_cap");
+        changed("crank_bounty", self.keepers.crank_bounty, crank_bounty);
+        changed("clip_bounty", self.keepers.clip_bounty, clip_bounty);
+        changed("clip_cap", self.keepers.clip_cap, clip_cap);
+        changed("epoch_cap", self.keepers.epoch_cap, epoch_cap);
+        self.keepers.crank_bounty = crank_bounty;
+        self.keepers.clip_bounty = clip_bounty;
+        self.keepers.clip_cap = clip_cap;
+        self.keepers.epoch_cap = epoch_cap;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::*;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, MockedBlockchain};
+
+    fn setup() -> Contract {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .build());
+        let mut contract = Contract::new(accounts(0));
+        contract.gfund.short.credit = QdAmount(10 * ONE);
+        contract.token.internal_deposit(&env::current_account_id(), 10 * ONE);
+        contract
+    }
+
+    #[test]
+    fn capped_per_epoch_and_by_gfund() {
+        let mut contract = setup();
+        contract.set_keeper_bounties(U128(ONE), U128(ONE / 100), U128(5 * ONE), U128(6 * ONE));
+        let keeper: AccountId = accounts(1).into();
+        assert_eq!(contract.reward(&keeper, QdAmount(4 * ONE), "update"), QdAmount(4 * ONE));
+        assert_eq!(contract.reward(&keeper, QdAmount(4 * ONE), "update"), QdAmount(2 * ONE)); // epoch cap
+        assert_eq!(contract.reward(&keeper, QdAmount(ONE), "update"), QdAmount::ZERO);
+        contract.crank.epoch += 1;
+        assert_eq!(contract.reward(&keeper, QdAmount(6 * ONE), "clip"), QdAmount(4 * ONE)); // what's left in gfund
+        assert_eq!(contract.get_keeper_rewards(accounts(1)).0, 10 * ONE);
+        let keepers = contract.get_keepers();
+        assert_eq!((keepers.spent.0, keepers.owed.0, keepers.paid.0), (4 * ONE, 10 * ONE, 10 * ONE));
+        assert_eq!(contract.gfund.short.credit, QdAmount::ZERO);
+    }
+
+    #[test]
+    fn claim() {
+        let mut contract = setup();
+        contract.token.internal_register_account(&accounts(1).into());
+        contract.reward(&accounts(1).into(), QdAmount(ONE), "update");
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(1)
+            .build());
+        assert_eq!(contract.claim_rewards().0, ONE);
+        assert_eq!(contract.get_qd_balance(accounts(1)).0, ONE);
+        assert_eq!(contract.get_keeper_rewards(accounts(1)).0, 0);
+        assert_eq!(contract.get_keepers().owed.0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Parameter is out of bounds: clip_bounty")]
+    fn clip_bounty_too_high() {
+        let mut contract = setup();
+        contract.set_keeper_bounties(U128(ONE), U128(ONE / 10), U128(ONE), U128(ONE));
+    }
+
+    #[test]
+    fn keepers_earn_bounties() {
+        let mut h = leveraged_long();
+        h.call(BOB, 50 * ONE, |c| c.deposit(U128(0), false));
+        h.call(BOB, 1, |c| c.renege(U128(100 * ONE), true, true)); // fee tops up the GuaranteeFund's QD
+        let gfund = h.pools().gfund_short_credit.0;
+        assert!(gfund > ONE / 20);
+
+        h.call(OWNER, 0, |c| c.update());
+        h.call(OWNER, 0, |c| c.update()); // stress both Pledges
+        assert_eq!(h.view(|c| c.get_keeper_rewards(accounts(OWNER))).0, 2 * CRANK_BOUNTY.0);
+        assert_eq!(h.events("reward"), 1);
+
+        h.set_price(43 * ONE / 10);
+        h.call(OWNER, 1, |c| c.clip(accounts(ALICE))); // clears 100 QD of debt
+        let bounty = std::cmp::min(ratio(CLIP_BOUNTY.0, 100 * ONE, ONE), gfund - 2 * CRANK_BOUNTY.0);
+        assert_eq!(h.view(|c| c.get_keeper_rewards(accounts(OWNER))).0, 2 * CRANK_BOUNTY.0 + bounty);
+        assert_eq!(h.pools().gfund_short_credit.0, gfund - 2 * CRANK_BOUNTY.0 - bounty);
+
+        let qd = h.qd(OWNER);
+        h.call(OWNER, 1, |c| c.claim_rewards());
+        assert_eq!(h.qd(OWNER), qd + 2 * CRANK_BOUNTY.0 + bounty);
+        assert_eq!(h.view(|c| c.get_keepers()).owed.0, 0);
+    }
+
+    #[test]
+    fn no_bounty_for_deleveraging() {
+        let mut h = leveraged_long();
+        h.call(BOB, 50 * ONE, |c| c.deposit(U128(0), false));
+        h.call(BOB, 1, |c| c.renege(U128(100 * ONE), true, true));
+        assert!(h.pools().gfund_short_credit.0 > 0);
+        h.call(ALICE, 1, |c| c.set_target(false, U128(MIN_CR.0 + ONE / 10)));
+        h.set_price(46 * ONE / 10); // 115%, above MIN_CR but below her target
+        h.call(OWNER, 1, |c| c.clip(accounts(ALICE)));
+        assert_eq!((h.events("deleverage"), h.events("reward")), (1, 0));
+        assert_eq!(h.view(|c| c.get_keeper_rewards(accounts(OWNER))).0, 0);
+    }
+}
@@ -7,60 +7,88 @@
     If the storage decreases, the contract will issue a refund for the cost of the released storage.
     Unused tokens from the attached deposit are also refunded, so attach more deposit than required.
   - To prevent the deployed contract from abused, it should not have any access keys on its account.
-*/ use near_contract_standards::fungible_token::FungibleToken;
+*/
+use near_contract_standards::fungible_token::FungibleToken;
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
 use near_contract_standards::fungible_token::metadata::{
     FungibleTokenMetadata, FungibleTokenMetadataProvider, FT_METADATA_SPEC
 }; 
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::{
-    env, log, near_bindgen, AccountId, Balance, PanicOnDefault, 
+    env, log, near_bindgen, AccountId, Balance, Gas, PanicOnDefault, 
     PromiseOrValue, Promise, assert_one_yocto 
 };
 use near_sdk::json_types::{ValidAccountId, U128};
-use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde::Serialize;
 use near_sdk::collections::{
-    LazyOption, LookupMap, TreeMap,
+    LazyOption, LookupMap, LookupSet,
     UnorderedMap, UnorderedSet
 }; 
-use uint::construct_uint;
 use std::convert::TryFrom;
 use std::convert::TryInto;
 near_sdk::setup_alloc!();
-construct_uint! {
-    /// 256-bit unsigned integer.
-    pub struct U256(4);
+mod wide { // the macro's expansion isn't up to date with clippy
+    #![allow(clippy::manual_div_ceil, clippy::assign_op_pattern)]
+    uint::construct_uint! {
+        /// 256-bit unsigned integer.
+        pub struct U256(4);
+    }
 }
+pub use wide::U256;
 use crate::pledge::*; mod pledge;
-use crate::utils::*; mod utils;
-use crate::pool::*; mod pool;
-use crate::grab::*; mod grab;
-use crate::bonk::*; mod bonk;
-use crate::get::*; mod get;
-use crate::out::*; mod out;
+use crate::utils::*; pub mod utils;
+use crate::math::*; pub mod math;
+pub mod fixed;
+use crate::units::*; pub mod units;
+use crate::pool::*; pub mod pool;
+mod grab;
+mod bonk;
+mod get;
+mod out;
+use crate::oracle::*; mod oracle;
+use crate::vol::*; mod vol;
+use crate::roles::*; mod roles;
+use crate::config::*; pub mod config;
+use crate::migrate::*; mod migrate;
+use crate::events::*; mod events;
+mod invariants;
+use crate::keeper::*; mod keeper;
+use crate::auction::*; mod auction;
+mod receiver;
+use crate::assets::*; mod assets;
+use crate::covariance::*; mod covariance;
+#[cfg(test)] mod harness;
 
-#[serde(crate = "near_sdk::serde")]
 #[derive(BorshDeserialize, BorshSerialize, Debug, Serialize)]
+#[serde(crate = "near_sdk::serde")]
 pub struct Crank {
     pub done: bool, // currently updating
-    pub index: usize, // amount of collateral
+    pub left: u64, // Pledges left to visit, counting down from the last index
     pub last: u64, // timestamp of last time Crank was updated
+    pub epoch: u64, // incremented each time a crank starts
 } impl Crank {
     pub fn new() -> Self {
         Self {
             done: true,
-            index: 0,
+            left: 0,
             last: 0,
+            epoch: 0,
         }
     }
 }
 
-#[serde(crate = "near_sdk::serde")]
+impl Default for Crank {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(BorshSerialize, BorshDeserialize, Debug, Serialize)]
+#[serde(crate = "near_sdk::serde")]
 pub struct Data { // Used in weighted median voting for solvency target
-    solvency: f64, // capital adequacy needed to back debt
-    median: f64, // Median of votes for Solvency Target
-    scale: f64, // (scale = target / solvency)
+    solvency: u128, // capital adequacy needed to back debt, 1e24-scaled
+    median: u128, // Median of votes for Solvency Target, 0 if nobody voted
+    scale: u128, // (scale = target / solvency)
     k: u64, // approx. index of median (+/- 1)
     sum_w_k: Balance, // sum(W[0..k])
     total: Balance,
@@ -70,32 +98,67 @@ pub struct Data { // Used in weighted median voting for solvency target
     w: Vec<Balance>
 } impl Data {
     pub fn new() -> Self {
-        Self { solvency: 1.0, 
-            median: -1.0, scale: 1.0,
+        Self { solvency: ONE, 
+            median: 0, scale: ONE,
             k: 0, sum_w_k: 0, total: 0, 
             y: Vec::new(), w: Vec::new()
         }
     }
 }
 
+impl Default for Data {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Vote { // an account's SolvencyTarget votes, as in 142 for 142%
+    pub short: i64, // -1 if the account did not vote for this side
+    pub long: i64,
+    pub stake: Balance // SP stake that the votes were last weighted with
+} impl Vote {
+    pub fn new() -> Self {
+        Self { short: -1, long: -1, stake: 0 }
+    }
+}
+
+impl Default for Vote {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct Contract { token: FungibleToken, // this contract is NEP141 token
-    price: u128,
-    vol: u128,
+    roles: Roles, // owner, guardian
+    config: Config, // governable protocol parameters
+    oracle: Oracle, // push-based price feed
+    vol: Volatility, // realized volatility of NEAR
     metadata: LazyOption<FungibleTokenMetadata>,
-    //votes: LookupMap<AccountId, f64>, // current solvency target vote...
+    votes: LookupMap<AccountId, Vote>, // current solvency target votes
     data_s: Data, // Data structure related to voting for solvency target
     data_l: Data, // Same, but for the long budget (above is for shorts)
     crank: Crank, // Used in `update` function
     pledges: UnorderedMap<AccountId, Pledge>,
+    charged: LookupMap<AccountId, u64>, // epoch in which each Pledge was last stressed
     short_crs: PledgesTreeMap<Pledge, ()>, 
     long_crs: PledgesTreeMap<Pledge, ()>,
+    baskets: UnorderedSet<AccountId>, // Pledges borrowing long against a basket, which `long_crs` may have mis-sorted
     stats: PledgeStats, // Global Risk Vars
-    blood: Pod, // Solvency Pool deposits 
+    blood: Pod<QdAmount, NearAmount>, // Solvency Pool deposits 
     gfund: Pool, // gfundPool, // Guarantee Fund
     live: Pool, // Active borrower assets
-    dead: Pool // Defaulted borrower assets
+    dead: DeadPool, // Defaulted borrower assets
+    keepers: Keepers, // bounties for cranking `update` and `clip`
+    auctions: Auctions, // of liquidated collateral, when enabled
+    margined: LookupSet<AccountId>, // Pledges that opted into cross-margin
+    absorption: Absorption, // of the DeadPool, per unit of SP deposit
+    marks: LookupMap<AccountId, Mark>, // where each Pledge last absorbed from the DeadPool
+    assets: UnorderedMap<AccountId, Asset>, // collateral besides NEAR
+    covariance: Covariance // between assets, for the variance of portfolios
 }
 
 // TODO QD SVG decode
@@ -119,22 +182,38 @@ impl Contract {
         metadata.assert_valid();
         let mut this = Self {
             token: FungibleToken::new(b"q".to_vec()),
-            price: ONE, // TODO remove
-            vol: 4666066, // TODO remove
+            roles: Roles::new(owner_id.clone().into()),
+            config: Config::new(),
+            oracle: Oracle::new(b"o".to_vec()),
+            vol: Volatility::new(b"x".to_vec()),
             metadata: LazyOption::new(b"m".to_vec(), Some(&metadata)),
             pledges: UnorderedMap::new(b"p".to_vec()),
-            short_crs: PledgesTreeMap::new(b"s".to_vec(), Sort::Composite, true),
-            long_crs: PledgesTreeMap::new(b"l".to_vec(), Sort::Composite, false),
+            charged: LookupMap::new(b"e".to_vec()),
+            short_crs: PledgesTreeMap::new(b"s".to_vec(), Sort::Banded, true),
+            long_crs: PledgesTreeMap::new(b"l".to_vec(), Sort::Banded, false),
+            baskets: UnorderedSet::new(b"g".to_vec()),
+            votes: LookupMap::new(b"v".to_vec()),
             data_l: Data::new(),
             data_s: Data::new(),
             crank: Crank::new(),
             stats: PledgeStats::new(), 
-            blood: Pod::new(0, 0),
+            blood: Pod::default(),
             gfund: Pool::new(), 
             live: Pool::new(),
-            dead: Pool::new(),
+            dead: DeadPool::new(),
+            keepers: Keepers::new(b"k".to_vec()),
+            auctions: Auctions::new(b"a".to_vec()),
+            margined: LookupSet::new(b"c".to_vec()),
+            absorption: Absorption::new(),
+            marks: LookupMap::new(b"b".to_vec()),
+            assets: UnorderedMap::new(b"t".to_vec()),
+            covariance: Covariance::new(b"r".to_vec()),
         };
         this.token.internal_register_account(owner_id.as_ref());
+        if owner_id.as_ref() != &env::current_account_id() { // holds the protocol's QD
+            this.token.internal_register_account(&env::current_account_id());
+        }
+        write_version(); // layout of this struct, see migrate.rs
         this
     }
 
@@ -146,100 +225,122 @@ impl Contract {
 	 *  in the same range range(1, len(Weights)) such that 
 	 *  sum(Weights[0:k]) > sum(Weights) / 2
 	*/
-    pub(crate) fn rebalance(&mut self, d: &mut Data, new_total: Balance, 
+    pub(crate) fn rebalance(&mut self, short: bool,
                             new_stake: Balance, new_vote: i64, 
                             old_stake: Balance, old_vote: i64) {
-        d.total = new_total;
-        let mut len = d.y.len();
-        assert!(len == d.w.len(), "Wrong Weights Length");	
-        assert!(new_vote >= 100 && new_vote <= 200, 
+        let d = if short { &mut self.data_s } else { &mut self.data_l };
+        assert!(d.y.len() == d.w.len(), "{}", ERR_WRONG_WEIGHTS_LENGTH);	
+        assert!((100..=200).contains(&new_vote), 
         "Allowable SolvencyTarget range is 100-200%");
 
-        let added: bool;
-        match d.y.binary_search(&new_vote) {
-            Ok(idx) => {
-                if new_stake != 0 {
-                    d.w[idx] = d.w[idx].saturating_add(new_stake);
-                }
-                added = false;
-            },
-            Err(idx) => {
-                d.y.insert(idx, new_vote);
-                d.w.insert(idx, new_stake);
-                added = true;
-                len += 1;
-            }
-        }
-        let median = (d.median * 100.0) as i64;
-        let mid_stake = d.total.checked_div(2).unwrap_or_else(|| 0);
-
         if old_vote != -1 && old_stake != 0 { // if not the first time user is voting
-            let idx = d.y.binary_search(&old_vote).unwrap_or_else(|x| panic!());
+            let idx = d.y.binary_search(&old_vote).expect(ERR_OLD_VOTE_NOT_FOUND);
             d.w[idx] = d.w[idx].saturating_sub(old_stake);
             if d.w[idx] == 0 {
                 d.y.remove(idx);
                 d.w.remove(idx);
-                if (idx as u64) >= d.k {
-                    d.k -= 1;
-                }
-                len -= 1;	
             }
+            d.total = d.total.saturating_sub(old_stake);
         }
-        if d.total != 0 && mid_stake != 0 {
-            if len == 1 || new_vote <= median {
-                d.sum_w_k = d.sum_w_k.saturating_add(new_stake.into());
-            }		  
-            if old_vote <= median {   
-                d.sum_w_k = d.sum_w_k.saturating_sub(old_stake.into());
-            }
-            if median > new_vote {
-                if added && len > 1 {
-                    d.k += 1;
-                }
-                while d.k >= 1 && ((d.sum_w_k.saturating_sub(d.w[d.k as usize])) >= mid_stake) {
-                    d.sum_w_k = d.sum_w_k.saturating_sub(d.w[d.k as usize]);
-                    d.k -= 1;			
-                }
-            } else {
-                while d.sum_w_k < mid_stake {
-                    d.k += 1;
-                    d.sum_w_k = d.sum_w_k.saturating_add(d.w[d.k as usize]);
+        if new_stake != 0 {
+            match d.y.binary_search(&new_vote) {
+                Ok(idx) => {
+                    d.w[idx] = d.w[idx].saturating_add(new_stake);
+                },
+                Err(idx) => {
+                    d.y.insert(idx, new_vote);
+                    d.w.insert(idx, new_stake);
                 }
             }
-            d.median = (d.y[d.k as usize] as f64) / 100.0; // convert (e.g.) 142 to 1.42
-            if d.sum_w_k == mid_stake {
-                let intermedian = d.median + (d.y[d.k as usize + 1] as f64) / 100.0;
-                d.median = intermedian / 2.0;
-            }
-        }  else {
-            d.sum_w_k = 0;
+            d.total = d.total.saturating_add(new_stake);
         }
-    } // TODO port from https://github.com/ricktobacco/sputnik-dao-contract/commit/02a66f26bf53f4fa8682a5a461175bdc9afd85f0
-        // * update voting weight when user update's their SolvencyDeposit
-        // * vote function where user indicates their SolvencyTarget
-        /**
-            pub fn vote(&mut self, new_vote: i128)  {
-                assert_eq!(env::attached_deposit(), 16 * env::storage_byte_cost());
-                assert!(new_vote > 0, "Vote cannot be negative");
-                let account = env::predecessor_account_id();
-                let old_vote: i128;
-                if let Some(vote) = self.votes.get(&account) {  
-                    old_vote = vote;
-                } else {
-                    old_vote = -1;
-                }
-                self.votes.insert(&account, &new_vote);
+        d.k = 0; 
+        d.sum_w_k = 0;
+        if d.total == 0 { // nobody is voting anymore, `risk` falls back to 100%
+            d.median = 0;
+            return;
+        }
+        // there are at most 101 distinct votes (100-200), 
+        // so it's cheap to walk up to the median from the bottom
+        let mut k: usize = 0;
+        let mut sum_w_k = d.w[0];
+        while sum_w_k < d.total - sum_w_k {
+            k += 1;
+            sum_w_k = sum_w_k.saturating_add(d.w[k]);
+        }
+        d.k = k as u64;
+        d.sum_w_k = sum_w_k;
+        d.median = d.y[k] as u128 * ONE / 100; // convert (e.g.) 142 to 1.42
+        if sum_w_k == d.total - sum_w_k && k + 1 < d.y.len() {
+            let intermedian = d.median + d.y[k + 1] as u128 * ONE / 100;
+            d.median = intermedian / 2;
+        }
+    } 
+
+    /// Value (in QD) of an account's SolvencyPool deposit, which weighs its votes
+    pub(crate) fn sp_stake(&self, pledge: &Pledge) -> Balance {
+        (pledge.near * self.get_price() + pledge.quid).0
+    }
+
+    /// Must be called whenever `pledge.near` or `pledge.quid` changes, so that
+    /// the account's votes (if any) get reweighted by its new SolvencyPool stake
+    pub(crate) fn on_stake_change(&mut self, pledge: &Pledge) {
+        if let Some(mut vote) = self.votes.get(&pledge.id) {
+            let stake = self.sp_stake(pledge);
+            if vote.short != -1 {
+                self.rebalance(true, stake, vote.short, vote.stake, vote.short);
             }
-            pub fn on_stake_change(&mut self, account: AccountId, balances: (Balance, Balance, Balance)) {
-                if let Some(old_vote) = self.votes.get(&account) {  
-                    self.rebalance(balances.2, balances.1, old_vote, balances.0, old_vote);    
-                }
+            if vote.long != -1 {
+                self.rebalance(false, stake, vote.long, vote.stake, vote.long);
             }
-            pub fn on_vote_change(&mut self, old_vote: i128, new_vote: i128, balances: (Balance, Balance)) {
-                self.rebalance(balances.1, balances.0, old_vote, balances.0, new_vote);
+            if stake == 0 { // withdrawing the whole SP deposit withdraws the votes too,
+                // and refunds the storage that they took up
+                let storage = env::storage_usage();
+                self.votes.remove(&pledge.id);
+                let freed = Balance::from(storage.saturating_sub(env::storage_usage()));
+                if freed > 0 {
+                    Promise::new(pledge.id.clone()).transfer(freed * env::storage_byte_cost());
+                }
+            } else {
+                vote.stake = stake;
+                self.votes.insert(&pledge.id, &vote);
             }
-        */
+        }
+    }
+
+    // SolvencyProviders vote for the SolvencyTarget (100-200%) of either
+    // side of the protocol, weighted by the value of their SP deposit;
+    // the attached NEAR pays for the storage the vote takes up, the rest
+    // is refunded (as is the storage, once the votes are withdrawn)
+    #[payable]
+    pub fn vote(&mut self, short: bool, target: u8) {
+        let deposit = env::attached_deposit();
+        let storage = env::storage_usage();
+        let account = env::predecessor_account_id();
+        let pledge = self.pledges.get(&account).expect(ERR_MUST_STAKE_BEFORE_VOTE);
+        let stake = self.sp_stake(&pledge);
+        assert!(stake > 0, "{}", ERR_ZERO_STAKE_BEFORE_VOTE);
         
+        let new_vote = target as i64;
+        self.on_stake_change(&pledge); // bring weight of existing votes up to date
+        let mut vote = self.votes.get(&account).unwrap_or_default();
+        let old_vote = if short { vote.short } else { vote.long };
+        self.rebalance(short, stake, new_vote, stake, old_vote);
+        if short {
+            vote.short = new_vote;
+        } else {
+            vote.long = new_vote;
+        }
+        vote.stake = stake;
+        self.votes.insert(&account, &vote);
+        let used = Balance::from(env::storage_usage().saturating_sub(storage));
+        let cost = used * env::storage_byte_cost();
+        assert!(deposit > 0 && deposit >= cost, "{}", ERR_VOTE_STORAGE);
+        if deposit > cost {
+            Promise::new(account).transfer(deposit - cost);
+        }
+    }
+
     fn on_account_closed(&mut self, account_id: AccountId, balance: Balance) {
         log!("Closed @{} with {}", account_id, balance);
     }
@@ -260,3 +361,54 @@ impl FungibleTokenMetadataProvider for Contract {
         self.metadata.get().unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::*;
+    use near_sdk::test_utils::accounts;
+
+    const DEPOSIT: Balance = ONE / 100; // more than a vote's storage costs
+
+    #[test]
+    fn median_moves_with_stake() {
+        let mut h = Harness::new(PRICE);
+        h.call(BOB, 50 * ONE, |c| c.deposit(U128(0), false)); // worth 250 QD
+        h.call(CAROL, 100 * ONE, |c| c.deposit(U128(0), false)); // worth 500 QD
+        h.call(BOB, DEPOSIT, |c| c.vote(false, 120));
+        h.call(CAROL, DEPOSIT, |c| c.vote(false, 180));
+        assert_eq!(h.contract.data_l.median, 18 * ONE / 10);
+        h.call(CAROL, 1, |c| c.renege(U128(80 * ONE), true, false)); // down to 100 QD
+        assert_eq!(h.contract.data_l.median, 12 * ONE / 10);
+        assert_eq!(h.contract.data_l.total, 350 * ONE);
+        assert_eq!(h.contract.data_s.median, 0); // nobody voted for shorts
+    }
+
+    #[test]
+    fn revote_replaces_the_old_vote() {
+        let mut h = Harness::new(PRICE);
+        h.call(BOB, 50 * ONE, |c| c.deposit(U128(0), false));
+        h.call(BOB, DEPOSIT, |c| c.vote(false, 120));
+        let cost = DEPOSIT - refunded();
+        assert!(cost > 0);
+        h.call(BOB, 1, |c| c.vote(false, 150)); // takes up no more storage
+        assert_eq!(h.contract.data_l.y, vec![150]);
+        assert_eq!(h.contract.data_l.total, 250 * ONE);
+        assert_eq!(h.contract.data_l.median, 15 * ONE / 10);
+        let vote = h.view(|c| c.get_vote(accounts(BOB))).unwrap();
+        assert_eq!((vote.long, vote.short), (150, -1));
+        // withdrawing the whole deposit withdraws the vote, and refunds its storage
+        h.call(BOB, 1, |c| c.renege(U128(50 * ONE), true, false));
+        assert!(h.view(|c| c.get_vote(accounts(BOB))).is_none());
+        assert_eq!(h.contract.data_l.median, 0);
+        assert!(refunded() > 50 * ONE - fee(50 * ONE)); // the NEAR and the storage
+    }
+
+    #[test]
+    #[should_panic(expected = "Attach enough NEAR to pay for the vote's storage")]
+    fn vote_pays_for_storage() {
+        let mut h = Harness::new(PRICE);
+        h.call(BOB, 50 * ONE, |c| c.deposit(U128(0), false));
+        h.call(BOB, 1, |c| c.vote(false, 120));
+    }
+}
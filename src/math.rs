@@ -0,0 +1,387 @@
+use crate::*;
+
+/*  Pure math of the protocol: no `env`, no storage, so that it can be
+ *  reused off-chain (see the quid-sim crate) exactly as the contract
+ *  runs it. Amounts are 1e24-scaled integers, and so are risk figures
+ *  (see fixed.rs)
+ */
+pub fn ratio(multiplier:u128, numerator: u128, denominator: u128) -> u128 { 
+    (
+        U256::from(numerator)
+            .checked_mul(U256::from(multiplier)).expect("Overflow")
+            .checked_div(U256::from(denominator)).expect("Overflow")
+    ).as_u128()
+}
+
+// collateral over debt, by what either is worth in QD at `price`
+#[allow(non_snake_case)]
+pub fn computeCR<C: Amount, D: Amount>(_price: Price, _collat: C, _debt: D) -> Ratio {
+    if !_debt.is_zero() {
+        // assert!(_collat > 0, "never supposed to happen");
+        if !_collat.is_zero() {
+            return _collat.in_qd(_price) / _debt.in_qd(_price);
+        }
+        else {
+            return Ratio::ZERO;
+        }
+    } 
+    else if !_collat.is_zero() {
+        return Ratio::MAX;
+    }
+    Ratio::ZERO
+}
+
+// CR of a long side whose collateral is NEAR plus a basket of other assets
+// (`basket` is their QD value, after haircuts), same as computeCR otherwise
+#[allow(non_snake_case)]
+pub fn computeBasketCR(_price: Price, _near: NearAmount, _basket: QdAmount, _debt: QdAmount) -> Ratio {
+    if _basket.is_zero() {
+        return computeCR(_price, _near, _debt);
+    }
+    computeCR(_price, _near * _price + _basket, _debt)
+}
+
+// Newton's method of integer square root. 
+// pub fn integer_sqrt(value: U256) -> U256 {
+//     let mut guess: U256 = (value + U256::one()) >> 1;
+//     let mut res = value;
+//     while guess < res {
+//         res = guess;
+//         guess = (value / guess + guess) >> 1;
+//     }
+//     res
+// }
+
+// A&S formula 26.2.23, t - (c0 + c1 t + c2 t²) / (1 + d1 t + d2 t² + d3 t³)
+#[allow(non_snake_case)]
+pub fn RationalApproximation(t: u128) -> i128 {
+    // the error of the approximation itself is below 4.5e-4
+    const C: [u128; 3] = [2_515517 * ONE / 1_000000, 802853 * ONE / 1_000000, 10328 * ONE / 1_000000];
+    const D: [u128; 3] = [1_432788 * ONE / 1_000000, 189269 * ONE / 1_000000, 1308 * ONE / 1_000000];
+    let num = ratio(t, ratio(t, C[2], ONE) + C[1], ONE) + C[0];
+    let den = ratio(t, ratio(t, ratio(t, D[2], ONE) + D[1], ONE) + D[0], ONE) + ONE;
+    t as i128 - ratio(ONE, num, den) as i128
+}
+
+// quantile of the standard normal distribution, for 0 < p < 1 (1e24-scaled)
+#[allow(non_snake_case)]
+pub fn NormalCDFInverse(p: u128) -> i128 {
+    assert!(p > 0 && p < ONE);
+    // F^-1(p) = -G^-1(p) below the median, G^-1(1-p) above it
+    let tail = if p < ONE / 2 { p } else { ONE - p };
+    let t = fixed::sqrt((-2 * fixed::ln(tail)) as u128);
+    if p < ONE / 2 { -RationalApproximation(t) } else { RationalApproximation(t) }
+}
+
+// calculate % loss given short Pledge's portfolio volatility & the statistical assumption of normality
+pub fn stress(avg: bool, sqrt_var: u128, short: bool) -> u128 { // max portfolio loss, 1e24-scaled
+    let mut alpha: u128 = 9 * ONE / 10; // 10% of the worst case scenarios
+    if avg {
+        alpha = ONE / 2;  // 50% of the avg case scenarios
+    }
+    let cdf = NormalCDFInverse(alpha);
+    let e1 = fixed::mul(cdf, cdf) / -2;
+    let e2 = ratio(sqrt_var, ratio(ONE, fixed::exp(e1), fixed::SQRT_TWO_PI), ONE - alpha);
+    let e2 = std::cmp::min(e2 as i128, fixed::EXP_MAX); // beyond ~1000% vol, not in practice
+    if short {
+        fixed::exp(e2) - ONE
+    } else {
+        ONE - fixed::exp(-e2)
+    }
+}
+
+// Used for pricing put & call options for borrowers contributing to the ActivePool
+#[allow(non_snake_case)]
+pub fn price(payoff: Balance, scale: u128, val_crypto: Balance, val_quid: Balance, ivol: u128, short: bool) -> u128 {
+    let max_rate: u128 = 42 * ONE / 100;
+    let min_rate: u128 = ratio(scale, 42 * ONE / 10_000, ONE); // * calibrate
+    if val_quid == 0 { // deep in the money for shorts, out of it for longs
+        return if short { max_rate } else { min_rate };
+    }
+    let div = ratio(ONE, val_crypto, val_quid);
+    let ln = fixed::ln(div);
+    let D: i128 = if ivol == 0 { // only the intrinsic value is left
+        ln.signum() * fixed::ERFC_ZERO as i128
+    } else {
+        let half_var = fixed::mul(ivol as i128, ivol as i128) / -2 /* times calibrate */;
+        let d = fixed::div(ln + half_var, ivol as i128); // * calibrate
+        fixed::div(d, fixed::SQRT_TWO as i128)
+    };
+    let rate: U256 = if short { // erfc is used instead of normal distribution
+        U256::from(payoff) * U256::from(fixed::erfc(-D)) / U256::from(2 * val_crypto)
+    } else {
+        U256::from(payoff) * U256::from(fixed::erfc(D)) / U256::from(2 * val_quid)
+    };
+    // rate *= calibrate;
+    if rate > U256::from(max_rate) {
+        max_rate
+    } else if rate < U256::from(min_rate) {
+        min_rate
+    } else {
+        rate.as_u128()
+    }
+}
+
+/*  Annual premium of one side of a Pledge, quoted from its value in
+ *  crypto (`val_near`, in QD) and in QD (`qd`), and the variance of
+ *  that crypto as a portfolio (see `portfolio_var`): the rate is a fraction
+ *  of the NEAR debt's value for shorts, and of the QD debt for longs.
+ *  Losses are how much the side would be underwater after the worst
+ *  10% of price moves (stress) and after an average move. All of it
+ *  is 1e24-scaled, amounts in QD and the rest as fractions
+ */
+pub struct Quote {
+    pub rate: u128,
+    pub premiums: Balance, // per year, in QD
+    pub stress_loss: Balance,
+    pub avg_loss: Balance,
+}
+
+// `multiplier` times `numerator` over `denominator`, capped at u128::MAX instead of overflowing
+pub fn saturating_ratio(multiplier: u128, numerator: u128, denominator: u128) -> u128 {
+    let r = U256::from(numerator) * U256::from(multiplier) / U256::from(denominator);
+    if r > U256::from(u128::MAX) { u128::MAX } else { r.as_u128() }
+}
+
+pub fn quote(val_near: Balance, qd: Balance, var: u128, scale: u128, short: bool) -> Quote {
+    let mut vol: u128 = fixed::sqrt(var); // portfolio volatility of the Pledge's crypto
+    if short {
+        // $ value of borrowed crypto in upward price shocks of avg & bad magnitudes
+        let mut pct: u128 = stress(true, vol, true);
+        let avg_val = saturating_ratio(ONE + pct, val_near, ONE);
+        pct = stress(false, vol, true);
+        let stress_val = saturating_ratio(ONE + pct, val_near, ONE);
+        let stress_loss = stress_val.saturating_sub(qd); // if it's not zero that means liquidation
+        let avg_loss = avg_val.saturating_sub(qd);
+
+        vol = ratio(scale, vol, ONE); // market determined implied volaility
+        let delta = pct + ONE;
+        let ln = fixed::mul(fixed::ln(delta), scale as i128); // * calibrate
+        let i_stress = fixed::exp(std::cmp::min(ln, fixed::EXP_MAX)) - ONE;
+        let payoff = saturating_ratio(ONE + i_stress, val_near, ONE).saturating_sub(qd);
+        let rate = price(payoff, scale, val_near, qd, vol, true);
+        Quote { rate, premiums: ratio(rate, val_near, ONE), stress_loss, avg_loss }
+    } else {
+        // $ value of crypto collateral in downward price shocks of bad & avg magnitudes
+        let mut pct: u128 = stress(true, vol, false);
+        let avg_val = ratio(ONE - pct, val_near, ONE);
+        pct = stress(false, vol, false);
+        let stress_val = ratio(ONE - pct, val_near, ONE);
+        let stress_loss = qd.saturating_sub(stress_val);
+        let avg_loss = qd.saturating_sub(avg_val);
+
+        vol = ratio(scale, vol, ONE); // market determined implied volaility
+        let delta = ONE - pct;
+        let kept = if delta == 0 { 0 } else { // 1 - i_stress
+            fixed::exp(fixed::mul(fixed::ln(delta), scale as i128)) // calibrate
+        };
+        let payoff = qd.saturating_sub(ratio(kept, val_near, ONE));
+        let rate = price(payoff, scale, val_near, qd, vol, false);
+        Quote { rate, premiums: ratio(rate, qd, ONE), stress_loss, avg_loss }
+    }
+}
+
+/*  Capital adequacy of the SolvencyPool (worth `val_sp` in QD) backing
+ *  one side of the LivePool, whose NEAR is worth `val_near` against
+ *  `qd`: own funds in normal markets over the Solvency Capital
+ *  Requirement, i.e. the deposits needed to survive a stress event;
+ *  None when there is no requirement to speak of (SCR isn't positive)
+ */
+pub fn solvency(val_sp: Balance, val_near: Balance, qd: Balance, ivol: u128, short: bool) -> Option<u128> {
+    let mva_s: i128; // market value of assets in stressed markets
+    let mvl_s: i128; // market value of liabilities in stressed markets
+    if !short {
+        let pct = stress(false, ivol, false);
+        mva_s = ratio(ONE - pct, val_near, ONE) as i128;
+        mvl_s = qd as i128 - mva_s;
+    } else {
+        let pct = stress(false, ivol, true);
+        mva_s = saturating_ratio(ONE + pct, val_near, ONE) as i128;
+        mvl_s = mva_s - qd as i128;
+    }
+    let own_n = val_sp as i128; // own funds normal markets, includes the reserve
+    let mut own_s = mva_s - mvl_s; // own funds stressed markets
+    if short && own_s > 0 {
+        own_s *= -1;
+    }
+    let scr = own_n - own_s;
+    if scr > 0 { Some(ratio(ONE, own_n as u128, scr as u128)) } else { None }
+}
+
+/*  Variance of a portfolio, wᵀΣw, where `w` are the fractions of its
+ *  value in each asset and `cov` is the covariance between the assets
+ *  (annualized): one asset with all the weight has its own variance
+ */
+pub fn portfolio_var(w: &[u128], cov: &[Vec<i128>]) -> u128 {
+    let mut var: i128 = 0;
+    for i in 0..w.len() {
+        for j in 0..w.len() {
+            var += fixed::mul(ratio(w[i], w[j], ONE) as i128, cov[i][j]);
+        }
+    }
+    std::cmp::max(var, 0) as u128 // rounding residue of (near) perfect hedges
+}
+
+// Σ from each asset's volatility and the correlation of each pair, Σij = ρij σi σj
+pub fn covariance(vols: &[u128], corr: &[Vec<i128>]) -> Vec<Vec<i128>> {
+    (0..vols.len()).map(|i| (0..vols.len())
+        .map(|j| fixed::mul(corr[i][j], ratio(vols[i], vols[j], ONE) as i128)).collect()
+    ).collect()
+}
+
+/*  Collateral to sell (and debt to repay with the proceeds), both in QD,
+ *  so that a Pledge worth `coll` against `debt` ends up at `min_cr`:
+ *  CR = (coll - x) / (debt - x)  =>  x = (CR * debt - coll) / (CR - 1)
+ */
+pub fn shrinkage(coll: QdAmount, debt: QdAmount, min_cr: Ratio) -> QdAmount {
+    let cr_x_debt = debt * min_cr;
+    cr_x_debt.checked_sub(coll).expect(ERR_SUB) / (min_cr - Ratio::ONE)
+}
+
+// scale factor for premiums, such that solvency moves towards the voted target
+pub fn scale(target: u128, solvency: u128, min_scale: u128, max_scale: u128) -> u128 {
+    let target = if target == 0 { ONE } else { target }; // nobody voted
+    if solvency == 0 {
+        return max_scale;
+    }
+    let scale = saturating_ratio(ONE, target, solvency);
+    if scale > max_scale {
+        max_scale
+    } else if scale < min_scale {
+        min_scale
+    } else {
+        scale
+    }
+}
+
+// squared log return between two prices, annualized by the nanosecs between them
+pub fn squared_return(from: u128, from_time: u64, to: u128, to_time: u64) -> u128 {
+    let r = fixed::ln(ratio(ONE, to, from)).unsigned_abs();
+    ratio(ONE_YEAR as u128, ratio(r, r, ONE), (to_time - from_time) as u128)
+}
+
+// volatility (scaled by VOL_SCALE, rounded) of `returns` that square to `sum` in total
+pub fn realized_vol(sum: u128, returns: u64) -> u128 {
+    let unit = ONE / VOL_SCALE;
+    (fixed::sqrt(sum / returns as u128) + unit / 2) / unit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the f64 risk engine that the fixed-point one replaced, as a reference
+    mod float {
+        pub fn normal_cdf_inverse(p: f64) -> f64 {
+            let t = (-2.0 * (if p < 0.5 { p } else { 1.0 - p }).ln()).sqrt();
+            let (c, d) = ([2.515517, 0.802853, 0.010328], [1.432788, 0.189269, 0.001308]);
+            let x = t - ((c[2] * t + c[1]) * t + c[0]) / (((d[2] * t + d[1]) * t + d[0]) * t + 1.0);
+            if p < 0.5 { -x } else { x }
+        }
+
+        pub fn stress(avg: bool, sqrt_var: f64, short: bool) -> f64 {
+            let alpha = if avg { 0.5 } else { 0.9 };
+            let cdf = normal_cdf_inverse(alpha);
+            let e2 = (-cdf * cdf / 2.0).exp() / (2.0 * std::f64::consts::PI).sqrt() / (1.0 - alpha) * sqrt_var;
+            if short { e2.exp() - 1.0 } else { 1.0 - (-e2).exp() }
+        }
+
+        pub fn price(payoff: f64, scale: f64, val_crypto: f64, val_quid: f64, ivol: f64, short: bool) -> f64 {
+            let d = ((val_crypto / val_quid).ln() - ivol * ivol / 2.0) / ivol / 2.0_f64.sqrt();
+            let rate = if short {
+                payoff * libm::erfc(-d) / 2.0 / val_crypto
+            } else {
+                payoff * libm::erfc(d) / 2.0 / val_quid
+            };
+            rate.max(0.0042 * scale).min(0.42)
+        }
+
+        pub fn premiums(val_near: f64, qd: f64, var: f64, scale: f64, short: bool) -> f64 {
+            let vol = var.sqrt();
+            let pct = stress(false, vol, short);
+            if short {
+                let payoff = (val_near * (1.0 + pct).powf(scale) - qd).max(0.0);
+                price(payoff, scale, val_near, qd, vol * scale, true) * val_near
+            } else {
+                let payoff = (qd - val_near * (1.0 - pct).powf(scale)).max(0.0);
+                price(payoff, scale, val_near, qd, vol * scale, false) * qd
+            }
+        }
+    }
+
+    fn f(x: u128) -> f64 {
+        x as f64 / ONE as f64
+    }
+
+    fn fixed(x: f64) -> u128 {
+        (x * 1e12).round() as u128 * (ONE / 1_000_000_000_000)
+    }
+
+    #[test]
+    fn normal_quantiles_as_floats() {
+        for p in 1..100 {
+            let p = p as f64 / 100.0;
+            let got = NormalCDFInverse(fixed(p)) as f64 / ONE as f64;
+            assert!((got - float::normal_cdf_inverse(p)).abs() < 1e-13, "{}", p);
+        }
+        // the approximation is itself within 4.5e-4 of the true quantile
+        assert!((NormalCDFInverse(9 * ONE / 10) as f64 / 1e24 - 1.2815515655446004).abs() < 4.5e-4);
+    }
+
+    #[test]
+    fn stress_as_floats() {
+        for vol in [0.01, 0.1, 0.4666066, 1.0, 2.5, 5.0] {
+            for (avg, short) in [(false, false), (true, false), (false, true), (true, true)] {
+                let want = float::stress(avg, vol, short);
+                let got = f(stress(avg, fixed(vol), short));
+                assert!((got - want).abs() < 1e-12 * want.max(1.0), "{} {} {}", vol, avg, short);
+            }
+        }
+    }
+
+    #[test]
+    fn premiums_as_floats() {
+        let qd = 1000.0;
+        for cr in [0.9, 1.1, 1.5, 2.0, 3.0] {
+            for vol in [0.2, 0.4666066, 1.0] {
+                for scale in [0.042, 1.0, 4.2] {
+                    for short in [false, true] {
+                        // shorts owe NEAR worth val_near against QD, longs the other way around
+                        let val_near = if short { qd / cr } else { qd * cr };
+                        let want = float::premiums(val_near, qd, vol * vol, scale, short);
+                        let q = quote(fixed(val_near), fixed(qd), fixed(vol * vol), fixed(scale), short);
+                        let got = f(q.premiums);
+                        assert!((got - want).abs() < 1e-9 * want, "{} {} {} {}", cr, vol, scale, short);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn solvency_as_floats() {
+        let vol = fixed(0.4666066);
+        let long = f(solvency(500 * ONE, 1500 * ONE, 1000 * ONE, vol, false).unwrap());
+        let pct = float::stress(false, 0.4666066, false);
+        let mva = (1.0 - pct) * 1500.0;
+        assert!((long - 500.0 / (500.0 - (2.0 * mva - 1000.0))).abs() < 1e-12);
+        let short = f(solvency(500 * ONE, 800 * ONE, 1000 * ONE, vol, true).unwrap());
+        assert!((short - 500.0 / 1500.0).abs() < 1e-12);
+        assert_eq!(scale(0, ONE / 2, MIN_SCALE, MAX_SCALE), 2 * ONE);
+        assert_eq!(scale(15 * ONE / 10, 0, MIN_SCALE, MAX_SCALE), MAX_SCALE);
+    }
+
+    #[test]
+    fn squared_returns_as_floats() {
+        for (from, to) in [(5.0_f64, 5.05), (5.0, 4.5), (0.37, 2.9), (5.0, 5.0)] {
+            let dt = ONE_HOUR * 8;
+            let want = (to / from).ln().powi(2) * ONE_YEAR as f64 / dt as f64;
+            let got = f(squared_return(fixed(from), 0, fixed(to), dt));
+            assert!((got - want).abs() < 1e-12 * want.max(1.0), "{} {}", from, to);
+        }
+        // 1% a day, every day
+        let daily = squared_return(ONE, 0, ONE + ONE / 100, ONE_YEAR / 365);
+        let want = 0.01_f64.ln_1p() * 365.0_f64.sqrt();
+        assert!((realized_vol(daily * 9, 9) as f64 / VOL_SCALE as f64 - want).abs() < 1e-7);
+    }
+}
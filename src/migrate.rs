@@ -0,0 +1,393 @@
+use crate::*;
+
+use near_sdk::env;
+use near_sdk::collections::TreeMap;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::serde::Serialize;
+
+/*  Layouts of the Contract's root state. The version is stored under
+ *  a key of its own, which the initial release predates: state without
+ *  one is in that layout. When changing the layout: snapshot the layout
+ *  that is being replaced as `ContractVn` below, add a variant here, and
+ *  teach `migrate` how to convert from it. Only layouts that were ever
+ *  deployed need a snapshot
+ */
+#[derive(BorshDeserialize, BorshSerialize, Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum StateVersion {
+    V1, // initial release, before oracle, roles and config
+    V2, // governed, keeper-cranked, with collateral baskets and fixed-point risk
+}
+pub const STATE_VERSION: StateVersion = StateVersion::V2;
+const VERSION_KEY: &[u8] = b"VERSION";
+
+// version of the state as stored, V1 if it predates versioning
+pub(crate) fn stored_version() -> StateVersion {
+    env::storage_read(VERSION_KEY).map_or(StateVersion::V1, |v| {
+        StateVersion::try_from_slice(&v).expect(ERR_UNKNOWN_STATE)
+    })
+}
+
+pub(crate) fn write_version() {
+    env::storage_write(VERSION_KEY, &STATE_VERSION.try_to_vec().unwrap());
+}
+
+// f64 fraction to 1e24-scaled, to 1e-12 (risk figures get recomputed by the next `update` anyway)
+fn fixed_of(x: f64) -> u128 {
+    (x.max(0.0) * 1e12).round() as u128 * 1_000_000_000_000
+}
+
+// Stats in V1, whose risk figures were f64 (amounts among them in yocto units)
+#[derive(BorshDeserialize, BorshSerialize, Clone, Default)]
+pub struct StatsV1 {
+    pub val_near: Balance,
+    pub stress_val: f64,
+    pub avg_val: f64,
+    pub stress_loss: f64,
+    pub avg_loss: f64,
+    pub premiums: f64,
+    pub rate: f64,
+}
+
+impl From<StatsV1> for Stats {
+    fn from(old: StatsV1) -> Self {
+        Self {
+            val_near: QdAmount(old.val_near), stress_val: QdAmount(old.stress_val.max(0.0) as Balance),
+            avg_val: QdAmount(old.avg_val.max(0.0) as Balance), stress_loss: QdAmount(old.stress_loss.max(0.0) as Balance),
+            avg_loss: QdAmount(old.avg_loss.max(0.0) as Balance), premiums: QdAmount(old.premiums.max(0.0) as Balance),
+            rate: Ratio(fixed_of(old.rate)), var: Ratio::ZERO // until the next `update`
+        }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Clone, Default)]
+pub struct PledgeStatsV1 {
+    pub long: StatsV1,
+    pub short: StatsV1,
+    pub val_near_sp: Balance,
+    pub val_total_sp: Balance,
+}
+
+impl From<PledgeStatsV1> for PledgeStats {
+    fn from(old: PledgeStatsV1) -> Self {
+        Self {
+            long: old.long.into(), short: old.short.into(),
+            val_near_sp: QdAmount(old.val_near_sp), val_total_sp: QdAmount(old.val_total_sp)
+        }
+    }
+}
+
+// Data for the votes on solvency targets in V1, -1 as the median if nobody voted
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct DataV1 {
+    pub solvency: f64,
+    pub median: f64,
+    pub scale: f64,
+    pub k: u64,
+    pub sum_w_k: Balance,
+    pub total: Balance,
+    pub y: Vec<i64>,
+    pub w: Vec<Balance>
+}
+
+impl From<DataV1> for Data {
+    fn from(old: DataV1) -> Self {
+        Self {
+            solvency: fixed_of(old.solvency), median: fixed_of(old.median), scale: fixed_of(old.scale),
+            k: old.k, sum_w_k: old.sum_w_k, total: old.total, y: old.y, w: old.w
+        }
+    }
+}
+
+// Pledges in V1, with one deleverage target and no basket
+#[derive(BorshDeserialize, BorshSerialize, Clone)]
+pub struct PledgeV1 {
+    pub long: LongPod,
+    pub short: ShortPod,
+    pub stats: PledgeStatsV1,
+    pub near: NearAmount,
+    pub quid: QdAmount,
+    pub id: AccountId,
+    pub target: Ratio
+}
+
+impl From<PledgeV1> for Pledge {
+    fn from(old: PledgeV1) -> Self {
+        Self {
+            long: old.long, short: old.short, stats: old.stats.into(),
+            near: old.near, quid: old.quid, id: old.id,
+            target: old.target, s_target: old.target, basket: vec![], basket_near: NearAmount::ZERO
+        }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct CrankV1 {
+    pub done: bool,
+    pub index: usize, // visited from the first index up
+    pub last: u64,
+}
+
+impl From<CrankV1> for Crank {
+    fn from(old: CrankV1) -> Self {
+        // an update that's under way can't be resumed in the other
+        // direction, the new code has to be deployed between updates
+        assert!(old.done, "{}", ERR_MIGRATE_MID_CRANK);
+        Self { done: true, left: 0, last: old.last, epoch: 0 }
+    }
+}
+
+// tree keys in V1 had the CR at the price of the day, and the Pledge itself
+#[derive(BorshDeserialize, BorshSerialize, Clone)]
+pub enum SortKeysV1 {
+    CRKey { pledge: PledgeV1, key: (u128, AccountId) },
+    CompositeKey { pledge: PledgeV1, key: (i128, u128, AccountId) },
+}
+
+impl SortKeysV1 {
+    fn key(&self) -> (i128, u128, &AccountId) {
+        match self {
+            SortKeysV1::CRKey { key, .. } => (0, key.0, &key.1),
+            SortKeysV1::CompositeKey { key, .. } => (key.0, key.1, &key.2),
+        }
+    }
+}
+
+impl PartialEq for SortKeysV1 {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+impl Eq for SortKeysV1 {}
+impl PartialOrd for SortKeysV1 {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for SortKeysV1 {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct PledgesTreeMapV1 {
+    pub value: TreeMap<SortKeysV1, ()>,
+    pub type_of_sort: Sort,
+    pub short: bool
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ContractV1 {
+    pub token: FungibleToken,
+    pub price: u128,
+    pub vol: u128,
+    pub metadata: LazyOption<FungibleTokenMetadata>,
+    pub data_s: DataV1,
+    pub data_l: DataV1,
+    pub crank: CrankV1,
+    pub pledges: UnorderedMap<AccountId, PledgeV1>,
+    pub short_crs: PledgesTreeMapV1,
+    pub long_crs: PledgesTreeMapV1,
+    pub stats: PledgeStatsV1,
+    pub blood: Pod<QdAmount, NearAmount>,
+    pub gfund: Pool,
+    pub live: Pool,
+    pub dead: DeadPool
+}
+
+impl From<ContractV1> for Contract {
+    fn from(mut old: ContractV1) -> Self {
+        // V1 had no notion of an owner, the account itself
+        // is the owner until it proposes somebody else
+        let mut oracle = Oracle::new(b"o".to_vec());
+        oracle.price = Price(old.price); // stale until reporters push
+        let mut vol = Volatility::new(b"x".to_vec());
+        vol.annual = old.vol;
+        // tree entries were keyed by the CR when they went in, and not found
+        // again after the price moved, and every Pledge gets rewritten in the
+        // new layout anyway: so the trees are rebuilt from the Pledges, once
+        let pledges = old.pledges.to_vec();
+        old.pledges.clear();
+        old.short_crs.value.clear();
+        old.long_crs.value.clear();
+        let mut this = Self {
+            token: old.token,
+            roles: Roles::new(env::current_account_id()),
+            config: Config::new(),
+            oracle,
+            vol,
+            metadata: old.metadata,
+            votes: LookupMap::new(b"v".to_vec()),
+            data_s: old.data_s.into(),
+            data_l: old.data_l.into(),
+            crank: old.crank.into(),
+            pledges: UnorderedMap::new(b"p".to_vec()),
+            charged: LookupMap::new(b"e".to_vec()),
+            short_crs: PledgesTreeMap::new(b"s".to_vec(), Sort::Banded, true),
+            long_crs: PledgesTreeMap::new(b"l".to_vec(), Sort::Banded, false),
+            baskets: UnorderedSet::new(b"g".to_vec()), // V1 only took NEAR
+            stats: old.stats.into(),
+            blood: old.blood,
+            gfund: old.gfund,
+            live: old.live,
+            dead: old.dead,
+            keepers: Keepers::new(b"k".to_vec()),
+            auctions: Auctions::new(b"a".to_vec()),
+            margined: LookupSet::new(b"c".to_vec()),
+            // Pledges without a mark absorb from an index of zero, so
+            // whatever is in the DeadPool now gets spread over all of them
+            absorption: Absorption::new(),
+            marks: LookupMap::new(b"b".to_vec()),
+            assets: UnorderedMap::new(b"t".to_vec()),
+            covariance: Covariance::new(b"r".to_vec())
+        };
+        for (id, pledge) in pledges {
+            let pledge = Pledge::from(pledge);
+            if !pledge.short.debit.is_zero() && !pledge.short.credit.is_zero() {
+                this.short_crs.insert(&pledge, &());
+            }
+            if !pledge.long.debit.is_zero() && !pledge.long.credit.is_zero() {
+                this.long_crs.insert(&pledge, &());
+            }
+            this.pledges.insert(&id, &pledge);
+        }
+        // holds the protocol's QD, which V1 never did
+        if !this.token.accounts.contains_key(&env::current_account_id()) {
+            this.token.internal_register_account(&env::current_account_id());
+        }
+        this
+    }
+}
+
+#[near_bindgen]
+impl Contract
+{
+    // Called right after deploying new code on top of existing state
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let state = env::storage_read(b"STATE").expect(ERR_NO_STATE);
+        let this = match stored_version() {
+            StateVersion::V1 => ContractV1::try_from_slice(&state).expect(ERR_UNKNOWN_STATE).into(),
+            StateVersion::V2 => Contract::try_from_slice(&state).expect(ERR_UNKNOWN_STATE), // already up to date
+        };
+        write_version();
+        this
+    }
+
+    pub fn get_state_version(&self) -> StateVersion {
+        stored_version()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, MockedBlockchain};
+
+    fn setup() {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0))
+            .build());
+    }
+
+    fn data_v1() -> DataV1 {
+        DataV1 { solvency: 1.0, median: -1.0, scale: 1.0, k: 0, sum_w_k: 0, total: 0, y: vec![], w: vec![] }
+    }
+
+    fn tree_v1(prefix: &[u8], short: bool) -> PledgesTreeMapV1 {
+        PledgesTreeMapV1 { value: TreeMap::new([prefix, b"v"].concat()), type_of_sort: Sort::Composite, short }
+    }
+
+    fn v1() -> ContractV1 {
+        let mut old = ContractV1 {
+            token: FungibleToken::new(b"q".to_vec()),
+            price: 5 * ONE,
+            vol: DEFAULT_VOL,
+            metadata: LazyOption::new(b"m".to_vec(), None),
+            data_s: data_v1(),
+            data_l: data_v1(),
+            crank: CrankV1 { done: true, index: 0, last: 42 },
+            pledges: UnorderedMap::new(b"p".to_vec()),
+            short_crs: tree_v1(b"s", true),
+            long_crs: tree_v1(b"l", false),
+            stats: Default::default(),
+            blood: Pod::new(QdAmount(ONE), NearAmount(2 * ONE)),
+            gfund: Pool::new(),
+            live: Pool::new(),
+            dead: DeadPool::new(),
+        };
+        let id: AccountId = accounts(1).into();
+        let pledge = PledgeV1 {
+            long: Pod::default(), short: Pod::default(),
+            stats: Default::default(),
+            near: NearAmount(2 * ONE), quid: QdAmount(ONE),
+            id: id.clone(), target: MIN_CR
+        };
+        old.pledges.insert(&id, &pledge);
+        old
+    }
+
+    #[test]
+    fn migrates_v1() {
+        setup();
+        env::state_write(&v1());
+        let contract = Contract::migrate();
+        assert_eq!(contract.get_state_version(), STATE_VERSION);
+        assert_eq!(contract.get_roles().owner, accounts(0).to_string());
+        assert_eq!(contract.get_price(), Price(5 * ONE));
+        assert_eq!(contract.get_vol(), DEFAULT_VOL);
+        assert_eq!((contract.crank.last, contract.crank.epoch), (42, 0));
+        assert_eq!(contract.get_pool_stats().blood_debit.0, 2 * ONE);
+        let pledge = contract.get_pledge(accounts(1)).unwrap();
+        assert_eq!(pledge.near_sp.0, 2 * ONE);
+        assert_eq!(pledge.quid_sp.0, ONE);
+        assert_eq!((pledge.target.0, pledge.s_target.0), (MIN_CR.0, MIN_CR.0));
+        assert!(pledge.basket.is_empty());
+        // the protocol's own QD is credited to the account itself
+        assert!(contract.token.accounts.contains_key(&env::current_account_id()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot migrate while an update is in progress")]
+    fn no_migrating_mid_crank() {
+        setup();
+        let mut old = v1();
+        old.crank.done = false;
+        env::state_write(&old);
+        Contract::migrate();
+    }
+
+    #[test]
+    fn rebuilds_cr_trees() {
+        setup();
+        let mut old = v1();
+        let mut pledge = old.pledges.get(&accounts(1).into()).unwrap();
+        pledge.long = Pod::new(NearAmount(10 * ONE), QdAmount(20 * ONE));
+        old.pledges.insert(&pledge.id, &pledge);
+        let stale = SortKeysV1::CompositeKey { pledge: pledge.clone(), key: (-25, 2 * ONE, pledge.id.clone()) };
+        old.long_crs.value.insert(&stale, &());
+        env::state_write(&old);
+        let contract = Contract::migrate();
+        assert_eq!((contract.long_crs.len(), contract.short_crs.len()), (1, 0));
+        let pledge = Pledge::from(pledge);
+        assert!(contract.long_crs.contains_key(&pledge));
+        let risky = contract.long_crs.between(None, Some(MIN_CR), Price(ONE)); // CR of 50%
+        assert_eq!(risky.map(|(p, _)| p.id).collect::<Vec<_>>(), vec![pledge.id]);
+        assert_eq!(contract.long_crs.between(None, Some(MIN_CR), Price(5 * ONE)).count(), 0);
+    }
+
+    #[test]
+    fn migrate_is_idempotent() {
+        setup();
+        let mut contract = Contract::new(accounts(0));
+        contract.config.crank_batch = 7;
+        env::state_write(&contract);
+        let contract = Contract::migrate();
+        assert_eq!(contract.get_state_version(), STATE_VERSION);
+        assert_eq!(contract.get_config().crank_batch, 7);
+    }
+}
@@ -0,0 +1,259 @@
+use crate::*;
+
+use near_sdk::{env, Balance, Timestamp};
+use near_sdk::collections::{UnorderedMap, Vector};
+use near_sdk::serde::Serialize;
+use near_sdk::json_types::{WrappedTimestamp, U128};
+
+#[derive(BorshDeserialize, BorshSerialize, Debug, Clone, Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Report { // a price (QD per NEAR, scaled by ONE) and when it was seen
+    pub price: U128,
+    pub timestamp: WrappedTimestamp,
+}
+
+// Bounded on-chain history, overwritten oldest first when full
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct History {
+    entries: Vector<Report>, // ring buffer
+    head: u64, // index of the oldest entry once it's full
+    cap: u64, // max number of entries kept
+} impl History {
+    pub fn new(prefix: Vec<u8>, cap: u64) -> Self {
+        Self { entries: Vector::new(prefix), head: 0, cap }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.entries.len()
+    }
+
+    pub fn push(&mut self, report: Report) {
+        if self.entries.len() < self.cap {
+            self.entries.push(&report);
+        } else {
+            self.entries.replace(self.head, &report);
+            self.head = (self.head + 1) % self.cap;
+        }
+    }
+
+    pub fn latest(&self) -> Option<Report> {
+        let len = self.entries.len();
+        if len == 0 {
+            return None;
+        }
+        self.entries.get((self.head + len - 1) % len)
+    }
+
+    // entries from oldest to newest
+    pub fn get(&self, from_index: u64, limit: u64) -> Vec<Report> {
+        let len = self.entries.len();
+        (from_index..std::cmp::min(from_index.saturating_add(limit), len))
+            .map(|index| self.entries.get((self.head + index) % len).unwrap())
+            .collect()
+    }
+}
+
+/*  Push-based price feed: whitelisted reporters (e.g. a Flux
+ *  FPO relayer, or our own bots) push their latest price, and
+ *  the protocol's price is the median of all the reports that
+ *  are still fresh. Every aggregated price gets appended to a
+ *  bounded history
+ */
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Oracle {
+    reports: UnorderedMap<AccountId, Report>, // whitelisted reporters and their latest report
+    history: History, // aggregated prices
+    pub price: Price, // last aggregated price
+    pub last: Timestamp, // when `price` was last aggregated
+} impl Oracle {
+    pub fn new(prefix: Vec<u8>) -> Self {
+        Self {
+            reports: UnorderedMap::new([&prefix[..], b"r"].concat()),
+            history: History::new([&prefix[..], b"h"].concat(), PRICE_HISTORY),
+            price: Price::ZERO,
+            last: 0,
+        }
+    }
+
+    pub fn add_reporter(&mut self, id: &AccountId) {
+        if self.reports.get(id).is_none() {
+            self.reports.insert(id, &Report { price: U128(0), timestamp: WrappedTimestamp::from(0) });
+        }
+    }
+
+    // the price is re-aggregated without the reporter's report
+    pub fn remove_reporter(&mut self, id: &AccountId) {
+        if self.reports.remove(id).is_some() && self.last > 0 {
+            self.aggregate(env::block_timestamp());
+        }
+    }
+
+    pub fn is_reporter(&self, id: &AccountId) -> bool {
+        self.reports.get(id).is_some()
+    }
+
+    pub fn reporters(&self) -> Vec<AccountId> {
+        self.reports.keys().collect()
+    }
+
+    pub fn is_stale(&self) -> bool {
+        self.last == 0 || env::block_timestamp()
+            .saturating_sub(self.last) > PRICE_MAX_AGE
+    }
+
+    // record the reporter's price, and re-aggregate using all of the fresh reports
+    pub fn report(&mut self, id: &AccountId, price: Balance) {
+        assert!(self.is_reporter(id), "{}", ERR_NOT_REPORTER);
+        assert!(price > 0, "{}", ERR_PRICE_ZERO);
+        let now = env::block_timestamp();
+        self.reports.insert(id, &Report {
+            price: U128(price), timestamp: WrappedTimestamp::from(now)
+        });
+        self.aggregate(now);
+    }
+
+    /*  Median of the fresh reports, as fresh as the latest of them.
+     *  Without any, the price is left as it was but marked stale
+     */
+    fn aggregate(&mut self, now: Timestamp) {
+        let fresh: Vec<Report> = self.reports.values()
+            .filter(|r| r.price.0 > 0 && now.saturating_sub(r.timestamp.0) <= PRICE_MAX_AGE)
+            .collect();
+        if fresh.is_empty() {
+            self.last = 0;
+            return;
+        }
+        let mut prices: Vec<Balance> = fresh.iter().map(|r| r.price.0).collect();
+        prices.sort_unstable();
+        // average the two middle reports (which are one and the same if odd)
+        let lo = prices[(prices.len() - 1) / 2];
+        let hi = prices[prices.len() / 2];
+        self.price = Price(lo / 2 + hi / 2 + (lo % 2 + hi % 2) / 2);
+        self.last = fresh.iter().map(|r| r.timestamp.0).max().unwrap();
+        self.history.push(Report { price: self.price.into(), timestamp: WrappedTimestamp::from(now) });
+    }
+
+    // aggregated prices, from oldest to newest
+    pub fn history(&self, from_index: u64, limit: u64) -> Vec<Report> {
+        self.history.get(from_index, limit)
+    }
+}
+
+#[near_bindgen]
+impl Contract
+{
+    // TODO Flux, https://github.com/fluxprotocol/fpo-near/blob/main/consumer/src/lib.rs
+    pub fn report_price(&mut self, price: U128) {
+        let account = env::predecessor_account_id();
+        self.oracle.report(&account, price.into());
+        self.vol.observe(self.oracle.price.0, env::block_timestamp());
+        Event::Price { reporter: account, reported: price, price: self.oracle.price.into() }.emit();
+    }
+
+    pub fn get_reporters(&self) -> Vec<AccountId> {
+        self.oracle.reporters()
+    }
+
+    pub fn get_price_history(&self, from_index: u64, limit: u64) -> Vec<Report> {
+        self.oracle.history(from_index, limit)
+    }
+
+    pub(crate) fn assert_fresh_price(&self) {
+        assert!(!self.oracle.is_stale(), "{}", ERR_STALE_PRICE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, MockedBlockchain};
+
+    const MINUTE: u64 = 60_000_000_000;
+
+    // stands in for an off-chain price feed pushing into the contract
+    struct MockReporter { id: AccountId }
+    impl MockReporter {
+        fn push(&self, oracle: &mut Oracle, price: Balance, at: u64) {
+            testing_env!(VMContextBuilder::new()
+                .predecessor_account_id(ValidAccountId::try_from(self.id.clone()).unwrap())
+                .block_timestamp(at)
+                .build());
+            oracle.report(&self.id, price);
+        }
+    }
+
+    fn setup(n: usize) -> (Oracle, Vec<MockReporter>) {
+        testing_env!(VMContextBuilder::new().build());
+        let mut oracle = Oracle::new(b"o".to_vec());
+        let reporters: Vec<MockReporter> = (0..n)
+            .map(|i| MockReporter { id: accounts(i).into() })
+            .collect();
+        for r in reporters.iter() {
+            oracle.add_reporter(&r.id);
+        }
+        (oracle, reporters)
+    }
+
+    #[test]
+    fn median_of_fresh_reports() {
+        let (mut oracle, r) = setup(3);
+        assert!(oracle.is_stale());
+        r[0].push(&mut oracle, 5 * ONE, MINUTE);
+        assert_eq!(oracle.price, Price(5 * ONE));
+        r[1].push(&mut oracle, 7 * ONE, 2 * MINUTE);
+        assert_eq!(oracle.price, Price(6 * ONE));
+        r[2].push(&mut oracle, 100 * ONE, 3 * MINUTE);
+        assert_eq!(oracle.price, Price(7 * ONE));
+        assert!(!oracle.is_stale());
+        // the first two reports expire, only the latest one is left
+        r[2].push(&mut oracle, 8 * ONE, 3 * MINUTE + PRICE_MAX_AGE);
+        assert_eq!(oracle.price, Price(8 * ONE));
+    }
+
+    #[test]
+    fn removing_a_reporter_reaggregates() {
+        let (mut oracle, r) = setup(3);
+        r[0].push(&mut oracle, 5 * ONE, MINUTE);
+        r[1].push(&mut oracle, 7 * ONE, 2 * MINUTE);
+        r[2].push(&mut oracle, 100 * ONE, 3 * MINUTE);
+        oracle.remove_reporter(&r[2].id);
+        assert_eq!(oracle.price, Price(6 * ONE));
+        assert_eq!(oracle.last, 2 * MINUTE); // as fresh as what's left
+        assert_eq!(oracle.history(0, 10).len(), 4);
+        oracle.remove_reporter(&r[1].id);
+        oracle.remove_reporter(&r[0].id);
+        assert_eq!(oracle.price, Price(5 * ONE));
+        assert!(oracle.is_stale()); // nobody's left to report
+    }
+
+    #[test]
+    fn goes_stale() {
+        let (mut oracle, r) = setup(1);
+        r[0].push(&mut oracle, ONE, MINUTE);
+        testing_env!(VMContextBuilder::new().block_timestamp(MINUTE + PRICE_MAX_AGE).build());
+        assert!(!oracle.is_stale());
+        testing_env!(VMContextBuilder::new().block_timestamp(MINUTE + PRICE_MAX_AGE + 1).build());
+        assert!(oracle.is_stale());
+    }
+
+    #[test]
+    #[should_panic(expected = "Only whitelisted reporters can report prices")]
+    fn rejects_unknown_reporter() {
+        let (mut oracle, _) = setup(1);
+        let stranger = MockReporter { id: accounts(5).into() };
+        stranger.push(&mut oracle, ONE, MINUTE);
+    }
+
+    #[test]
+    fn bounded_history() {
+        let (mut oracle, r) = setup(1);
+        for i in 1..=(PRICE_HISTORY + 2) {
+            r[0].push(&mut oracle, i as Balance * ONE, i * MINUTE);
+        }
+        let history = oracle.history(0, PRICE_HISTORY + 10);
+        assert_eq!(history.len() as u64, PRICE_HISTORY);
+        assert_eq!(history[0].price.0, 3 * ONE);
+        assert_eq!(history.last().unwrap().price.0, (PRICE_HISTORY as Balance + 2) * ONE);
+    }
+}
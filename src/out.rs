@@ -1,49 +1,53 @@
 use crate::*;
 
 
-use near_sdk::{env, log, Balance, Promise};
-use near_sdk::serde::{Deserialize, Serialize};
-use near_sdk::json_types::{WrappedBalance, WrappedTimestamp, U128};
+use near_sdk::{env, Balance, Promise};
+use near_sdk::json_types::U128;
 
 #[near_bindgen]
 impl Contract 
 {
     #[payable]
     pub fn borrow(&mut self, amount: U128, short: bool) -> PromiseOrValue<U128> { 
-        let mut cr: u128; 
+        let mut cr: Ratio; 
         let mut transfer = false;
         
-        let mut amt: Balance = amount.into();
+        let amt: Balance = amount.into();
         let deposit = env::attached_deposit();
-        assert!(self.crank.done, "Update in progress");
-        assert!(deposit > 0 && amt > ONE, ERR_AMT_TOO_LOW);
+        self.assert_not_paused();
+        self.assert_fresh_price();
+        assert!(deposit > 0 && amt > ONE, "{}", ERR_AMT_TOO_LOW);
         
         let account = env::predecessor_account_id();
         let mut pledge = self.fetch_pledge(&account, true);
+        let cr_before = self.crs(&pledge);
         
         if !short {
-            cr = computeCR(self.get_price(), pledge.long.credit, pledge.long.debit, false);
-            assert!(cr == 0 || cr >= MIN_CR, "Cannot borrow while your current CR is below minimum");
+            self.assert_fresh_basket(&pledge);
+            cr = self.long_cr(&pledge);
+            assert!(cr.is_zero() || cr >= self.config.min_cr, "Cannot borrow while your current CR is below minimum");
             if deposit >= ONE {
                 pledge.long.credit = pledge.long.credit
-                    .checked_add(deposit).expect(ERR_ADD);
+                    .checked_add(NearAmount(deposit)).expect(ERR_ADD);
                
                 self.live.long.credit = self.live.long.credit
-                    .checked_add(deposit).expect(ERR_ADD);
+                    .checked_add(NearAmount(deposit)).expect(ERR_ADD);
             }
             let new_debt = pledge.long.debit 
-                .checked_add(amt).expect(ERR_ADD);
+                .checked_add(QdAmount(amt)).expect(ERR_ADD);
             
-            //assert!(new_debt >= MIN_DEBT, "Value of debt must be worth above $90 of QD");
+            assert!(new_debt >= self.config.min_debt, "{}", ERR_MIN_DEBT);
             
-            cr = computeCR(self.get_price(), pledge.long.credit, new_debt, false);
-            if cr >= MIN_CR { // requested amount to borrow is within measure of collateral
+            cr = computeBasketCR(self.get_price(), pledge.long.credit, self.basket_val(&pledge), new_debt);
+            if cr >= self.config.min_cr { // requested amount to borrow is within measure of collateral
                 self.mint(&account, amt);
                 // TODO pull from GFund (or in mint)
                 pledge.long.debit = new_debt;
+                self.live.long.debit = self.live.long.debit
+                    .checked_add(QdAmount(amt)).expect(ERR_ADD);
             } 
             else { // instead of throwing a "below MIN_CR" error right away, try to satisfy loan
-                assert!(true, "can't go below MIN CR");
+                assert!(pledge.basket.is_empty(), "{}", ERR_BELOW_MIN_CR); // the valve only knows NEAR
                 (self.live.long, pledge.long) = self.valve(account.clone(),
                     false, new_debt, 
                     self.live.long.clone(),
@@ -53,23 +57,27 @@ impl Contract
         } else { // borrowing short
             if deposit > 1 { /* if they dont have QD and they send in NEAR, 
                 we can just immediately invert it and use that as coll */
-                self.invert(deposit); // QD value of the NEAR debt being cleared 
-                pledge.short.credit = pledge.short.credit.checked_add( // QD value of the NEAR deposit
-                    ratio(self.get_price(), deposit, ONE)).expect(ERR_ADD);
+                self.invert(NearAmount(deposit)); // QD value of the NEAR debt being cleared 
+                let in_qd = NearAmount(deposit) * self.get_price(); // QD value of the NEAR deposit
+                pledge.short.credit = pledge.short.credit.checked_add(in_qd).expect(ERR_ADD);
+                self.live.short.credit = self.live.short.credit.checked_add(in_qd).expect(ERR_ADD);
             }
-            cr = computeCR(self.get_price(), pledge.short.credit, pledge.short.debit, true);
-            assert!(cr == 0 || cr >= MIN_CR, "Cannot borrow while your current CR is below minimum"); 
+            cr = computeCR(self.get_price(), pledge.short.credit, pledge.short.debit);
+            assert!(cr.is_zero() || cr >= self.config.min_cr, "Cannot borrow while your current CR is below minimum"); 
             
             let new_debt = pledge.short.debit
-                .checked_add(amt).expect(ERR_ADD);
+                .checked_add(NearAmount(amt)).expect(ERR_ADD);
 
-            let new_debt_in_qd = ratio(self.get_price(), new_debt, ONE);
+            let new_debt_in_qd = new_debt * self.get_price();
             
-            //assert!(new_debt_in_qd >= MIN_DEBT, "Value of debt must be worth above $90 of QD");
+            assert!(new_debt_in_qd >= self.config.min_debt, "{}", ERR_MIN_DEBT);
             
-            cr = ratio(ONE, pledge.short.credit, new_debt_in_qd);
-            if cr >= MIN_CR {
+            cr = pledge.short.credit / new_debt_in_qd;
+            if cr >= self.config.min_cr {
                 transfer = true; // when borrowing within their means, we disperse NEAR that the borrower can sell
+                pledge.short.debit = new_debt;
+                self.live.short.debit = self.live.short.debit
+                    .checked_add(NearAmount(amt)).expect(ERR_ADD);
             } else {
                 (self.live.short, pledge.short) = self.valve(account.clone(),
                     true, new_debt_in_qd, 
@@ -79,132 +87,113 @@ impl Contract
             }
         }
         self.save_pledge(&account, &mut pledge, !short, short);
+        Event::Borrow { 
+            account: account.clone(), short, amount, near: deposit.into(), cr_before,
+            cr_after: self.crs(&pledge), price: self.get_price().into()
+        }.emit();
+        self.debug_invariants();
         if transfer { // transfer bool is a workaround for "borrow after move" compile error
             return PromiseOrValue::Promise(Promise::new(account).transfer(amt));
         } 
-        return PromiseOrValue::Value(U128(0));
+        PromiseOrValue::Value(U128(0))
     }
 
     // TODO make sure that insurers get also have internal accounts not just borrowers 
     pub(crate) fn mint(&mut self, id: &AccountId, amt: u128) { // mint $QD stablecoins
-        if self.token.accounts.get(&id).is_some() {
-            self.token.internal_deposit(&id, amt);
+        if self.token.accounts.get(id).is_some() {
+            self.token.internal_deposit(id, amt);
         } else {
-            self.token.internal_register_account(&id);
-            self.token.internal_deposit(&id, amt);
+            self.token.internal_register_account(id);
+            self.token.internal_deposit(id, amt);
         }
     }
 
     // https://twitter.com/1x_Brasil/status/1522663741023731714
-    pub(crate) fn valve(&mut self, id: AccountId, short: bool, new_debt_in_qd: u128, mut live: Pod, mut pledge: Pod) -> (Pod, Pod) {
+    pub(crate) fn valve<C: Amount, D: Amount>(&mut self, id: AccountId, short: bool, new_debt_in_qd: QdAmount,
+                                              mut live: Pod<C, D>, mut pledge: Pod<C, D>) -> (Pod<C, D>, Pod<C, D>) {
         let mut check_zero = false;
-        let now_liq_qd: Balance = self.token.ft_balance_of(
+        let price = self.get_price();
+        let now_liq_qd = QdAmount(self.token.ft_balance_of(
             ValidAccountId::try_from(id.clone()).unwrap()
-        ).into();
-        let now_coll_in_qd: Balance;
-        let now_debt_in_qd: Balance;
-        if short {
-            now_debt_in_qd = ratio(self.get_price(), pledge.debit, ONE);
-            now_coll_in_qd = pledge.credit; 
-        } else {
-            now_coll_in_qd = ratio(self.get_price(), pledge.credit, ONE);
-            now_debt_in_qd = pledge.debit;   
-        }
-        let mut net_val: Balance = now_liq_qd
+        ).into());
+        let now_coll_in_qd = pledge.credit.in_qd(price);
+        let now_debt_in_qd = pledge.debit.in_qd(price);
+        let mut net_val: QdAmount = now_liq_qd
             .checked_add(now_coll_in_qd).expect(ERR_ADD)
             .checked_sub(now_debt_in_qd).expect(ERR_SUB);
         
-        let mut fee_amt: Balance = net_val.checked_sub( // (net_val - (1 - 1 / 1.1 = 0.090909...) * col_init) / 11
-            ratio(DOT_OH_NINE, now_coll_in_qd, ONE)
-        ).expect(ERR_SUB).checked_div(11).expect(ERR_DIV); // = 1 + (1 - 1 / 1.1) / fee_% 
+        let fee = self.config.fee;
+        let margin = Ratio::ONE - Ratio::ONE / self.config.min_cr; // 1 - 1 / 1.1 = 0.090909...
+        let mut fee_amt: QdAmount = net_val.checked_sub( // (net_val - margin * col_init) / 11
+            now_coll_in_qd * margin
+        ).expect(ERR_SUB) / (Ratio::ONE + margin / fee); // 11 = 1 + (1 - 1 / 1.1) / fee_% 
         
-        let mut qd_to_buy: Balance = fee_amt // (fee_amt / fee_%) i.e div 0.009090909...
-            .checked_mul(110).expect(ERR_MUL);
-        let mut end_coll_in_qd: Balance = qd_to_buy
+        let mut qd_to_buy: QdAmount = fee_amt / fee; // (fee_amt / fee_%) i.e div 0.009090909...
+        let mut end_coll_in_qd: QdAmount = qd_to_buy
             .checked_add(now_coll_in_qd).expect(ERR_ADD);
 
-        let max_debt = ratio(ONE, end_coll_in_qd, MIN_CR);    
-        let final_debt: Balance;
+        let max_debt = end_coll_in_qd / self.config.min_cr;    
+        let final_debt: QdAmount;
         if new_debt_in_qd >= max_debt {
             final_debt = max_debt;
             check_zero = true;
         } else { // max_debt is larger than the requested debt  
             final_debt = new_debt_in_qd;
-            end_coll_in_qd = ratio(MIN_CR, final_debt, ONE);
+            end_coll_in_qd = final_debt * self.config.min_cr;
             qd_to_buy = end_coll_in_qd // no need to mint all this QD, gets partially minted in `redeem`, excluding the
                 .checked_sub(now_coll_in_qd).expect(ERR_SUB); // amount cleared against DeadPool's QDebt
-            fee_amt = ratio(FEE, qd_to_buy, ONE);
+            fee_amt = qd_to_buy * fee;
         }
         net_val -= fee_amt;
-        self.mint(&env::current_account_id(), fee_amt); // mint fee in QD
-        let eleventh = fee_amt.checked_div(11).expect(ERR_DIV);
+        self.mint(&env::current_account_id(), fee_amt.0); // mint fee in QD
+        let eleventh = self.config.gf_cut(fee_amt);
         
         let rest = fee_amt.checked_sub(eleventh).expect(ERR_SUB);
         self.dead.short.debit = self.dead.short.debit.checked_add(rest).expect(ERR_ADD);
         self.gfund.short.credit = self.gfund.short.credit.checked_add(eleventh).expect(ERR_ADD);
     
-        if short {
-            pledge.credit = end_coll_in_qd;
-            pledge.debit = ratio(ONE, final_debt, self.get_price());
-            
-            live.credit = live.credit
-                .checked_add(qd_to_buy).expect(ERR_ADD);
-            let near_to_sell = ratio(ONE, qd_to_buy, self.get_price());
-            // NEAR spent on buying QD collateral must be paid back by the borrower to unlock the QD
-            live.debit = live.debit
-                .checked_add(near_to_sell).expect(ERR_ADD);
-            
-            // we must first redeem QD that we mint out of thin air to purchase the NEAR, 
+        // final collateral and debt, in the currency of each
+        let old_coll = pledge.credit;
+        pledge.credit = C::of_qd(end_coll_in_qd, price);
+        pledge.debit = D::of_qd(final_debt, price);
+        
+        live.credit = live.credit + (pledge.credit - old_coll);
+        // what was spent on buying collateral must be paid back by the borrower to unlock it
+        live.debit = live.debit + D::of_qd(qd_to_buy, price);
+        
+        /******/ self.redeem(qd_to_buy); /******/
+        if short { // we must first redeem QD that we mint out of thin air to purchase the NEAR, 
             // before burning NEAR debt with it to purchase QD (undoing the mint) collat
-            self.redeem(qd_to_buy);
-            self.invert(near_to_sell);     
-        } else {    
-            // get final collateral value in NEAR
-            let end_coll = ratio(ONE, end_coll_in_qd, self.get_price());
-            pledge.credit = end_coll;
-            pledge.debit = final_debt;
-
-            let delta_coll = end_coll
-                .checked_sub(pledge.credit).expect(ERR_SUB);
-            live.credit = live.credit
-                .checked_add(delta_coll).expect(ERR_ADD);
-            // QD spent on buying NEAR collateral must be paid back by the borrower to unlock the NEAR
-            live.debit = live.debit
-                .checked_add(qd_to_buy).expect(ERR_ADD);
-            
-            /******/ self.redeem(qd_to_buy); /******/
+            self.invert(qd_to_buy / price);
         }
         /*
             Liquid NEAR value in QD
                 = (FinalDebt + Net) * (1 - 1.10 / (Net / FinalDebt + 1))
             Net = liquid QD + initial QD collat - initial NEAR debt in QD                  
         */
-        let net_div_debt = ratio(
-            ONE, net_val, final_debt
-        ).checked_add(ONE).expect(ERR_ADD);
+        let net_div_debt = (net_val / final_debt)
+            .checked_add(Ratio::ONE).expect(ERR_ADD);
 
-        let between = ONE.checked_sub( // `between` must >= 0 as a rule
-            ratio(ONE, MIN_CR, net_div_debt)    
+        let between = Ratio::ONE.checked_sub( // `between` must >= 0 as a rule
+            self.config.min_cr / net_div_debt
         ).expect("Illegal borrow attempt"); 
 
-        let end_liq_qd = ratio(between, 
-            final_debt.checked_add(net_val).expect(ERR_ADD),
-        ONE);
+        let end_liq_qd = final_debt.checked_add(net_val).expect(ERR_ADD) * between;
 
-        assert!(!check_zero || end_liq_qd == 0, "Something went wrong in `borrow");
+        assert!(!check_zero || end_liq_qd.is_zero(), "Something went wrong in `borrow");
         
-        let delta_liq_qd: i128 = end_liq_qd.try_into().unwrap();
+        let delta_liq_qd: i128 = end_liq_qd.0.try_into().unwrap();
         let mut liq_qd = delta_liq_qd
-            .checked_sub(now_liq_qd.try_into().unwrap()).expect(ERR_SUB);
+            .checked_sub(now_liq_qd.0.try_into().unwrap()).expect(ERR_SUB);
         if liq_qd > 0 {
             self.mint(&id, liq_qd.try_into().unwrap());
         } 
         else if liq_qd < 0 { liq_qd *= -1;
             self.token.internal_withdraw(&id, liq_qd.try_into().unwrap());   
         }
-        assert!(computeCR(self.get_price(), pledge.credit, pledge.debit, short) >= MIN_CR, 
+        assert!(computeCR(price, pledge.credit, pledge.debit) >= self.config.min_cr, 
         "Cannot do operation that would result in short CR below min"); 
-        return (live, pledge);
+        (live, pledge)
     }
 
     /**
@@ -216,110 +205,98 @@ impl Contract
      */
     #[payable]
     pub fn renege(&mut self, amount: U128, sp: bool, qd: bool) -> PromiseOrValue<U128> {
-        // TODO change vote
-        assert!(self.crank.done, "Update in progress");
+        self.assert_not_paused();
+        self.assert_fresh_price();
         assert_one_yocto();
         
         let amt: Balance = amount.into();
-        assert!(amt > ONE, ERR_AMT_TOO_LOW);
+        assert!(amt > ONE, "{}", ERR_AMT_TOO_LOW);
         
-        let cr: u128; let mut min: u128; 
+        let cr: Ratio; 
         let mut transfer: bool = false;
         
         let account = env::predecessor_account_id();
         let mut pledge = self.fetch_pledge(&account, false);
+        let cr_before = self.crs(&pledge);
         
         let all_qd: Balance = self.token.ft_balance_of(
             ValidAccountId::try_from(env::current_account_id()).unwrap()).into();
 
-        let mut fee = ratio(FEE, amt, ONE);
+        // in QD or NEAR, depending on `qd`
+        let mut fee = ratio(self.config.fee.0, amt, ONE);
+        let total_fee = fee;
         let mut amt_sub_fee = amt.checked_sub(fee).expect(ERR_SUB);
-        let gf_cut = fee.checked_div(11).expect(ERR_DIV);
+        let gf_cut = self.config.gf_cut(fee);
         fee -= gf_cut;
 
         if !sp { // we are withdrawing collateral from a borrowing position
             if qd {
-                pledge.short.credit = pledge.short.credit.checked_sub(amt).expect(ERR_SUB);
-                cr = computeCR(self.get_price(), pledge.short.credit, pledge.short.debit, true);
-                assert!(cr >= MIN_CR, ERR_BELOW_MIN_CR);
+                pledge.short.credit = pledge.short.credit.checked_sub(QdAmount(amt)).expect(ERR_SUB);
+                cr = computeCR(self.get_price(), pledge.short.credit, pledge.short.debit);
+                assert!(cr >= self.config.min_cr, "{}", ERR_BELOW_MIN_CR);
 
-                min = std::cmp::min(all_qd, amt_sub_fee); // maximum dispensable QD
+                let min = std::cmp::min(all_qd, amt_sub_fee); // maximum dispensable QD
                 if amt_sub_fee > min { // there's not enough QD in the contract to send
                     amt_sub_fee -= min; // remainder to be gfundn as...
                     self.gfund.long.debit = self.gfund.long.debit // ...protocol debt  
-                        .checked_add(amt_sub_fee).expect(ERR_ADD);
+                        .checked_add(QdAmount(amt_sub_fee)).expect(ERR_ADD);
                 }
                 self.token.internal_deposit(&account, amt_sub_fee); // send QD to the signer
                 self.token.internal_withdraw(&env::current_account_id(), min);
-                self.live.short.credit = self.live.short.credit.checked_sub(amt).expect(ERR_SUB);
+                self.live.short.credit = self.live.short.credit.checked_sub(QdAmount(amt)).expect(ERR_SUB);
                 
-                self.dead.short.debit = self.dead.short.debit.checked_add(fee).expect(ERR_ADD); // pay fee
-                self.gfund.short.credit = self.gfund.short.credit.checked_add(gf_cut).expect(ERR_ADD);
+                self.dead.short.debit = self.dead.short.debit.checked_add(QdAmount(fee)).expect(ERR_ADD); // pay fee
+                self.gfund.short.credit = self.gfund.short.credit.checked_add(QdAmount(gf_cut)).expect(ERR_ADD);
             }
             else {
                 transfer = true; // we are sending NEAR to the user
-                pledge.long.credit = pledge.long.credit.checked_sub(amt).expect(ERR_SUB);
-                cr = computeCR(self.get_price(), pledge.long.credit, pledge.long.debit, false);
-                assert!(cr >= MIN_CR, ERR_BELOW_MIN_CR);
+                pledge.long.credit = pledge.long.credit.checked_sub(NearAmount(amt)).expect(ERR_SUB);
+                self.assert_fresh_basket(&pledge);
+                cr = self.long_cr(&pledge);
+                assert!(cr >= self.config.min_cr, "{}", ERR_BELOW_MIN_CR);
                 let near = env::account_balance();
                 if amt_sub_fee > near { // there's not enough NEAR in the contract to send
-                    let in_qd = ratio(self.get_price(), amt_sub_fee - near, ONE);
+                    let in_qd = NearAmount(amt_sub_fee - near) * self.get_price();
                     amt_sub_fee = near;
-                    self.token.internal_deposit(&account, in_qd); // mint requested QD
+                    self.token.internal_deposit(&account, in_qd.0); // mint requested QD
                     self.gfund.long.debit = self.gfund.long.debit // freeze as protocol debt  
                         .checked_add(in_qd).expect(ERR_ADD);
                 }
-                self.live.long.credit = self.live.long.credit.checked_sub(amt).expect(ERR_SUB);
-                self.dead.long.debit = self.dead.long.debit.checked_add(fee).expect(ERR_ADD);
-                self.gfund.long.credit = self.gfund.long.credit.checked_add(gf_cut).expect(ERR_ADD);
+                self.live.long.credit = self.live.long.credit.checked_sub(NearAmount(amt)).expect(ERR_SUB);
+                self.dead.long.debit = self.dead.long.debit.checked_add(NearAmount(fee)).expect(ERR_ADD);
+                self.gfund.long.credit = self.gfund.long.credit.checked_add(NearAmount(gf_cut)).expect(ERR_ADD);
             }   
         } else { // we are withdrawing deposits from the SolvencyPool
-            let mut remainder;
             if qd {
+                let amt = QdAmount(amt);
                 pledge.quid = pledge.quid.checked_sub(amt).expect(ERR_SUB);
-                min = std::cmp::min(self.blood.credit, amt); // maximum dispensable QD
-                self.blood.credit -= min;
-                remainder = amt - min;
-                if remainder > 0 {
-                    min = std::cmp::min(self.gfund.short.credit, remainder);
-                    self.gfund.short.credit -= min;
-                    if remainder > min {
-                        remainder -= min;
-                        self.gfund.long.debit = self.gfund.long.debit
-                            .checked_add(remainder).expect(ERR_ADD);      
-                    }
-                }
+                self.blood.credit = self.blood.credit.checked_sub(amt).expect(ERR_SUB);
                 self.token.internal_withdraw(&env::current_account_id(), amt_sub_fee); 
                 self.token.internal_deposit(&account, amt_sub_fee); // send QD to the signer
-                self.dead.short.debit = self.dead.short.debit.checked_add(fee).expect(ERR_ADD); // pay fee
-                self.gfund.short.credit = self.gfund.short.credit.checked_add(gf_cut).expect(ERR_ADD);
+                self.dead.short.debit = self.dead.short.debit.checked_add(QdAmount(fee)).expect(ERR_ADD); // pay fee
+                self.gfund.short.credit = self.gfund.short.credit.checked_add(QdAmount(gf_cut)).expect(ERR_ADD);
             } else {
                 transfer = true;
+                let amt = NearAmount(amt);
                 pledge.near = pledge.near.checked_sub(amt).expect(ERR_SUB);
-                min = std::cmp::min(self.blood.debit, amt); // maximum dispensable NEAR
-                self.blood.debit -= min;
-                remainder = amt - min;
-                if remainder > 0 {
-                    min = std::cmp::min(self.gfund.long.credit, remainder); // maximum dispensable NEAR
-                    self.gfund.long.credit -= min;
-                    if remainder > min {
-                        remainder -= min;
-                        amt_sub_fee -= remainder;
-                        let in_qd = ratio(self.get_price(), remainder, ONE);
-                        self.token.internal_deposit(&account, in_qd); // mint requested QD
-                        self.gfund.long.debit = self.gfund.long.debit // freeze as protocol debt  
-                            .checked_add(in_qd).expect(ERR_ADD);
-                    }
-                }
-                self.dead.long.debit = self.dead.long.debit.checked_add(fee).expect(ERR_ADD); // pay fee
-                self.gfund.long.credit = self.gfund.long.credit.checked_add(gf_cut).expect(ERR_ADD);
+                self.blood.debit = self.blood.debit.checked_sub(amt).expect(ERR_SUB);
+                self.dead.long.debit = self.dead.long.debit.checked_add(NearAmount(fee)).expect(ERR_ADD); // pay fee
+                self.gfund.long.credit = self.gfund.long.credit.checked_add(NearAmount(gf_cut)).expect(ERR_ADD);
             }
         }
+        if sp {
+            self.on_stake_change(&pledge);
+        }
         self.save_pledge(&account, &mut pledge, !sp && !qd, !sp && qd);
+        Event::Renege { 
+            account: account.clone(), sp, qd, amount, fee: total_fee.into(), cr_before,
+            cr_after: self.crs(&pledge), price: self.get_price().into()
+        }.emit();
+        self.debug_invariants();
         if transfer { // workaround for "borrow after move" compile error
             return PromiseOrValue::Promise(Promise::new(account).transfer(amt_sub_fee));
         }
-        return PromiseOrValue::Value(U128(0));
+        PromiseOrValue::Value(U128(0))
     }
 
     // Close out caller's borrowing position by paying
@@ -327,24 +304,169 @@ impl Contract
     #[payable]
     pub fn fold(&mut self, short: bool) { 
         assert_one_yocto();
+        self.assert_not_paused();
+        self.assert_fresh_price();
         let id = env::predecessor_account_id();
         let mut pledge = self.fetch_pledge(&id, false);
+        let cr_before = self.crs(&pledge);
+        let debt: U128 = if short { pledge.short.debit.into() } else { pledge.long.debit.into() };
         if short {
-            let cr = computeCR(self.get_price(), pledge.short.credit, pledge.short.debit, true);
-            if cr > KILL_CR { // mainly a sanity check, an underwater pledge will almost certainly
+            let cr = computeCR(self.get_price(), pledge.short.credit, pledge.short.debit);
+            if cr > self.config.kill_cr { // mainly a sanity check, an underwater pledge will almost certainly
                 // take QD and sell it for NEAR internally in the interest of proper accounting
-                let qd = ratio(self.get_price(), pledge.short.debit, ONE);
+                let qd = pledge.short.debit * self.get_price();
                 self.redeem(qd); // https://youtu.be/IYXRSR0xNVc?t=111 pledges will probably
                 // be clipped before its owner can fold in time to prevent that from occuring...
-                self.turn( pledge.short.debit, false, true, &mut pledge);
+                self.short_turn(pledge.short.debit, false, &mut pledge);
             }
         } else {
-            let cr = computeCR(self.get_price(), pledge.long.credit, pledge.long.debit, false);
-            if cr > KILL_CR {
-                let near = ratio(ONE, pledge.long.debit, self.get_price());
+            self.assert_fresh_basket(&pledge);
+            let cr = self.long_cr(&pledge);
+            // only NEAR gets sold, what's owed against the basket is left to repay
+            let qd = std::cmp::min(pledge.long.debit, pledge.long.credit * self.get_price());
+            if cr > self.config.kill_cr && !qd.is_zero() {
+                let near = qd / self.get_price();
                 self.invert(near);
-                self.turn(pledge.long.debit, false, false, &mut pledge);
+                self.long_turn(qd, false, &mut pledge);
             }
-        }   
+        }
+        Event::Fold { 
+            account: id, short, debt, cr_before,
+            cr_after: self.crs(&pledge), price: self.get_price().into()
+        }.emit();
+        self.debug_invariants();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::*;
+
+    #[test]
+    fn borrow_long() {
+        let mut h = Harness::new(PRICE);
+        h.call(ALICE, 100 * ONE, |c| c.borrow(U128(200 * ONE), false));
+        assert_eq!(h.events("borrow"), 1);
+        let pledge = h.pledge(ALICE).unwrap();
+        assert_eq!(pledge.credit.0, 100 * ONE);
+        assert_eq!(pledge.debit.0, 200 * ONE);
+        assert_eq!(h.qd(ALICE), 200 * ONE);
+        let pools = h.pools();
+        assert_eq!(pools.live_long_credit.0, 100 * ONE);
+        assert_eq!(pools.live_long_debit.0, 200 * ONE);
+        assert_eq!(pools.dead_long_debit.0, 0);
+    }
+
+    #[test]
+    fn borrow_short_against_qd() {
+        let mut h = Harness::new(PRICE);
+        h.call(ALICE, 100 * ONE, |c| c.borrow(U128(200 * ONE), false));
+        h.call(ALICE, 1, |c| c.deposit(U128(150 * ONE), true));
+        let out = h.call(ALICE, 1, |c| c.borrow(U128(20 * ONE), true));
+        h.paid(out, 20 * ONE);
+        let pledge = h.pledge(ALICE).unwrap();
+        assert_eq!(pledge.s_credit.0, 150 * ONE);
+        assert_eq!(pledge.s_debit.0, 20 * ONE);
+        assert_eq!(h.qd(ALICE), 50 * ONE);
+        let pools = h.pools();
+        assert_eq!(pools.live_short_credit.0, 150 * ONE);
+        assert_eq!(pools.live_short_debit.0, 20 * ONE);
+        assert_eq!(h.balance, 80 * ONE + 3);
+    }
+
+    #[test]
+    fn solvency_deposits_and_renege() {
+        let mut h = Harness::new(PRICE);
+        h.call(BOB, 100 * ONE, |c| c.borrow(U128(200 * ONE), false));
+        h.call(BOB, 10 * ONE, |c| c.deposit(U128(100 * ONE), false));
+        let pledge = h.pledge(BOB).unwrap();
+        assert_eq!(pledge.near_sp.0, 10 * ONE);
+        assert_eq!(pledge.quid_sp.0, 100 * ONE);
+        assert_eq!(h.qd(BOB), 100 * ONE);
+        let pools = h.pools();
+        assert_eq!(pools.blood_debit.0, 10 * ONE);
+        assert_eq!(pools.blood_credit.0, 100 * ONE);
+
+        let out = h.call(BOB, 1, |c| c.renege(U128(4 * ONE), true, false));
+        h.paid(out, 4 * ONE - fee(4 * ONE));
+        assert_eq!(h.pledge(BOB).unwrap().near_sp.0, 6 * ONE);
+        let pools = h.pools();
+        assert_eq!(pools.blood_debit.0, 6 * ONE);
+        let cut = fee(4 * ONE) / GFUND_DIV as Balance;
+        assert_eq!(pools.gfund_long_credit.0, cut);
+        assert_eq!(pools.dead_long_debit.0, fee(4 * ONE) - cut);
+
+        h.call(BOB, 1, |c| c.renege(U128(50 * ONE), true, true));
+        assert_eq!(h.pledge(BOB).unwrap().quid_sp.0, 50 * ONE);
+        assert_eq!(h.qd(BOB), 150 * ONE - fee(50 * ONE));
+        assert_eq!(h.pools().blood_credit.0, 50 * ONE);
+    }
+
+    #[test]
+    fn withdraw_collateral_and_repay() {
+        let mut h = Harness::new(PRICE);
+        h.call(ALICE, 100 * ONE, |c| c.borrow(U128(200 * ONE), false));
+        let out = h.call(ALICE, 1, |c| c.renege(U128(40 * ONE), false, false));
+        h.paid(out, 40 * ONE - fee(40 * ONE));
+        let pledge = h.pledge(ALICE).unwrap();
+        assert_eq!(pledge.credit.0, 60 * ONE);
+        assert_eq!(h.pools().live_long_credit.0, 60 * ONE);
+
+        h.call(ALICE, 1, |c| c.swap(U128(150 * ONE), true, false));
+        assert_eq!(h.events("repay"), 1);
+        let pledge = h.pledge(ALICE).unwrap();
+        assert_eq!(pledge.debit.0, 50 * ONE);
+        assert_eq!(pledge.credit.0, 60 * ONE); // repaying doesn't release collateral
+        assert_eq!(h.qd(ALICE), 50 * ONE);
+        assert_eq!(h.pools().live_long_debit.0, 50 * ONE);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot do operation that would result in CR below min")]
+    fn renege_below_min_cr() {
+        let mut h = Harness::new(PRICE);
+        h.call(ALICE, 100 * ONE, |c| c.borrow(U128(400 * ONE), false));
+        h.call(ALICE, 1, |c| c.renege(U128(20 * ONE), false, false));
+    }
+
+    #[test]
+    #[should_panic(expected = "Price is stale, waiting for oracle reporters")]
+    fn no_renege_at_a_stale_price() {
+        let mut h = Harness::new(PRICE);
+        h.call(ALICE, 100 * ONE, |c| c.borrow(U128(200 * ONE), false));
+        h.advance(PRICE_MAX_AGE + 1);
+        h.call(ALICE, 1, |c| c.renege(U128(10 * ONE), false, false));
+    }
+
+    #[test]
+    #[should_panic(expected = "Price is stale, waiting for oracle reporters")]
+    fn no_fold_at_a_stale_price() {
+        let mut h = leveraged_long();
+        h.advance(PRICE_MAX_AGE + 1);
+        h.call(BOB, 1, |c| c.fold(false));
+    }
+
+    #[test]
+    fn fold_long() {
+        let mut h = Harness::new(PRICE);
+        h.call(BOB, 200 * ONE, |c| c.borrow(U128(400 * ONE), false));
+        h.call(BOB, 1, |c| c.deposit(U128(300 * ONE), false));
+        h.call(ALICE, 100 * ONE, |c| c.borrow(U128(200 * ONE), false));
+        h.call(ALICE, 1, |c| c.fold(false));
+        assert_eq!(h.events("fold"), 1);
+        let pledge = h.pledge(ALICE).unwrap();
+        assert_eq!(pledge.debit.0, 0);
+        assert_eq!(pledge.credit.0, 60 * ONE); // 40 NEAR were sold to repay 200 QD
+        assert_eq!(h.qd(ALICE), 200 * ONE);
+        let pools = h.pools();
+        assert_eq!(pools.live_long_credit.0, 260 * ONE);
+        assert_eq!(pools.live_long_debit.0, 400 * ONE);
+        // Bob's QD bought the 40 NEAR, which his deposit absorbs
+        assert_eq!((pools.dead_long_credit.0, pools.dead_long_debit.0), (200 * ONE, 40 * ONE));
+        h.absorb(BOB);
+        let pools = h.pools();
+        assert!(absorbed(pools.blood_credit.0, 100 * ONE));
+        assert!(absorbed(pools.blood_debit.0, 40 * ONE));
     }
-}
\ No newline at end of file
+}
@@ -1,88 +1,56 @@
 
 use crate::*;
-use libm;
 
-use near_sdk::{env, log, Balance, Promise};
-use near_sdk::serde::{Deserialize, Serialize};
-use near_sdk::json_types::{WrappedBalance, WrappedTimestamp, U128};
+use near_sdk::Balance;
+use near_sdk::serde::Serialize;
+use near_sdk::json_types::WrappedBalance;
 
-#[serde(crate = "near_sdk::serde")]
 #[derive(BorshDeserialize, BorshSerialize, Clone, Serialize)]
+#[serde(crate = "near_sdk::serde")]
 pub struct Stats {
-    pub val_near: Balance, // $ value of crypto assets
-    pub stress_val: f64, //  $ value of the Solvency Pool in stress 
-    pub avg_val: f64, // $ value of the Solvency Pool in average stress 
-    pub stress_loss: f64, // % loss that Solvency pool would suffer in a stress event
-    pub avg_loss: f64, // % loss that Solvency pool would suffer in an average stress event
-    pub premiums: f64, // $ amount of premiums borrower would pay in a year to insure their collateral
-    pub rate: f64, // annualized rate borrowers pay in periodic premiums to insure their collateral
+    pub val_near: QdAmount, // $ value of crypto assets
+    pub stress_val: QdAmount, //  $ value of the Solvency Pool in stress 
+    pub avg_val: QdAmount, // $ value of the Solvency Pool in average stress 
+    pub stress_loss: QdAmount, // $ loss that Solvency pool would suffer in a stress event
+    pub avg_loss: QdAmount, // $ loss that Solvency pool would suffer in an average stress event
+    pub premiums: QdAmount, // $ amount of premiums borrower would pay in a year to insure their collateral
+    pub rate: Ratio, // annualized rate borrowers pay in periodic premiums to insure their collateral
+    pub var: Ratio, // annualized variance of the crypto at stake, as a portfolio (wᵀΣw)
 }
 impl Stats {
     pub fn new() -> Self {
         Self {
-            val_near: 0,
-            stress_val: 0.0,
-            avg_val: 0.0,
-            stress_loss: 0.0,
-            avg_loss: 0.0,
-            premiums: 0.0,
-            rate: 0.0,
-        }
-    }
-    pub fn clone(&self) -> Self {
-        Self {
-            val_near: self.val_near.clone(),
-            stress_val: self.stress_val.clone(),
-            avg_val: self.avg_val.clone(),
-            stress_loss: self.stress_loss.clone(),
-            avg_loss: self.avg_loss.clone(),
-            premiums: self.premiums.clone(),
-            rate: self.rate.clone(),
+            val_near: QdAmount::ZERO,
+            stress_val: QdAmount::ZERO,
+            avg_val: QdAmount::ZERO,
+            stress_loss: QdAmount::ZERO,
+            avg_loss: QdAmount::ZERO,
+            premiums: QdAmount::ZERO,
+            rate: Ratio::ZERO,
+            var: Ratio::ZERO,
         }
     }
 }
 
-#[serde(crate = "near_sdk::serde")]
 #[derive(BorshDeserialize, BorshSerialize, Clone, Serialize)]
+#[serde(crate = "near_sdk::serde")]
 pub struct PledgeStats {
     pub long: Stats,
     pub short: Stats,
-    pub val_near_sp: Balance, // $ value of the NEAR solvency deposit
-    pub val_total_sp: Balance, // total $ value of val_near plus $QD solvency deposit
+    pub val_near_sp: QdAmount, // $ value of the NEAR solvency deposit
+    pub val_total_sp: QdAmount, // total $ value of val_near plus $QD solvency deposit
 }
 impl PledgeStats {
     pub fn new() -> Self {
         Self {
             long: Stats::new(),
             short: Stats::new(),
-            val_near_sp: 0,
-            val_total_sp: 0
-        }
-    }
-    pub fn clone(&self) -> Self {
-        Self {
-            long: self.long.clone(), 
-            short: self.short.clone(),
-            val_near_sp: self.val_near_sp.clone(),
-            val_total_sp: self.val_total_sp.clone()
+            val_near_sp: QdAmount::ZERO,
+            val_total_sp: QdAmount::ZERO
         }
     }
 }
 
-#[serde(crate = "near_sdk::serde")]
-#[derive(BorshDeserialize, BorshSerialize, Clone, Serialize)]
-pub struct Pledge { // each User is a Pledge, whether or not borrowing
-    // borrowing users will have non-zero values in `long` and `short`
-    pub long: Pod, // debt in $QD, collateral in NEAR
-    pub short: Pod, // debt in NEAR, collateral in $QD
-    // pub assets: Pool, // pledge.assets.long.debit
-    // instead of pledge.long.debit
-    pub stats: PledgeStats, // risk management metrics
-    pub near: Balance, // SolvencyPool deposit of NEAR
-    pub quid: Balance, // SolvencyPool deposit of $QD
-    pub id: AccountId,
-    pub target: u128
-}
 /*
  * Every great magic trick consists of three parts or acts. 
  * The first part is called "The Pledge". The magician shows 
@@ -90,53 +58,73 @@ pub struct Pledge { // each User is a Pledge, whether or not borrowing
  * He shows you this object, perhaps asks you to inspect it,
  * to see if it is indeed real, unaltered, normal...it's not.
 */
-impl Pledge {
-    pub fn clone(&self) -> Self {
-        Self {
-            long: self.long.clone(),
-            short: self.short.clone(),
-            stats: self.stats.clone(),
-            near: self.near,
-            quid: self.quid,
-            id: self.id.clone(),
-            target: self.target
-        }
-    }
+#[derive(BorshDeserialize, BorshSerialize, Clone, Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Pledge { // each User is a Pledge, whether or not borrowing
+    // borrowing users will have non-zero values in `long` and `short`
+    pub long: LongPod, // debt in $QD, collateral in NEAR
+    pub short: ShortPod, // debt in NEAR, collateral in $QD
+    pub stats: PledgeStats, // risk management metrics
+    pub near: NearAmount, // SolvencyPool deposit of NEAR
+    pub quid: QdAmount, // SolvencyPool deposit of $QD
+    pub id: AccountId,
+    pub target: Ratio, // CR that the long side gets deleveraged back up to, see `deleverage`
+    pub s_target: Ratio, // same for the short side
+    pub basket: Vec<(AccountId, Balance)>, // long collateral besides NEAR, see assets.rs
+    pub basket_near: NearAmount // what `basket` was worth in NEAR when the long side was last saved
 }
 
 pub trait PledgeForTreeMap: Clone + BorshSerialize + BorshDeserialize {
     fn get_id(&self) -> AccountId;
-    fn get_debt_amt(&self, short: bool) -> U128;
-    fn get_coll_val(&self, short: bool) -> U128;
-    fn get_CR(&self, short: bool, price: u128) -> U128;
+    fn get_long_debt(&self) -> QdAmount;
+    fn get_short_debt(&self) -> NearAmount;
+    fn get_long_coll(&self) -> NearAmount;
+    fn get_short_coll(&self) -> QdAmount;
+    #[allow(non_snake_case)]
+    fn get_CR(&self, short: bool, price: Price) -> Ratio;
+    fn get_coll_per_debt(&self, short: bool) -> Ratio;
 }
 impl PledgeForTreeMap for Pledge {
     fn get_id(&self) -> AccountId {
         self.id.clone()
     }
 
-    fn get_debt_amt(&self, short: bool) -> U128 {
-        if short {
-            return self.short.debit.into()
-        } else {
-            return self.long.debit.into()
-        }
+    fn get_long_debt(&self) -> QdAmount {
+        self.long.debit
+    }
+
+    fn get_short_debt(&self) -> NearAmount {
+        self.short.debit
     }
 
-    fn get_coll_val(&self, short: bool) -> U128 {
+    // the basket counts at its worth as of the last save, the trees can't reprice it
+    fn get_long_coll(&self) -> NearAmount {
+        self.long.credit + self.basket_near
+    }
+
+    fn get_short_coll(&self) -> QdAmount {
+        self.short.credit
+    }
+
+    fn get_CR(&self, short: bool, price: Price) -> Ratio {
         if short {
-            return self.short.credit.into()
+            computeCR(price, self.short.credit, self.short.debit)
         } else {
-            return self.long.credit.into()
+            computeCR(price, self.get_long_coll(), self.long.debit)
         }
     }
 
-    fn get_CR(&self, short: bool, price: u128) -> U128 {
-        if short {
-            return computeCR(price, self.short.credit, self.short.debit, true).into();
+    // in their own units, so that it sorts the same as the CR at any price
+    fn get_coll_per_debt(&self, short: bool) -> Ratio {
+        let (credit, debit) = if short {
+            (self.short.credit.0, self.short.debit.0)
         } else {
-            return computeCR(price, self.long.credit, self.long.debit, false).into();
+            (self.get_long_coll().0, self.long.debit.0)
+        };
+        if debit == 0 {
+            return Ratio::MAX;
         }
+        Ratio(ratio(ONE, credit, debit))
     }
 }
 
@@ -148,7 +136,10 @@ pub struct PledgeView {
     pub credit: WrappedBalance,
     pub s_credit: WrappedBalance,
     pub quid_sp: WrappedBalance,
-    pub near_sp: WrappedBalance
+    pub near_sp: WrappedBalance,
+    pub target: WrappedBalance,
+    pub s_target: WrappedBalance,
+    pub basket: Vec<(AccountId, WrappedBalance)>
 }
  
 impl From<&Pledge> for PledgeView {
@@ -160,6 +151,9 @@ impl From<&Pledge> for PledgeView {
             s_credit: p.short.credit.into(),
             near_sp: p.near.into(),
             quid_sp: p.quid.into(),
+            target: p.target.into(),
+            s_target: p.s_target.into(),
+            basket: p.basket.iter().map(|(token, amt)| (token.clone(), (*amt).into())).collect(),
         }
     }
 }
@@ -170,188 +164,131 @@ impl Contract {
         let mut dead_short = false;
         let mut dead_long = false;
         if short_touched {
-            if self.short_crs.contains_key(&pledge, self.get_price()) {
-                self.short_crs.remove(&pledge, self.get_price());
-            }
-            if pledge.short.debit > 0 && pledge.short.credit > 0 {
-                self.short_crs.insert(&pledge, &(), self.get_price());
+            if !pledge.short.debit.is_zero() && !pledge.short.credit.is_zero() {
+                self.short_crs.insert(pledge, &()); // replaces where it was
             } else {
-                dead_short = true;
+                self.short_crs.remove(pledge);
+                dead_short = pledge.short.credit.is_zero();
             }
         }
         if long_touched {
-            if self.long_crs.contains_key(&pledge, self.get_price()) {
-                self.long_crs.remove(&pledge, self.get_price());
+            let coll = !pledge.long.credit.is_zero() || !pledge.basket.is_empty();
+            pledge.basket_near = self.basket_near(pledge);
+            if !pledge.long.debit.is_zero() && coll {
+                self.long_crs.insert(pledge, &());
+            } else {
+                self.long_crs.remove(pledge);
             }
-            if pledge.long.debit > 0 && pledge.long.credit > 0 {
-                self.long_crs.insert(&pledge, &(), self.get_price());
+            if !pledge.long.debit.is_zero() && !pledge.basket.is_empty() {
+                self.baskets.insert(id);
             } else {
-                dead_long = true; // TODO check
+                self.baskets.remove(id);
             }
+            dead_long = !coll; // collateral left without debt is kept
+        }
+        if dead_short && dead_long && pledge.quid.is_zero()
+        &&  pledge.near.is_zero() { 
+            self.pledges.remove(id);
+            self.charged.remove(id);
+            self.marks.remove(id);
+        } else if self.pledges.insert(id, pledge).is_none() {
+            if !self.crank.done {
+                // opened after the update started, nothing is due until the next one
+                self.charged.insert(id, &self.crank.epoch);
+            }
+            // nor does it absorb what was in the DeadPool before it was opened
+            self.absorption.accrue(&self.dead, self.blood.credit, self.blood.debit);
+            let index = self.absorption.index.clone();
+            self.marks.insert(id, &Mark { index, last_absorbed: env::block_timestamp() });
         }
-        if dead_short && dead_long && (pledge.quid == 0)
-        &&  (pledge.near == 0) { self.pledges.remove(id); }
-        else { self.pledges.insert(id, pledge); }
     }
 
     pub(crate) fn stress_pledge(&mut self, id: AccountId) { 
         let mut p: Pledge = self.pledges.get(&id).unwrap(); 
-        let mut iVvol = self.get_vol() as f64; // get annualized volatility of NEAR
         let mut short_touched = false;
         let mut long_touched = false;
-        let mut due: Balance = 0;         
-        let mut cr = computeCR(self.get_price(), p.short.credit, p.short.debit, true);
-        if cr < KILL_CR { 
-            let nums = self.try_kill_pledge(&p, true);
-            p.quid = nums.0; 
-            p.near = nums.2;
-            p.short.credit = nums.1;
-            p.short.debit = nums.3;
+        let price = self.get_price();
+        let mut cr = computeCR(price, p.short.credit, p.short.debit);
+        if !p.short.debit.is_zero() && cr < self.config.kill_cr { 
+            self.try_kill_pledge(&mut p, true);
+            short_touched = true;
+        } else if self.deleverage(&mut p, true) {
             short_touched = true;
         }
-        // TODO check precision stuff
-        p.stats.short.val_near = self.get_price() * p.short.debit / ONE; // will be sum of each borrowed crypto amt * its price
-        let mut val_near = p.stats.short.val_near as f64;
-        let mut qd: f64 = p.short.credit as f64;
-        if val_near > 0.0 { // $ value of Pledge' NEAR debt
-            let mut iW: f64 = val_near; // the amount of this crypto in the user's short debt portfolio, times the crypto's price
-            iW /= val_near; // 1.0 for now...TODO later each crypto will carry a different weight in the portfolio
-            let var: f64 = (iW * iW) * (iVvol * iVvol); // aggregate for all Pledge's crypto debt
-            let mut vol: f64 = var.sqrt(); // portfolio volatility of the Pledge's borrowed crypto
+        p.stats.short.val_near = p.short.debit * price; // only NEAR is borrowed
+        p.stats.short.var = Ratio(self.portfolio(&[(NEAR_ASSET.to_string(), p.stats.short.val_near.0)]).1);
+        let mut val_near = p.stats.short.val_near.0;
+        let mut qd = p.short.credit.0;
+        if val_near > 0 { // $ value of Pledge' NEAR debt
             short_touched = true;
-
-            // $ value of borrowed crypto in upward price shocks of avg & bad magnitudes
-            let mut pct: f64 = stress(true, vol, true);
-            let avg_val: f64 = (1.0 + pct) * val_near;
-            pct = stress(false, vol, true);
-            let stress_val: f64 = (1.0 + pct) * val_near;
-            let mut stress_loss: f64 = stress_val - qd; // stressed value
-    
-            if stress_loss < 0.0 {
-                stress_loss = 0.0; // better if this is zero, if it's not 
-                // that means liquidation (debt value worth > QD collat)
-            } 
-            let mut avg_loss: f64 = avg_val - qd;
-            if avg_loss < 0.0 {   avg_loss = 0.0;   }
-
-            // stats.short.stress_loss += stress_loss; 
-            p.stats.short.stress_loss = stress_loss;
-            // stats.short.avg_loss += avg_loss; 
-            p.stats.short.avg_loss = avg_loss;
-
-            vol *= self.data_s.scale; // market determined implied volaility
-            let delta: f64 = pct + 1.0;
-            let ln: f64 = delta.ln() * self.data_s.scale; // * calibrate
-            let i_stress: f64 = ln.exp() - 1.0;
-            let mut payoff: f64 = val_near * (1.0 + i_stress);
-            if payoff > qd {
-                payoff -= qd;
-            } else {
-                payoff = 0.0;
-            };
-            p.stats.short.rate = price(
-                payoff, self.data_s.scale, val_near, qd, vol, true
-            );
-            p.stats.short.premiums = p.stats.short.rate * val_near;
-            self.stats.short.premiums += p.stats.short.premiums;
-            due = (p.stats.short.premiums / PERIOD).round() as Balance;
+            let quote = quote(val_near, qd, p.stats.short.var.0, self.data_s.scale, true);
+            p.stats.short.stress_loss = QdAmount(quote.stress_loss);
+            p.stats.short.avg_loss = QdAmount(quote.avg_loss);
+            p.stats.short.rate = Ratio(quote.rate);
+            p.stats.short.premiums = QdAmount(quote.premiums);
+            self.stats.short.premiums = self.stats.short.premiums.saturating_add(p.stats.short.premiums);
+            let owed = QdAmount(self.config.per_period(p.stats.short.premiums.0));
+            let due = std::cmp::min(owed, p.short.credit);
             
+            let cr_before = computeCR(price, p.short.credit, p.short.debit);
             p.short.credit = p.short.credit // the user pays their due by losing a bit of QD collateral
                 .checked_sub(due).expect(ERR_SUB);
-            
-            self.live.short.credit = self.live.short.credit // reduce QD collateral in the LivePool
-                .checked_sub(due).expect(ERR_SUB);
-            
+            Event::Premium { 
+                account: id.clone(), short: true, due: due.into(), rate: p.stats.short.rate.into(), cr_before: cr_before.into(),
+                cr_after: computeCR(price, p.short.credit, p.short.debit).into(),
+                price: price.into()
+            }.emit();
             // TODO scale for this
-            let gf: Balance = due.checked_div(11).expect(ERR_DIV);
-            due -= gf; // decrement from what is being paid into the gfund pool 
-            self.gfund.short.credit = self.gfund.short.credit
-                .checked_add(gf).expect(ERR_ADD);
-            
-            // pay SolvencyProviders by reducing how much they're owed to absorb in QD debt
-            if self.dead.long.credit > due { 
-                self.dead.long.credit -= due;
-            } else { // take the remainder and add it to QD collateral to be absorbed from DeadPool
-                due -= self.dead.long.credit;
-                self.dead.long.credit = 0;
-                self.dead.short.debit = self.dead.short.debit
-                    .checked_add(due).expect(ERR_ADD);
-            }     
+            Pool::short_premium(&mut self.live, &mut self.dead, &mut self.gfund, due, self.config.gf_cut(due));
+            if due < owed { // out of collateral, what's left of the side gets liquidated
+                self.try_kill_pledge(&mut p, true);
+            }
         }     
-        cr = computeCR(self.get_price(), p.long.credit, p.long.debit, false);
-        if cr < KILL_CR { 
-            let nums = self.try_kill_pledge(&p, false);
-            p.near = nums.0; 
-            p.quid = nums.2;
-            p.long.credit = nums.1;
-            p.long.debit = nums.3;
+        cr = self.long_cr(&p);
+        if !p.long.debit.is_zero() && cr < self.config.kill_cr { 
+            self.try_kill_pledge(&mut p, false);
             long_touched = true;         
+        } else if self.deleverage(&mut p, false) {
+            long_touched = true;
         }
-        // TODO unsafe
-        p.stats.long.val_near = self.get_price() * p.long.credit / ONE; // will be sum of each crypto collateral amt * its price
-        val_near = p.stats.long.val_near as f64;
-        qd = p.long.debit as f64;
-        if val_near > 0.0 {
-            let mut iW: f64 = val_near; // the amount of this crypto in the user's long collateral portfolio, times the crypto's price
-            iW /= val_near; // 1.0 for now...TODO later each crypto will carry a different weight in the portfolio
-            let var: f64 = (iW * iW) * (iVvol * iVvol); // aggregate for all Pledge's crypto collateral
-            let mut vol: f64 = var.sqrt(); // total portfolio volatility of the Pledge's crypto collateral
+        // each crypto in the basket carries its own weight in the portfolio
+        let mut holdings = vec![(NEAR_ASSET.to_string(), (p.long.credit * price).0)];
+        holdings.extend(self.basket_worth(&p));
+        let (worth, var) = self.portfolio(&holdings);
+        (p.stats.long.val_near, p.stats.long.var) = (QdAmount(worth), Ratio(var));
+        val_near = worth;
+        qd = p.long.debit.0;
+        if val_near > 0 {
             long_touched = true;
-
-            // $ value of crypto collateral in downward price shocks of bad & avg magnitudes
-            let mut pct: f64 = stress(true, vol, false);
-            let avg_val: f64 = (1.0 - pct) * val_near;
-            pct = stress(false, vol, false);
-            let stress_val: f64 = (1.0 - pct) * val_near;
-            
-            let mut stress_loss: f64 = qd - stress_val;
-            if stress_loss < 0.0 {   stress_loss = 0.0;  }
-            let mut avg_loss: f64 = qd - avg_val;
-            if avg_loss < 0.0 {   avg_loss = 0.0;   }
-
-            // stats.long.stress_loss += stress_loss; 
-            p.stats.long.stress_loss = stress_loss;
-            // stats.long.avg_loss += avg_loss; 
-            p.stats.long.avg_loss = avg_loss;
-            
-            vol *= self.data_l.scale; // market determined implied volaility
-            let delta: f64 = (-1.0 * pct) + 1.0;
-            let ln: f64 = delta.ln() * self.data_l.scale; // calibrate
-            let i_stress: f64 = -1.0 * (ln.exp() - 1.0);
-            let mut payoff: f64 = val_near * (1.0 - i_stress);
-            if payoff > qd {
-                payoff = 0.0;
-            } else {
-                payoff = p.long.debit as f64 - payoff;
-            };
-            p.stats.long.rate = price(payoff, self.data_l.scale, val_near, qd, vol, false);
-            p.stats.long.premiums = p.stats.long.rate * qd;
-            self.stats.long.premiums += p.stats.long.premiums;
-            due = (p.stats.short.premiums / PERIOD).round() as Balance;
-            let mut due_in_near = ratio(1, self.get_price(), due);
+            let quote = quote(val_near, qd, var, self.data_l.scale, false);
+            p.stats.long.stress_loss = QdAmount(quote.stress_loss);
+            p.stats.long.avg_loss = QdAmount(quote.avg_loss);
+            p.stats.long.rate = Ratio(quote.rate);
+            p.stats.long.premiums = QdAmount(quote.premiums);
+            self.stats.long.premiums = self.stats.long.premiums.saturating_add(p.stats.long.premiums);
+            let due = QdAmount(self.config.per_period(p.stats.long.premiums.0));
+            // premiums are paid in NEAR, what the NEAR can't pay comes out of the basket
+            let owed = due / price;
+            let due_in_near = std::cmp::min(owed, p.long.credit);
             
+            let cr_before = self.long_cr(&p);
             p.long.credit = p.long.credit // A Pledge's long side is credited with NEAR collateral
                 .checked_sub(due_in_near).expect(ERR_SUB);
-            
-            self.live.long.credit = self.live.long.credit
-                .checked_sub(due_in_near).expect(ERR_SUB);
-
-            let gf: Balance = due_in_near.checked_div(11).expect(ERR_DIV);
-            due_in_near -= gf;
-            self.gfund.long.credit = self.gfund.long.credit
-                .checked_add(gf).expect(ERR_ADD);
-            
-            // pay SolvencyProviders by reducing how much they're owed to absorb in NEAR debt
-            if self.dead.short.credit > due_in_near { 
-                self.dead.short.credit -= due_in_near;
-            } else { // take the remainder and add it to NEAR collateral to be absorbed from DeadPool
-                due_in_near -= self.dead.short.credit;
-                
-                self.dead.short.credit = 0;
-                self.dead.long.debit = self.dead.long.debit
-                    .checked_add(due_in_near).expect(ERR_ADD);
-            }  
+            let mut paid = due_in_near; // in NEAR, or its worth
+            if due_in_near < owed {
+                let basket = self.pay_from_basket(&mut p, (owed - due_in_near) * price);
+                self.token.internal_deposit(&env::current_account_id(), basket.0);
+                Pool::basket_premium(&mut self.dead, &mut self.gfund, basket, self.config.gf_cut(basket));
+                paid += basket / price;
+            }
+            Event::Premium { 
+                account: id.clone(), short: false, due: paid.into(), rate: p.stats.long.rate.into(), cr_before: cr_before.into(),
+                cr_after: self.long_cr(&p).into(),
+                price: price.into()
+            }.emit();
+            Pool::long_premium(&mut self.live, &mut self.dead, &mut self.gfund, due_in_near, self.config.gf_cut(due_in_near));
         }
+        self.charged.insert(&id, &self.crank.epoch);
         self.save_pledge(&id, &mut p, long_touched, short_touched);
     }
 }
@@ -1,9 +1,9 @@
 use crate::*;
 
 
-use near_sdk::{env, log, Balance, Promise};
-use near_sdk::serde::{Deserialize, Serialize};
-use near_sdk::json_types::{WrappedBalance, WrappedTimestamp, U128};
+use near_sdk::{env, Balance, Timestamp};
+use near_sdk::serde::Serialize;
+use near_sdk::json_types::U128;
 
 
 #[derive(Serialize)]
@@ -44,37 +44,191 @@ pub struct PoolStats {
     }
 }
 
+#[derive(BorshDeserialize, BorshSerialize, Debug, Clone, Default, Serialize)]
 #[serde(crate = "near_sdk::serde")]
-#[derive(BorshDeserialize, BorshSerialize, Debug, Clone, Serialize)]
-pub struct Pod { // Used in all Pools, and in individual users' Pledges
-    pub credit: Balance, // amount of QD collateral in shorts, NEAR in longs
-    pub debit: Balance // amount of QDebt in longs, NEAR debt in shorts
-} impl Pod { 
-    pub fn new(ins: Balance, outs: Balance) -> Self {
+pub struct Pod<C, D> { // Used in all Pools, and in individual users' Pledges
+    pub credit: C, // amount of QD collateral in shorts, NEAR in longs
+    pub debit: D // amount of QDebt in longs, NEAR debt in shorts
+} impl<C, D> Pod<C, D> { 
+    pub fn new(ins: C, outs: D) -> Self {
         Self { 
             credit: ins, 
             debit: outs 
         } 
     }
-    pub fn clone(&self) -> Self {
+}
+
+pub type LongPod = Pod<NearAmount, QdAmount>; // NEAR collateral against QD debt
+pub type ShortPod = Pod<QdAmount, NearAmount>; // QD collateral against NEAR debt
+
+#[derive(BorshDeserialize, BorshSerialize, Debug, Clone, Default, Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Pool<L = LongPod, S = ShortPod> { // Pools have a long Pod and a short Pod
+    pub long: L, // debt and collateral of QD borrowers
+    pub short: S, // debt and collateral of NEAR borrowers
+} impl<L: Default, S: Default> Pool<L, S> { // There are 4 capital pools in QuiD Protocol
+    pub fn new() -> Self {
         Self {
-            credit: self.credit, 
-            debit: self.debit,
+            long: L::default(),
+            short: S::default()
         }
     }
 }
 
-#[serde(crate = "near_sdk::serde")]
-#[derive(BorshDeserialize, BorshSerialize, Debug, Serialize)]
-pub struct Pool { // Pools have a long Pod and a short Pod
-    pub long: Pod, // debt and collateral of QD borrowers
-    pub short: Pod, // debt and collateral of NEAR borrowers
-} impl Pool { // There are 4 capital pools in QuiD Protocol
+/*  The DeadPool keeps each side the other way around: what defaulted
+ *  borrowers owe is its credit, and what backed it is its debit. So
+ *  its long Pod holds QD debt against NEAR, and its short Pod holds
+ *  NEAR debt against QD, as redemptions and inversions clear them
+ */
+pub type DeadPool = Pool<Pod<QdAmount, NearAmount>, Pod<NearAmount, QdAmount>>;
+
+impl Pool {
+    /*  A premium is `due` out of a Pledge's collateral (QD for shorts, NEAR
+     *  for longs), `gf` of it goes to the GuaranteeFund and the rest pays
+     *  SolvencyProviders, by reducing how much they're owed to absorb from
+     *  the DeadPool, or else adding to what they'll absorb from it
+     */
+    pub fn short_premium(live: &mut Pool, dead: &mut DeadPool, gfund: &mut Pool, due: QdAmount, gf: QdAmount) {
+        live.short.credit -= due; // reduce QD collateral in the LivePool
+        Pool::qd_premium(dead, gfund, due, gf);
+    }
+
+    /*  What a long's NEAR can't pay of its premium comes out of its basket,
+     *  `due` is the QD it's worth. The protocol mints that much QD to pay
+     *  it with as it would a short's, and owes it (as the GuaranteeFund's
+     *  QD debt) until the tokens are sold to repay it, see `buy_asset`
+     */
+    pub fn basket_premium(dead: &mut DeadPool, gfund: &mut Pool, due: QdAmount, gf: QdAmount) {
+        gfund.long.debit += due;
+        Pool::qd_premium(dead, gfund, due, gf);
+    }
+
+    fn qd_premium(dead: &mut DeadPool, gfund: &mut Pool, due: QdAmount, gf: QdAmount) {
+        let mut rest = due - gf;
+        gfund.short.credit += gf;
+        if dead.long.credit > rest { // QD debt
+            dead.long.credit -= rest;
+        } else { // take the remainder and add it to QD collateral to be absorbed from DeadPool
+            rest -= dead.long.credit;
+            dead.long.credit = QdAmount::ZERO;
+            dead.short.debit += rest;
+        }
+    }
+
+    pub fn long_premium(live: &mut Pool, dead: &mut DeadPool, gfund: &mut Pool, due: NearAmount, gf: NearAmount) {
+        let mut rest = due - gf;
+        live.long.credit -= due;
+        gfund.long.credit += gf;
+        if dead.short.credit > rest { // NEAR debt
+            dead.short.credit -= rest;
+        } else { // take the remainder and add it to NEAR collateral to be absorbed from DeadPool
+            rest -= dead.short.credit;
+            dead.short.credit = NearAmount::ZERO;
+            dead.long.debit += rest;
+        }
+    }
+
+    /*  Moves a liquidated side's `debt` and `collat` from the LivePool
+     *  to the DeadPool, the GuaranteeFund takes on what the collateral
+     *  falls short of the debt by (in units of the debt)
+     */
+    pub fn short_snatch(live: &mut Pool, dead: &mut DeadPool, gfund: &mut Pool, debt: NearAmount, collat: QdAmount, price: Price) {
+        // we are moving crypto debt and QD collateral from LivePool to DeadPool
+        live.short.credit -= collat;
+        live.short.debit -= debt;
+        assert!(debt * price > collat, "Borrower was not supposed to be liquidated");
+        Pool::short_bury(dead, gfund, debt, collat, price);
+    }
+
+    pub fn long_snatch(live: &mut Pool, dead: &mut DeadPool, gfund: &mut Pool, debt: QdAmount, collat: NearAmount, price: Price) {
+        // we are moving QD debt and crypto collateral
+        live.long.credit -= collat;
+        live.long.debit -= debt;
+        assert!(debt > collat * price, "Borrower was not supposed to be liquidated");
+        Pool::long_bury(dead, gfund, debt, collat, price);
+    }
+
+    /*  Adds defaulted `debt` and `collat` to the DeadPool, except for
+     *  what the collateral falls short of the debt by (in units of the 
+     *  debt), which the GuaranteeFund takes on
+     */
+    pub fn short_bury(dead: &mut DeadPool, gfund: &mut Pool, debt: NearAmount, collat: QdAmount, price: Price) {
+        dead.short.debit += collat;
+        let delta_debt = (debt * price).saturating_sub(collat) / price;
+        dead.short.credit += debt - delta_debt;
+        gfund.short.debit += delta_debt;
+    }
+
+    pub fn long_bury(dead: &mut DeadPool, gfund: &mut Pool, debt: QdAmount, collat: NearAmount, price: Price) {
+        dead.long.debit += collat;
+        let delta = debt.saturating_sub(collat * price);
+        dead.long.credit += debt - delta;
+        gfund.long.debit += delta;
+    }
+}
+
+/*  DeadPool absorption works like rewards per share: whatever lands in
+ *  the DeadPool gets spread over the SolvencyPool's deposits by bumping
+ *  a cumulative index, and each Pledge absorbs its deposit times how
+ *  much the index grew since it last did (see `fetch_pledge`). Longs'
+ *  defaulted QD debt and their NEAR go to QD deposits, shorts' NEAR
+ *  debt and their QD go to NEAR deposits, as those are what repay them.
+ *  So what a Pledge absorbs doesn't depend on the order Pledges do it
+ *  in, nor on how often, and absorbing again right away absorbs nothing
+ */
+#[derive(BorshDeserialize, BorshSerialize, Debug, Clone, Default, PartialEq)]
+pub struct Index {
+    pub near: Balance, // NEAR gained per QD deposited, 1e24-scaled
+    pub qd_debt: Balance, // QD debt per QD deposited
+    pub near_debt: Balance, // NEAR debt per NEAR deposited
+    pub qd: Balance, // QD gained per NEAR deposited
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Debug, Default)]
+pub struct Mark { // where a Pledge last absorbed from the DeadPool
+    pub index: Index,
+    pub last_absorbed: Timestamp,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Absorption {
+    pub index: Index,
+    indexed: DeadPool, // of what's in the DeadPool, how much the index accounts for
+    seen: DeadPool, // what was in the DeadPool when the index was last bumped
+} impl Absorption {
     pub fn new() -> Self {
-        Self {
-            long: Pod::new(0, 0),
-            short: Pod::new(0, 0)
+        Self { index: Index::default(), indexed: DeadPool::new(), seen: DeadPool::new() }
+    }
+
+    // spreads what was added to the DeadPool since the last call over
+    // QD deposits (`qd`) and NEAR deposits (`near`) of the SolvencyPool
+    pub fn accrue(&mut self, dead: &DeadPool, qd: QdAmount, near: NearAmount) {
+        let (qd, near) = (qd.0, near.0); // the index is per unit of either
+        fn bump(index: &mut Balance, indexed: &mut Balance, seen: &mut Balance, now: Balance, basis: Balance) {
+            if now < *seen { // absorbed (or netted by premiums), out of what was indexed first
+                *indexed = indexed.saturating_sub(*seen - now);
+            }
+            *seen = now;
+            // dust deposits would blow up the index, what's left waits for more
+            if now > *indexed && basis >= ONE {
+                *index = index.checked_add(ratio(ONE, now - *indexed, basis)).expect(ERR_ADD);
+                *indexed = now;
+            }
         }
+        bump(&mut self.index.near, &mut self.indexed.long.debit.0,
+             &mut self.seen.long.debit.0, dead.long.debit.0, qd);
+        bump(&mut self.index.qd_debt, &mut self.indexed.long.credit.0,
+             &mut self.seen.long.credit.0, dead.long.credit.0, qd);
+        bump(&mut self.index.near_debt, &mut self.indexed.short.credit.0,
+             &mut self.seen.short.credit.0, dead.short.credit.0, near);
+        bump(&mut self.index.qd, &mut self.indexed.short.debit.0,
+             &mut self.seen.short.debit.0, dead.short.debit.0, near);
+    }
+}
+
+impl Default for Absorption {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -85,70 +239,88 @@ impl Contract
     // add collateral to LivePool / deposits to SolvencyPool
     // attach a deposit for adding NEAR, amount's for adding QD
     pub fn deposit(&mut self, qd_amt: U128, live: bool) {
-        // TODO change vote
-        assert!(self.crank.done, "Update in progress");
+        self.assert_not_paused();
         let deposit = env::attached_deposit();
-        assert!(deposit > 0, ERR_AMT_TOO_LOW);
-        let mut amt: Balance = qd_amt.into();
-        let mut left = amt;
-        let mut min: Balance;
-        
+        assert!(deposit > 0, "{}", ERR_AMT_TOO_LOW);
         let account = env::predecessor_account_id();
-        let mut pledge = self.fetch_pledge(&account, true);
+        self.stash(&account, NearAmount(deposit), qd_amt.into(), live, false);
+        self.debug_invariants();
+    }
+
+    /*  Adds `near` and `qd` to the account's collateral (`live`) or to its
+     *  SP deposit. QD comes out of the account's balance, or else out of
+     *  its SP deposit, unless the contract `holds` it already (because it
+     *  was transferred in with `ft_transfer_call`). Returns the QD added
+     */
+    pub(crate) fn stash(&mut self, account: &AccountId, near: NearAmount, qd: QdAmount, live: bool, holds: bool) -> QdAmount {
+        let mut amt: QdAmount = qd;
+        let mut left = if holds { QdAmount::ZERO } else { amt };
+        let mut min: QdAmount;
+        
+        let mut pledge = self.fetch_pledge(account, true);
+        let cr_before = self.crs(&pledge);
         let mut long_touched = false; let mut short_touched = false;
 
         // TODO if live = true && no borrowing position open
 
-        if deposit > 1 {
+        if near > NearAmount(1) {
             if live { // adding NEAR collateral to borrower's long position
                 long_touched = true;
                 pledge.long.credit = pledge.long.credit
-                    .checked_add(deposit).expect(ERR_ADD);
+                    .checked_add(near).expect(ERR_ADD);
 
                 self.live.long.credit = self.live.long.credit
-                    .checked_add(deposit).expect(ERR_ADD);
+                    .checked_add(near).expect(ERR_ADD);
             } 
             else { // adding NEAR deposit to user's SolvencyPool position
                 pledge.near = pledge.near
-                    .checked_add(deposit).expect(ERR_ADD);
+                    .checked_add(near).expect(ERR_ADD);
 
                 self.blood.debit = self.blood.debit // crypto can be loaned out as debit to SP
-                    .checked_add(deposit).expect(ERR_ADD);            
+                    .checked_add(near).expect(ERR_ADD);            
             }
         }
-        if amt > 0 {
-            let liq_qd: Balance = self.token.ft_balance_of(
+        if !left.is_zero() {
+            let liq_qd: QdAmount = self.token.ft_balance_of(
                 ValidAccountId::try_from(account.clone()).unwrap()
             ).into();
             min = std::cmp::min(liq_qd, amt);
-            if min > 0 {
-                self.token.internal_withdraw(&account, min);
-                self.token.internal_deposit(&env::current_account_id(), min);
+            if !min.is_zero() {
+                self.token.internal_withdraw(account, min.0);
+                self.token.internal_deposit(&env::current_account_id(), min.0);
                 left -= min;
             }
-            if left > 0 {
+            if !left.is_zero() {
                 min = std::cmp::min(left, pledge.quid);
                 left -= min;
-                pledge.quid -= min;
+                pledge.quid -= min; // moved out of the SolvencyPool
+                self.blood.credit = self.blood.credit
+                    .checked_sub(min).expect(ERR_SUB);
             }
             amt -= left;
-            if amt > 0 {    
-                if live { // adding QD collateral to borrower's short position
-                    short_touched = true;
-                    pledge.short.credit = pledge.short.credit
-                        .checked_add(amt).expect(ERR_ADD);
-                    self.live.short.credit = self.live.short.credit
-                        .checked_add(amt).expect(ERR_ADD);
-                } 
-                else { // adding QD deposit to user's SolvencyPool position
-                    pledge.quid = pledge.quid
-                        .checked_add(amt).expect(ERR_ADD);
-                    self.blood.credit = self.blood.credit
-                        .checked_add(amt).expect(ERR_ADD);
-                }
+        }
+        if !amt.is_zero() {    
+            if live { // adding QD collateral to borrower's short position
+                short_touched = true;
+                pledge.short.credit = pledge.short.credit
+                    .checked_add(amt).expect(ERR_ADD);
+                self.live.short.credit = self.live.short.credit
+                    .checked_add(amt).expect(ERR_ADD);
+            } 
+            else { // adding QD deposit to user's SolvencyPool position
+                pledge.quid = pledge.quid
+                    .checked_add(amt).expect(ERR_ADD);
+                self.blood.credit = self.blood.credit
+                    .checked_add(amt).expect(ERR_ADD);
             }
         }
-        self.save_pledge(&account, &mut pledge, long_touched, short_touched);
+        self.on_stake_change(&pledge);
+        self.save_pledge(account, &mut pledge, long_touched, short_touched);
+        Event::Deposit { 
+            account: account.clone(), live, near: near.into(), qd: amt.into(), cr_before,
+            cr_after: self.crs(&pledge), price: self.get_price().into()
+        }.emit();
+        amt
     }
 
     // Invoked a la github.com/Narwallets/meta-pool-heartbeat
@@ -156,38 +328,56 @@ impl Contract
     // and re-pricing options for borrowers on account of this, 
     // and SolvencyTarget as SP's weighted-median voting concedes
     pub fn update(&mut self) {
+        self.assert_not_paused();
+        self.assert_fresh_price();
         if !self.crank.done {
-            // let mut pledges = &mut self.pledges; // BUG inside loop throws 
-            // "cannot borrow `*self` as mutable more than once at a time"
-            let mut keys = &self.pledges.to_vec();
-            let len = self.pledges.len() as usize;
-            let start = self.crank.index.clone();
-            let left: usize = len - start;
-            let mut many = 42; // arbitrary number of Pledges to iterate at a time
-            // limited by maximum gas that can be burned in one transaction call
-            if 42 > left {    many = left;    }
-            let stop = start + many;
-            for idx in start..stop { 
-                let id = keys[idx].0.clone();
-                self.stress_pledge(id);
-                self.crank.index += 1;
+            /*  Pledges are visited from the last index down: removing one
+             *  (UnorderedMap swaps the last into its slot) can only move
+             *  a Pledge that was already visited below the cursor, and
+             *  inserted ones land above it. Either way `charged` records
+             *  the epoch each Pledge was last stressed in, so none of them
+             *  pays twice. Batches are as large as the attached gas allows
+             */
+            let mut most: Gas = 0; // gas burned by the costliest Pledge so far
+            let mut visited: u32 = 0;
+            let mut stressed: Balance = 0; // not counting those charged in this epoch already
+            while visited < self.config.crank_batch {
+                let left = std::cmp::min(self.crank.left, self.pledges.len());
+                if left == 0 {
+                    break;
+                }
+                let before = env::used_gas();
+                if before + most + CRANK_GAS_RESERVE > env::prepaid_gas() {
+                    break;
+                }
+                self.crank.left = left - 1;
+                let id = self.pledges.keys_as_vector().get(self.crank.left).unwrap();
+                if self.charged.get(&id) != Some(self.crank.epoch) {
+                    self.stress_pledge(id);
+                    stressed += 1;
+                }
+                most = std::cmp::max(most, env::used_gas() - before);
+                visited += 1;
             }
-            if self.crank.index == len {
-                self.crank.index = 0;
+            self.crank.left = std::cmp::min(self.crank.left, self.pledges.len());
+            if self.crank.left == 0 {
                 self.crank.done = true;
                 self.crank.last = env::block_timestamp();
-            }  
+            } else {
+                assert!(visited > 0, "{}", ERR_CRANK_GAS);
+            }
+            let bounty = self.keepers.crank_bounty * stressed as u128;
+            self.reward(&env::predecessor_account_id(), bounty, "update");
         } else {
             let timestamp = env::block_timestamp();
             let time_delta = timestamp - self.crank.last;
-            if time_delta >= EIGHT_HOURS {
+            if time_delta >= self.config.update_interval {
                 self.crank.done = false;
+                self.crank.epoch += 1;
+                self.crank.left = self.pledges.len();
                 let price = self.get_price();
-                self.stats.val_near_sp = self.blood.debit.checked_mul(price).expect(
-                    "Multiplication Overflow in `update`"
-                );
-                self.stats.val_total_sp = self.blood.credit
-                    .checked_add(self.stats.val_near_sp).expect(ERR_ADD);
+                self.stats.val_near_sp = self.blood.debit * price;
+                self.stats.val_total_sp = self.blood.credit + self.stats.val_near_sp;
                 self.sp_stress(None, false); // stress the long side of the SolvencyPool
                 self.sp_stress(None, true); // stress the short side of the SolvencyPool
                 self.risk(false); self.risk(true); // calculate solvency and scale factor 
@@ -195,150 +385,262 @@ impl Contract
                 env::panic(b"Too early to run an update, please wait"); 
             }
         }
+        self.debug_invariants();
     }  
 
-    pub(crate) fn sp_stress(&mut self, maybe_id: Option<AccountId>, short: bool) -> f64 {
-        let ivol = self.get_vol() as f64;
+    #[allow(non_snake_case)]
+    pub(crate) fn sp_stress(&mut self, maybe_id: Option<AccountId>, short: bool) -> QdAmount {
         let price = self.get_price();
         let mut global = true;
-        let mut iW: f64 = 0.0; 
-        let mut jW: f64 = 0.0;
-        if self.stats.val_near_sp > 0 {
-            iW = self.stats.val_near_sp
-                .checked_div(self.stats.val_total_sp).expect(ERR_DIV) as f64;
-     
-            jW = self.blood.credit
-                .checked_div(self.stats.val_total_sp).expect(ERR_DIV) as f64;
+        let mut iW: u128 = 0; // weight of NEAR in the SolvencyPool
+        let mut jW: u128 = 0; // weight of QD
+        if !self.stats.val_total_sp.is_zero() {
+            iW = (self.stats.val_near_sp / self.stats.val_total_sp).0;
+            jW = (self.blood.credit / self.stats.val_total_sp).0;
         }
         if let Some(id) = maybe_id {
             global = false;
             if let Some(p) = self.pledges.get(&id) {
-                let p_near_val = p.near
-                    .checked_mul(price).expect(ERR_MUL);
+                let p_near_val = (p.near * price).0;
             
-                let value = p.quid.checked_add(p_near_val).expect(ERR_ADD);
+                let value = p.quid.0.checked_add(p_near_val).expect(ERR_ADD);
 
-                let mut delta_near: Balance = 0;
-                let mut delta_qd: Balance = 0;
                 if value > 0 {
-                    if self.stats.val_near_sp > 0 {
-                        delta_near = self.stats.val_near_sp
-                            .checked_sub(p_near_val).expect(ERR_SUB);
-                    }
-                    if self.blood.credit > 0 {
-                        delta_qd = self.blood.credit
-                            .checked_sub(p.quid).expect(ERR_SUB);
-                    }
-                    let delta_val = self.stats.val_total_sp
-                        .checked_sub(value).expect(ERR_SUB);
+                    // the rest of the SolvencyPool, at the same price as theirs
+                    let delta_near = (self.blood.debit * price).0
+                        .checked_sub(p_near_val).expect(ERR_SUB);
+                    let delta_qd = self.blood.credit.checked_sub(p.quid).expect(ERR_SUB).0;
+                    let delta_val = delta_near.checked_add(delta_qd).expect(ERR_ADD);
                     
-                    iW = delta_near
-                        .checked_div(delta_val).expect(ERR_DIV) as f64;
-
-                    jW = delta_qd
-                        .checked_div(delta_val).expect(ERR_DIV) as f64;
+                    if delta_val > 0 { // nothing's left of the SolvencyPool without them otherwise
+                        iW = ratio(ONE, delta_near, delta_val);
+                        jW = ratio(ONE, delta_qd, delta_val);
+                    } else {
+                        iW = 0;
+                        jW = 0;
+                    }
                 }            
             }
         }
-        let var: f64 = (2.0 * iW * jW * ivol) + (iW * iW * ivol * ivol);
-        if var > 0.0 {
-            let vol = var.sqrt(); // total volatility of the SolvencyPool
+        let var = portfolio_var(&[iW, jW], &self.cov_of(&[NEAR_ASSET.to_string(), env::current_account_id()]));
+        if var > 0 {
+            let vol = fixed::sqrt(var); // total volatility of the SolvencyPool
             // % loss that total SP deposits would suffer in a stress event
             let stress_pct = stress(false, vol, short);
             let avg_pct = stress(true, vol, short);
-            let mut stress_val: f64 = self.stats.val_total_sp as f64;
-            let mut avg_val: f64 = stress_val;
+            let mut stress_val = self.stats.val_total_sp;
+            let mut avg_val = stress_val;
             if !short {
-                stress_val *= 1.0 - stress_pct; 
-                avg_val *= 1.0 - avg_pct;
+                stress_val = stress_val * Ratio(ONE - stress_pct); 
+                avg_val = avg_val * Ratio(ONE - avg_pct);
                 if global {
+                    self.stats.long.var = Ratio(var);
                     self.stats.long.stress_val = stress_val;
                     self.stats.long.avg_val = avg_val;
                 } 
             } else {
-                stress_val *= 1.0 + stress_pct;
-                avg_val *= 1.0 + avg_pct;
+                stress_val = QdAmount(saturating_ratio(ONE + stress_pct, stress_val.0, ONE));
+                avg_val = QdAmount(saturating_ratio(ONE + avg_pct, avg_val.0, ONE));
                 if global {
+                    self.stats.short.var = Ratio(var);
                     self.stats.short.stress_val = stress_val;
                     self.stats.short.avg_val = avg_val;
                 } 
             }
-            return stress_val;
+            stress_val
         } else {
-            return 0.0;
+            QdAmount::ZERO
         }
     }
     
     pub(crate) fn risk(&mut self, short: bool) {
-        let mvl_s: f64; // market value of liabilities in stressed markets 
-        let mva_s: f64; // market value of assets in stressed markets 
-        let mva_n = self.stats.val_total_sp; //market value of insurance assets in normal markets,
-        // includes the reserve which is implemented as an insurer, collateral is not an asset of the insurers
-        
-        let mut vol = self.get_vol() as f64; 
-        let val_near: f64;
-        if !short {
-            val_near = self.live.long.credit
-                .checked_mul(self.get_price()).expect(ERR_MUL) as f64;
-
-            let qd: f64 = self.live.long.debit as f64;
-            let mut pct: f64 = stress(false, vol, false);
-            
-            let stress_val = (1.0 - pct) * val_near;
-            let stress_loss = qd - stress_val;
-            
-            mva_s = stress_val; // self.stats.long.stress_val;
-            mvl_s = stress_loss; // self.stats.long.stress_loss;
-        } else {
-            val_near = self.live.short.debit
-                .checked_mul(self.get_price()).expect(ERR_MUL) as f64;
-            
-            let qd: f64 = self.live.short.credit as f64;
-            let mut pct: f64 = stress(false, vol, true);
-            
-            let stress_val = (1.0 + pct) * val_near;
-            let stress_loss = stress_val - qd;
-
-            mva_s = stress_val; // self.stats.short.stress_val;
-            mvl_s = stress_loss; // self.stats.short.stress_loss;
-        }    
-        let own_n = mva_n as f64; // own funds normal markets
-        let mut own_s = mva_s - mvl_s; // own funds stressed markets
-        if short && own_s > 0.0 {
-            own_s *= -1.0;   
-        }
-        // S.olvency C.apial R.equirement is the amount of... 
-        // deposited assets needed to survive a stress event
-        let scr = own_n - own_s;
-        assert!(scr > 0.0, "SCR can't be 0");
-        let solvency = own_n / scr; // represents capital adequacy to back $QD
+        let vol = self.annual_vol(); 
+        // market value of insurance assets in normal markets, includes the
+        // reserve which is implemented as an insurer, collateral is not an asset of the insurers
+        let val_sp = self.stats.val_total_sp.0;
         if short {
-            let mut target = self.data_s.median;
-            if target == -1.0 {
-                target = 1.0;
-            }
-            // assume target = 1.5 or 150%
-            let mut scale = target / solvency;
-            if scale > 4.2 {
-                scale = 4.2;    
-            } else if scale < 0.042 {
-                scale = 0.042;
+            let val_near = (self.live.short.debit * self.get_price()).0;
+            let qd = self.live.short.credit.0;
+            // represents capital adequacy to back $QD
+            // without a capital requirement to speak of, the last figures stand
+            if let Some(solvency) = solvency(val_sp, val_near, qd, vol, true) {
+                self.data_s.solvency = solvency;
+                self.data_s.scale = scale(self.data_s.median, solvency, 
+                    self.config.min_scale, self.config.max_scale);
             }
-            self.data_s.scale = scale;
-            self.data_s.solvency = solvency
         } else {
-            let mut target = self.data_l.median;
-            if target == -1.0 {
-                target = 1.0;
+            let val_near = (self.live.long.credit * self.get_price()).0;
+            let qd = self.live.long.debit.0;
+            if let Some(solvency) = solvency(val_sp, val_near, qd, vol, false) {
+                self.data_l.solvency = solvency;
+                self.data_l.scale = scale(self.data_l.median, solvency, 
+                    self.config.min_scale, self.config.max_scale);
             }
-            let mut scale = target / solvency;
-            if scale > 4.2 {
-                scale = 4.2;    
-            } else if scale < 0.042 {
-                scale = 0.042;
-            }
-            self.data_l.scale = scale;
-            self.data_l.solvency = solvency;
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::*;
+    use near_sdk::test_utils::accounts;
+
+    #[test]
+    fn accrues_what_lands_in_the_dead_pool() {
+        let mut absorption = Absorption::new();
+        let mut dead = DeadPool::new();
+        dead.long.debit = NearAmount(10 * ONE); // NEAR gained, per QD deposited
+        absorption.accrue(&dead, QdAmount(40 * ONE), NearAmount::ZERO);
+        assert_eq!(absorption.index.near, ONE / 4);
+        absorption.accrue(&dead, QdAmount(40 * ONE), NearAmount::ZERO); // nothing new
+        assert_eq!(absorption.index.near, ONE / 4);
+
+        // a Pledge absorbs half, then as much lands again
+        dead.long.debit = NearAmount(5 * ONE);
+        absorption.accrue(&dead, QdAmount(40 * ONE), NearAmount::ZERO);
+        dead.long.debit = NearAmount(15 * ONE);
+        absorption.accrue(&dead, QdAmount(40 * ONE), NearAmount::ZERO);
+        assert_eq!(absorption.index.near, ONE / 2);
+
+        // without NEAR deposits to spread over, it waits for some
+        dead.short.credit = NearAmount(2 * ONE);
+        absorption.accrue(&dead, QdAmount(40 * ONE), NearAmount(ONE / 2));
+        assert_eq!(absorption.index.near_debt, 0);
+        absorption.accrue(&dead, QdAmount(40 * ONE), NearAmount(4 * ONE));
+        assert_eq!(absorption.index.near_debt, ONE / 2);
+    }
+
+    #[test]
+    fn sp_stress_at_the_price_of_the_day() {
+        let mut h = leveraged_long();
+        h.call(BOB, 50 * ONE, |c| c.deposit(U128(0), false)); // the SP's NEAR, valued at 5
+        h.set_price(6 * ONE);
+        let bob: AccountId = accounts(BOB).into();
+        let before = h.view(|c| c.stats.long.stress_val);
+        // his 50 NEAR are worth 300, and all there is of the SolvencyPool besides his QD
+        let stressed = h.call(OWNER, 0, |c| c.sp_stress(Some(bob), false));
+        assert_eq!(stressed, QdAmount::ZERO); // nothing's left without him to stress
+        assert_eq!(h.view(|c| c.stats.long.stress_val), before); // nor is the pool's restressed
+    }
+
+    #[test]
+    fn update_charges_premiums() {
+        let mut h = leveraged_long();
+        h.call(BOB, 50 * ONE, |c| c.deposit(U128(0), false));
+        h.call(OWNER, 0, |c| c.update()); // stress the SolvencyPool
+        assert!(!h.view(|c| c.crank.done));
+        h.call(OWNER, 0, |c| c.update()); // stress each Pledge
+        assert!(h.view(|c| c.crank.done));
+        assert_eq!(h.events("premium"), 2); // both borrowers are long
+
+        let (alice, bob) = (h.pledge(ALICE).unwrap(), h.pledge(BOB).unwrap());
+        assert!(alice.credit.0 < 100 * ONE && bob.credit.0 < 200 * ONE);
+        let pools = h.pools();
+        assert_eq!(pools.live_long_credit.0, alice.credit.0 + bob.credit.0);
+        let paid = 300 * ONE - pools.live_long_credit.0;
+        assert!(rounded(pools.gfund_long_credit.0 + pools.dead_long_debit.0, paid));
+    }
+
+    #[test]
+    fn charges_a_pledge_before_it_changes_mid_crank() {
+        let mut h = leveraged_long();
+        h.call(BOB, 50 * ONE, |c| c.deposit(U128(0), false));
+        h.call(OWNER, 0, |c| c.update()); // the crank starts, nobody is charged yet
+        h.call(ALICE, 10 * ONE, |c| c.deposit(U128(0), true));
+        assert_eq!(h.events("premium"), 1); // on the 100 NEAR she had
+        let credit = h.pledge(ALICE).unwrap().credit.0;
+        assert!(credit < 110 * ONE && credit > 100 * ONE);
+
+        assert_eq!(crank(&mut h), 1); // Bob, she's paid already
+        assert_eq!(h.pledge(ALICE).unwrap().credit.0, credit);
+    }
+
+    #[test]
+    #[should_panic(expected = "Too early to run an update, please wait")]
+    fn update_waits_for_interval() {
+        let mut h = leveraged_long();
+        h.call(BOB, 50 * ONE, |c| c.deposit(U128(0), false));
+        h.call(OWNER, 0, |c| c.update());
+        h.call(OWNER, 0, |c| c.update());
+        h.advance(EIGHT_HOURS - ONE_HOUR);
+        h.set_price(PRICE);
+        h.call(OWNER, 0, |c| c.update());
+    }
+
+    #[test]
+    fn update_is_bounded_by_gas() {
+        let mut h = leveraged_long();
+        h.call(BOB, 50 * ONE, |c| c.deposit(U128(0), false));
+        h.call(OWNER, 0, |c| c.update());
+        h.call(OWNER, 1, |c| c.set_crank_batch(1));
+        let used = h.call(OWNER, 0, |c| { // what one Pledge costs
+            c.update();
+            env::used_gas()
+        });
+        h.call(OWNER, 1, |c| c.set_crank_batch(CRANK_BATCH));
+        h.call(OWNER, 0, |c| c.crank = Crank { done: false, left: 2, last: 0, epoch: 2 });
+        h.gas = used + CRANK_GAS_RESERVE; // enough for the first Pledge only
+        h.call(OWNER, 0, |c| c.update());
+        assert_eq!((h.events("premium"), h.view(|c| c.crank.left)), (1, 1));
+        h.gas = GAS;
+        assert_eq!(crank(&mut h), 1); // the other one
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough gas attached to update a single Pledge")]
+    fn update_needs_gas_for_a_pledge() {
+        let mut h = leveraged_long();
+        h.call(BOB, 50 * ONE, |c| c.deposit(U128(0), false));
+        h.call(OWNER, 0, |c| c.update());
+        h.gas = CRANK_GAS_RESERVE;
+        h.call(OWNER, 0, |c| c.update());
+    }
+
+    #[test]
+    fn update_survives_pledges_coming_and_going() {
+        let mut h = Harness::new(PRICE);
+        h.call(DAVE, 50 * ONE, |c| c.deposit(U128(0), false)); // first index
+        h.call(BOB, 200 * ONE, |c| c.borrow(U128(400 * ONE), false));
+        h.call(BOB, 1, |c| c.deposit(U128(300 * ONE), false));
+        h.call(ALICE, 100 * ONE, |c| c.borrow(U128(400 * ONE), false));
+        h.call(OWNER, 1, |c| c.set_crank_batch(1));
+        h.call(OWNER, 0, |c| c.update()); // stress the SolvencyPool
+        h.call(OWNER, 0, |c| c.update()); // last index first
+        assert_eq!(h.events("premium"), 1);
+        assert!(h.pledge(ALICE).unwrap().credit.0 < 100 * ONE);
+
+        // opened mid-crank, to be swapped into Dave's slot below the cursor
+        h.call(CAROL, 100 * ONE, |c| c.borrow(U128(400 * ONE), false));
+        h.call(DAVE, 0, |c| {
+            let id: AccountId = accounts(DAVE).into();
+            let mut pledge = c.pledges.get(&id).unwrap();
+            c.blood.debit -= pledge.near;
+            pledge.near = NearAmount::ZERO;
+            c.save_pledge(&id, &mut pledge, true, true);
+        });
+        assert_eq!(h.view(|c| c.pledges.keys_as_vector().get(0)), Some(accounts(CAROL).into()));
+        assert_eq!(crank(&mut h), 1); // only Bob is left to pay
+        assert_eq!(h.pledge(CAROL).unwrap().credit.0, 100 * ONE);
+
+        h.advance(EIGHT_HOURS);
+        h.set_price(PRICE);
+        assert_eq!(crank(&mut h), 3);
+        assert!(h.pledge(CAROL).unwrap().credit.0 < 100 * ONE);
+    }
+
+    #[test]
+    fn deposit_from_solvency_pool() {
+        let mut h = Harness::new(PRICE);
+        h.call(ALICE, 100 * ONE, |c| c.borrow(U128(200 * ONE), false));
+        h.call(ALICE, 1, |c| c.deposit(U128(150 * ONE), false));
+        // more than her liquid QD, the rest comes out of her SP deposit
+        h.call(ALICE, 1, |c| c.deposit(U128(100 * ONE), true));
+        let pledge = h.pledge(ALICE).unwrap();
+        assert_eq!(pledge.quid_sp.0, 100 * ONE);
+        assert_eq!(pledge.s_credit.0, 100 * ONE);
+        assert_eq!(h.qd(ALICE), 0);
+        let pools = h.pools();
+        assert_eq!(pools.blood_credit.0, 100 * ONE);
+        assert_eq!(pools.live_short_credit.0, 100 * ONE);
+    }
+}
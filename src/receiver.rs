@@ -0,0 +1,134 @@
+use crate::*;
+
+use near_sdk::{env, serde_json};
+use near_sdk::serde::Deserialize;
+use near_sdk::json_types::{ValidAccountId, U128};
+
+/*  QD sent to this contract with `ft_transfer_call` does what its `msg`
+ *  says, for the sender, in one transaction:
+ *      {"action":"deposit","live":true}  QD collateral for the short side,
+ *                                        or an SP deposit if `live` is false
+ *      {"action":"repay"}                burns the sender's QD debt
+ *      {"action":"redeem","min_out":"1"} sells QD for at least `min_out` NEAR
+ *  QD that wasn't used (more than the debt, say) is refunded, as is all
+ *  of it if the action fails. Listed collateral (see assets.rs) may only
+ *  be deposited, into the basket of the sender's long side, so its `msg`
+ *  must be {"action":"deposit","live":true}
+ */
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde", tag = "action", rename_all = "snake_case")]
+enum Action {
+    Deposit { live: bool },
+    Repay,
+    Redeem { min_out: U128 },
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for Contract {
+    fn ft_on_transfer(&mut self, sender_id: ValidAccountId, amount: U128, msg: String) -> PromiseOrValue<U128> {
+        let token = env::predecessor_account_id();
+        assert!(token == env::current_account_id() || self.assets.get(&token).is_some(), "{}", ERR_NOT_QD);
+        self.assert_not_paused();
+        let action: Action = serde_json::from_str(&msg).expect(ERR_BAD_MSG);
+        let account: AccountId = sender_id.into();
+        if token != env::current_account_id() {
+            match action {
+                Action::Deposit { live: true } => self.stash_asset(&account, &token, amount.0),
+                _ => env::panic(ERR_BAD_MSG.as_bytes()),
+            }
+            self.debug_invariants();
+            return PromiseOrValue::Value(U128(0));
+        }
+        let held = env::current_account_id(); // where the QD is now
+        let amount = QdAmount::from(amount);
+        let unused = match action {
+            Action::Deposit { live } => {
+                amount - self.stash(&account, NearAmount::ZERO, amount, live, true)
+            },
+            Action::Repay => {
+                self.assert_fresh_price();
+                let mut pledge = self.fetch_pledge(&account, false);
+                let cr_before = self.crs(&pledge);
+                let repaid = self.repay_qd(&mut pledge, amount, &held);
+                assert!(!repaid.is_zero(), "{}", ERR_NO_DEBT);
+                Event::Repay { 
+                    account, short: false, amount: repaid.into(), cr_before,
+                    cr_after: self.crs(&pledge), price: self.get_price().into()
+                }.emit();
+                amount - repaid
+            },
+            Action::Redeem { min_out } => {
+                self.assert_fresh_price();
+                let near = self.sell_qd(&account, amount, &held);
+                assert!(near >= NearAmount::from(min_out), "{}", ERR_MIN_OUT);
+                QdAmount::ZERO
+            },
+        };
+        self.debug_invariants();
+        PromiseOrValue::Value(unused.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::*;
+
+    #[test]
+    fn transfer_call_deposits() {
+        let mut h = leveraged_long();
+        assert_eq!(h.transfer_call(BOB, 50 * ONE, r#"{"action":"deposit","live":false}"#), 0);
+        assert_eq!(h.pledge(BOB).unwrap().quid_sp.0, 350 * ONE);
+        assert_eq!(h.transfer_call(BOB, 50 * ONE, r#"{"action":"deposit","live":true}"#), 0);
+        assert_eq!(h.pledge(BOB).unwrap().s_credit.0, 50 * ONE);
+        assert_eq!(h.qd(BOB), 400 * ONE);
+        assert_eq!((h.pools().blood_credit.0, h.pools().live_short_credit.0), (350 * ONE, 50 * ONE));
+    }
+
+    #[test]
+    fn transfer_call_refunds_what_repay_did_not_use() {
+        let mut h = leveraged_long();
+        let refund = h.transfer_call(BOB, 450 * ONE, r#"{"action":"repay"}"#);
+        assert_eq!(refund, 50 * ONE); // Bob only owed 400
+        assert_eq!(h.events("repay"), 1);
+        assert_eq!(h.pledge(BOB).unwrap().debit.0, 0);
+        assert_eq!(h.qd(BOB), 100 * ONE);
+        assert_eq!(h.pools().live_long_debit.0, 400 * ONE);
+    }
+
+    #[test]
+    fn transfer_call_redeems() {
+        let mut h = leveraged_long();
+        h.set_price(43 * ONE / 10); // Alice is at 107.5%, redeemable
+        let msg = format!(r#"{{"action":"redeem","min_out":"{}"}}"#, 10 * ONE - fee(10 * ONE));
+        assert_eq!(h.transfer_call(BOB, 43 * ONE, &msg), 0);
+        h.balance -= 10 * ONE - fee(10 * ONE);
+        assert_eq!(h.events("redeem"), 1);
+        assert_eq!(h.qd(BOB), 457 * ONE);
+        assert_eq!(h.pledge(ALICE).unwrap().debit.0, 357 * ONE);
+    }
+
+    #[test]
+    #[should_panic(expected = "Would get less than the minimum out")]
+    fn transfer_call_redeem_respects_min_out() {
+        let mut h = leveraged_long();
+        h.set_price(43 * ONE / 10);
+        h.transfer_call(BOB, 43 * ONE, &format!(r#"{{"action":"redeem","min_out":"{}"}}"#, 10 * ONE));
+    }
+
+    #[test]
+    #[should_panic(expected = "Message must be a deposit, repay or redeem action")]
+    fn transfer_call_needs_an_action() {
+        let mut h = leveraged_long();
+        h.transfer_call(BOB, ONE, r#"{"action":"borrow"}"#);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only QD or listed collateral can be transferred in")]
+    fn only_listed_tokens_come_in() {
+        let mut h = Harness::new(PRICE);
+        let mut wnear = MockToken::new("wnear");
+        wnear.mint(ALICE, ONE);
+        h.transfer_asset(&mut wnear, ALICE, ONE, STAKE);
+    }
+}
@@ -0,0 +1,205 @@
+use crate::*;
+
+use near_sdk::env;
+use near_sdk::serde::Serialize;
+
+/*  Who gets to do what, besides borrowers and SolvencyProviders:
+ *  - owner: manages the other roles and protocol parameters,
+ *    ownership is transferred in two steps (propose, then accept)
+ *  - guardian: can only hit the brakes, by pausing the protocol
+ *    or revoking a misbehaving oracle reporter
+ *  - reporters: whitelisted in the Oracle, push prices
+ */
+#[derive(BorshDeserialize, BorshSerialize, Debug, Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Roles {
+    pub owner: AccountId,
+    pub pending_owner: Option<AccountId>, // proposed by owner, until they accept
+    pub guardian: Option<AccountId>,
+    pub paused: bool,
+} impl Roles {
+    pub fn new(owner: AccountId) -> Self {
+        Self {
+            owner,
+            pending_owner: None,
+            guardian: None,
+            paused: false,
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract
+{
+    pub(crate) fn assert_owner(&self) {
+        assert!(env::predecessor_account_id() == self.roles.owner, "{}", ERR_NOT_OWNER);
+    }
+
+    pub(crate) fn assert_guardian(&self) {
+        let caller = env::predecessor_account_id();
+        assert!(caller == self.roles.owner
+            || Some(caller) == self.roles.guardian, "{}", ERR_NOT_GUARDIAN);
+    }
+
+    pub(crate) fn assert_not_paused(&self) {
+        assert!(!self.roles.paused, "{}", ERR_PAUSED);
+    }
+
+    pub fn get_roles(&self) -> &Roles {
+        &self.roles
+    }
+
+    #[payable]
+    pub fn propose_owner(&mut self, account: ValidAccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.roles.pending_owner = Some(account.into());
+    }
+
+    #[payable]
+    pub fn accept_owner(&mut self) {
+        assert_one_yocto();
+        let caller = env::predecessor_account_id();
+        assert!(Some(caller.clone()) == self.roles.pending_owner, "{}", ERR_NOT_PENDING_OWNER);
+        Event::SetOwner { old: self.roles.owner.clone(), new: caller.clone() }.emit();
+        self.roles.owner = caller;
+        self.roles.pending_owner = None;
+    }
+
+    #[payable]
+    pub fn set_guardian(&mut self, account: Option<ValidAccountId>) {
+        assert_one_yocto();
+        self.assert_owner();
+        let new: Option<AccountId> = account.map(|a| a.into());
+        Event::SetGuardian { old: self.roles.guardian.clone(), new: new.clone() }.emit();
+        self.roles.guardian = new;
+    }
+
+    #[payable]
+    pub fn add_reporter(&mut self, account: ValidAccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.oracle.add_reporter(account.as_ref());
+        Event::AddReporter { account: account.into() }.emit();
+    }
+
+    #[payable]
+    pub fn remove_reporter(&mut self, account: ValidAccountId) {
+        assert_one_yocto();
+        self.assert_guardian();
+        self.oracle.remove_reporter(account.as_ref());
+        self.remove_asset_reporter(account.as_ref());
+        Event::RemoveReporter { account: account.into() }.emit();
+    }
+
+    #[payable]
+    pub fn pause(&mut self) {
+        assert_one_yocto();
+        self.assert_guardian();
+        self.roles.paused = true;
+        Event::Pause { by: env::predecessor_account_id() }.emit();
+    }
+
+    #[payable]
+    pub fn unpause(&mut self) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.roles.paused = false;
+        Event::Unpause { by: env::predecessor_account_id() }.emit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::{testing_env, MockedBlockchain};
+
+    fn as_caller(account: usize) {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(account))
+            .attached_deposit(1)
+            .build());
+    }
+
+    fn setup() -> Contract {
+        as_caller(0);
+        Contract::new(accounts(0))
+    }
+
+    #[test]
+    fn two_step_ownership_transfer() {
+        let mut contract = setup();
+        contract.propose_owner(accounts(1));
+        assert_eq!(contract.get_roles().owner, accounts(0).to_string());
+        as_caller(1);
+        contract.accept_owner();
+        assert_eq!(contract.get_roles().owner, accounts(1).to_string());
+        assert!(contract.get_roles().pending_owner.is_none());
+        contract.add_reporter(accounts(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the proposed owner can accept ownership")]
+    fn only_proposed_owner_accepts() {
+        let mut contract = setup();
+        contract.propose_owner(accounts(1));
+        as_caller(2);
+        contract.accept_owner();
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn only_owner_adds_reporters() {
+        let mut contract = setup();
+        as_caller(1);
+        contract.add_reporter(accounts(1));
+    }
+
+    #[test]
+    fn guardian_pauses_and_revokes() {
+        let mut contract = setup();
+        contract.set_guardian(Some(accounts(1)));
+        contract.add_reporter(accounts(2));
+        as_caller(1);
+        contract.remove_reporter(accounts(2));
+        contract.pause();
+        assert!(contract.get_reporters().is_empty());
+        assert!(contract.get_roles().paused);
+    }
+
+    #[test]
+    fn roles_emit() {
+        let mut contract = setup();
+        contract.set_guardian(Some(accounts(1)));
+        contract.add_reporter(accounts(2));
+        contract.remove_reporter(accounts(2));
+        contract.pause();
+        contract.unpause();
+        let logs = get_logs();
+        let events: Vec<&str> = logs.iter()
+            .map(|l| l.split(r#""event":""#).nth(1).unwrap().split('"').next().unwrap())
+            .collect();
+        assert_eq!(events, vec!["set_guardian", "add_reporter", "remove_reporter", "pause", "unpause"]);
+        assert!(logs[0].ends_with(r#""data":{"old":null,"new":"bob"}}"#));
+        assert!(logs[3].ends_with(r#""data":{"by":"alice"}}"#));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn guardian_cannot_unpause() {
+        let mut contract = setup();
+        contract.set_guardian(Some(accounts(1)));
+        as_caller(1);
+        contract.pause();
+        contract.unpause();
+    }
+
+    #[test]
+    #[should_panic(expected = "Protocol is paused")]
+    fn paused_blocks_swaps() {
+        let mut contract = setup();
+        contract.pause();
+        contract.swap(U128(ONE), false, true);
+    }
+}
@@ -0,0 +1,270 @@
+use crate::*;
+
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::json_types::U128;
+use std::ops::{Add, AddAssign, Div, Mul, Sub, SubAssign};
+
+/*  Amounts in QD and in NEAR (yocto), prices (QD per NEAR) and ratios
+ *  (CRs, fees, shares) are all 1e24-scaled u128s, each with a type of
+ *  its own, so that mixing them up doesn't compile. Only conversions
+ *  that make sense are implemented:
+ *      NearAmount * Price = QdAmount, QdAmount / Price = NearAmount
+ *      QdAmount / QdAmount = Ratio, QdAmount * Ratio = QdAmount
+ *  (and the same for NearAmount; a Price scales by a Ratio too),
+ *  each rounding down like `ratio`.
+ *  Arithmetic panics on overflow, like the `checked_*` calls it stands
+ *  in for. To Borsh and JSON they're plain u128s, so neither the state
+ *  nor the views change; `.0` is the way out, into the risk math
+ */
+macro_rules! unit {
+    ($name:ident) => {
+        #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize,
+                 Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+        #[serde(crate = "near_sdk::serde", transparent)]
+        pub struct $name(pub u128);
+
+        impl $name {
+            pub const ZERO: Self = Self(0);
+            pub const MAX: Self = Self(u128::MAX);
+
+            pub fn is_zero(self) -> bool {
+                self.0 == 0
+            }
+
+            pub fn checked_add(self, other: Self) -> Option<Self> {
+                self.0.checked_add(other.0).map(Self)
+            }
+
+            pub fn checked_sub(self, other: Self) -> Option<Self> {
+                self.0.checked_sub(other.0).map(Self)
+            }
+
+            pub fn saturating_add(self, other: Self) -> Self {
+                Self(self.0.saturating_add(other.0))
+            }
+
+            pub fn saturating_sub(self, other: Self) -> Self {
+                Self(self.0.saturating_sub(other.0))
+            }
+        }
+
+        impl Add for $name {
+            type Output = Self;
+            fn add(self, other: Self) -> Self {
+                self.checked_add(other).expect(ERR_ADD)
+            }
+        }
+
+        impl Sub for $name {
+            type Output = Self;
+            fn sub(self, other: Self) -> Self {
+                self.checked_sub(other).expect(ERR_SUB)
+            }
+        }
+
+        impl AddAssign for $name {
+            fn add_assign(&mut self, other: Self) {
+                *self = *self + other;
+            }
+        }
+
+        impl SubAssign for $name {
+            fn sub_assign(&mut self, other: Self) {
+                *self = *self - other;
+            }
+        }
+
+        impl std::iter::Sum for $name {
+            fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+                iter.fold(Self::ZERO, |sum, amt| sum + amt)
+            }
+        }
+
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+
+        impl From<U128> for $name {
+            fn from(v: U128) -> Self {
+                Self(v.0)
+            }
+        }
+
+        impl From<$name> for U128 {
+            fn from(v: $name) -> Self {
+                U128(v.0)
+            }
+        }
+    };
+}
+
+// what's the same about QD and NEAR amounts: scaling them by ratios
+macro_rules! amount {
+    ($name:ident) => {
+        unit!($name);
+
+        impl Mul<Ratio> for $name {
+            type Output = Self;
+            fn mul(self, r: Ratio) -> Self {
+                Self(ratio(r.0, self.0, ONE))
+            }
+        }
+
+        impl Mul<$name> for Ratio {
+            type Output = $name;
+            fn mul(self, amt: $name) -> $name {
+                amt * self
+            }
+        }
+
+        impl Div<Ratio> for $name {
+            type Output = Self;
+            fn div(self, r: Ratio) -> Self {
+                Self(ratio(ONE, self.0, r.0))
+            }
+        }
+
+        impl Div for $name {
+            type Output = Ratio;
+            fn div(self, other: Self) -> Ratio {
+                Ratio(ratio(ONE, self.0, other.0))
+            }
+        }
+
+        impl Mul<u128> for $name { // so many of, like the bounty per Pledge
+            type Output = Self;
+            fn mul(self, times: u128) -> Self {
+                Self(self.0.checked_mul(times).expect(ERR_MUL))
+            }
+        }
+
+        impl Div<u128> for $name { // into parts, like the GuaranteeFund's cut
+            type Output = Self;
+            fn div(self, parts: u128) -> Self {
+                Self(self.0.checked_div(parts).expect(ERR_DIV))
+            }
+        }
+    };
+}
+
+amount!(QdAmount);
+amount!(NearAmount);
+unit!(Price); // QD per NEAR
+unit!(Ratio); // ONE is 100%
+
+impl Ratio {
+    pub const ONE: Self = Self(ONE);
+}
+
+impl Mul for Ratio {
+    type Output = Self;
+    fn mul(self, other: Self) -> Self {
+        Self(ratio(self.0, other.0, ONE))
+    }
+}
+
+impl Div for Ratio {
+    type Output = Self;
+    fn div(self, other: Self) -> Self {
+        Self(ratio(ONE, self.0, other.0))
+    }
+}
+
+// a Price at a discount or a premium, like an auction's
+impl Mul<Ratio> for Price {
+    type Output = Self;
+    fn mul(self, r: Ratio) -> Self {
+        Self(ratio(r.0, self.0, ONE))
+    }
+}
+
+impl Div<Ratio> for Price {
+    type Output = Self;
+    fn div(self, r: Ratio) -> Self {
+        Self(ratio(ONE, self.0, r.0))
+    }
+}
+
+impl Mul<Price> for NearAmount {
+    type Output = QdAmount;
+    fn mul(self, price: Price) -> QdAmount {
+        QdAmount(ratio(price.0, self.0, ONE))
+    }
+}
+
+impl Mul<NearAmount> for Price {
+    type Output = QdAmount;
+    fn mul(self, near: NearAmount) -> QdAmount {
+        near * self
+    }
+}
+
+impl Div<Price> for QdAmount {
+    type Output = NearAmount;
+    fn div(self, price: Price) -> NearAmount {
+        NearAmount(ratio(ONE, self.0, price.0))
+    }
+}
+
+/*  Either currency, for what's the same about both sides of a Pledge:
+ *  collateral and debt are compared by what they're worth in QD
+ */
+pub trait Amount: Copy + Ord + Default + Add<Output = Self> + Sub<Output = Self> {
+    fn in_qd(self, price: Price) -> QdAmount; // what it's worth
+    fn of_qd(qd: QdAmount, price: Price) -> Self; // how much of it is worth `qd`
+    fn is_zero(self) -> bool;
+}
+
+impl Amount for QdAmount {
+    fn in_qd(self, _price: Price) -> QdAmount {
+        self
+    }
+
+    fn of_qd(qd: QdAmount, _price: Price) -> Self {
+        qd
+    }
+
+    fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl Amount for NearAmount {
+    fn in_qd(self, price: Price) -> QdAmount {
+        self * price
+    }
+
+    fn of_qd(qd: QdAmount, price: Price) -> Self {
+        qd / price
+    }
+
+    fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_at_the_price() {
+        let price = Price(5 * ONE);
+        assert_eq!(NearAmount(3 * ONE) * price, QdAmount(15 * ONE));
+        assert_eq!(QdAmount(15 * ONE) / price, NearAmount(3 * ONE));
+        assert_eq!(QdAmount(11 * ONE) / QdAmount(10 * ONE), Ratio(11 * ONE / 10));
+        assert_eq!(QdAmount(11 * ONE) / Ratio(11 * ONE / 10), QdAmount(10 * ONE));
+        assert_eq!(Ratio(ONE / 2) * NearAmount(3 * ONE), NearAmount(3 * ONE / 2));
+        assert_eq!(NearAmount(ONE).in_qd(price), QdAmount::of_qd(QdAmount(5 * ONE), price));
+        // rounds down, like `ratio`
+        assert_eq!(QdAmount(ONE) / Price(3 * ONE), NearAmount(333333_333333_333333_333333));
+    }
+
+    #[test]
+    #[should_panic(expected = "Subtraction underflow")]
+    fn panics_below_zero() {
+        let _ = QdAmount(ONE) - QdAmount(ONE + 1);
+    }
+}
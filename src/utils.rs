@@ -6,275 +6,167 @@ use near_sdk::collections::TreeMap;
 use near_sdk::IntoStorageKey;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use std::ops::Bound;
-use core::f64;
 
-pub const TWO_PI: f64 = 2.0 * PI;
-pub const PERIOD: f64 = 1095.0; // = (365*24)/8h of dues 
-pub const ONE_HOUR: u64 = 360_000_000_000;
+pub const ONE_HOUR: u64 = 3_600_000_000_000; // nanosecs
+pub const ONE_YEAR: u64 = 31_536_000_000_000_000; // 365 days in nanosecs
 pub const EIGHT_HOURS: u64 = 28_800_000_000_000; // nanosecs
 pub const ONE: u128 = 1_000000_000000_000000_000000;
-pub const PI: f64 = 3.14159265358979323846264338327950288;
-pub const MIN_CR: u128 = 1_100_000_000_000_000_000_000_000;
-pub const KILL_CR: u128 = 1_000_000_000_000_000_000_000_000;
-pub const DOT_OH_NINE: u128 = 90_909_090_909_090_909_090_909;
-pub const FEE: u128 = 9_090_909_090_909_090_909_090; // TODO votable FEE, via SputnikV3
-pub const MIN_DEBT: u128 = 90_909_090_909_090_909_090_909_090;
-
-// pub stNEAR: AccountId = "meta-pool.near".parse().unwrap(); // mainnet
-// pub stNEAR: AccountId = "meta-v2.pool.testnet".parse().unwrap();
-// pub stNEAR: AccountId = "v2.ref-finance.near".parse().unwrap(); // mainnet
-// pub stNEAR: AccountId = "v2.ref-finance.testnet".parse().unwrap();
+// initial values of the governable Config
+pub const MIN_CR: Ratio = Ratio(1_100_000_000_000_000_000_000_000);
+pub const KILL_CR: Ratio = Ratio(1_000_000_000_000_000_000_000_000);
+pub const FEE: Ratio = Ratio(9_090_909_090_909_090_909_090);
+pub const MIN_DEBT: QdAmount = QdAmount(90_909_090_909_090_909_090_909_090);
+pub const CRANK_BATCH: u32 = 42; // most Pledges per `update` call, within the gas attached
+pub const CRANK_GAS_RESERVE: Gas = 20_000_000_000_000; // left for the rest of an `update` call
+pub const TURN_MANY: u32 = 10;
+pub const MAX_TARGET_CR: Ratio = Ratio(10 * ONE); // highest CR a Pledge may deleverage up to
+pub const CR_BAND: u128 = 10_000_000_000_000_000_000_000; // CR trees group Pledges by bands 1% wide
+pub const AUCTION_START: Ratio = Ratio(1_200_000_000_000_000_000_000_000); // lots start at 120% of the price
+pub const AUCTION_FLOOR: Ratio = Ratio(700_000_000_000_000_000_000_000); // and decay down to 70% of it
+pub const AUCTION_DURATION: u64 = ONE_HOUR; // nanosecs
+pub const CRANK_BOUNTY: QdAmount = QdAmount(10_000_000_000_000_000_000_000); // 0.01 QD per Pledge stressed
+pub const CLIP_BOUNTY: Ratio = Ratio(5_000_000_000_000_000_000_000); // 0.5% of the debt a clip clears
+pub const CLIP_BOUNTY_CAP: QdAmount = QdAmount(100_000_000_000_000_000_000_000_000); // 100 QD per clip
+pub const EPOCH_BOUNTY_CAP: QdAmount = QdAmount(1_000_000_000_000_000_000_000_000_000); // 1000 QD per update interval
+pub const GFUND_DIV: u32 = 11;
+pub const MIN_SCALE: u128 = 42 * ONE / 1000;
+pub const MAX_SCALE: u128 = 42 * ONE / 10;
+pub const PRICE_MAX_AGE: u64 = 600_000_000_000; // 10 minutes in nanosecs
+pub const PRICE_HISTORY: u64 = 144; // max number of aggregated prices kept
+pub const VOL_SCALE: u128 = 10_000_000; // volatility is a fraction scaled by this
+pub const DEFAULT_VOL: u128 = 4_666_066; // 46.66% annualized, until it can be estimated
+pub const VOL_INTERVAL: u64 = ONE_HOUR; // min time between closes sampled for volatility
+pub const VOL_WINDOW: u64 = 168; // a week's worth of hourly closes
+pub const VOL_MIN_CLOSES: u64 = 24; // a day's worth of closes before trusting the estimate
+pub const DEFAULT_QD_VOL: u128 = 100_000; // 1% annualized, QD's own (against the dollar)
+pub const CORR_SCALE: i64 = 10_000_000; // correlation is a fraction scaled by this
+pub const NEAR_ASSET: &str = "near"; // how NEAR is keyed among other assets, QD is keyed as this contract
+
+// collateral besides NEAR gets listed with `set_asset`, e.g. stNEAR is
+// "meta-pool.near" on mainnet ("meta-v2.pool.testnet" on testnet), and
+// wNEAR is "wrap.near" ("wrap.testnet")
+pub const MAX_HAIRCUT: u128 = 500_000_000_000_000_000_000_000; // 50% of an asset's price
+pub const MAX_ASSET_VOL: u128 = 5 * VOL_SCALE; // 500% annualized
+pub const GAS_FOR_FT_TRANSFER: Gas = 10_000_000_000_000;
+pub const GAS_FOR_RESOLVE: Gas = 10_000_000_000_000; // `on_asset_withdrawn`
 
 // ======= Error Strings ==================
 
-pub const ERR_ADD: &'static str =
+pub const ERR_ADD: &str =
     "Addition overflow";
-pub const ERR_DIV: &'static str =
+pub const ERR_DIV: &str =
     "Division overflow";
-pub const ERR_MUL: &'static str =
+pub const ERR_MUL: &str =
     "Multiplication overflow";
-pub const ERR_SUB: &'static str =
+pub const ERR_SUB: &str =
     "Subtraction underflow";
-pub const ERR_BELOW_MIN_CR: &'static str =
+pub const ERR_BELOW_MIN_CR: &str =
     "Cannot do operation that would result in CR below min";
-pub const ERR_AMT_TOO_LOW: &'static str = 
+pub const ERR_MIN_DEBT: &str = 
+    "Value of debt must be worth above the minimum debt in QD";
+pub const ERR_AMT_TOO_LOW: &str = 
     "Amount must be larger than 0";
-pub const ERR_MAX_LEVERAGE: &'static str = 
+pub const ERR_MAX_LEVERAGE: &str = 
     "Leverage must be between 2-10x";
-// TODO
-// pub const OldVoteNotFound: &'static str = 
-//     "OldVoteNotFound";
-// pub const WrongWeightsLength: &'static str = 
-//     "WrongWeightsLength";
-// pub const MustStakeBeforeVote: &'static str = 
-//     "MustStakeBeforeVote";
-// pub const ZeroStakeBeforeVote: &'static str = 
-//     "ZeroStakeBeforeVote";
+pub const ERR_NO_STATE: &str =
+    "There is no state to migrate";
+pub const ERR_UNKNOWN_STATE: &str =
+    "Cannot decode the state in the layout of its version";
+pub const ERR_MIGRATE_MID_CRANK: &str =
+    "Cannot migrate while an update is in progress";
+pub const ERR_CRANK_GAS: &str =
+    "Not enough gas attached to update a single Pledge";
+pub const ERR_NO_LOT: &str =
+    "There is no such lot";
+pub const ERR_LOT_NOT_EXPIRED: &str =
+    "The lot is still being auctioned";
+pub const ERR_MIN_OUT: &str =
+    "Would get less than the minimum out";
+pub const ERR_NOTHING_TO_CLAIM: &str =
+    "There are no keeper rewards to claim";
+pub const ERR_NOT_QD: &str =
+    "Only QD or listed collateral can be transferred in";
+pub const ERR_NO_ASSET: &str =
+    "This asset isn't listed as collateral";
+pub const ERR_BAD_MSG: &str =
+    "Message must be a deposit, repay or redeem action";
+pub const ERR_NO_DEBT: &str =
+    "There is no debt to repay";
+pub const ERR_NO_PLEDGE: &str =
+    "Pledge doesn't exist";
+pub const ERR_TARGET: &str =
+    "Target CR must be between the configured min_cr and 1000%";
+pub const ERR_OUT_OF_BOUNDS: &str =
+    "Parameter is out of bounds";
+pub const ERR_NOT_OWNER: &str =
+    "Only the owner can call this method";
+pub const ERR_NOT_PENDING_OWNER: &str =
+    "Only the proposed owner can accept ownership";
+pub const ERR_NOT_GUARDIAN: &str =
+    "Only the owner or guardian can call this method";
+pub const ERR_PAUSED: &str =
+    "Protocol is paused";
+pub const ERR_STALE_PRICE: &str =
+    "Price is stale, waiting for oracle reporters";
+pub const ERR_NOT_REPORTER: &str =
+    "Only whitelisted reporters can report prices";
+pub const ERR_PRICE_ZERO: &str =
+    "Price must be larger than 0";
+pub const ERR_OLD_VOTE_NOT_FOUND: &str = 
+    "Old vote not found";
+pub const ERR_WRONG_WEIGHTS_LENGTH: &str = 
+    "Wrong weights length";
+pub const ERR_MUST_STAKE_BEFORE_VOTE: &str = 
+    "Must deposit into the SolvencyPool before voting";
+pub const ERR_ZERO_STAKE_BEFORE_VOTE: &str = 
+    "Cannot vote with zero SolvencyPool stake";
+pub const ERR_VOTE_STORAGE: &str = 
+    "Attach enough NEAR to pay for the vote's storage";
+pub const ERR_MARGIN_STORAGE: &str =
+    "Attach enough NEAR to pay for cross-margin's storage";
 // ========================================
 
 #[derive(Debug, BorshDeserialize, BorshSerialize)]
 pub enum Sort {
     Composite,
     CollaterlizationRatio,
-}
-
-pub fn ratio(multiplier:u128, numerator: u128, denominator: u128) -> u128 { 
-    return (
-        U256::from(numerator)
-            .checked_mul(U256::from(multiplier)).expect("Overflow")
-            .checked_div(U256::from(denominator)).expect("Overflow")
-    ).as_u128();
-}
-
-pub fn computeCR(_price: u128, _collat: u128, _debt: u128, _short: bool) -> u128 {
-    if _debt > 0 {
-        // assert!(_collat > 0, "never supposed to happen");
-        if _collat > 0 {
-            if _short {
-                let debt = ratio(_price, _debt, ONE);
-                return ratio(ONE, _collat, debt);
-            } else {
-                return ratio(_price, _collat, _debt);
-            }
-        }
-        else {
-            return 0;
-        }
-    } 
-    else if _collat > 0 {
-        return u128::MAX;
-    }
-    return 0;
-}
-
-// Newton's method of integer square root. 
-// pub fn integer_sqrt(value: U256) -> U256 {
-//     let mut guess: U256 = (value + U256::one()) >> 1;
-//     let mut res = value;
-//     while guess < res {
-//         res = guess;
-//         guess = (value / guess + guess) >> 1;
-//     }
-//     res
-// }
-
-pub fn RationalApproximation(t: f64) -> f64 {
-    // Abramowitz and Stegun formula 26.2.23.
-    // The absolute value of the error should be less than 4.5 e-4.
-    let c: [f64; 3] = [2.515517, 0.802853, 0.010328];
-    let d: [f64; 3] = [1.432788, 0.189269, 0.001308];
-    t - ((c[2] * t + c[1]) * t + c[0]) / 
-        (((d[2] * t + d[1]) * t + d[0]) * t + 1.0)
-}
-
-pub fn NormalCDFInverse(p: f64) -> f64 {
-    assert!(p > 0.0 && p < 1.0);
-    // See article above for explanation of this section.
-    if p < 0.5 { // F^-1(p) = -G^-1(p)
-        let n: f64 = -2.0 * p.ln();
-        return -1.0 * RationalApproximation( n.sqrt() );
-    }
-    else { // F^-1(p) = G^-1(1-p)
-        let l: f64 = 1.0 - p;
-        let n: f64 = -2.0 * l.ln();
-        return RationalApproximation(n.sqrt());
-    }
-}
-
-// calculate % loss given short Pledge's portfolio volatility & the statistical assumption of normality
-pub fn stress(avg: bool, sqrt_var: f64, short: bool) -> f64 { // max portfolio loss in %
-    let mut alpha: f64 = 0.90; // 10% of the worst case scenarios
-    if avg {
-        alpha = 0.50;  // 50% of the avg case scenarios
-    }
-    let cdf = NormalCDFInverse(alpha);
-    let e1 = -1.0 * (cdf * cdf) / 2.0;
-    let mut e2 = ((e1.exp() / TWO_PI.sqrt()) / (1.0 - alpha)) * sqrt_var;
-    if short {
-        return e2.exp() - 1.0;    
-    } else {
-        e2 *= -1.0;
-        return -1.0 * (e2.exp() - 1.0);
-    }
-}
-
-// Used for pricing put & call options for borrowers contributing to the ActivePool
-pub fn price(payoff: f64, scale: f64, val_crypto: f64, val_quid: f64, ivol: f64, short: bool) -> f64 {
-    let max_rate: f64 = 0.42;
-    let min_rate: f64 = 0.0042 * scale; // * calibrate
-    let sqrt_two: f64 = 2.0_f64.sqrt();
-    let div = val_crypto / val_quid;
-    let ln = div.ln();
-    let d: f64 = (ln + (ivol * ivol / -2.0)/* times calibrate */) / ivol; // * calibrate
-    let D = d / sqrt_two;
-    let mut rate: f64;
-    if short { // erfc is used instead of normal distribution
-        rate = (payoff * libm::erfc(-1.0 * D) / 2.0) / val_crypto;
-    } else {
-        rate = (payoff * libm::erfc(D) / 2.0) / val_quid;
-    }
-    // rate *= calibrate;
-    if rate > max_rate {
-        rate = max_rate;
-    } else if rate < min_rate {
-        rate = min_rate;
-    }
-    return rate;
+    Banded, // by band of CR, then by debt (largest first)
 }
 
 // ======= TreeMap wrapper for sorting by CR, by 4ire Labs ==================
 
-#[derive(BorshDeserialize, BorshSerialize, Clone)]
-pub enum SortKeys<U: PledgeForTreeMap> {
-    CRKey { pledge: U, key: (u128, AccountId) },
-    CompositeKey { pledge: U, key: (i128, u128, AccountId) },
-}
-
-impl<U: PledgeForTreeMap> std::cmp::PartialEq for SortKeys<U> {
-    fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (
-                SortKeys::CRKey {
-                    pledge: _,
-                    key: self_key,
-                },
-                SortKeys::CRKey {
-                    pledge: _,
-                    key: other_key,
-                },
-            ) => self_key == other_key,
-            (
-                SortKeys::CompositeKey {
-                    pledge: _,
-                    key: self_key,
-                },
-                SortKeys::CompositeKey {
-                    pledge: _,
-                    key: other_key,
-                },
-            ) => self_key == other_key,
-            (SortKeys::CRKey { pledge: _, key: _ }, SortKeys::CompositeKey { pledge: _, key: _ }) => {
-                env::panic(b"Using different sort")
-            }
-            (SortKeys::CompositeKey { pledge: _, key: _ }, SortKeys::CRKey { pledge: _, key: _ }) => {
-                env::panic(b"Using different sort")
-            }
-        }
-    }
-}
-
-impl<U: PledgeForTreeMap> std::cmp::PartialOrd for SortKeys<U> {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        match (self, other) {
-            (
-                SortKeys::CRKey {
-                    pledge: _,
-                    key: self_key,
-                },
-                SortKeys::CRKey {
-                    pledge: _,
-                    key: other_key,
-                },
-            ) => Some(self_key.cmp(other_key)),
-            (
-                SortKeys::CompositeKey {
-                    pledge: _,
-                    key: self_key,
-                },
-                SortKeys::CompositeKey {
-                    pledge: _,
-                    key: other_key,
-                },
-            ) => Some(self_key.cmp(other_key)),
-            (SortKeys::CRKey { pledge: _, key: _ }, SortKeys::CompositeKey { pledge: _, key: _ }) => {
-                env::panic(b"Using different sort")
-            }
-            (SortKeys::CompositeKey { pledge: _, key: _ }, SortKeys::CRKey { pledge: _, key: _ }) => {
-                env::panic(b"Using different sort")
-            }
-        }
-    }
+/*  Keys don't depend on the price: Pledges are ranked by collateral
+ *  per unit of debt in their own units (NEAR per QD for longs, QD per
+ *  NEAR for shorts), which sorts them the same as their CR at any price.
+ *  The key each Pledge was inserted with is indexed by its AccountId,
+ *  so it can be removed after its balances changed. Banded keys group
+ *  Pledges into bands of CR_BAND (on a log scale, so a band is as wide
+ *  at any price), and sort each band by debt, largest first
+ */
+#[derive(BorshDeserialize, BorshSerialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SortKeys { // a map only ever holds one kind
+    CRKey { key: (u128, AccountId) },
+    CompositeKey { key: (i128, u128, AccountId) },
+    BandKey { key: (i32, u128, AccountId) }, // band, u128::MAX - debt
 }
 
-impl<U: PledgeForTreeMap> std::cmp::Eq for SortKeys<U> {}
-impl<U: PledgeForTreeMap> std::cmp::Ord for SortKeys<U> {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        match (self, other) {
-            (
-                SortKeys::CRKey {
-                    pledge: _,
-                    key: self_key,
-                },
-                SortKeys::CRKey {
-                    pledge: _,
-                    key: other_key,
-                },
-            ) => self_key.cmp(other_key),
-            (
-                SortKeys::CompositeKey {
-                    pledge: _,
-                    key: self_key,
-                },
-                SortKeys::CompositeKey {
-                    pledge: _,
-                    key: other_key,
-                },
-            ) => self_key.cmp(other_key),
-            (SortKeys::CRKey { pledge: _, key: _ }, SortKeys::CompositeKey { pledge: _, key: _ }) => {
-                env::panic(b"Using different sort")
-            }
-            (SortKeys::CompositeKey { pledge: _, key: _ }, SortKeys::CRKey { pledge: _, key: _ }) => {
-                env::panic(b"Using different sort")
-            }
-        }
+// band of collateral per unit of debt (1e24-scaled), lowest CR first
+pub fn band(rank: u128) -> i32 {
+    if rank == 0 { // no collateral at all
+        return i32::MIN;
     }
+    fixed::ln(rank).div_euclid(fixed::ln(ONE + CR_BAND)) as i32
 }
 
-impl<U: PledgeForTreeMap> SortKeys<U> {
-    pub fn new(pledge: &U, id: AccountId, sort: &Sort, short: bool, price: u128) -> Self {
+impl SortKeys {
+    pub fn new<U: PledgeForTreeMap>(pledge: &U, sort: &Sort, short: bool) -> Self {
+        let id = pledge.get_id();
+        let rank = pledge.get_coll_per_debt(short).0;
+        // a side's debt in its own units, which is all a sort within the side needs
+        let debt = if short { pledge.get_short_debt().0 } else { pledge.get_long_debt().0 };
         match sort {
             Sort::Composite => {
-                let mut deb = pledge.get_debt_amt(short).0;
+                let mut deb = debt;
                 let mut i = 0;
                 let magnitude: i128 = loop {
                     if deb / 10 == 0 {
@@ -283,149 +175,131 @@ impl<U: PledgeForTreeMap> SortKeys<U> {
                     deb /= 10;
                     i += 1;
                 };
-                SortKeys::CompositeKey {
-                    pledge: pledge.clone(),
-                    key: (-magnitude, pledge.get_CR(short, price).0, id),
-                }
+                SortKeys::CompositeKey { key: (-magnitude, rank, id) }
             }
-            Sort::CollaterlizationRatio => SortKeys::CRKey {
-                pledge: pledge.clone(),
-                key: (pledge.get_CR(short, price).0, id),
+            Sort::CollaterlizationRatio => SortKeys::CRKey { key: (rank, id) },
+            Sort::Banded => SortKeys::BandKey {
+                key: (band(rank), u128::MAX - debt, id)
             },
         }
     }
-    pub fn get_pledge(&self) -> U {
-        match self {
-            SortKeys::CRKey { pledge, key: _ } => pledge.clone(),
-            SortKeys::CompositeKey { pledge, key: _ } => pledge.clone(),
-        }
-    }
 }
 
 #[derive(BorshDeserialize, BorshSerialize)]
 pub struct PledgesTreeMap<K: PledgeForTreeMap, V: BorshSerialize + BorshDeserialize> {
-    value: TreeMap<SortKeys<K>, V>,
+    value: TreeMap<SortKeys, (K, V)>,
+    index: LookupMap<AccountId, SortKeys>, // key each Pledge is in the tree under
     type_of_sort: Sort,
     short: bool
 }
 
-impl<V: BorshSerialize + BorshDeserialize, K: PledgeForTreeMap> PledgesTreeMap<K, V> {
+impl<V: BorshSerialize + BorshDeserialize + Clone, K: PledgeForTreeMap> PledgesTreeMap<K, V> {
     pub fn new<S: IntoStorageKey>(prefix: S, type_of_sort: Sort, short: bool) -> Self {
         let prefix = prefix.into_storage_key();
         PledgesTreeMap {
             type_of_sort,
-            value: TreeMap::new([&prefix[..], &[b'v']].concat()),
+            value: TreeMap::new([&prefix[..], b"v"].concat()),
+            index: LookupMap::new([&prefix[..], b"i"].concat()),
             short
         }
     }
 
+    fn sort_key(&self, key: &K) -> SortKeys {
+        SortKeys::new(key, &self.type_of_sort, self.short)
+    }
+
+    fn pledge_at(&self, sort_key: Option<SortKeys>) -> Option<K> {
+        sort_key.and_then(|k| self.value.get(&k)).map(|(pledge, _)| pledge)
+    }
+
     pub fn len(&self) -> u64 {
         self.value.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.value.len() == 0
+    }
+
     pub fn clear(&mut self) {
+        for (pledge, _) in self.to_vec() {
+            self.index.remove(&pledge.get_id());
+        }
         self.value.clear()
     }
 
-    pub fn contains_key(&self, key: &K, price: u128) -> bool {
-        self.value
-            .contains_key(&SortKeys::new(key, key.get_id(), &self.type_of_sort, self.short, price))
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.index.contains_key(&key.get_id())
     }
 
-    pub fn get(&self, key: &K, price: u128) -> Option<V> {
-        self.value
-            .get(&SortKeys::new(key, key.get_id(), &self.type_of_sort, self.short, price))
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.index.get(&key.get_id())
+            .and_then(|sort_key| self.value.get(&sort_key))
+            .map(|(_, val)| val)
     }
 
-    pub fn insert(&mut self, key: &K, val: &V, price: u128) -> Option<V> {
-        self.value
-            .insert(&SortKeys::new(key, key.get_id(), &self.type_of_sort, self.short, price), val)
+    // (re)places the Pledge by its current balances
+    pub fn insert(&mut self, key: &K, val: &V) -> Option<V> {
+        let old = self.remove(key);
+        let sort_key = self.sort_key(key);
+        self.index.insert(&key.get_id(), &sort_key);
+        self.value.insert(&sort_key, &(key.clone(), val.clone()));
+        old
     }
 
-    pub fn remove(&mut self, key: &K, price: u128) -> Option<V> {
-        self.value
-            .remove(&SortKeys::new(key, key.get_id(), &self.type_of_sort, self.short, price))
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.index.remove(&key.get_id())
+            .and_then(|sort_key| self.value.remove(&sort_key))
+            .map(|(_, val)| val)
     }
 
     pub fn min(&self) -> Option<K> {
-        self.value.min().map(|sort_key| sort_key.get_pledge())
+        self.pledge_at(self.value.min())
     }
 
     pub fn max(&self) -> Option<K> {
-        self.value.max().map(|sort_key| sort_key.get_pledge())
+        self.pledge_at(self.value.max())
     }
 
-    pub fn higher(&self, key: &K, price: u128) -> Option<K> {
-        self.value
-            .higher(&SortKeys::new(key, key.get_id(), &self.type_of_sort, self.short, price))
-            .map(|sort_key| sort_key.get_pledge())
+    pub fn higher(&self, key: &K) -> Option<K> {
+        self.pledge_at(self.value.higher(&self.sort_key(key)))
     }
 
-    pub fn lower(&self, key: &K, price: u128) -> Option<K> {
-        self.value
-            .lower(&SortKeys::new(key, key.get_id(), &self.type_of_sort, self.short, price))
-            .map(|sort_key| sort_key.get_pledge())
+    pub fn lower(&self, key: &K) -> Option<K> {
+        self.pledge_at(self.value.lower(&self.sort_key(key)))
     }
 
-    pub fn ceil_key(&self, key: &K, price: u128) -> Option<K> {
-        self.value
-            .ceil_key(&SortKeys::new(key, key.get_id(), &self.type_of_sort, self.short, price))
-            .map(|sort_key| sort_key.get_pledge())
+    pub fn ceil_key(&self, key: &K) -> Option<K> {
+        self.pledge_at(self.value.ceil_key(&self.sort_key(key)))
     }
 
-    pub fn floor_key(&self, key: &K, price: u128) -> Option<K> {
-        self.value
-            .floor_key(&SortKeys::new(key, key.get_id(), &self.type_of_sort, self.short, price))
-            .map(|sort_key| sort_key.get_pledge())
+    pub fn floor_key(&self, key: &K) -> Option<K> {
+        self.pledge_at(self.value.floor_key(&self.sort_key(key)))
     }
 
     pub fn iter<'a>(&'a self) -> impl Iterator<Item = (K, V)> + 'a {
-        self.value
-            .iter()
-            .map(|(sort_key, val)| (sort_key.get_pledge(), val))
+        self.value.iter().map(|(_, entry)| entry)
     }
 
-    pub fn iter_from<'a>(&'a self, key: K, price: u128) -> impl Iterator<Item = (K, V)> + 'a {
-        self.value
-            .iter_from(SortKeys::new(&key, key.get_id(), &self.type_of_sort, self.short, price))
-            .map(|(sort_key, val)| (sort_key.get_pledge(), val))
+    pub fn iter_from<'a>(&'a self, key: K) -> impl Iterator<Item = (K, V)> + 'a {
+        self.value.iter_from(self.sort_key(&key)).map(|(_, entry)| entry)
     }
 
     pub fn iter_rev<'a>(&'a self) -> impl Iterator<Item = (K, V)> + 'a {
-        self.value
-            .iter_rev()
-            .map(|(sort_key, val)| (sort_key.get_pledge(), val))
+        self.value.iter_rev().map(|(_, entry)| entry)
     }
 
-    pub fn iter_rev_from<'a>(&'a self, key: K, price: u128) -> impl Iterator<Item = (K, V)> + 'a {
-        self.value
-            .iter_rev_from(SortKeys::new(&key, key.get_id(), &self.type_of_sort, self.short, price))
-            .map(|(sort_key, val)| (sort_key.get_pledge(), val))
+    pub fn iter_rev_from<'a>(&'a self, key: K) -> impl Iterator<Item = (K, V)> + 'a {
+        self.value.iter_rev_from(self.sort_key(&key)).map(|(_, entry)| entry)
     }
 
-    pub fn range<'a>(&'a self, r: (Bound<K>, Bound<K>), price: u128) -> impl Iterator<Item = (K, V)> + 'a {
-        let (lo, hi) = r;
-        let lo = match &lo {
-            Bound::Included(key) => {
-                Bound::Included(SortKeys::new(key, key.get_id(), &self.type_of_sort, self.short, price))
-            }
-            Bound::Excluded(key) => {
-                Bound::Excluded(SortKeys::new(key, key.get_id(), &self.type_of_sort, self.short, price))
-            }
-            _ => Bound::Unbounded,
-        };
-        let hi = match &hi {
-            Bound::Included(key) => {
-                Bound::Included(SortKeys::new(key, key.get_id(), &self.type_of_sort, self.short, price))
-            }
-            Bound::Excluded(key) => {
-                Bound::Excluded(SortKeys::new(key, key.get_id(), &self.type_of_sort, self.short, price))
-            }
+    pub fn range<'a>(&'a self, r: (Bound<K>, Bound<K>)) -> impl Iterator<Item = (K, V)> + 'a {
+        let bound = |b: &Bound<K>| match b {
+            Bound::Included(key) => Bound::Included(self.sort_key(key)),
+            Bound::Excluded(key) => Bound::Excluded(self.sort_key(key)),
             _ => Bound::Unbounded,
         };
-        self.value
-            .range((lo, hi))
-            .map(|(sort_key, val)| (sort_key.get_pledge(), val))
+        let (lo, hi) = (bound(&r.0), bound(&r.1));
+        self.value.range((lo, hi)).map(|(_, entry)| entry)
     }
 
     pub fn to_vec(&self) -> Vec<(K, V)> {
@@ -435,6 +309,38 @@ impl<V: BorshSerialize + BorshDeserialize, K: PledgeForTreeMap> PledgesTreeMap<K
     pub fn get_top(&self, n: usize) -> Vec<(K, V)> {
         self.iter().take(n).collect::<Vec<(K, V)>>()
     }
+
+    /*  Pledges with a CR in [lo, hi) at `price` (either bound optional),
+     *  riskiest band first, largest debt first within a band. Only the
+     *  bands that may hold such Pledges are visited (Banded sort only)
+     */
+    pub fn between<'a>(&'a self, lo: Option<Ratio>, hi: Option<Ratio>, price: Price) -> impl Iterator<Item = (K, V)> + 'a {
+        assert!(matches!(self.type_of_sort, Sort::Banded), "Not sorted by bands");
+        let short = self.short;
+        // CR is rank * price for longs, rank / price for shorts
+        let rank = |cr: Ratio| if short { ratio(cr.0, price.0, ONE) } else { ratio(cr.0, ONE, price.0) };
+        // a band to either side, in case of rounding at the edges
+        let from = |cr: Ratio| SortKeys::BandKey { key: (band(rank(cr)).saturating_sub(1), 0, String::new()) };
+        let to = |cr: Ratio| SortKeys::BandKey { key: (band(rank(cr)).saturating_add(2), 0, String::new()) };
+        // TreeMap::range yields nothing when unbounded below, so start at the min
+        let mut lo_key = match lo {
+            Some(cr) => Bound::Included(from(cr)),
+            None => self.value.min().map_or(Bound::Unbounded, Bound::Included),
+        };
+        let mut hi_key = hi.map_or(Bound::Unbounded, |cr| Bound::Excluded(to(cr)));
+        if let (Bound::Included(a), Bound::Excluded(b)) = (&lo_key, &hi_key) {
+            if a >= b { // nothing in between, and TreeMap::range panics on it
+                lo_key = Bound::Excluded(b.clone());
+                hi_key = Bound::Included(b.clone());
+            }
+        }
+        self.value.range((lo_key, hi_key))
+            .map(|(_, entry)| entry)
+            .filter(move |(pledge, _)| {
+                let cr = pledge.get_CR(short, price);
+                lo.is_none_or(|lo| cr >= lo) && hi.is_none_or(|hi| cr < hi)
+            })
+    }
 }
 
 impl<'a, K: PledgeForTreeMap, V: BorshSerialize + BorshDeserialize> IntoIterator
@@ -452,8 +358,8 @@ impl<'a, K: PledgeForTreeMap, V: BorshSerialize + BorshDeserialize> IntoIterator
 }
 
 pub struct PledgesTreeMapIntoIterator<'a, K: PledgeForTreeMap, V: BorshSerialize + BorshDeserialize> {
-    map: &'a TreeMap<SortKeys<K>, V>,
-    current_key: Option<SortKeys<K>>,
+    map: &'a TreeMap<SortKeys, (K, V)>,
+    current_key: Option<SortKeys>,
 }
 
 impl<'a, K: PledgeForTreeMap, V: BorshSerialize + BorshDeserialize> Iterator
@@ -464,13 +370,25 @@ impl<'a, K: PledgeForTreeMap, V: BorshSerialize + BorshDeserialize> Iterator
         match &self.current_key {
             None => None,
             Some(current_key) => {
-                let result = self
-                    .map
-                    .get(current_key)
-                    .map(|val| (current_key.get_pledge(), val));
-                self.current_key = self.map.ceil_key(current_key);
+                let result = self.map.get(current_key);
+                self.current_key = self.map.higher(current_key);
                 result
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bands_are_one_percent_wide() {
+        assert_eq!(band(ONE), 0);
+        assert_eq!(band(ONE + ONE / 100), 1); // exactly the next band
+        assert_eq!(band(ONE + ONE / 100 - 1), 0);
+        assert_eq!(band(ONE - 1), -1);
+        assert_eq!(band(11 * ONE / 10), 9); // 1.01^9 < 1.1 < 1.01^10
+        assert_eq!(band(0), i32::MIN);
+    }
+}
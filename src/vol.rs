@@ -0,0 +1,104 @@
+use crate::*;
+
+use near_sdk::{Balance, Timestamp};
+use near_sdk::json_types::{WrappedTimestamp, U128};
+
+/*  Realized volatility of the NEAR price (in QD), as it's used by
+ *  `stress` and `price`: annualized, expressed as a fraction that's
+ *  scaled by VOL_SCALE (so 4666066 stands for 46.66% per year).
+ *  Closes are sampled from the oracle at most once per VOL_INTERVAL
+ *  into a rolling window, and the estimate is close-to-close: each
+ *  squared log return is annualized by the time that elapsed since
+ *  the previous close, and these are averaged over the whole window
+ *  (the sum is kept as a running total, so sampling is O(1) in gas)
+ */
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Volatility {
+    closes: History, // rolling window of sampled prices
+    sum: u128, // sum of annualized squared log returns between the closes, 1e24-scaled
+    pub annual: u128, // current estimate, scaled by VOL_SCALE
+} impl Volatility {
+    pub fn new(prefix: Vec<u8>) -> Self {
+        Self {
+            closes: History::new(prefix, VOL_WINDOW),
+            sum: 0,
+            annual: DEFAULT_VOL, // until there are enough closes
+        }
+    }
+
+    pub fn observe(&mut self, price: Balance, now: Timestamp) {
+        let close = Report { price: U128(price), timestamp: WrappedTimestamp::from(now) };
+        if let Some(last) = self.closes.latest() {
+            if now.saturating_sub(last.timestamp.0) < VOL_INTERVAL {
+                return;
+            }
+            if self.closes.len() == VOL_WINDOW { // oldest return drops out of the window
+                let oldest = self.closes.get(0, 2);
+                self.sum -= between(&oldest[0], &oldest[1]);
+            }
+            self.sum += between(&last, &close);
+        }
+        self.closes.push(close);
+        if self.closes.len() >= VOL_MIN_CLOSES {
+            self.annual = realized_vol(self.sum, self.closes.len() - 1);
+        }
+    }
+}
+
+fn between(from: &Report, to: &Report) -> u128 {
+    squared_return(from.price.0, from.timestamp.0, to.price.0, to.timestamp.0)
+}
+
+#[near_bindgen]
+impl Contract
+{
+    // annualized volatility of NEAR as a 1e24-scaled fraction, for use in `stress` and `price`
+    pub(crate) fn annual_vol(&self) -> u128 {
+        self.get_vol() * (ONE / VOL_SCALE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::{testing_env, MockedBlockchain};
+
+    fn setup() -> Volatility {
+        testing_env!(VMContextBuilder::new().build());
+        Volatility::new(b"x".to_vec())
+    }
+
+    #[test]
+    fn default_until_enough_closes() {
+        let mut vol = setup();
+        for i in 0..(VOL_MIN_CLOSES - 1) {
+            vol.observe((i as Balance + 1) * ONE, i * ONE_HOUR);
+        }
+        assert_eq!(vol.annual, DEFAULT_VOL);
+    }
+
+    #[test]
+    fn flat_price_has_no_vol() {
+        let mut vol = setup();
+        for i in 0..VOL_MIN_CLOSES {
+            vol.observe(5 * ONE, i * ONE_HOUR);
+        }
+        assert_eq!(vol.annual, 0);
+    }
+
+    #[test]
+    fn annualizes_hourly_returns() {
+        let mut vol = setup();
+        let up = ONE + ONE / 100;
+        for i in 0..(VOL_WINDOW + 10) { // wraps around the window
+            vol.observe(if i % 2 == 0 { ONE } else { up }, i * ONE_HOUR);
+            // too soon after the last close, so it's not sampled
+            vol.observe(2 * ONE, i * ONE_HOUR + ONE_HOUR / 2);
+        }
+        // +1% and back every hour
+        let expected = 1.01_f64.ln() * (365.0 * 24.0_f64).sqrt();
+        let got = vol.annual as f64 / VOL_SCALE as f64;
+        assert!((got - expected).abs() < 1e-4, "{} vs {}", got, expected);
+    }
+}